@@ -0,0 +1,114 @@
+// Times every stage of the pipeline on its own, for each script in
+// test/benchmark, so that changes to one of them can be tracked over time.
+// Criterion keeps the results of the last run and says how far each benchmark
+// moved since.
+//
+//     cargo bench --bench pipeline -- [FILTER]
+//
+// only runs the benchmarks whose name, like `parse/fib.lox`, matches FILTER.
+// Interpreting takes seconds for most scripts, so it only gets the fewest
+// samples criterion allows.
+
+use std::fs;
+use std::hint::black_box;
+use std::path::PathBuf;
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use rjlox::config::Config;
+use rjlox::interpreter::Interpreter;
+use rjlox::shared::{Rc, RefCell};
+use rjlox::stmt::Stmt;
+
+const DIR: &str = "test/benchmark";
+
+/// Each script with its source, already scanned and parsed for the stages after
+struct Script {
+    name: String,
+    source: String,
+    tokens: Vec<rjlox::scanner::Token>,
+    statements: Vec<Stmt>,
+}
+
+fn scripts() -> Vec<Script> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(DIR)
+        .expect("The benchmarks are run from the root of the repository")
+        .flatten()
+        .map(|x| x.path())
+        .filter(|x| x.extension().is_some_and(|x| x == "lox"))
+        .collect();
+    paths.sort();
+    paths
+        .into_iter()
+        .map(|path| {
+            let source = fs::read_to_string(&path).unwrap();
+            let tokens = rjlox::scan(&source).expect("Benchmarks scan");
+            let statements = rjlox::parse(tokens.clone()).expect("Benchmarks parse");
+            Script {
+                name: path.file_name().unwrap().to_string_lossy().to_string(),
+                source,
+                tokens,
+                statements,
+            }
+        })
+        .collect()
+}
+
+fn interpreter() -> Rc<RefCell<Interpreter>> {
+    Rc::new(RefCell::new(Interpreter::with_config(&Config::default())))
+}
+
+fn pipeline(c: &mut Criterion) {
+    let scripts = scripts();
+
+    let mut group = c.benchmark_group("scan");
+    for script in &scripts {
+        group.bench_with_input(BenchmarkId::from_parameter(&script.name), script, |b, x| {
+            b.iter(|| black_box(rjlox::scan(&x.source)).ok())
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("parse");
+    for script in &scripts {
+        group.bench_with_input(BenchmarkId::from_parameter(&script.name), script, |b, x| {
+            b.iter_batched(
+                || x.tokens.clone(),
+                |tokens| black_box(rjlox::parse(tokens)).ok(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("resolve");
+    let resolver = interpreter();
+    for script in &scripts {
+        group.bench_with_input(BenchmarkId::from_parameter(&script.name), script, |b, x| {
+            b.iter(|| black_box(rjlox::resolve(&x.statements, &resolver)).ok())
+        });
+    }
+    group.finish();
+
+    // Each run gets an interpreter of its own, resolved before the clock starts
+    let mut group = c.benchmark_group("interpret");
+    group.sample_size(10);
+    for script in &scripts {
+        group.bench_with_input(BenchmarkId::from_parameter(&script.name), script, |b, x| {
+            b.iter_batched(
+                || {
+                    let interpreter = interpreter();
+                    rjlox::resolve(&x.statements, &interpreter).expect("Benchmarks resolve");
+                    interpreter
+                },
+                |interpreter| {
+                    rjlox::interpret(&x.statements, &interpreter).expect("Benchmarks run")
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, pipeline);
+criterion_main!(benches);
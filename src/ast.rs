@@ -0,0 +1,218 @@
+// Builds syntax trees without going through source text, for code generators
+// and tests. The tokens are made up: they all sit at line 0, column 0, so that
+// errors about them can't be mistaken for errors in a script.
+//
+//     use rjlox::ast::*;
+//     use rjlox::scanner::TokenType as TT;
+//     let program = vec![
+//         var_decl("x", Some(number(1.0))),
+//         print(binary(var("x"), TT::Plus, number(2.0))),
+//     ];
+
+use crate::expr::{Expr, ExprId};
+use crate::scanner::{Literal, Token, TokenType as TT};
+use crate::shared::Rc;
+use crate::stmt::Stmt;
+use crate::symbol::Symbol;
+
+fn token(token: TT, lexeme: &str) -> Token {
+    Token {
+        token,
+        lexeme: Symbol::intern(lexeme),
+        literal: Literal::None,
+        line: 0,
+        column: 0,
+        file: None,
+    }
+}
+
+fn name(name: &str) -> Token {
+    token(TT::Identifier, name)
+}
+
+/// An operator token, with the text the scanner would have given it
+fn operator(op: TT) -> Token {
+    let lexeme = match op {
+        TT::Minus => "-",
+        TT::Plus => "+",
+        TT::Slash => "/",
+        TT::Star => "*",
+        TT::Bang => "!",
+        TT::BangEqual => "!=",
+        TT::EqualEqual => "==",
+        TT::Greater => ">",
+        TT::GreaterEqual => ">=",
+        TT::Less => "<",
+        TT::LessEqual => "<=",
+        TT::And => "and",
+        TT::Or => "or",
+        TT::Comma => ",",
+        _ => panic!("{:?} is not an operator", op),
+    };
+    token(op, lexeme)
+}
+
+pub fn number(n: f64) -> Expr {
+    Expr::Literal(Literal::Double(n))
+}
+
+pub fn integer(n: i64) -> Expr {
+    Expr::Literal(Literal::Integer(n))
+}
+
+pub fn string(text: &str) -> Expr {
+    Expr::Literal(Literal::String(Rc::from(text)))
+}
+
+pub fn boolean(value: bool) -> Expr {
+    Expr::Literal(Literal::Boolean(value))
+}
+
+pub fn nil() -> Expr {
+    Expr::Literal(Literal::None)
+}
+
+pub fn var(variable: &str) -> Expr {
+    Expr::Variable(ExprId::fresh(), name(variable))
+}
+
+pub fn assign(variable: &str, value: Expr) -> Expr {
+    Expr::Assign(ExprId::fresh(), name(variable), Rc::new(value))
+}
+
+/// `op` is one of the arithmetic, comparison or equality operators, or
+/// `TT::Comma`
+pub fn binary(left: Expr, op: TT, right: Expr) -> Expr {
+    Expr::Binary(Rc::new(left), operator(op), Rc::new(right))
+}
+
+/// `op` is `TT::And` or `TT::Or`
+pub fn logical(left: Expr, op: TT, right: Expr) -> Expr {
+    Expr::Logical(Rc::new(left), operator(op), Rc::new(right))
+}
+
+/// `op` is `TT::Minus` or `TT::Bang`
+pub fn unary(op: TT, right: Expr) -> Expr {
+    Expr::Unary(operator(op), Rc::new(right))
+}
+
+pub fn grouping(expr: Expr) -> Expr {
+    Expr::Grouping(Rc::new(expr))
+}
+
+pub fn call(callee: Expr, arguments: Vec<Expr>) -> Expr {
+    let arguments = arguments.into_iter().map(Rc::new).collect();
+    Expr::Call(Rc::new(callee), token(TT::RightParen, ")"), arguments)
+}
+
+pub fn get(object: Expr, property: &str) -> Expr {
+    Expr::Get(ExprId::fresh(), Rc::new(object), name(property))
+}
+
+pub fn set(object: Expr, property: &str, value: Expr) -> Expr {
+    Expr::Set(Rc::new(object), name(property), Rc::new(value))
+}
+
+pub fn this() -> Expr {
+    Expr::This(ExprId::fresh(), token(TT::This, "this"))
+}
+
+/// `super.method`
+pub fn super_(method: &str) -> Expr {
+    Expr::Super(ExprId::fresh(), token(TT::Super, "super"), name(method))
+}
+
+pub fn expression(expr: Expr) -> Stmt {
+    Stmt::Expression(Rc::new(expr))
+}
+
+pub fn print(expr: Expr) -> Stmt {
+    Stmt::Print(token(TT::Print, "print"), Rc::new(expr))
+}
+
+/// `var variable = initializer;`, or just `var variable;` without one
+pub fn var_decl(variable: &str, initializer: Option<Expr>) -> Stmt {
+    Stmt::Var(name(variable), initializer.map(Rc::new), false)
+}
+
+/// `const constant = value;`
+pub fn const_decl(constant: &str, value: Expr) -> Stmt {
+    Stmt::Var(name(constant), Some(Rc::new(value)), true)
+}
+
+pub fn block(statements: Vec<Stmt>) -> Stmt {
+    Stmt::Block(statements.into())
+}
+
+pub fn if_(condition: Expr, then_branch: Stmt, else_branch: Option<Stmt>) -> Stmt {
+    Stmt::If(
+        Rc::new(condition),
+        Rc::new(then_branch),
+        else_branch.map(Rc::new),
+    )
+}
+
+pub fn while_(condition: Expr, body: Stmt) -> Stmt {
+    Stmt::While(token(TT::While, "while"), Rc::new(condition), Rc::new(body))
+}
+
+pub fn for_(
+    initializer: Option<Stmt>,
+    condition: Option<Expr>,
+    increment: Option<Expr>,
+    body: Stmt,
+) -> Stmt {
+    Stmt::For(
+        token(TT::For, "for"),
+        initializer.map(Rc::new),
+        condition.map(Rc::new),
+        increment.map(Rc::new),
+        Rc::new(body),
+    )
+}
+
+/// `loop { body }`
+pub fn loop_(body: Vec<Stmt>) -> Stmt {
+    Stmt::For(
+        name("loop"),
+        None,
+        None,
+        None,
+        Rc::new(Stmt::Block(body.into())),
+    )
+}
+
+/// `for (variable in collection) body`
+pub fn for_in(variable: &str, collection: Expr, body: Stmt) -> Stmt {
+    Stmt::ForIn(
+        token(TT::For, "for"),
+        name(variable),
+        Rc::new(collection),
+        Rc::new(body),
+    )
+}
+
+/// `return value;`, `nil` standing for a bare `return;`
+pub fn return_(value: Expr) -> Stmt {
+    Stmt::Return(token(TT::Return, "return"), Rc::new(value))
+}
+
+/// A function declaration, or a method in `class`
+pub fn function(function: &str, params: &[&str], body: Vec<Stmt>) -> Stmt {
+    let params = params.iter().map(|x| name(x)).collect();
+    Stmt::Function(name(function), params, body.into(), None)
+}
+
+/// `methods` are made with `function`
+pub fn class(class: &str, superclass: Option<&str>, methods: Vec<Stmt>) -> Stmt {
+    Stmt::Class(name(class), superclass.map(var), methods, None)
+}
+
+/// `test "name" { ... }`
+pub fn test(test: &str, body: Vec<Stmt>) -> Stmt {
+    let name = Token {
+        literal: Literal::String(Rc::from(test)),
+        ..token(TT::String, test)
+    };
+    Stmt::Test(name, body.into())
+}
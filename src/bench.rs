@@ -0,0 +1,84 @@
+use crate::cli::exit_code;
+use colored::Colorize;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_DIR: &str = "test/benchmark";
+pub const DEFAULT_REPEAT: usize = 3;
+
+/// All the Lox scripts directly inside `dir`, in a stable order
+fn scripts(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let entries = fs::read_dir(dir)
+        .map_err(|e| format!("Can't read benchmark directory '{}': {}", dir.display(), e))?;
+    let mut scripts: Vec<PathBuf> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "lox"))
+        .collect();
+    scripts.sort();
+    Ok(scripts)
+}
+
+/// Runs the script in a fresh interpreter process so that runs don't share any
+/// state, and returns how long it took. Output of the script is thrown away.
+fn time_script(exe: &Path, script: &Path) -> Result<Duration, String> {
+    let start = Instant::now();
+    let status = Command::new(exe)
+        .arg(script)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map_err(|e| e.to_string())?;
+    let elapsed = start.elapsed();
+    if status.success() {
+        Ok(elapsed)
+    } else {
+        Err(format!("exited with {}", status))
+    }
+}
+
+/// Runs every benchmark `repeat` times and prints min/mean timings as a Markdown
+/// table, the same format used in the README.
+pub fn run(dir: &Path, repeat: usize) -> i32 {
+    let exe = env::current_exe().expect("Can't find the rjlox executable...");
+    let scripts = match scripts(dir) {
+        Ok(scripts) => scripts,
+        Err(e) => {
+            eprintln!("{}", format!("Error: {}", e).red());
+            return exit_code::NO_INPUT;
+        }
+    };
+
+    let mut code = exit_code::OK;
+    println!("| Benchmark | Runs | Min (s) | Mean (s) |");
+    println!("|-----------|------|---------|----------|");
+    for script in scripts {
+        let name = script.file_name().unwrap_or_default().to_string_lossy();
+        let times: Result<Vec<Duration>, String> =
+            (0..repeat).map(|_| time_script(&exe, &script)).collect();
+        match times {
+            Ok(times) => {
+                let min = times.iter().min().copied().unwrap_or_default();
+                let mean = times.iter().sum::<Duration>() / repeat.max(1) as u32;
+                println!(
+                    "| {} | {} | {:.2} | {:.2} |",
+                    name,
+                    repeat,
+                    min.as_secs_f64(),
+                    mean.as_secs_f64()
+                );
+            }
+            Err(e) => {
+                eprintln!(
+                    "{}",
+                    format!("Error: Benchmark '{}' failed: {}", name, e).red()
+                );
+                code = exit_code::SOFTWARE;
+            }
+        }
+    }
+    code
+}
@@ -0,0 +1,24 @@
+// Both backends run calls in a loop of their own, not on the Rust stack, so a
+// call can't hold the guard `Span::enter` gives for as long as it runs. The
+// frame the call is in holds the span instead, entered when the call starts
+// and left when the frame goes.
+
+use tracing::Span;
+
+/// A span that is entered for as long as it is kept. Unlike `EnteredSpan`, it
+/// can be sent to other threads, as frames have to with the `sync` feature.
+#[derive(Debug)]
+pub struct CallSpan(Span);
+
+impl CallSpan {
+    pub fn enter(span: Span) -> Self {
+        span.with_subscriber(|(id, dispatch)| dispatch.enter(id));
+        CallSpan(span)
+    }
+}
+
+impl Drop for CallSpan {
+    fn drop(&mut self) {
+        self.0.with_subscriber(|(id, dispatch)| dispatch.exit(id));
+    }
+}
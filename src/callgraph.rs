@@ -0,0 +1,444 @@
+// Which functions call which, worked out from the syntax tree alone, for
+// `rjlox call-graph` to show recursion and the functions nothing calls. Without
+// running anything it can only guess at times:
+//
+// - `f()` is a call to the function or class `f` in scope where it is made,
+//   calling a class being a call to its `init`
+// - `this.m()` and `super.m()` call the `m` of the class or its superclasses
+// - `x.m()` may call any method `m`, whatever `x` is
+// - calls to what isn't declared in the scripts, natives say, are left out,
+//   and so are functions only passed around, which look uncalled
+//
+// The script itself and its tests are where calls start from.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+use serde::Serialize;
+
+use crate::expr::Expr;
+use crate::scanner::Token;
+use crate::stmt::Stmt;
+use crate::symbol::Symbol;
+
+#[derive(Debug)]
+pub struct CallGraph {
+    /// The script first, then functions, methods and tests as they are
+    /// declared
+    pub functions: Vec<Function>,
+    /// Which function calls which, by their index in `functions`, each pair
+    /// once
+    pub calls: Vec<(usize, usize)>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Function {
+    /// Methods are named after their class, and functions declared in
+    /// another one after it, like `Shape.area` or `outer.inner`
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    /// 0 for the script
+    pub line: usize,
+    /// Whether it runs without being called, being the script or a test
+    pub root: bool,
+    /// Whether some chain of calls from a root gets to it
+    pub reachable: bool,
+    /// Whether some chain of calls from it gets back to it
+    pub recursive: bool,
+}
+
+/// What the JSON is made of, with the calls by the names of the functions
+#[derive(Serialize)]
+struct Json<'a> {
+    functions: &'a [Function],
+    calls: Vec<Call<'a>>,
+}
+
+#[derive(Serialize)]
+struct Call<'a> {
+    from: &'a str,
+    to: &'a str,
+}
+
+impl CallGraph {
+    pub fn new(statements: &[Stmt]) -> Self {
+        let script = Function {
+            name: "<script>".into(),
+            file: None,
+            line: 0,
+            root: true,
+            reachable: true,
+            recursive: false,
+        };
+        let mut builder = Builder {
+            functions: vec![script],
+            calls: Vec::new(),
+            classes: Vec::new(),
+            scopes: vec![HashMap::new()],
+            caller: 0,
+            class: None,
+        };
+        builder.declare_globals(statements);
+        builder.stmts(statements);
+        let mut graph = CallGraph {
+            functions: builder.functions,
+            calls: builder.calls,
+        };
+        graph.analyze();
+        graph
+    }
+
+    fn analyze(&mut self) {
+        let mut callees = vec![Vec::new(); self.functions.len()];
+        for &(from, to) in &self.calls {
+            callees[from].push(to);
+        }
+        // Everything reached from the roots
+        let roots = (0..self.functions.len()).filter(|x| self.functions[*x].root);
+        for reached in reach(&callees, roots) {
+            self.functions[reached].reachable = true;
+        }
+        for (i, function) in self.functions.iter_mut().enumerate() {
+            function.recursive = reach(&callees, callees[i].iter().copied()).contains(&i);
+        }
+    }
+
+    /// A Graphviz graph, where the functions nothing reachable calls are
+    /// dashed and recursive ones have a double border
+    pub fn dot(&self) -> String {
+        let mut out =
+            String::from("digraph calls {\n  node [fontname=\"monospace\", shape=box];\n");
+        for (i, function) in self.functions.iter().enumerate() {
+            let mut attributes = format!("label=\"{}\"", function.name.replace('"', "\\\""));
+            if !function.reachable {
+                attributes.push_str(", style=dashed");
+            }
+            if function.recursive {
+                attributes.push_str(", peripheries=2");
+            }
+            let _ = writeln!(out, "  n{} [{}];", i, attributes);
+        }
+        for (from, to) in &self.calls {
+            let _ = writeln!(out, "  n{} -> n{};", from, to);
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    /// The functions, then the calls from one to the other by name
+    pub fn json(&self) -> String {
+        let calls = self
+            .calls
+            .iter()
+            .map(|&(from, to)| Call {
+                from: &self.functions[from].name,
+                to: &self.functions[to].name,
+            })
+            .collect::<Vec<_>>();
+        let json = Json {
+            functions: &self.functions,
+            calls,
+        };
+        let json = serde_json::to_string(&json).expect("Call graphs are plain data");
+        format!("{}\n", json)
+    }
+}
+
+/// The functions some chain of calls from `from` gets to, `from` included
+fn reach(callees: &[Vec<usize>], from: impl Iterator<Item = usize>) -> HashSet<usize> {
+    let mut reached = HashSet::new();
+    let mut pending = from.collect::<Vec<_>>();
+    while let Some(function) = pending.pop() {
+        if reached.insert(function) {
+            pending.extend(&callees[function]);
+        }
+    }
+    reached
+}
+
+/// What a name in scope stands for
+#[derive(Debug, Clone, Copy)]
+enum Target {
+    Function(usize),
+    Class(usize),
+    /// A variable or parameter, which could hold anything
+    Other,
+}
+
+struct Class {
+    superclass: Option<usize>,
+    methods: HashMap<Symbol, usize>,
+}
+
+struct Builder {
+    functions: Vec<Function>,
+    calls: Vec<(usize, usize)>,
+    classes: Vec<Class>,
+    scopes: Vec<HashMap<Symbol, Target>>,
+    /// The function whose body is being gone through
+    caller: usize,
+    /// The class whose methods are being gone through
+    class: Option<usize>,
+}
+
+impl Builder {
+    /// Globals can be called before they are declared, from functions
+    /// called later
+    fn declare_globals(&mut self, statements: &[Stmt]) {
+        for statement in statements {
+            match statement {
+                Stmt::Class(name, superclass, methods, _) => {
+                    let class = self.class(superclass.as_ref(), methods, &name.lexeme);
+                    self.scopes[0].insert(name.lexeme.clone(), Target::Class(class));
+                }
+                // Functions declared again stay the same function
+                Stmt::Function(name, _, _, _)
+                    if !matches!(self.scopes[0].get(&name.lexeme), Some(Target::Function(_))) =>
+                {
+                    let function = self.function(name.lexeme.to_string(), name);
+                    self.scopes[0].insert(name.lexeme.clone(), Target::Function(function));
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn function(&mut self, name: String, token: &Token) -> usize {
+        self.functions.push(Function {
+            name,
+            file: token.file.as_ref().map(|x| x.to_string()),
+            line: token.line,
+            root: false,
+            reachable: false,
+            recursive: false,
+        });
+        self.functions.len() - 1
+    }
+
+    /// Declares the methods of a class right away, for them to call each
+    /// other in any order
+    fn class(&mut self, superclass: Option<&Expr>, methods: &[Stmt], name: &str) -> usize {
+        let superclass = match superclass {
+            Some(Expr::Variable(_, parent)) => match self.lookup(&parent.lexeme) {
+                Some(Target::Class(parent)) => Some(parent),
+                _ => None,
+            },
+            _ => None,
+        };
+        let mut class = Class {
+            superclass,
+            methods: HashMap::new(),
+        };
+        for method in methods {
+            if let Stmt::Function(method, _, _, _) = method {
+                let function = self.function(format!("{}.{}", name, method.lexeme), method);
+                class.methods.insert(method.lexeme.clone(), function);
+            }
+        }
+        self.classes.push(class);
+        self.classes.len() - 1
+    }
+
+    fn lookup(&self, name: &Symbol) -> Option<Target> {
+        self.scopes.iter().rev().find_map(|x| x.get(name)).copied()
+    }
+
+    fn declare(&mut self, name: &Symbol, target: Target) {
+        let scope = self
+            .scopes
+            .last_mut()
+            .expect("There is always the global scope");
+        scope.insert(name.clone(), target);
+    }
+
+    /// The method `name` of `class` or the closest of its superclasses
+    fn method(&self, class: Option<usize>, name: &Symbol) -> Option<usize> {
+        let mut class = class;
+        while let Some(current) = class {
+            if let Some(method) = self.classes[current].methods.get(name) {
+                return Some(*method);
+            }
+            class = self.classes[current].superclass;
+        }
+        None
+    }
+
+    fn call(&mut self, callee: usize) {
+        let call = (self.caller, callee);
+        if !self.calls.contains(&call) {
+            self.calls.push(call);
+        }
+    }
+
+    fn stmts(&mut self, statements: &[Stmt]) {
+        for statement in statements {
+            self.stmt(statement);
+        }
+    }
+
+    /// Goes through `body` as the body of `function`
+    fn body(&mut self, function: usize, params: &[Token], body: &[Stmt]) {
+        let caller = std::mem::replace(&mut self.caller, function);
+        self.scopes.push(HashMap::new());
+        for param in params {
+            self.declare(&param.lexeme, Target::Other);
+        }
+        self.stmts(body);
+        self.scopes.pop();
+        self.caller = caller;
+    }
+
+    fn stmt(&mut self, statement: &Stmt) {
+        let global = self.scopes.len() == 1;
+        match statement {
+            Stmt::Block(statements) => {
+                self.scopes.push(HashMap::new());
+                self.stmts(statements);
+                self.scopes.pop();
+            }
+            Stmt::Class(name, superclass, methods, _) => {
+                let class = match self.lookup(&name.lexeme) {
+                    Some(Target::Class(class)) if global => class,
+                    _ => {
+                        let qualified = self.qualified(&name.lexeme);
+                        self.class(superclass.as_ref(), methods, &qualified)
+                    }
+                };
+                self.declare(&name.lexeme, Target::Class(class));
+                let enclosing = self.class.replace(class);
+                for method in methods {
+                    if let Stmt::Function(name, params, body, _) = method {
+                        if let Some(function) = self.method(Some(class), &name.lexeme) {
+                            self.body(function, params, body);
+                        }
+                    }
+                }
+                self.class = enclosing;
+            }
+            Stmt::Expression(expr) | Stmt::Print(_, expr) | Stmt::Return(_, expr) => {
+                self.expr(expr)
+            }
+            Stmt::For(_, initializer, condition, increment, body) => {
+                self.scopes.push(HashMap::new());
+                if let Some(initializer) = initializer {
+                    self.stmt(initializer);
+                }
+                for expr in [condition, increment].into_iter().flatten() {
+                    self.expr(expr);
+                }
+                self.stmt(body);
+                self.scopes.pop();
+            }
+            Stmt::ForIn(_, name, collection, body) => {
+                self.expr(collection);
+                self.scopes.push(HashMap::new());
+                self.declare(&name.lexeme, Target::Other);
+                self.stmt(body);
+                self.scopes.pop();
+            }
+            Stmt::Function(name, params, body, _) => {
+                let function = match self.lookup(&name.lexeme) {
+                    Some(Target::Function(function)) if global => function,
+                    _ => self.function(self.qualified(&name.lexeme), name),
+                };
+                // Declared before its body, which can call it
+                self.declare(&name.lexeme, Target::Function(function));
+                self.body(function, params, body);
+            }
+            Stmt::If(condition, then_branch, else_branch) => {
+                self.expr(condition);
+                self.stmt(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.stmt(else_branch);
+                }
+            }
+            Stmt::Test(name, body) => {
+                self.functions.push(Function {
+                    name: format!("test {}", name.literal),
+                    file: name.file.as_ref().map(|x| x.to_string()),
+                    line: name.line,
+                    root: true,
+                    reachable: false,
+                    recursive: false,
+                });
+                self.body(self.functions.len() - 1, &[], body);
+            }
+            Stmt::Var(name, initializer, _) => {
+                if let Some(initializer) = initializer {
+                    self.expr(initializer);
+                }
+                self.declare(&name.lexeme, Target::Other);
+            }
+            Stmt::While(_, condition, body) => {
+                self.expr(condition);
+                self.stmt(body);
+            }
+        }
+    }
+
+    /// Functions and classes declared in a function are named after it
+    fn qualified(&self, name: &str) -> String {
+        match self.caller {
+            0 => name.to_string(),
+            caller => format!("{}.{}", self.functions[caller].name, name),
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Call(callee, _, arguments) => {
+                self.callee(callee);
+                for argument in arguments {
+                    self.expr(argument);
+                }
+            }
+            Expr::Assign(_, _, value) | Expr::Grouping(value) | Expr::Unary(_, value) => {
+                self.expr(value)
+            }
+            Expr::Binary(left, _, right)
+            | Expr::Logical(left, _, right)
+            | Expr::Set(left, _, right) => {
+                self.expr(left);
+                self.expr(right);
+            }
+            Expr::Get(_, object, _) => self.expr(object),
+            Expr::Literal(_) | Expr::Super(_, _, _) | Expr::This(_, _) | Expr::Variable(_, _) => (),
+        }
+    }
+
+    fn callee(&mut self, callee: &Expr) {
+        match callee {
+            Expr::Variable(_, name) => match self.lookup(&name.lexeme) {
+                Some(Target::Function(function)) => self.call(function),
+                Some(Target::Class(class)) => {
+                    if let Some(init) = self.method(Some(class), &Symbol::intern("init")) {
+                        self.call(init);
+                    }
+                }
+                Some(Target::Other) | None => (),
+            },
+            Expr::Get(_, object, name) if matches!(**object, Expr::This(_, _)) => {
+                if let Some(method) = self.method(self.class, &name.lexeme) {
+                    self.call(method);
+                }
+            }
+            Expr::Get(_, object, name) => {
+                self.expr(object);
+                let methods = self
+                    .classes
+                    .iter()
+                    .filter_map(|x| x.methods.get(&name.lexeme).copied())
+                    .collect::<Vec<_>>();
+                methods.into_iter().for_each(|x| self.call(x));
+            }
+            Expr::Super(_, _, method) => {
+                let superclass = self.class.and_then(|x| self.classes[x].superclass);
+                if let Some(method) = self.method(superclass, &method.lexeme) {
+                    self.call(method);
+                }
+            }
+            callee => self.expr(callee),
+        }
+    }
+}
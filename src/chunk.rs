@@ -0,0 +1,134 @@
+use crate::shared::Rc;
+
+use crate::scanner::Token;
+use crate::symbol::Symbol;
+use crate::vm::Value;
+
+/// One instruction of the virtual machine. Operands are indices into the
+/// constants or names of the chunk, stack slots, upvalues or globals, argument
+/// counts and jump targets, all within the chunk of the function running.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Constant(u32),
+    Nil,
+    True,
+    False,
+    /// A variable declared without a value, which can't be read until assigned
+    Unassigned,
+    Pop,
+    GetLocal(u32),
+    SetLocal(u32),
+    GetGlobal(u32),
+    DefineGlobal(u32),
+    /// Defines a global declared with `const`, which `SetGlobal` then refuses
+    DefineConstant(u32),
+    SetGlobal(u32),
+    GetUpvalue(u32),
+    SetUpvalue(u32),
+    /// The operand is the name of the property
+    GetProperty(u32),
+    SetProperty(u32),
+    /// Looks a method up on the superclass on top of the stack, and binds it to
+    /// the instance below it
+    GetSuper(u32),
+    Equal,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Not,
+    Negate,
+    Print,
+    Jump(u32),
+    /// Jumps when the value on top of the stack is falsey, leaving it there
+    JumpIfFalse(u32),
+    JumpIfTrue(u32),
+    Call(u32),
+    /// `receiver.name(arguments)` in one go, without binding the method first
+    Invoke(u32, u32),
+    /// `super.name(arguments)`, with the superclass on top of the arguments
+    SuperInvoke(u32, u32),
+    /// Makes a closure of the function in the given constant, capturing the
+    /// upvalues it lists
+    Closure(u32),
+    /// Pops a local captured by a closure, moving it to the heap
+    CloseUpvalue,
+    Return,
+    Class(u32),
+    /// Copies the methods of the superclass on top of the stack into the class
+    /// below it, and pops the subclass
+    Inherit,
+    /// Adds the closure on top of the stack as a method of the class below it
+    Method(u32),
+}
+
+/// What the runtime errors of an instruction point at
+#[derive(Debug, Clone, PartialEq)]
+pub struct Site {
+    pub token: Token,
+    /// The object a property is looked up on, as written in the source, for
+    /// a hint when it turns out to be `nil`
+    pub subject: Option<Rc<str>>,
+    /// The closing parenthesis of a call fused into the instruction, like
+    /// `Invoke`, where calling errors point
+    pub paren: Option<Token>,
+}
+
+/// The compiled code of a function or script
+#[derive(Debug, Default)]
+pub struct Chunk {
+    pub code: Vec<Op>,
+    pub constants: Vec<Value>,
+    /// The properties, methods and classes named in the code
+    pub names: Vec<Symbol>,
+    /// Where each instruction came from, as an index into `sites`
+    locations: Vec<u32>,
+    sites: Vec<Site>,
+}
+
+impl Chunk {
+    pub fn write(&mut self, op: Op, site: Site) -> usize {
+        // Instructions in a row mostly come from the same token
+        if self.sites.last() != Some(&site) {
+            self.sites.push(site);
+        }
+        self.locations.push(self.sites.len() as u32 - 1);
+        self.code.push(op);
+        self.code.len() - 1
+    }
+
+    pub fn add_constant(&mut self, value: Value) -> u32 {
+        self.constants.push(value);
+        self.constants.len() as u32 - 1
+    }
+
+    /// The index of a name, the same one for the same name
+    pub fn add_name(&mut self, name: &Symbol) -> u32 {
+        match self.names.iter().position(|x| x == name) {
+            Some(index) => index as u32,
+            None => {
+                self.names.push(name.clone());
+                self.names.len() as u32 - 1
+            }
+        }
+    }
+
+    /// Where the instruction at `offset` came from
+    pub fn site(&self, offset: usize) -> &Site {
+        &self.sites[self.locations[offset] as usize]
+    }
+
+    /// Every site of the chunk, each one once
+    pub fn sites(&self) -> &[Site] {
+        &self.sites
+    }
+
+    /// The index into `sites` of where each instruction came from
+    pub fn locations(&self) -> &[u32] {
+        &self.locations
+    }
+}
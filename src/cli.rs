@@ -0,0 +1,372 @@
+use crate::bench;
+use crate::config::{ColorChoice, Config, ErrorFormat, Limits};
+use crate::error::Lint;
+use crate::sandbox::SandboxPolicy;
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+
+/// Exit codes, following the BSD sysexits.h convention like the book does
+pub mod exit_code {
+    pub const OK: i32 = 0;
+    /// Some of the checks done by `test` or `fmt --check` didn't pass
+    pub const FAILURE: i32 = 1;
+    pub const USAGE: i32 = 64;
+    /// The script has scanning, parsing or resolving errors
+    pub const DATA_ERROR: i32 = 65;
+    pub const NO_INPUT: i32 = 66;
+    /// `serve` couldn't listen where it was asked to
+    pub const UNAVAILABLE: i32 = 69;
+    /// The script failed at runtime
+    pub const SOFTWARE: i32 = 70;
+    /// A compiled script couldn't be saved
+    pub const CANT_CREATE: i32 = 73;
+    pub const CONFIG: i32 = 78;
+}
+
+const EXIT_CODES: &str = "Exit codes:
+  0   success
+  1   failed tests or files that need formatting
+  64  wrong command line usage
+  65  syntax or resolution errors in a script, or an invalid compiled one
+  66  a script or directory couldn't be read
+  69  the server couldn't listen on the address it was given
+  70  runtime error
+  73  a compiled script couldn't be saved
+  78  invalid config file
+
+64, 65 and 70 can be changed in the [exit-codes] section of the config file.";
+
+/// A tree-walking interpreter for the Lox language from Crafting Interpreters.
+///
+/// Without a subcommand, runs the given scripts, or starts a REPL when there are none.
+#[derive(Debug, Parser)]
+#[command(
+    name = "rjlox",
+    version,
+    args_conflicts_with_subcommands = true,
+    after_help = EXIT_CODES
+)]
+pub struct Cli {
+    /// When to color diagnostics [default: auto, or the config file]
+    #[arg(long, global = true, value_enum)]
+    pub color: Option<ColorChoice>,
+
+    /// How to print diagnostics [default: auto, or the config file]
+    #[arg(long, global = true, value_enum, visible_alias = "diagnostics")]
+    pub error_format: Option<ErrorFormat>,
+
+    /// Report warnings of this kind, also the ones off by default
+    #[arg(long, global = true, value_name = "LINT", value_enum)]
+    pub warn: Vec<Lint>,
+
+    /// Don't report warnings of this kind
+    #[arg(long, global = true, value_name = "LINT", value_enum)]
+    pub allow: Vec<Lint>,
+
+    /// Fail on warnings of this kind, as if they were errors
+    #[arg(long, global = true, value_name = "LINT", value_enum)]
+    pub deny: Vec<Lint>,
+
+    /// Make adding a number and a string a runtime error, as in the book
+    #[arg(long, global = true)]
+    pub strict: bool,
+
+    /// Make `print` a native function, called as `print(x)`, instead of a
+    /// statement
+    #[arg(long, global = true)]
+    pub native_print: bool,
+
+    /// Keep scripts away from files, the network, processes and the
+    /// environment, and cap the limits low enough for untrusted ones
+    #[arg(long, global = true)]
+    pub sandbox: bool,
+
+    /// Let scripts load native extensions with `loadExtension`, which run
+    /// outside of any limits
+    #[cfg(all(feature = "extensions", unix))]
+    #[arg(long, global = true, conflicts_with = "sandbox")]
+    pub allow_extensions: bool,
+
+    /// Trace to stderr how long each stage took for each script, with -vv
+    /// every call too, and with -vvv the tokens and statements made. Without
+    /// it, RUST_LOG picks what is traced.
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    pub verbose: u8,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[command(flatten)]
+    pub run: RunArgs,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run scripts in order, sharing the same globals
+    Run(RunArgs),
+    /// Start an interactive prompt evaluating expressions
+    Repl(ReplArgs),
+    /// Run the ```lox code blocks of Markdown documents in order, sharing the
+    /// same globals, and print the documents with what each block printed
+    /// after it
+    RunMd(RunMdArgs),
+    /// Serve a page to write and run scripts in from a browser, which runs
+    /// them here, sandboxed as in the browser playground
+    Serve {
+        #[arg(long, default_value_t = 8080)]
+        port: u16,
+        /// Where to listen, 0.0.0.0 for anyone on the network to be able to
+        #[arg(long, default_value = "127.0.0.1")]
+        host: String,
+    },
+    /// Scan, parse and resolve scripts without running them
+    Check {
+        #[arg(required = true)]
+        files: Vec<String>,
+    },
+    /// Compile a script to bytecode, which can then be run without scanning,
+    /// parsing and resolving it again
+    Compile {
+        /// Fold constant expressions before compiling
+        #[arg(long)]
+        opt: bool,
+        /// Where to save it [default: the script with a .loxc extension]
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+        file: String,
+    },
+    /// Rewrite scripts in the canonical style
+    Fmt {
+        /// Don't write anything, fail if some file would be changed
+        #[arg(long)]
+        check: bool,
+        #[arg(required = true)]
+        files: Vec<String>,
+    },
+    /// Run scripts and check them against their `// expect` comments, or run
+    /// the `test` blocks of the ones that have some
+    Test {
+        /// Skip scripts under this path, can be given several times
+        #[arg(long, value_name = "PATH")]
+        exclude: Vec<PathBuf>,
+        /// Only run the `test` blocks whose name contains this, skipping the
+        /// scripts checked against their comments
+        #[arg(long, value_name = "NAME")]
+        filter: Option<String>,
+        #[arg(default_value = "test")]
+        dir: PathBuf,
+    },
+    /// Time every script in a directory
+    Bench {
+        /// How many times to run each script
+        #[arg(long, value_name = "N", default_value_t = bench::DEFAULT_REPEAT)]
+        repeat: usize,
+        #[arg(default_value = bench::DEFAULT_DIR)]
+        dir: PathBuf,
+    },
+    /// Print the syntax tree of scripts
+    Ast {
+        #[arg(long, value_enum, default_value_t)]
+        format: AstFormat,
+        #[arg(required = true)]
+        files: Vec<String>,
+    },
+    /// Print which functions of scripts call which, as far as can be told
+    /// without running them, for all of them together
+    CallGraph {
+        #[arg(long, value_enum, default_value_t)]
+        format: CallGraphFormat,
+        #[arg(required = true)]
+        files: Vec<String>,
+    },
+    /// Translate scripts to another language, as one program, to run them
+    /// without the interpreter
+    Transpile {
+        #[arg(long, value_enum, default_value_t)]
+        target: Target,
+        #[arg(required = true)]
+        files: Vec<String>,
+    },
+    /// Print the classes, methods and functions of scripts, with their
+    /// parameters and the `///` comments right before them
+    Doc {
+        #[arg(long, value_enum, default_value_t)]
+        format: DocFormat,
+        #[arg(required = true)]
+        files: Vec<String>,
+    },
+    /// Print scripts with their syntax highlighted, what doesn't scan
+    /// included
+    Highlight {
+        #[arg(long, value_enum, default_value_t)]
+        format: HighlightFormat,
+        #[arg(required = true)]
+        files: Vec<String>,
+    },
+    /// Print the tokens of scripts
+    Tokens {
+        #[arg(required = true)]
+        files: Vec<String>,
+    },
+}
+
+/// How `ast` prints syntax trees
+#[derive(Debug, Default, Clone, Copy, PartialEq, ValueEnum)]
+pub enum AstFormat {
+    /// One S-expression per statement
+    #[default]
+    Text,
+    /// The statements of each script as a JSON array, which loads back into
+    /// `Vec<Stmt>` with serde
+    Json,
+    /// A Graphviz graph for each script, for `dot` to draw
+    Dot,
+}
+
+/// How `call-graph` prints calls
+#[derive(Debug, Default, Clone, Copy, PartialEq, ValueEnum)]
+pub enum CallGraphFormat {
+    /// A Graphviz graph, with the functions never called dashed and the
+    /// recursive ones circled twice
+    #[default]
+    Dot,
+    /// The functions, then the calls between them
+    Json,
+}
+
+/// What `transpile` translates scripts to
+#[derive(Debug, Default, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Target {
+    /// JavaScript, for Node.js or a browser, printing with `console.log`
+    #[default]
+    Js,
+}
+
+/// How `doc` prints documentation
+#[derive(Debug, Default, Clone, Copy, PartialEq, ValueEnum)]
+pub enum DocFormat {
+    #[default]
+    Markdown,
+    /// A page of its own
+    Html,
+}
+
+/// How `highlight` prints scripts
+#[derive(Debug, Default, Clone, Copy, PartialEq, ValueEnum)]
+pub enum HighlightFormat {
+    /// With escape codes, whatever the `--color` option
+    #[default]
+    Ansi,
+    /// A `pre` element to put in a page
+    Html,
+}
+
+/// Bounds for classrooms and autograders running untrusted programs
+#[derive(Debug, Default, Args)]
+pub struct LimitArgs {
+    /// Maximum number of nested calls [default: 1000]
+    #[arg(long, value_name = "N")]
+    pub max_stack_depth: Option<usize>,
+    /// Maximum number of statements executed and expressions evaluated
+    #[arg(long, value_name = "N")]
+    pub max_steps: Option<u64>,
+    /// Maximum number of bytes printed
+    #[arg(long, value_name = "N")]
+    pub max_output_bytes: Option<usize>,
+    /// Maximum number of bytes taken by variables, instances and strings,
+    /// roughly
+    #[arg(long, value_name = "N")]
+    pub max_memory_bytes: Option<usize>,
+}
+
+impl LimitArgs {
+    pub fn limits(&self) -> Limits {
+        Limits {
+            max_stack_depth: self.max_stack_depth,
+            max_steps: self.max_steps,
+            max_output_bytes: self.max_output_bytes,
+            max_memory_bytes: self.max_memory_bytes,
+        }
+    }
+}
+
+#[derive(Debug, Default, Args)]
+pub struct RunArgs {
+    /// Print every statement to stderr just before executing it
+    #[arg(long)]
+    pub trace: bool,
+    /// Print to stderr where every variable, `this` and `super` was found to
+    /// be: how many scopes up and in which slot, or among the globals
+    #[arg(long)]
+    pub resolve_dump: bool,
+    /// Report the time spent in each stage at the end
+    #[arg(long)]
+    pub time: bool,
+    /// Fold constant expressions before running
+    #[arg(long)]
+    pub opt: bool,
+    /// Compile to bytecode and run it on a virtual machine, which is faster
+    /// than walking the syntax tree but can't trace statements
+    #[arg(long, conflicts_with_all = ["trace", "leak_check", "coverage", "lcov"])]
+    pub vm: bool,
+    /// Report the objects still keeping each other alive at the end, and
+    /// where they were made
+    #[arg(long)]
+    pub leak_check: bool,
+    /// Show at the end how many times each line of the scripts ran, and
+    /// which branches were never taken
+    #[arg(long)]
+    pub coverage: bool,
+    /// Save which lines and branches ran to FILE, in the lcov format
+    #[arg(long, value_name = "FILE")]
+    pub lcov: Option<PathBuf>,
+    /// Scan scripts as they are read instead of reading them in whole first,
+    /// for very large ones. Diagnostics then don't quote the source.
+    #[arg(long)]
+    pub stream: bool,
+    #[command(flatten)]
+    pub limits: LimitArgs,
+    /// Scripts to run, in order, either sources or compiled `.loxc` files,
+    /// which always run on the virtual machine. `-` reads a script from
+    /// standard input, scanning it as it comes.
+    pub files: Vec<String>,
+}
+
+#[derive(Debug, Default, Args)]
+pub struct ReplArgs {
+    #[command(flatten)]
+    pub limits: LimitArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct RunMdArgs {
+    #[command(flatten)]
+    pub limits: LimitArgs,
+    #[arg(required = true)]
+    pub files: Vec<String>,
+}
+
+impl Cli {
+    /// Flags given on the command line win over the config file
+    pub fn merge_into(&self, limits: &LimitArgs, config: &mut Config) {
+        if let Some(color) = self.color {
+            config.color = color;
+        }
+        if let Some(error_format) = self.error_format {
+            config.error_format = error_format;
+        }
+        config.warn.extend(self.warn.iter().copied());
+        config.allow.extend(self.allow.iter().copied());
+        config.deny.extend(self.deny.iter().copied());
+        config.strict |= self.strict;
+        config.native_print |= self.native_print;
+        if self.sandbox {
+            config.sandbox = SandboxPolicy::sandboxed();
+        }
+        #[cfg(all(feature = "extensions", unix))]
+        {
+            config.sandbox.extensions |= self.allow_extensions;
+        }
+        config.limits = limits.limits().or(config.limits);
+    }
+}
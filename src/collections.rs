@@ -0,0 +1,316 @@
+// Lists and maps, which Lox has no syntax for, as host objects the natives
+// give scripts: `csvParse` and the like return them, `List()` and `Map()` make
+// empty ones. They and the natives using them only exist in the tree-walker,
+// as the VM has no host objects.
+//
+//     var rows = List();
+//     rows.push("a");
+//     rows.get(0);     // "a"
+//     rows.length;     // 1
+
+use std::any::Any;
+use std::collections::HashMap;
+
+use crate::csv;
+use crate::gc::{Trace, Tracer};
+use crate::interpreter::Interpreter;
+use crate::lox_callable::NativeFunction;
+use crate::lox_object::LoxObject;
+use crate::shared::{Rc, RefCell};
+use crate::value::Value;
+
+/// A growable list of values, indexed from 0
+#[derive(Debug, Default)]
+pub struct List(RefCell<Vec<Value>>);
+
+impl List {
+    pub fn new(values: Vec<Value>) -> Self {
+        List(RefCell::new(values))
+    }
+
+    /// A copy of what it holds, for natives taking lists
+    pub fn values(&self) -> Vec<Value> {
+        self.0.borrow().clone()
+    }
+
+    /// `value` as a list, if it is one
+    pub fn of(value: &Value) -> Option<&List> {
+        match value {
+            Value::Foreign(object) => object.as_any()?.downcast_ref(),
+            _ => None,
+        }
+    }
+
+    fn index(&self, index: &Value) -> Result<usize, String> {
+        match index.number() {
+            Some(n) if n.fract() == 0.0 && n >= 0.0 => {
+                let length = self.0.borrow().len();
+                (n < length as f64)
+                    .then_some(n as usize)
+                    .ok_or_else(|| format!("Index {} is out of range for a list of {}.", n, length))
+            }
+            _ => Err("List indices must be whole numbers.".into()),
+        }
+    }
+}
+
+impl LoxObject for List {
+    fn class_name(&self) -> &str {
+        "List"
+    }
+
+    fn get(&self, name: &str) -> Option<Value> {
+        (name == "length").then(|| Value::Integer(self.0.borrow().len() as i64))
+    }
+
+    fn method_arity(&self, name: &str) -> Option<usize> {
+        match name {
+            "pop" => Some(0),
+            "get" | "push" => Some(1),
+            "set" => Some(2),
+            _ => None,
+        }
+    }
+
+    fn call_method(
+        &self,
+        _: &mut Interpreter,
+        name: &str,
+        arguments: &[Value],
+    ) -> Result<Value, String> {
+        match name {
+            "get" => {
+                let index = self.index(&arguments[0])?;
+                Ok(self.0.borrow()[index].clone())
+            }
+            "set" => {
+                let index = self.index(&arguments[0])?;
+                self.0.borrow_mut()[index] = arguments[1].clone();
+                Ok(Value::None)
+            }
+            "push" => {
+                self.0.borrow_mut().push(arguments[0].clone());
+                Ok(Value::None)
+            }
+            _ => self
+                .0
+                .borrow_mut()
+                .pop()
+                .ok_or_else(|| "Can't pop from an empty list.".into()),
+        }
+    }
+
+    fn trace(&self, tracer: &mut Tracer) {
+        for value in self.values() {
+            value.trace(tracer);
+        }
+    }
+
+    fn as_any(&self) -> Option<&dyn Any> {
+        Some(self)
+    }
+}
+
+/// Values by string keys, which it keeps in the order they were first set
+#[derive(Debug, Default)]
+pub struct Map(RefCell<Vec<(Rc<str>, Value)>>);
+
+impl Map {
+    pub fn new(entries: Vec<(Rc<str>, Value)>) -> Self {
+        Map(RefCell::new(entries))
+    }
+
+    /// A copy of what it holds, in the order it was set
+    pub fn entries(&self) -> Vec<(Rc<str>, Value)> {
+        self.0.borrow().clone()
+    }
+
+    /// `value` as a map, if it is one
+    pub fn of(value: &Value) -> Option<&Map> {
+        match value {
+            Value::Foreign(object) => object.as_any()?.downcast_ref(),
+            _ => None,
+        }
+    }
+
+    fn key(key: &Value) -> Result<&str, String> {
+        match key {
+            Value::String(key) => Ok(key),
+            _ => Err("Map keys must be strings.".into()),
+        }
+    }
+}
+
+impl LoxObject for Map {
+    fn class_name(&self) -> &str {
+        "Map"
+    }
+
+    fn get(&self, name: &str) -> Option<Value> {
+        (name == "length").then(|| Value::Integer(self.0.borrow().len() as i64))
+    }
+
+    fn method_arity(&self, name: &str) -> Option<usize> {
+        match name {
+            "keys" => Some(0),
+            "get" | "has" => Some(1),
+            "set" => Some(2),
+            _ => None,
+        }
+    }
+
+    fn call_method(
+        &self,
+        _: &mut Interpreter,
+        name: &str,
+        arguments: &[Value],
+    ) -> Result<Value, String> {
+        if name == "keys" {
+            let keys = self
+                .0
+                .borrow()
+                .iter()
+                .map(|(key, _)| Value::String(key.clone()))
+                .collect();
+            return Ok(Value::Foreign(Rc::new(List::new(keys))));
+        }
+        let key = Map::key(&arguments[0])?;
+        let mut entries = self.0.borrow_mut();
+        let entry = entries.iter_mut().find(|(k, _)| &**k == key);
+        match (name, entry) {
+            ("get", entry) => Ok(entry.map_or(Value::None, |(_, value)| value.clone())),
+            ("has", entry) => Ok(Value::Boolean(entry.is_some())),
+            (_, Some((_, value))) => {
+                *value = arguments[1].clone();
+                Ok(Value::None)
+            }
+            (_, None) => {
+                entries.push((key.into(), arguments[1].clone()));
+                Ok(Value::None)
+            }
+        }
+    }
+
+    fn trace(&self, tracer: &mut Tracer) {
+        let values: Vec<Value> = self
+            .0
+            .borrow()
+            .iter()
+            .map(|(_, value)| value.clone())
+            .collect();
+        for value in values {
+            value.trace(tracer);
+        }
+    }
+
+    fn as_any(&self) -> Option<&dyn Any> {
+        Some(self)
+    }
+}
+
+/// `List()`, `Map()` and the natives reading and writing CSV with them
+pub fn natives() -> Vec<NativeFunction> {
+    vec![
+        NativeFunction::new("List", 0, |_, _| Ok(foreign(List::default()))),
+        NativeFunction::new("Map", 0, |_, _| Ok(foreign(Map::default()))),
+        NativeFunction::new("csvParse", 1, |_, arguments| {
+            let records = csv::parse(text(&arguments[0])?)?;
+            let records = records.into_iter().map(|record| {
+                let fields = record.into_iter().map(|x| Value::String(x.into()));
+                foreign(List::new(fields.collect()))
+            });
+            Ok(foreign(List::new(records.collect())))
+        }),
+        NativeFunction::new("csvRecords", 1, |_, arguments| {
+            let mut records = csv::parse(text(&arguments[0])?)?.into_iter();
+            let header: Vec<Rc<str>> = records
+                .next()
+                .unwrap_or_default()
+                .into_iter()
+                .map(Rc::from)
+                .collect();
+            let records = records.enumerate().map(|(i, record)| {
+                if record.len() != header.len() {
+                    return Err(format!(
+                        "Record {} has {} fields but the header has {}.",
+                        i + 1,
+                        record.len(),
+                        header.len()
+                    ));
+                }
+                let fields = record.into_iter().map(|x| Value::String(x.into()));
+                Ok(foreign(Map::new(
+                    header.iter().cloned().zip(fields).collect(),
+                )))
+            });
+            Ok(foreign(List::new(records.collect::<Result<_, _>>()?)))
+        }),
+        NativeFunction::new("csvStringify", 1, |_, arguments| {
+            let rows = List::of(&arguments[0]).ok_or("Rows must be a list of lists.")?;
+            let records = rows.values().into_iter().map(|row| {
+                let fields = List::of(&row).ok_or("Rows must be a list of lists.")?;
+                Ok(fields.values().iter().map(field).collect())
+            });
+            let records: Vec<Vec<String>> = records.collect::<Result<_, String>>()?;
+            Ok(Value::String(csv::write(&records).into()))
+        }),
+    ]
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(values: Vec<Value>) -> Self {
+        foreign(List::new(values))
+    }
+}
+
+/// The keys are sorted, since a `HashMap` has no order of its own to keep
+impl From<HashMap<String, Value>> for Value {
+    fn from(entries: HashMap<String, Value>) -> Self {
+        let mut entries: Vec<(Rc<str>, Value)> = entries
+            .into_iter()
+            .map(|(key, value)| (Rc::from(key), value))
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        foreign(Map::new(entries))
+    }
+}
+
+impl TryFrom<Value> for Vec<Value> {
+    type Error = Value;
+
+    fn try_from(value: Value) -> Result<Self, Value> {
+        List::of(&value).map(List::values).ok_or(value)
+    }
+}
+
+impl TryFrom<Value> for HashMap<String, Value> {
+    type Error = Value;
+
+    fn try_from(value: Value) -> Result<Self, Value> {
+        let entries = Map::of(&value).map(Map::entries).ok_or(value)?;
+        Ok(entries
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value))
+            .collect())
+    }
+}
+
+fn foreign(object: impl LoxObject + 'static) -> Value {
+    Value::Foreign(Rc::new(object))
+}
+
+fn text(value: &Value) -> Result<&str, String> {
+    match value {
+        Value::String(text) => Ok(text),
+        _ => Err("Argument must be a string.".into()),
+    }
+}
+
+/// A value as a CSV field, empty for `nil` and as printed otherwise
+fn field(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.to_string(),
+        Value::None => String::new(),
+        _ => value.to_string(),
+    }
+}
@@ -0,0 +1,632 @@
+use crate::shared::Rc;
+
+use crate::chunk::{Chunk, Op, Site};
+use crate::expr::Expr;
+use crate::formatter;
+use crate::scanner::{Literal, Token, TokenType as TT};
+use crate::stmt::Stmt;
+use crate::symbol::Symbol;
+use crate::vm::{Capture, Function, Value, Vm};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FunctionType {
+    Script,
+    Function,
+    Initializer,
+    Method,
+}
+
+struct Local {
+    name: Symbol,
+    depth: usize,
+    captured: bool,
+}
+
+/// A function being compiled, with the variables in scope so far
+struct FunctionState {
+    name: Option<Token>,
+    kind: FunctionType,
+    arity: usize,
+    chunk: Chunk,
+    /// Slot 0 holds the function itself, or the instance for methods
+    locals: Vec<Local>,
+    captures: Vec<Capture>,
+    scope_depth: usize,
+}
+
+impl FunctionState {
+    fn new(name: Option<Token>, kind: FunctionType, arity: usize) -> Self {
+        let receiver = match kind {
+            FunctionType::Initializer | FunctionType::Method => "this",
+            FunctionType::Script | FunctionType::Function => "",
+        };
+        FunctionState {
+            name,
+            kind,
+            arity,
+            chunk: Chunk::default(),
+            locals: vec![Local {
+                name: Symbol::intern(receiver),
+                depth: 0,
+                captured: false,
+            }],
+            captures: Vec::new(),
+            scope_depth: 0,
+        }
+    }
+
+    fn resolve_local(&self, name: &Symbol) -> Option<u32> {
+        self.locals
+            .iter()
+            .rposition(|x| x.name == *name)
+            .map(|x| x as u32)
+    }
+
+    fn add_capture(&mut self, capture: Capture) -> u32 {
+        match self.captures.iter().position(|x| *x == capture) {
+            Some(index) => index as u32,
+            None => {
+                self.captures.push(capture);
+                self.captures.len() as u32 - 1
+            }
+        }
+    }
+}
+
+/// Compiles resolved statements to bytecode for `vm`, which numbers their
+/// globals. The resolver already reported everything that could be wrong with
+/// them, so compiling can't fail.
+pub fn compile(statements: &[Stmt], vm: &mut Vm) -> Rc<Function> {
+    // Only instructions of constants come before the first token, and they can
+    // only fail at running out of steps
+    let start = Token {
+        token: TT::Eof,
+        lexeme: Symbol::intern(""),
+        literal: Literal::None,
+        line: 1,
+        column: 1,
+        file: None,
+    };
+    let mut compiler = Compiler {
+        vm,
+        functions: vec![FunctionState::new(None, FunctionType::Script, 0)],
+        site: Site {
+            token: start,
+            subject: None,
+            paren: None,
+        },
+    };
+    for stmt in statements {
+        compiler.statement(stmt);
+    }
+    compiler.emit(Op::Nil);
+    compiler.emit(Op::Return);
+    compiler.finish()
+}
+
+struct Compiler<'a> {
+    vm: &'a mut Vm,
+    /// The innermost one last
+    functions: Vec<FunctionState>,
+    /// Where the instructions being emitted come from
+    site: Site,
+}
+
+impl Compiler<'_> {
+    fn current(&mut self) -> &mut FunctionState {
+        self.functions
+            .last_mut()
+            .expect("The script is always compiled")
+    }
+
+    fn finish(&mut self) -> Rc<Function> {
+        let state = self.functions.pop().expect("The script is always compiled");
+        Rc::new(Function {
+            name: state.name,
+            arity: state.arity,
+            chunk: state.chunk,
+            captures: state.captures,
+        })
+    }
+
+    /// Makes the instructions emitted next point at `token`
+    fn at(&mut self, token: &Token) {
+        self.site = Site {
+            token: token.clone(),
+            subject: None,
+            paren: None,
+        };
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        let site = self.site.clone();
+        self.current().chunk.write(op, site)
+    }
+
+    /// Points the jump at `offset` to the next instruction
+    fn patch(&mut self, offset: usize) {
+        let target = self.current().chunk.code.len() as u32;
+        match &mut self.current().chunk.code[offset] {
+            Op::Jump(to) | Op::JumpIfFalse(to) | Op::JumpIfTrue(to) => *to = target,
+            _ => unreachable!("Only jumps are patched"),
+        }
+    }
+
+    fn here(&mut self) -> u32 {
+        self.current().chunk.code.len() as u32
+    }
+
+    fn constant(&mut self, value: Value) {
+        let index = self.current().chunk.add_constant(value);
+        self.emit(Op::Constant(index));
+    }
+
+    fn name(&mut self, name: &Symbol) -> u32 {
+        self.current().chunk.add_name(name)
+    }
+
+    fn begin_scope(&mut self) {
+        self.current().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        let state = self.current();
+        state.scope_depth -= 1;
+        let depth = state.scope_depth;
+        while let Some(local) = self.current().locals.pop_if(|x| x.depth > depth) {
+            self.emit(if local.captured {
+                Op::CloseUpvalue
+            } else {
+                Op::Pop
+            });
+        }
+    }
+
+    fn add_local(&mut self, name: &Symbol) {
+        let state = self.current();
+        let depth = state.scope_depth;
+        state.locals.push(Local {
+            name: name.clone(),
+            depth,
+            captured: false,
+        });
+    }
+
+    /// Defines the variable whose value is on top of the stack, where it is
+    /// already the local when in a scope
+    fn define(&mut self, name: &Symbol) {
+        self.define_variable(name, false);
+    }
+
+    /// `define`, for a `const` when `constant`. Only globals need to know, as
+    /// the resolver stops all the assignments to local constants.
+    fn define_variable(&mut self, name: &Symbol, constant: bool) {
+        if self.current().scope_depth > 0 {
+            self.add_local(name);
+        } else {
+            let index = self.vm.global_index(name) as u32;
+            self.emit(if constant {
+                Op::DefineConstant(index)
+            } else {
+                Op::DefineGlobal(index)
+            });
+        }
+    }
+
+    /// Where the function at `level` finds `name`, capturing it from the
+    /// enclosing functions if needed
+    fn resolve_upvalue(&mut self, level: usize, name: &Symbol) -> Option<u32> {
+        if level == 0 {
+            return None;
+        }
+        let enclosing = &mut self.functions[level - 1];
+        if let Some(slot) = enclosing.resolve_local(name) {
+            enclosing.locals[slot as usize].captured = true;
+            let capture = Capture {
+                local: true,
+                index: slot,
+            };
+            return Some(self.functions[level].add_capture(capture));
+        }
+        let index = self.resolve_upvalue(level - 1, name)?;
+        let capture = Capture {
+            local: false,
+            index,
+        };
+        Some(self.functions[level].add_capture(capture))
+    }
+
+    /// The instructions reading and writing the variable `name`
+    fn variable_ops(&mut self, name: &Symbol) -> (Op, Op) {
+        if let Some(slot) = self.current().resolve_local(name) {
+            return (Op::GetLocal(slot), Op::SetLocal(slot));
+        }
+        let level = self.functions.len() - 1;
+        if let Some(index) = self.resolve_upvalue(level, name) {
+            return (Op::GetUpvalue(index), Op::SetUpvalue(index));
+        }
+        let index = self.vm.global_index(name) as u32;
+        (Op::GetGlobal(index), Op::SetGlobal(index))
+    }
+
+    fn get_variable(&mut self, name: &Token) {
+        let (get, _) = self.variable_ops(&name.lexeme);
+        self.at(name);
+        self.emit(get);
+    }
+
+    fn statements(&mut self, statements: &[Stmt]) {
+        for stmt in statements {
+            self.statement(stmt);
+        }
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Block(statements) => {
+                self.begin_scope();
+                self.statements(statements);
+                self.end_scope();
+            }
+            Stmt::Class(name, superclass, methods, _) => self.class(name, superclass, methods),
+            Stmt::Expression(e) => {
+                self.expr(e);
+                self.emit(Op::Pop);
+            }
+            Stmt::For(keyword, initializer, condition, increment, body) => {
+                self.begin_scope();
+                if let Some(initializer) = initializer {
+                    self.statement(initializer);
+                }
+                let start = self.here();
+                let exit = condition.as_ref().map(|condition| {
+                    self.expr(condition);
+                    self.at(keyword);
+                    let exit = self.emit(Op::JumpIfFalse(0));
+                    self.emit(Op::Pop);
+                    exit
+                });
+                self.statement(body);
+                if let Some(increment) = increment {
+                    self.expr(increment);
+                    self.emit(Op::Pop);
+                }
+                self.at(keyword);
+                self.emit(Op::Jump(start));
+                if let Some(exit) = exit {
+                    self.patch(exit);
+                    self.emit(Op::Pop);
+                }
+                self.end_scope();
+            }
+            Stmt::ForIn(keyword, name, collection, body) => {
+                self.begin_scope();
+                self.expr(collection);
+                self.protocol(keyword, "iterator", Some(collection));
+                // In a slot of its own, which no variable can be named after
+                let iterator = self.current().locals.len() as u32;
+                self.add_local(&Symbol::intern(""));
+                let start = self.here();
+                self.emit(Op::GetLocal(iterator));
+                self.protocol(keyword, "done", None);
+                self.emit(Op::Not);
+                let exit = self.emit(Op::JumpIfFalse(0));
+                self.emit(Op::Pop);
+                // Every iteration binds the variable anew, like the resolver has it
+                self.begin_scope();
+                self.emit(Op::GetLocal(iterator));
+                self.protocol(keyword, "next", None);
+                self.add_local(&name.lexeme);
+                self.statement(body);
+                self.end_scope();
+                self.at(keyword);
+                self.emit(Op::Jump(start));
+                self.patch(exit);
+                self.emit(Op::Pop);
+                self.end_scope();
+            }
+            Stmt::Function(name, params, body, _) => {
+                // Declared first, so that the function can call itself
+                if self.current().scope_depth > 0 {
+                    self.add_local(&name.lexeme);
+                    self.function(name, params, body, FunctionType::Function);
+                } else {
+                    self.function(name, params, body, FunctionType::Function);
+                    self.define(&name.lexeme);
+                }
+            }
+            Stmt::If(condition, then_branch, else_branch) => {
+                // The arms of `else if` chains are compiled in a loop, and all
+                // jump to the end once taken
+                let mut arm = (condition, then_branch, else_branch);
+                let mut end_jumps = Vec::new();
+                loop {
+                    let (condition, then_branch, else_branch) = arm;
+                    self.expr(condition);
+                    let then_jump = self.emit(Op::JumpIfFalse(0));
+                    self.emit(Op::Pop);
+                    self.statement(then_branch);
+                    end_jumps.push(self.emit(Op::Jump(0)));
+                    self.patch(then_jump);
+                    self.emit(Op::Pop);
+                    match else_branch.as_deref() {
+                        Some(Stmt::If(condition, then_branch, else_branch)) => {
+                            arm = (condition, then_branch, else_branch)
+                        }
+                        Some(else_branch) => {
+                            self.statement(else_branch);
+                            break;
+                        }
+                        None => break,
+                    }
+                }
+                for jump in end_jumps {
+                    self.patch(jump);
+                }
+            }
+            Stmt::Print(keyword, e) => {
+                self.expr(e);
+                self.at(keyword);
+                self.emit(Op::Print);
+            }
+            Stmt::Return(keyword, e) => {
+                self.at(keyword);
+                // The resolver only lets initializers return without a value
+                if self.current().kind == FunctionType::Initializer {
+                    self.emit(Op::GetLocal(0));
+                } else {
+                    self.expr(e);
+                }
+                self.emit(Op::Return);
+            }
+            // Only `rjlox test` runs them, on the tree-walker
+            Stmt::Test(_, _) => (),
+            Stmt::Var(name, initializer, constant) => {
+                match initializer {
+                    Some(initializer) => self.expr(initializer),
+                    None => {
+                        self.at(name);
+                        self.emit(Op::Unassigned);
+                    }
+                }
+                self.at(name);
+                self.define_variable(&name.lexeme, *constant);
+            }
+            Stmt::While(keyword, condition, body) => {
+                let start = self.here();
+                self.expr(condition);
+                self.at(keyword);
+                let exit = self.emit(Op::JumpIfFalse(0));
+                self.emit(Op::Pop);
+                self.statement(body);
+                self.at(keyword);
+                self.emit(Op::Jump(start));
+                self.patch(exit);
+                self.emit(Op::Pop);
+            }
+        }
+    }
+
+    fn function(&mut self, name: &Token, params: &[Token], body: &[Stmt], kind: FunctionType) {
+        self.functions
+            .push(FunctionState::new(Some(name.clone()), kind, params.len()));
+        self.begin_scope();
+        for param in params {
+            self.add_local(&param.lexeme);
+        }
+        self.statements(body);
+        self.at(name);
+        if kind == FunctionType::Initializer {
+            self.emit(Op::GetLocal(0));
+        } else {
+            self.emit(Op::Nil);
+        }
+        self.emit(Op::Return);
+        let function = self.finish();
+        let index = self.current().chunk.add_constant(Value::Function(function));
+        self.at(name);
+        self.emit(Op::Closure(index));
+    }
+
+    fn class(&mut self, name: &Token, superclass: &Option<Expr>, methods: &[Stmt]) {
+        let class_name = self.name(&name.lexeme);
+        self.at(name);
+        self.emit(Op::Class(class_name));
+        self.define(&name.lexeme);
+
+        if let Some(Expr::Variable(_, parent)) = superclass {
+            self.get_variable(parent);
+            self.begin_scope();
+            self.add_local(&Symbol::intern("super"));
+            self.get_variable(name);
+            self.at(name);
+            self.emit(Op::Inherit);
+        }
+
+        self.get_variable(name);
+        for method in methods {
+            if let Stmt::Function(method_name, params, body, _) = method {
+                let kind = if method_name.lexeme == "init" {
+                    FunctionType::Initializer
+                } else {
+                    FunctionType::Method
+                };
+                self.function(method_name, params, body, kind);
+                let index = self.name(&method_name.lexeme);
+                self.emit(Op::Method(index));
+            }
+        }
+        self.emit(Op::Pop);
+
+        if superclass.is_some() {
+            self.end_scope();
+        }
+    }
+
+    /// Calls a method of the iteration protocol on the value on top of the
+    /// stack, its errors pointing at the `for` of the loop
+    fn protocol(&mut self, keyword: &Token, method: &str, subject: Option<&Expr>) {
+        let index = self.name(&Symbol::intern(method));
+        self.property_site(
+            subject.unwrap_or(&Expr::Literal(Literal::None)),
+            &with_lexeme(keyword, method),
+            Some(keyword),
+        );
+        self.emit(Op::Invoke(index, 0));
+    }
+
+    /// Where property errors point, with the object the property is looked
+    /// up on for the hint when it's `nil`
+    fn property_site(&mut self, obj: &Expr, name: &Token, paren: Option<&Token>) {
+        self.site = Site {
+            token: name.clone(),
+            subject: match obj {
+                Expr::Literal(_) => None,
+                _ => Some(formatter::expr(obj).into()),
+            },
+            paren: paren.cloned(),
+        };
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Assign(_, name, value) => {
+                self.expr(value);
+                let (_, set) = self.variable_ops(&name.lexeme);
+                self.at(name);
+                self.emit(set);
+            }
+            Expr::Binary(left, op, right) if op.token == TT::Comma => {
+                self.expr(left);
+                self.emit(Op::Pop);
+                self.expr(right);
+            }
+            Expr::Binary(left, op, right) => {
+                self.expr(left);
+                self.expr(right);
+                self.at(op);
+                let instruction = match op.token {
+                    TT::BangEqual => {
+                        self.emit(Op::Equal);
+                        Op::Not
+                    }
+                    TT::EqualEqual => Op::Equal,
+                    TT::Greater => Op::Greater,
+                    TT::GreaterEqual => Op::GreaterEqual,
+                    TT::Less => Op::Less,
+                    TT::LessEqual => Op::LessEqual,
+                    TT::Plus => Op::Add,
+                    TT::Minus => Op::Subtract,
+                    TT::Star => Op::Multiply,
+                    TT::Slash => Op::Divide,
+                    _ => unreachable!("The parser only makes binary operators of these"),
+                };
+                self.emit(instruction);
+            }
+            Expr::Call(callee, paren, arguments) => match &**callee {
+                Expr::Get(_, obj, name) => {
+                    self.expr(obj);
+                    self.arguments(arguments);
+                    let index = self.name(&name.lexeme);
+                    self.property_site(obj, name, Some(paren));
+                    self.emit(Op::Invoke(index, arguments.len() as u32));
+                }
+                Expr::Super(_, keyword, method) => {
+                    self.get_variable(&this(keyword));
+                    self.arguments(arguments);
+                    self.get_variable(&with_lexeme(keyword, "super"));
+                    let index = self.name(&method.lexeme);
+                    self.site = Site {
+                        token: method.clone(),
+                        subject: None,
+                        paren: Some(paren.clone()),
+                    };
+                    self.emit(Op::SuperInvoke(index, arguments.len() as u32));
+                }
+                _ => {
+                    self.expr(callee);
+                    self.arguments(arguments);
+                    self.at(paren);
+                    self.emit(Op::Call(arguments.len() as u32));
+                }
+            },
+            Expr::Get(_, obj, name) => {
+                self.expr(obj);
+                let index = self.name(&name.lexeme);
+                self.property_site(obj, name, None);
+                self.emit(Op::GetProperty(index));
+            }
+            Expr::Set(obj, name, value) => {
+                self.expr(obj);
+                self.expr(value);
+                let index = self.name(&name.lexeme);
+                self.property_site(obj, name, None);
+                self.emit(Op::SetProperty(index));
+            }
+            Expr::Super(_, keyword, method) => {
+                self.get_variable(&this(keyword));
+                self.get_variable(&with_lexeme(keyword, "super"));
+                let index = self.name(&method.lexeme);
+                self.at(method);
+                self.emit(Op::GetSuper(index));
+            }
+            Expr::This(_, keyword) => self.get_variable(keyword),
+            Expr::Grouping(inner) => self.expr(inner),
+            Expr::Literal(lit) => match lit {
+                Literal::Integer(n) => self.constant(Value::Integer(*n)),
+                Literal::Double(n) => self.constant(Value::Number(*n)),
+                Literal::String(s) => self.constant(Value::String(s.clone())),
+                Literal::Boolean(true) => {
+                    self.emit(Op::True);
+                }
+                Literal::Boolean(false) => {
+                    self.emit(Op::False);
+                }
+                _ => {
+                    self.emit(Op::Nil);
+                }
+            },
+            Expr::Logical(left, op, right) => {
+                self.expr(left);
+                self.at(op);
+                let jump = if op.token == TT::Or {
+                    self.emit(Op::JumpIfTrue(0))
+                } else {
+                    self.emit(Op::JumpIfFalse(0))
+                };
+                self.emit(Op::Pop);
+                self.expr(right);
+                self.patch(jump);
+            }
+            Expr::Unary(op, right) => {
+                self.expr(right);
+                self.at(op);
+                self.emit(if op.token == TT::Minus {
+                    Op::Negate
+                } else {
+                    Op::Not
+                });
+            }
+            Expr::Variable(_, name) => self.get_variable(name),
+        }
+    }
+
+    fn arguments(&mut self, arguments: &[Rc<Expr>]) {
+        for argument in arguments {
+            self.expr(argument);
+        }
+    }
+}
+
+/// `super` is only ever found with `this` in the same method, read with the
+/// position of the `super` keyword
+fn this(keyword: &Token) -> Token {
+    with_lexeme(keyword, "this")
+}
+
+fn with_lexeme(token: &Token, lexeme: &str) -> Token {
+    Token {
+        lexeme: Symbol::intern(lexeme),
+        ..token.clone()
+    }
+}
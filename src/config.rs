@@ -0,0 +1,285 @@
+use crate::cli::exit_code;
+use crate::error::{self, Level, Lint, Style};
+use crate::sandbox::SandboxPolicy;
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+
+/// Files looked up for defaults, the first one found wins. Both are TOML.
+const CONFIG_FILES: [&str; 2] = [".rjloxrc", "rjlox.toml"];
+
+/// Nested calls allowed unless configured otherwise, deep enough for any
+/// reasonable recursion while still catching runaway ones quickly
+pub const DEFAULT_MAX_STACK_DEPTH: usize = 1000;
+
+/// Bounds on what a script may do. Only the stack is bounded by default, so
+/// that runaway recursion is reported instead of eating up all the memory.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Limits {
+    pub max_stack_depth: Option<usize>,
+    pub max_steps: Option<u64>,
+    pub max_output_bytes: Option<usize>,
+    /// Checked against an estimate of what environments, instances and
+    /// strings take, which the garbage collector keeps up to date
+    pub max_memory_bytes: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_stack_depth: Some(DEFAULT_MAX_STACK_DEPTH),
+            max_steps: None,
+            max_output_bytes: None,
+            max_memory_bytes: None,
+        }
+    }
+}
+
+impl Limits {
+    /// Fills in every limit not set here with the one from `defaults`
+    pub fn or(self, defaults: Limits) -> Limits {
+        Limits {
+            max_stack_depth: self.max_stack_depth.or(defaults.max_stack_depth),
+            max_steps: self.max_steps.or(defaults.max_steps),
+            max_output_bytes: self.max_output_bytes.or(defaults.max_output_bytes),
+            max_memory_bytes: self.max_memory_bytes.or(defaults.max_memory_bytes),
+        }
+    }
+}
+
+/// The exit codes that tell apart the ways a script can fail, for graders and
+/// embedders whose conventions differ from the book's
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ExitCodes {
+    pub usage: i32,
+    pub data_error: i32,
+    pub software: i32,
+}
+
+impl Default for ExitCodes {
+    fn default() -> Self {
+        ExitCodes {
+            usage: exit_code::USAGE,
+            data_error: exit_code::DATA_ERROR,
+            software: exit_code::SOFTWARE,
+        }
+    }
+}
+
+impl ExitCodes {
+    /// The code to exit with instead of one from `exit_code`
+    pub fn map(&self, code: i32) -> i32 {
+        match code {
+            exit_code::USAGE => self.usage,
+            exit_code::DATA_ERROR => self.data_error,
+            exit_code::SOFTWARE => self.software,
+            _ => code,
+        }
+    }
+}
+
+/// How variables, functions, parameters and methods are to be named, classes
+/// always being PascalCase
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Naming {
+    /// `likeThis`, as the book does
+    #[default]
+    CamelCase,
+    /// `like_this`
+    SnakeCase,
+}
+
+impl fmt::Display for Naming {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Naming::CamelCase => "camelCase",
+            Naming::SnakeCase => "snake_case",
+        })
+    }
+}
+
+/// Settings of the lints only reported when asked for
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct LintConfig {
+    pub naming: Naming,
+    /// Statements a function may have, counting the ones nested in others
+    pub max_function_length: usize,
+    /// Files, or directories of them, that are only there for others to use
+    /// and so shouldn't print. Compared to the paths given on the command line.
+    pub libraries: Vec<PathBuf>,
+}
+
+impl Default for LintConfig {
+    fn default() -> Self {
+        LintConfig {
+            naming: Naming::default(),
+            max_function_length: 50,
+            libraries: Vec::new(),
+        }
+    }
+}
+
+impl LintConfig {
+    pub fn is_library(&self, path: &Path) -> bool {
+        self.libraries.iter().any(|x| path.starts_with(x))
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorChoice {
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Colors are only used for diagnostics, so `auto` looks at stderr and honors
+    /// the NO_COLOR convention (https://no-color.org)
+    pub fn apply(self) {
+        let enabled = match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                env::var_os("NO_COLOR").is_none_or(|x| x.is_empty()) && io::stderr().is_terminal()
+            }
+        };
+        colored::control::set_override(enabled);
+    }
+}
+
+/// How diagnostics are written to stderr
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorFormat {
+    /// `rich` when stderr is a terminal, `short` otherwise
+    #[default]
+    Auto,
+    /// Just the `[line X:Y]` message
+    Short,
+    /// The message followed by the offending source line, underlined
+    Rich,
+    /// One JSON object per line, for editors and graders
+    Json,
+}
+
+impl ErrorFormat {
+    pub fn apply(self) {
+        let style = match self {
+            ErrorFormat::Short => Style::Short,
+            ErrorFormat::Rich => Style::Rich,
+            ErrorFormat::Json => Style::Json,
+            ErrorFormat::Auto if io::stderr().is_terminal() => Style::Rich,
+            ErrorFormat::Auto => Style::Short,
+        };
+        error::set_style(style);
+    }
+}
+
+/// Defaults read from a config file. Command line flags take precedence over
+/// everything in here.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub prompt: String,
+    pub color: ColorChoice,
+    pub error_format: ErrorFormat,
+    pub limits: Limits,
+    /// Names of the native functions to define, all of them when missing
+    pub natives: Option<Vec<String>>,
+    /// Warnings reported on top of the default ones
+    pub warn: Vec<Lint>,
+    /// Warnings not reported at all
+    pub allow: Vec<Lint>,
+    /// Warnings that fail the script like errors
+    pub deny: Vec<Lint>,
+    pub lint: LintConfig,
+    /// Only add numbers to numbers and strings to strings, as the book does
+    pub strict: bool,
+    /// Make `print` a native function, called like any other, rather than a
+    /// statement
+    pub native_print: bool,
+    /// What scripts may reach outside of the interpreter, and caps on `limits`
+    pub sandbox: SandboxPolicy,
+    pub exit_codes: ExitCodes,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            prompt: ">>> ".into(),
+            color: ColorChoice::Auto,
+            error_format: ErrorFormat::Auto,
+            limits: Limits::default(),
+            natives: None,
+            warn: Vec::new(),
+            allow: Vec::new(),
+            deny: Vec::new(),
+            lint: LintConfig::default(),
+            strict: false,
+            native_print: false,
+            sandbox: SandboxPolicy::default(),
+            exit_codes: ExitCodes::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        toml::from_str(contents).map_err(|e| e.message().to_string())
+    }
+
+    /// Looks for a config file in the current directory, then in the home
+    /// directory, falling back to the defaults when there is none.
+    pub fn load() -> Result<Self, String> {
+        let dirs = [
+            Some(PathBuf::from(".")),
+            env::var_os("HOME").map(PathBuf::from),
+        ];
+        for dir in dirs.iter().flatten() {
+            for name in CONFIG_FILES {
+                let path = dir.join(name);
+                if let Ok(contents) = fs::read_to_string(&path) {
+                    return Config::parse(&contents)
+                        .map_err(|e| format!("Invalid config file '{}': {}", path.display(), e));
+                }
+            }
+        }
+        Ok(Config::default())
+    }
+
+    /// The limits scripts run with, once capped by the sandbox
+    pub fn effective_limits(&self) -> Limits {
+        self.sandbox.cap(self.limits)
+    }
+
+    /// `deny` wins over `allow`, which wins over `warn`, whatever the order they
+    /// were given in
+    pub fn level(&self, lint: Lint) -> Level {
+        let given = |lints: &[Lint]| lints.iter().any(|x| x.covers(lint));
+        if given(&self.deny) {
+            Level::Deny
+        } else if given(&self.allow) {
+            Level::Allow
+        } else if given(&self.warn) {
+            Level::Warn
+        } else {
+            lint.default_level()
+        }
+    }
+
+    pub fn native_enabled(&self, name: &str) -> bool {
+        self.natives
+            .as_ref()
+            .is_none_or(|natives| natives.iter().any(|x| x == name))
+    }
+}
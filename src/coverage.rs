@@ -0,0 +1,202 @@
+// Which lines and branches of scripts ran, for test suites written in Lox to
+// measure how much of the libraries they exercise. Lines are the ones
+// statements start on, blocks aside, and branches the two ways out of the
+// condition of an `if`, `while` or `for`.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::scanner::Token;
+use crate::shared::Rc;
+use crate::stmt::Stmt;
+
+#[derive(Debug, Default)]
+pub struct Coverage {
+    files: BTreeMap<Rc<str>, FileCoverage>,
+}
+
+#[derive(Debug, Default)]
+struct FileCoverage {
+    /// How many times statements starting on each line ran, for every line
+    /// with one
+    lines: BTreeMap<usize, u64>,
+    /// How many times each condition was true and false, by where the
+    /// statement branching on it starts
+    branches: BTreeMap<(usize, usize), [u64; 2]>,
+}
+
+impl FileCoverage {
+    fn lines_hit(&self) -> usize {
+        self.lines.values().filter(|x| **x > 0).count()
+    }
+
+    fn branches_hit(&self) -> usize {
+        self.branches.values().flatten().filter(|x| **x > 0).count()
+    }
+}
+
+impl Coverage {
+    /// Registers the lines and branches of `statements`, so that the ones that
+    /// never run are reported too. `script` is the file of the tokens that
+    /// don't name one.
+    pub fn add(&mut self, script: &Rc<str>, statements: &[Stmt]) {
+        for statement in statements {
+            self.add_stmt(script, statement);
+        }
+    }
+
+    fn add_stmt(&mut self, script: &Rc<str>, statement: &Stmt) {
+        if !matches!(statement, Stmt::Block(_)) {
+            if let Some(token) = statement.token() {
+                self.file(script, token)
+                    .lines
+                    .entry(token.line)
+                    .or_default();
+            }
+        }
+        if let Some(token) = condition(statement) {
+            let position = (token.line, token.column);
+            self.file(script, token)
+                .branches
+                .entry(position)
+                .or_default();
+        }
+        match statement {
+            Stmt::Block(statements)
+            | Stmt::Function(_, _, statements, _)
+            | Stmt::Test(_, statements) => self.add(script, statements),
+            // The methods are only declared along with the class, so only
+            // their bodies ever run
+            Stmt::Class(_, _, methods, _) => {
+                for method in methods {
+                    if let Stmt::Function(_, _, body, _) = method {
+                        self.add(script, body);
+                    }
+                }
+            }
+            Stmt::For(_, initializer, _, _, body) => {
+                if let Some(initializer) = initializer {
+                    self.add_stmt(script, initializer);
+                }
+                self.add_stmt(script, body);
+            }
+            Stmt::ForIn(_, _, _, body) => self.add_stmt(script, body),
+            Stmt::If(_, then_branch, else_branch) => {
+                self.add_stmt(script, then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.add_stmt(script, else_branch);
+                }
+            }
+            Stmt::While(_, _, body) => self.add_stmt(script, body),
+            Stmt::Expression(_) | Stmt::Print(_, _) | Stmt::Return(_, _) | Stmt::Var(_, _, _) => (),
+        }
+    }
+
+    fn file(&mut self, script: &Rc<str>, token: &Token) -> &mut FileCoverage {
+        let file = token.file.as_ref().unwrap_or(script);
+        if !self.files.contains_key(file) {
+            self.files.insert(file.clone(), FileCoverage::default());
+        }
+        self.files.get_mut(file).expect("Just inserted")
+    }
+
+    /// Counts `statement` as run, blocks aside
+    pub(crate) fn hit(&mut self, script: &Rc<str>, statement: &Stmt) {
+        if let Some(token) = statement.token() {
+            *self
+                .file(script, token)
+                .lines
+                .entry(token.line)
+                .or_default() += 1;
+        }
+    }
+
+    /// Counts the way taken out of the condition of `statement`, if it has one
+    pub(crate) fn branch(&mut self, script: &Rc<str>, statement: &Stmt, taken: bool) {
+        if let Some(token) = condition(statement) {
+            let position = (token.line, token.column);
+            let branches = self.file(script, token).branches.entry(position);
+            branches.or_default()[usize::from(!taken)] += 1;
+        }
+    }
+
+    /// The report in the lcov tracefile format, which `genhtml`, editors and
+    /// coverage services read
+    pub fn lcov(&self) -> String {
+        let mut out = String::new();
+        for (file, coverage) in &self.files {
+            let _ = writeln!(out, "TN:\nSF:{}", file);
+            let mut previous = (0, 0);
+            for (&(line, _), taken) in &coverage.branches {
+                // Branches are told apart by their index among those on the line
+                let block = if previous.0 == line {
+                    previous.1 + 1
+                } else {
+                    0
+                };
+                previous = (line, block);
+                let ran = taken.iter().any(|x| *x > 0);
+                for (branch, count) in taken.iter().enumerate() {
+                    let count = if ran { count.to_string() } else { "-".into() };
+                    let _ = writeln!(out, "BRDA:{},{},{},{}", line, block, branch, count);
+                }
+            }
+            let _ = writeln!(out, "BRF:{}", coverage.branches.len() * 2);
+            let _ = writeln!(out, "BRH:{}", coverage.branches_hit());
+            for (line, count) in &coverage.lines {
+                let _ = writeln!(out, "DA:{},{}", line, count);
+            }
+            let _ = writeln!(out, "LF:{}", coverage.lines.len());
+            let _ = writeln!(out, "LH:{}", coverage.lines_hit());
+            out.push_str("end_of_record\n");
+        }
+        out
+    }
+
+    /// A summary of every file, each followed by its source when `source`
+    /// has it, with the times each line ran in front. Lines that never ran
+    /// are marked `#####`, and `!` follows the count of lines with a branch
+    /// never taken.
+    pub fn annotate<'a>(&self, source: impl Fn(&str) -> Option<&'a str>) -> String {
+        let mut out = String::new();
+        for (file, coverage) in &self.files {
+            let _ = writeln!(
+                out,
+                "[coverage] {}: {}/{} lines, {}/{} branches",
+                file,
+                coverage.lines_hit(),
+                coverage.lines.len(),
+                coverage.branches_hit(),
+                coverage.branches.len() * 2
+            );
+            let Some(source) = source(file) else {
+                continue;
+            };
+            for (number, text) in source.lines().enumerate().map(|(i, x)| (i + 1, x)) {
+                let count = match coverage.lines.get(&number) {
+                    Some(0) => "#####".to_string(),
+                    Some(count) => count.to_string(),
+                    None => String::new(),
+                };
+                let partial = coverage
+                    .branches
+                    .range((number, 0)..(number + 1, 0))
+                    .any(|(_, taken)| taken.contains(&0));
+                let mark = if partial { '!' } else { ' ' };
+                let _ = writeln!(out, "{:>6}{} | {}", count, mark, text);
+            }
+        }
+        out
+    }
+}
+
+/// Where the statement starts, for the statements that branch on a condition.
+/// Conditions made of a literal have no token of their own.
+fn condition(statement: &Stmt) -> Option<&Token> {
+    match statement {
+        Stmt::If(_, _, _) | Stmt::While(_, _, _) | Stmt::For(_, _, Some(_), _, _) => {
+            statement.token()
+        }
+        _ => None,
+    }
+}
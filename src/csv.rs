@@ -0,0 +1,80 @@
+// CSV as RFC 4180 has it, for the natives reading and writing it: fields
+// separated by commas, records by newlines, and quotes around fields holding
+// either or quotes, which are doubled. Every field is a string.
+
+use std::borrow::Cow;
+
+/// The records of `text`. Lines may end in `\r\n` too, and the last one
+/// needn't end at all.
+pub fn parse(text: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut line = 1;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if field.is_empty() => {
+                let start = line;
+                loop {
+                    match chars.next() {
+                        Some('"') if chars.peek() == Some(&'"') => {
+                            chars.next();
+                            field.push('"');
+                        }
+                        Some('"') => break,
+                        Some(c) => {
+                            line += (c == '\n') as usize;
+                            field.push(c);
+                        }
+                        None => {
+                            return Err(format!("Unterminated quoted field on line {}.", start))
+                        }
+                    }
+                }
+                match chars.peek() {
+                    None | Some(',' | '\n' | '\r') => (),
+                    Some(_) => {
+                        return Err(format!(
+                            "Expected a comma after the quoted field on line {}.",
+                            line
+                        ))
+                    }
+                }
+            }
+            ',' => record.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => (),
+            '\n' => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+                line += 1;
+            }
+            c => field.push(c),
+        }
+    }
+    if !text.is_empty() && !text.ends_with('\n') {
+        record.push(field);
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// `records` as CSV, with every line ending in a newline
+pub fn write(records: &[Vec<String>]) -> String {
+    let mut text = String::new();
+    for record in records {
+        let fields: Vec<_> = record.iter().map(|x| quoted(x)).collect();
+        text.push_str(&fields.join(","));
+        text.push('\n');
+    }
+    text
+}
+
+/// `field` in quotes if it has to be
+fn quoted(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
@@ -0,0 +1,124 @@
+// What `rjlox doc` prints: the classes, methods and functions declared at the
+// top of scripts, with their parameters and the `///` comments right before
+// them. Docs are written in Markdown, which the HTML keeps as plain text.
+
+use std::fmt::Write;
+
+use crate::expr::Expr;
+use crate::stmt::Stmt;
+
+/// A documented declaration, methods only ever having no methods of their own
+struct Item<'a> {
+    signature: String,
+    doc: Option<&'a str>,
+    methods: Vec<Item<'a>>,
+}
+
+fn items(statements: &[Stmt]) -> Vec<Item<'_>> {
+    statements.iter().filter_map(item).collect()
+}
+
+fn item(statement: &Stmt) -> Option<Item<'_>> {
+    match statement {
+        Stmt::Class(name, superclass, methods, doc) => {
+            let superclass = match superclass {
+                Some(Expr::Variable(_, parent)) => format!(" < {}", parent.lexeme),
+                _ => String::new(),
+            };
+            Some(Item {
+                signature: format!("class {}{}", name.lexeme, superclass),
+                doc: doc.as_deref(),
+                methods: methods.iter().filter_map(method).collect(),
+            })
+        }
+        Stmt::Function(_, _, _, doc) => Some(Item {
+            signature: format!("fun {}", signature(statement)?),
+            doc: doc.as_deref(),
+            methods: Vec::new(),
+        }),
+        _ => None,
+    }
+}
+
+fn method(statement: &Stmt) -> Option<Item<'_>> {
+    let Stmt::Function(_, _, _, doc) = statement else {
+        return None;
+    };
+    Some(Item {
+        signature: signature(statement)?,
+        doc: doc.as_deref(),
+        methods: Vec::new(),
+    })
+}
+
+fn signature(function: &Stmt) -> Option<String> {
+    let Stmt::Function(name, params, _, _) = function else {
+        return None;
+    };
+    let params = params.iter().map(|x| &*x.lexeme).collect::<Vec<_>>();
+    Some(format!("{}({})", name.lexeme, params.join(", ")))
+}
+
+/// One section per file, named as given
+pub fn markdown(files: &[(&str, &[Stmt])]) -> String {
+    let mut out = String::new();
+    for (i, (file, statements)) in files.iter().enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        let _ = writeln!(out, "# {}", file);
+        for item in items(statements) {
+            markdown_item(&mut out, &item, "##");
+            for method in &item.methods {
+                markdown_item(&mut out, method, "###");
+            }
+        }
+    }
+    out
+}
+
+fn markdown_item(out: &mut String, item: &Item, heading: &str) {
+    let _ = writeln!(out, "\n{} `{}`", heading, item.signature);
+    if let Some(doc) = item.doc {
+        let _ = writeln!(out, "\n{}", doc);
+    }
+}
+
+/// A page of its own, with a paragraph for each blank line separated part of
+/// the docs
+pub fn html(files: &[(&str, &[Stmt])]) -> String {
+    let mut out = String::from(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>Documentation</title>\n</head>\n<body>\n",
+    );
+    for (file, statements) in files {
+        let _ = writeln!(out, "<h1>{}</h1>", escape(file));
+        for item in items(statements) {
+            html_item(&mut out, &item, "h2");
+            for method in &item.methods {
+                html_item(&mut out, method, "h3");
+            }
+        }
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+fn html_item(out: &mut String, item: &Item, heading: &str) {
+    let _ = writeln!(
+        out,
+        "<{0}><code>{1}</code></{0}>",
+        heading,
+        escape(&item.signature)
+    );
+    for paragraph in item.doc.iter().flat_map(|x| x.split("\n\n")) {
+        let _ = writeln!(out, "<p>{}</p>", escape(paragraph.trim()));
+    }
+}
+
+pub(crate) fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,1100 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::process::exit;
+use std::time::{Duration, Instant};
+use tracing::span::EnteredSpan;
+use tracing::{field, info_span, trace};
+
+use clap::Parser as _;
+use colored::Colorize;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::{CmdKind, Highlighter};
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Editor, Helper};
+
+use crate::callgraph::CallGraph;
+use crate::cli::{
+    exit_code, AstFormat, CallGraphFormat, Cli, Command, DocFormat, HighlightFormat, LimitArgs,
+    RunArgs, Target,
+};
+use crate::config::Config;
+use crate::coverage::Coverage;
+use crate::error::{self, Level, LoxError, Style, Warning};
+use crate::interpreter::Interpreter;
+use crate::parser::Parser;
+use crate::resolver::{Reference, Resolver};
+use crate::scanner::{self, Scanner};
+use crate::shared::{Rc, RefCell};
+use crate::stmt::Stmt;
+use crate::vm::Vm;
+use crate::{
+    bench, compiler, doc, formatter, graphviz, highlight, javascript, linter, loxc, notebook,
+    optimizer, server, test_runner,
+};
+
+/// Time spent in each stage of the pipeline, summed over all the files run
+#[derive(Debug, Default)]
+struct Timings {
+    scanning: Duration,
+    parsing: Duration,
+    resolving: Duration,
+    interpreting: Duration,
+}
+
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = self.scanning + self.parsing + self.resolving + self.interpreting;
+        writeln!(f, "[time] scanning:     {:>12.3?}", self.scanning)?;
+        writeln!(f, "[time] parsing:      {:>12.3?}", self.parsing)?;
+        writeln!(f, "[time] resolving:    {:>12.3?}", self.resolving)?;
+        writeln!(f, "[time] interpreting: {:>12.3?}", self.interpreting)?;
+        write!(f, "[time] total:        {:>12.3?}", total)
+    }
+}
+
+/// Runs scripts for the command line, reporting what goes wrong on stderr
+struct Driver {
+    interpreter: Rc<RefCell<Interpreter>>,
+    timings: Timings,
+    config: Config,
+    /// Every script read so far, by file name, to quote in diagnostics
+    sources: HashMap<Rc<str>, String>,
+    /// Whether to fold constants between parsing and resolving
+    optimize: bool,
+    /// Runs the scripts instead of the interpreter when there is one
+    vm: Option<Vm>,
+    /// Whether to scan scripts as they are read rather than read them first
+    stream: bool,
+    /// Whether to print where the references of scripts point once resolved
+    resolve_dump: bool,
+}
+
+impl Driver {
+    pub fn new(config: Config) -> Self {
+        Driver {
+            interpreter: Rc::new(RefCell::new(Interpreter::with_config(&config))),
+            timings: Timings::default(),
+            config,
+            sources: HashMap::new(),
+            optimize: false,
+            vm: None,
+            stream: false,
+            resolve_dump: false,
+        }
+    }
+
+    /// Reads a script and makes it the current one
+    fn read(&mut self, filename: &str) -> Result<String, i32> {
+        let contents = read_source(filename)?;
+        let name = self.make_current(filename);
+        self.sources.insert(name, contents.clone());
+        Ok(contents)
+    }
+
+    /// Opens a script to read as it is scanned and makes it the current one,
+    /// standard input for `-`. Diagnostics can't quote it, as it isn't kept.
+    fn open(&mut self, filename: &str) -> Result<Box<dyn BufRead>, i32> {
+        self.make_current(filename);
+        if filename == STDIN {
+            return Ok(Box::new(io::stdin().lock()));
+        }
+        match fs::File::open(filename) {
+            Ok(file) => Ok(Box::new(BufReader::new(file))),
+            Err(e) => {
+                eprintln!(
+                    "{}",
+                    format!("Error: Can't read '{}': {}", filename, e).red()
+                );
+                Err(exit_code::NO_INPUT)
+            }
+        }
+    }
+
+    fn make_current(&mut self, filename: &str) -> Rc<str> {
+        let name: Rc<str> = Rc::from(filename);
+        if let Some(vm) = &mut self.vm {
+            vm.script = name.clone();
+        }
+        self.interpreter.borrow_mut().script = name.clone();
+        name
+    }
+
+    /// The script `file` refers to, the current one when it is unnamed because
+    /// there is only one
+    fn source(&self, file: Option<Rc<str>>) -> (Rc<str>, Option<&str>) {
+        let file = file.unwrap_or_else(|| self.interpreter.borrow().script.clone());
+        let source = self.sources.get(&file).map(|x| x.as_str());
+        (file, source)
+    }
+
+    /// Diagnostics go wherever the interpreter writes to, nowhere if that fails
+    fn warn(&self, warning: &Warning) {
+        let (file, source) = self.source(warning.token.file.clone());
+        let _ = warn(
+            &mut self.interpreter.borrow_mut().stderr,
+            warning,
+            &file,
+            source,
+        );
+    }
+
+    /// Warnings can't all be found before running, like adding a number to a string
+    fn warn_at_runtime(&mut self) {
+        let warnings = match &mut self.vm {
+            Some(vm) => vm.take_warnings(),
+            None => self.interpreter.borrow_mut().take_warnings(),
+        };
+        warnings.iter().for_each(|x| self.warn(x));
+    }
+
+    fn report(&self, error: &LoxError) {
+        let (file, source) = self.source(error.location().and_then(|x| x.file));
+        let _ = report(
+            &mut self.interpreter.borrow_mut().stderr,
+            error,
+            &file,
+            source,
+        );
+    }
+
+    /// Everything still in a cycle at the end of the run, which plain reference
+    /// counting would never free
+    fn report_cycles(&self) {
+        let cycles = self.interpreter.borrow_mut().cycles();
+        for cycle in &cycles {
+            match cycle.len() {
+                1 => eprintln!("[leak-check] Object referring to itself:"),
+                n => eprintln!("[leak-check] Reference cycle between {} objects:", n),
+            }
+            cycle.iter().for_each(|x| eprintln!("  {}", x));
+        }
+        match cycles.len() {
+            1 => eprintln!("[leak-check] 1 cycle found."),
+            n => eprintln!("[leak-check] {} cycles found.", n),
+        }
+    }
+
+    /// Shows the scripts annotated with how many times each line ran when
+    /// `annotate`, and saves the lcov report to `lcov` when given
+    fn report_coverage(&self, annotate: bool, lcov: Option<&Path>) -> Result<(), i32> {
+        let interpreter = self.interpreter.borrow();
+        let Some(coverage) = &interpreter.coverage else {
+            return Ok(());
+        };
+        if annotate {
+            eprint!(
+                "{}",
+                coverage.annotate(|file| self.sources.get(file).map(|x| x.as_str()))
+            );
+        }
+        if let Some(lcov) = lcov {
+            if let Err(e) = fs::write(lcov, coverage.lcov()) {
+                eprintln!(
+                    "{}",
+                    format!("Error: Can't write '{}': {}", lcov.display(), e).red()
+                );
+                return Err(exit_code::CANT_CREATE);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn run_files(&mut self, filenames: &[String]) -> i32 {
+        // Diagnostics only mention the file when there is more than one to choose from
+        let named = filenames.len() > 1;
+        let mut ahead = self.parse_ahead(filenames, named).into_iter();
+        for filename in filenames {
+            let _span = info_span!("script", file = %filename).entered();
+            let file = named.then(|| Rc::from(filename.as_str()));
+            let ran = if let Some((contents, parsed)) = ahead.next().flatten() {
+                let name = self.make_current(filename);
+                self.sources.insert(name, contents);
+                self.run_parsed(parsed)
+            } else if loxc::is_compiled(filename) {
+                self.run_compiled(filename, named)
+            } else if self.stream || filename == STDIN {
+                match self.open(filename) {
+                    Ok(reader) => self.run(Scanner::from_reader(reader, file)),
+                    Err(err) => return err,
+                }
+            } else {
+                let contents = match self.read(filename) {
+                    Ok(contents) => contents,
+                    Err(err) => return err,
+                };
+                self.run(Scanner::with_file(&contents, file))
+            };
+            if let Err(err) = ran {
+                return err;
+            }
+        }
+        exit_code::OK
+    }
+
+    /// Scans and parses the scripts on all cores at once, before any of them
+    /// is resolved and run in order. The ones that can't be read in whole are
+    /// left out, for `run_files` to report why when it gets to them.
+    #[cfg(feature = "sync")]
+    fn parse_ahead(&self, filenames: &[String], named: bool) -> Vec<Option<(String, Parsed)>> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let mut parsed: Vec<_> = filenames.iter().map(|_| None).collect();
+        if self.stream || filenames.len() < 2 {
+            return parsed;
+        }
+        let next = AtomicUsize::new(0);
+        let workers = std::thread::available_parallelism()
+            .map_or(1, |x| x.get())
+            .min(filenames.len());
+        let optimize = self.optimize;
+        let native_print = self.config.native_print;
+        let work = || {
+            let mut done = Vec::new();
+            loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(filename) = filenames.get(i) else {
+                    break;
+                };
+                if filename == STDIN || loxc::is_compiled(filename) {
+                    continue;
+                }
+                if let Ok(contents) = fs::read(filename).map(decode) {
+                    let file = named.then(|| Rc::from(filename.as_str()));
+                    let script = parse(Scanner::with_file(&contents, file), optimize, native_print);
+                    done.push((i, contents, script));
+                }
+            }
+            done
+        };
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..workers)
+                .map(|_| {
+                    std::thread::Builder::new()
+                        .stack_size(STACK_SIZE)
+                        .spawn_scoped(scope, work)
+                        .expect("Something went wrong with starting the parsers...")
+                })
+                .collect();
+            for worker in workers {
+                for (i, contents, script) in worker.join().expect("Parsing never panics") {
+                    parsed[i] = Some((contents, script));
+                }
+            }
+        });
+        parsed
+    }
+
+    /// Tokens and syntax trees can't leave the thread they were made on
+    #[cfg(not(feature = "sync"))]
+    fn parse_ahead(&self, filenames: &[String], _named: bool) -> Vec<Option<(String, Parsed)>> {
+        filenames.iter().map(|_| None).collect()
+    }
+
+    pub fn run_prompt(&mut self) {
+        let mut rl = Editor::<Prompt, DefaultHistory>::new()
+            .expect("Something went wrong with starting rustyline...");
+        // The prompt is colored along with diagnostics
+        if colored::control::SHOULD_COLORIZE.should_colorize() {
+            rl.set_helper(Some(Prompt));
+        }
+        loop {
+            let readline = rl.readline(&self.config.prompt);
+            match readline {
+                Ok(line) => {
+                    let _ = rl.add_history_entry(line.as_str());
+                    let _ = self.run_repl(&line);
+                }
+                Err(ReadlineError::Interrupted) => {
+                    println!("^C");
+                    break;
+                }
+                Err(ReadlineError::Eof) => {
+                    println!("^D");
+                    break;
+                }
+                Err(err) => {
+                    eprintln!("{}", format!("Error: {:?}", err).red());
+                    break;
+                }
+            }
+        }
+    }
+
+    pub fn run_repl(&mut self, source: &str) -> Result<(), i32> {
+        // Errors quote the line they were found on
+        let script = self.interpreter.borrow().script.clone();
+        self.sources.insert(script, source.to_string());
+        // scan tokens and print them
+        let mut scan = scanner::Scanner::new(source);
+        let raw_tokens = scan.scan_tokens();
+        let mut scanned = true;
+        for token in raw_tokens {
+            trace!("token {:?}", token);
+            if let Err(e) = token {
+                self.report(e);
+                scanned = false;
+            }
+        }
+        if !scanned {
+            return Err(exit_code::DATA_ERROR);
+        }
+        let tokens = raw_tokens.iter().flatten().cloned().collect::<Vec<_>>();
+        let mut parser = Parser::new(tokens);
+        if self.config.native_print {
+            parser = parser.with_native_print();
+        }
+        if let Ok(expr) = parser.parse_expr() {
+            let res = self.interpreter.borrow_mut().evaluate(&expr);
+            self.warn_at_runtime();
+            return match res {
+                Ok(val) => {
+                    let _ = writeln!(self.interpreter.borrow_mut().stdout, "{}", val);
+                    Ok(())
+                }
+                Err(e) => {
+                    self.report(&e);
+                    Err(exit_code::SOFTWARE)
+                }
+            };
+        }
+        Err(exit_code::DATA_ERROR)
+    }
+
+    /// Scans, parses and resolves a script. Scanning errors don't stop the later
+    /// stages, so that as many errors as possible get reported in one go, but
+    /// the script is given up on once they are all out.
+    fn compile<I: Iterator<Item = char>>(&mut self, scan: Scanner<I>) -> Result<Vec<Stmt>, i32> {
+        let parsed = parse(scan, self.optimize, self.config.native_print);
+        self.resolve(parsed)
+    }
+
+    /// Reports the errors found while parsing, and resolves the statements
+    fn resolve(&mut self, parsed: Parsed) -> Result<Vec<Stmt>, i32> {
+        self.timings.scanning += parsed.scanning;
+        self.timings.parsing += parsed.parsing;
+        parsed.scan_errors.iter().for_each(|x| self.report(x));
+        let scanned = parsed.scan_errors.is_empty();
+        let statements: Vec<Stmt> = match parsed.statements {
+            Ok(statements) => statements,
+            Err(errors) => {
+                errors.iter().for_each(|x| self.report(x));
+                return Err(exit_code::DATA_ERROR);
+            }
+        };
+
+        let span = info_span!("resolve", warnings = field::Empty).entered();
+        let start = Instant::now();
+        // Reporting needs the interpreter back, for its stderr
+        let (resolved, warnings, references) = {
+            let mut interpreter = self.interpreter.borrow_mut();
+            let mut resolver = Resolver::new(&mut interpreter);
+            if self.resolve_dump {
+                resolver = resolver.recording_references();
+            }
+            let resolved = resolver.resolve(&statements);
+            let mut warnings = resolver.warnings().to_vec();
+            let references = resolver.references().to_vec();
+            let library = self.config.lint.is_library(Path::new(&*interpreter.script));
+            warnings.extend(linter::lint(&statements, &self.config.lint, library));
+            warnings.sort_by_key(|x| (x.token.line, x.token.column));
+            (resolved, warnings, references)
+        };
+        let resolving = start.elapsed();
+        self.timings.resolving += resolving;
+        span.record("warnings", warnings.len());
+        drop(span);
+        self.dump_references(&references);
+        if let Err(errors) = resolved {
+            errors.iter().for_each(|x| self.report(x));
+            return Err(exit_code::DATA_ERROR);
+        }
+        if !scanned {
+            return Err(exit_code::DATA_ERROR);
+        }
+        let mut denied = false;
+        for warning in &warnings {
+            match self.config.level(warning.lint) {
+                Level::Allow => continue,
+                Level::Warn => (),
+                Level::Deny => denied = true,
+            }
+            self.warn(warning);
+        }
+        if denied {
+            return Err(exit_code::DATA_ERROR);
+        }
+        Ok(statements)
+    }
+
+    /// Prints where each reference points, the way `--trace` prints statements
+    fn dump_references(&self, references: &[Reference]) {
+        let mut interpreter = self.interpreter.borrow_mut();
+        let script = interpreter.script.clone();
+        for reference in references {
+            let name = &reference.name;
+            let file = name.file.as_ref().unwrap_or(&script);
+            let _ = writeln!(
+                interpreter.stderr,
+                "[resolve] {}:{}:{} {}: {}",
+                file, name.line, name.column, name.lexeme, reference.resolution
+            );
+        }
+    }
+
+    pub fn run<I: Iterator<Item = char>>(&mut self, scan: Scanner<I>) -> Result<(), i32> {
+        let parsed = parse(scan, self.optimize, self.config.native_print);
+        self.run_parsed(parsed)
+    }
+
+    fn run_parsed(&mut self, parsed: Parsed) -> Result<(), i32> {
+        let statements = self.resolve(parsed)?;
+        {
+            let interpreter = &mut *self.interpreter.borrow_mut();
+            if let Some(coverage) = &mut interpreter.coverage {
+                coverage.add(&interpreter.script, &statements);
+            }
+        }
+        let span = info_span!("interpret", ok = field::Empty).entered();
+        let start = Instant::now();
+        let interpreted = match &mut self.vm {
+            Some(vm) => {
+                let script = compiler::compile(&statements, vm);
+                vm.interpret(script)
+            }
+            None => self
+                .interpreter
+                .borrow_mut()
+                .interpret(&statements)
+                .map(|_| ()),
+        };
+        self.finish(span, start, interpreted)
+    }
+
+    /// Runs a script saved by `compile_file` on the VM. Diagnostics point into
+    /// the source it was compiled from, which is named in them when `named`.
+    fn run_compiled(&mut self, filename: &str, named: bool) -> Result<(), i32> {
+        let bytes = fs::read(filename).map_err(|e| {
+            eprintln!(
+                "{}",
+                format!("Error: Can't read '{}': {}", filename, e).red()
+            );
+            exit_code::NO_INPUT
+        })?;
+        let vm = self.vm.as_mut().expect("Compiled scripts run on the VM");
+        let (script, source) = loxc::read(&bytes, vm, named).map_err(|e| {
+            eprintln!(
+                "{}",
+                format!("Error: Can't load '{}': {}", filename, e).red()
+            );
+            exit_code::DATA_ERROR
+        })?;
+        vm.script = source.clone();
+        self.interpreter.borrow_mut().script = source;
+        let span = info_span!("interpret", ok = field::Empty).entered();
+        let start = Instant::now();
+        let interpreted = vm.interpret(script);
+        self.finish(span, start, interpreted)
+    }
+
+    /// Reports how running a script started at `start` went, closing the
+    /// span it ran in
+    fn finish(
+        &mut self,
+        span: EnteredSpan,
+        start: Instant,
+        interpreted: Result<(), LoxError>,
+    ) -> Result<(), i32> {
+        self.timings.interpreting += start.elapsed();
+        span.record("ok", interpreted.is_ok());
+        drop(span);
+        self.warn_at_runtime();
+        if let Err(e) = interpreted {
+            self.report(&e);
+            return Err(exit_code::SOFTWARE);
+        };
+        Ok(())
+    }
+
+    /// Compiles a script to bytecode and saves it to `output`, for the VM to
+    /// run without going through the source again
+    pub fn compile_file(&mut self, filename: &str, output: &Path) -> i32 {
+        let contents = match self.read(filename) {
+            Ok(contents) => contents,
+            Err(err) => return err,
+        };
+        let statements = match self.compile(Scanner::new(&contents)) {
+            Ok(statements) => statements,
+            Err(err) => return err,
+        };
+        let mut vm = Vm::with_config(&self.config);
+        let script = compiler::compile(&statements, &mut vm);
+        if let Err(e) = fs::write(output, loxc::write(&script, &vm, filename)) {
+            eprintln!(
+                "{}",
+                format!("Error: Can't write '{}': {}", output.display(), e).red()
+            );
+            return exit_code::CANT_CREATE;
+        }
+        exit_code::OK
+    }
+
+    /// Reports the static errors in all the files, without running any of them
+    pub fn check_files(&mut self, filenames: &[String]) -> i32 {
+        let named = filenames.len() > 1;
+        let mut code = exit_code::OK;
+        for filename in filenames {
+            let contents = match self.read(filename) {
+                Ok(contents) => contents,
+                Err(err) => return err,
+            };
+            let file = named.then(|| Rc::from(filename.as_str()));
+            if self.compile(Scanner::with_file(&contents, file)).is_err() {
+                code = exit_code::DATA_ERROR;
+            }
+        }
+        code
+    }
+
+    pub fn print_ast(&mut self, format: AstFormat, filenames: &[String]) -> i32 {
+        let named = filenames.len() > 1;
+        for filename in filenames {
+            let contents = match self.read(filename) {
+                Ok(contents) => contents,
+                Err(err) => return err,
+            };
+            let file = named.then(|| Rc::from(filename.as_str()));
+            match self.compile(Scanner::with_file(&contents, file)) {
+                Ok(statements) => match format {
+                    AstFormat::Text => statements.iter().for_each(|x| println!("{}", x)),
+                    AstFormat::Json => println!(
+                        "{}",
+                        serde_json::to_string(&statements).expect("Syntax trees are plain data")
+                    ),
+                    AstFormat::Dot => print!("{}", graphviz::dot(&statements)),
+                },
+                Err(err) => return err,
+            }
+        }
+        exit_code::OK
+    }
+
+    /// The files make up a single program, as when they are run together
+    pub fn print_call_graph(&mut self, format: CallGraphFormat, filenames: &[String]) -> i32 {
+        let named = filenames.len() > 1;
+        let mut statements = Vec::new();
+        for filename in filenames {
+            let contents = match self.read(filename) {
+                Ok(contents) => contents,
+                Err(err) => return err,
+            };
+            let file = named.then(|| Rc::from(filename.as_str()));
+            match self.compile(Scanner::with_file(&contents, file)) {
+                Ok(parsed) => statements.extend(parsed),
+                Err(err) => return err,
+            }
+        }
+        let graph = CallGraph::new(&statements);
+        match format {
+            CallGraphFormat::Dot => print!("{}", graph.dot()),
+            CallGraphFormat::Json => print!("{}", graph.json()),
+        }
+        exit_code::OK
+    }
+
+    /// Translates the files together, as they would run together
+    pub fn transpile(&mut self, target: Target, filenames: &[String]) -> i32 {
+        let named = filenames.len() > 1;
+        let mut statements = Vec::new();
+        for filename in filenames {
+            let contents = match self.read(filename) {
+                Ok(contents) => contents,
+                Err(err) => return err,
+            };
+            let file = named.then(|| Rc::from(filename.as_str()));
+            match self.compile(Scanner::with_file(&contents, file)) {
+                Ok(parsed) => statements.extend(parsed),
+                Err(err) => return err,
+            }
+        }
+        match target {
+            Target::Js => print!("{}", javascript::transpile(&statements)),
+        }
+        exit_code::OK
+    }
+
+    /// Runs the ```lox blocks of Markdown documents in order, as one program,
+    /// printing the documents with what each block printed right after it
+    pub fn run_markdown(&mut self, filenames: &[String]) -> i32 {
+        let named = filenames.len() > 1;
+        let printed = notebook::Captured::default();
+        self.interpreter.borrow_mut().stdout = Box::new(printed.clone());
+        for filename in filenames {
+            let markdown = match self.read(filename) {
+                Ok(markdown) => markdown,
+                Err(err) => return err,
+            };
+            let mut at = 0;
+            for block in notebook::blocks(&markdown) {
+                print!("{}", &markdown[at..block.end]);
+                at = block.end;
+                let file = named.then(|| Rc::from(filename.as_str()));
+                let ran = self.run(Scanner::with_file(&block.code, file));
+                let output = printed.take();
+                if !output.is_empty() {
+                    if !markdown[..at].ends_with('\n') {
+                        println!();
+                    }
+                    print!("```text\n{}```\n", output);
+                }
+                if let Err(err) = ran {
+                    return err;
+                }
+            }
+            print!("{}", &markdown[at..]);
+        }
+        exit_code::OK
+    }
+
+    /// Documents all the files at once, once they all check out
+    pub fn print_docs(&mut self, format: DocFormat, filenames: &[String]) -> i32 {
+        let named = filenames.len() > 1;
+        let mut parsed = Vec::new();
+        for filename in filenames {
+            let contents = match self.read(filename) {
+                Ok(contents) => contents,
+                Err(err) => return err,
+            };
+            let file = named.then(|| Rc::from(filename.as_str()));
+            match self.compile(Scanner::with_file(&contents, file)) {
+                Ok(statements) => parsed.push((filename.as_str(), statements)),
+                Err(err) => return err,
+            }
+        }
+        let files = parsed
+            .iter()
+            .map(|(file, statements)| (*file, statements.as_slice()))
+            .collect::<Vec<_>>();
+        match format {
+            DocFormat::Markdown => print!("{}", doc::markdown(&files)),
+            DocFormat::Html => print!("{}", doc::html(&files)),
+        }
+        exit_code::OK
+    }
+}
+
+/// Highlights the line being typed at the prompt
+struct Prompt;
+
+impl Highlighter for Prompt {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        Cow::Owned(highlight::ansi(line))
+    }
+
+    fn highlight_char(&self, _line: &str, _pos: usize, _kind: CmdKind) -> bool {
+        true
+    }
+}
+
+impl Completer for Prompt {
+    type Candidate = String;
+}
+
+impl Hinter for Prompt {
+    type Hint = String;
+}
+
+impl Validator for Prompt {}
+
+impl Helper for Prompt {}
+
+/// A script scanned and parsed, with nothing reported yet, which needs no
+/// interpreter and so can be done anywhere ahead of running it
+struct Parsed {
+    scan_errors: Vec<LoxError>,
+    statements: Result<Vec<Stmt>, Vec<LoxError>>,
+    scanning: Duration,
+    parsing: Duration,
+}
+
+/// `native_print` is `Config::native_print`
+fn parse<I: Iterator<Item = char>>(
+    mut scan: Scanner<I>,
+    optimize: bool,
+    native_print: bool,
+) -> Parsed {
+    let span = info_span!("scan", tokens = field::Empty, errors = field::Empty).entered();
+    let start = Instant::now();
+    let raw_tokens = scan.scan_tokens();
+    let scanning = start.elapsed();
+    let mut scan_errors = Vec::new();
+    for token in raw_tokens {
+        trace!("token {:?}", token);
+        if let Err(e) = token {
+            scan_errors.push(e.clone());
+        }
+    }
+    span.record("tokens", raw_tokens.len() - scan_errors.len());
+    span.record("errors", scan_errors.len());
+    drop(span);
+    let span = info_span!("parse", statements = field::Empty, errors = field::Empty).entered();
+    let start = Instant::now();
+    let tokens = raw_tokens.iter().flatten().cloned().collect::<Vec<_>>();
+    let mut parser = Parser::new(tokens).with_docs(scan.comments());
+    if native_print {
+        parser = parser.with_native_print();
+    }
+    let statements = match parser.parse() {
+        Ok(statements) if optimize => Ok(optimizer::optimize(&statements)),
+        parsed => parsed,
+    };
+    let parsing = start.elapsed();
+    match &statements {
+        Ok(statements) => {
+            statements.iter().for_each(|x| trace!("statement {}", x));
+            span.record("statements", statements.len());
+            span.record("errors", 0);
+        }
+        Err(errors) => {
+            span.record("statements", 0);
+            span.record("errors", errors.len());
+        }
+    }
+    drop(span);
+    Parsed {
+        scan_errors,
+        statements,
+        scanning,
+        parsing,
+    }
+}
+
+/// The file name that stands for standard input
+const STDIN: &str = "-";
+
+/// Deep recursion would otherwise bury the error under its own trace
+const TRACE_FRAMES: usize = 20;
+
+/// Prints an error from `file` to `out`. When diagnostics are rich, it is
+/// followed by the part of `source` it points at and the calls that led to it.
+fn report(
+    out: &mut dyn Write,
+    error: &LoxError,
+    file: &str,
+    source: Option<&str>,
+) -> io::Result<()> {
+    match error::style() {
+        Style::Json => writeln!(out, "{}", error.diagnostic(file)),
+        Style::Short => writeln!(out, "{}", error.to_string().red()),
+        Style::Rich => {
+            writeln!(out, "{}", error.to_string().red())?;
+            if let Some(snippet) = source.and_then(|x| error.snippet(file, x)) {
+                writeln!(out, "{}", snippet)?;
+            }
+            let trace = error.trace();
+            for frame in trace.iter().take(TRACE_FRAMES) {
+                writeln!(out, "  {}", frame)?;
+            }
+            if trace.len() > TRACE_FRAMES {
+                writeln!(out, "  ... and {} more", trace.len() - TRACE_FRAMES)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Same as `report`, for warnings
+fn warn(
+    out: &mut dyn Write,
+    warning: &Warning,
+    file: &str,
+    source: Option<&str>,
+) -> io::Result<()> {
+    match error::style() {
+        Style::Json => warning
+            .diagnostics(file)
+            .iter()
+            .try_for_each(|x| writeln!(out, "{}", x)),
+        Style::Short => writeln!(out, "{}", warning.to_string().yellow()),
+        Style::Rich => {
+            writeln!(out, "{}", warning.to_string().yellow())?;
+            if let Some(snippet) = source.and_then(|x| warning.snippet(file, x)) {
+                writeln!(out, "{}", snippet)?;
+            }
+            if let Some(note) = &warning.note {
+                writeln!(out, "note: {}", note.message)?;
+                if let Some(snippet) = source.and_then(|x| note.snippet(file, x)) {
+                    writeln!(out, "{}", snippet)?;
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Reads a script, reporting why it couldn't be read. Broken UTF-8 reads as
+/// U+FFFD, as it does when scripts are scanned while read.
+fn read_source(filename: &str) -> Result<String, i32> {
+    read_bytes(filename).map(decode)
+}
+
+fn read_bytes(filename: &str) -> Result<Vec<u8>, i32> {
+    fs::read(filename).map_err(|e| {
+        eprintln!(
+            "{}",
+            format!("Error: Can't read '{}': {}", filename, e).red()
+        );
+        exit_code::NO_INPUT
+    })
+}
+
+fn decode(bytes: Vec<u8>) -> String {
+    String::from_utf8(bytes).unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned())
+}
+
+fn fmt(check: bool, files: &[String]) -> i32 {
+    let mut code = exit_code::OK;
+    for file in files {
+        let bytes = match read_bytes(file) {
+            Ok(bytes) => bytes,
+            Err(err) => return err,
+        };
+        // Unlike scripts run, as written back what was broken would be lost
+        let Ok(source) = String::from_utf8(bytes) else {
+            eprintln!(
+                "{}",
+                format!("Error: Can't format '{}': it isn't valid UTF-8.", file).red()
+            );
+            code = exit_code::DATA_ERROR;
+            continue;
+        };
+        match formatter::format_source(&source) {
+            Ok(formatted) if formatted == source => (),
+            Ok(_) if check => {
+                println!("Would reformat {}", file);
+                code = code.max(exit_code::FAILURE);
+            }
+            Ok(formatted) => {
+                fs::write(file, formatted).expect("Something went wrong writing the file...")
+            }
+            Err(errors) => {
+                for error in &errors {
+                    let _ = report(&mut io::stderr(), error, file, Some(&source));
+                }
+                code = exit_code::DATA_ERROR;
+            }
+        }
+    }
+    code
+}
+
+/// Only ever returns when it can't listen on `host` and `port`
+fn serve(host: &str, port: u16) -> i32 {
+    let listener = match std::net::TcpListener::bind((host, port)) {
+        Ok(listener) => listener,
+        Err(e) => {
+            let message = format!("Error: Can't listen on {}:{}: {}", host, port, e);
+            eprintln!("{}", message.red());
+            return exit_code::UNAVAILABLE;
+        }
+    };
+    match listener.local_addr() {
+        Ok(address) => println!("Listening on http://{}", address),
+        Err(e) => eprintln!("{}", format!("Error: {}", e).red()),
+    }
+    server::serve(listener);
+    exit_code::OK
+}
+
+/// Errors are only highlighted, not reported
+fn highlighted(format: HighlightFormat, files: &[String]) -> i32 {
+    for file in files {
+        let source = match read_source(file) {
+            Ok(source) => source,
+            Err(err) => return err,
+        };
+        match format {
+            HighlightFormat::Ansi => print!("{}", highlight::ansi(&source)),
+            HighlightFormat::Html => print!("{}", highlight::html(&source)),
+        }
+    }
+    exit_code::OK
+}
+
+fn tokens(files: &[String]) -> i32 {
+    let mut code = exit_code::OK;
+    for file in files {
+        let source = match read_source(file) {
+            Ok(source) => source,
+            Err(err) => return err,
+        };
+        for token in scanner::Scanner::new(&source).scan_tokens() {
+            match token {
+                Ok(token) => println!(
+                    "{}:{} {:?} {}",
+                    token.line, token.column, token.token, token.lexeme
+                ),
+                Err(e) => {
+                    let _ = report(&mut io::stderr(), e, file, Some(&source));
+                    code = exit_code::DATA_ERROR;
+                }
+            }
+        }
+    }
+    code
+}
+
+fn load_config(cli: &Cli, limits: &LimitArgs) -> Config {
+    let mut config = Config::load().unwrap_or_else(|e| {
+        cli.color.unwrap_or_default().apply();
+        eprintln!("{}", format!("Error: {}", e).red());
+        exit(exit_code::CONFIG);
+    });
+    cli.merge_into(limits, &mut config);
+    config.color.apply();
+    config.error_format.apply();
+    config
+}
+
+fn run(cli: &Cli, args: &RunArgs) -> i32 {
+    let mut driver = Driver::new(load_config(cli, &args.limits));
+    driver.interpreter.borrow_mut().trace = args.trace;
+    driver.optimize = args.opt;
+    driver.stream = args.stream;
+    driver.resolve_dump = args.resolve_dump;
+    let compiled = args.files.iter().any(|x| loxc::is_compiled(x));
+    if compiled && args.trace {
+        eprintln!("{}", "Error: Compiled scripts can't be traced".red());
+        return exit_code::USAGE;
+    }
+    if compiled && args.resolve_dump {
+        eprintln!("{}", "Error: Compiled scripts were resolved already".red());
+        return exit_code::USAGE;
+    }
+    if compiled && args.leak_check {
+        eprintln!(
+            "{}",
+            "Error: Compiled scripts can't be checked for leaks".red()
+        );
+        return exit_code::USAGE;
+    }
+    if args.leak_check {
+        driver.interpreter.borrow_mut().heap.keep_sites();
+    }
+    let coverage = args.coverage || args.lcov.is_some();
+    if compiled && coverage {
+        eprintln!(
+            "{}",
+            "Error: Compiled scripts can't be measured for coverage".red()
+        );
+        return exit_code::USAGE;
+    }
+    if coverage {
+        driver.interpreter.borrow_mut().coverage = Some(Coverage::default());
+    }
+    if args.vm || compiled {
+        driver.vm = Some(Vm::with_config(&driver.config));
+    }
+    if args.files.is_empty() {
+        driver.run_prompt();
+        return exit_code::OK;
+    }
+    let code = driver.run_files(&args.files);
+    if args.leak_check {
+        driver.report_cycles();
+    }
+    if coverage {
+        if let Err(code) = driver.report_coverage(args.coverage, args.lcov.as_deref()) {
+            return code;
+        }
+    }
+    if args.time {
+        eprintln!("{}", driver.timings);
+    }
+    code
+}
+
+/// Lox calls don't take any Rust stack, but parsing, resolving and printing
+/// syntax trees still recurse on them, and long chains of operators make deep
+/// trees. Only the pages actually used get allocated.
+pub const STACK_SIZE: usize = 256 << 20;
+
+/// Runs the command line the binary was given, returning the exit code.
+/// `trace` sets up where spans and events go, for the `-v` given.
+pub fn run_cli(trace: fn(u8)) -> i32 {
+    let cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        Err(e) => {
+            let _ = e.print();
+            return if e.use_stderr() {
+                exit_code::USAGE
+            } else {
+                exit_code::OK
+            };
+        }
+    };
+    trace(cli.verbose);
+
+    match &cli.command {
+        None => run(&cli, &cli.run),
+        Some(Command::Run(args)) => run(&cli, args),
+        Some(Command::Repl(args)) => {
+            let mut driver = Driver::new(load_config(&cli, &args.limits));
+            driver.run_prompt();
+            exit_code::OK
+        }
+        Some(Command::Serve { port, host }) => serve(host, *port),
+        Some(Command::Check { files }) => {
+            let mut driver = Driver::new(load_config(&cli, &LimitArgs::default()));
+            driver.check_files(files)
+        }
+        Some(Command::Compile { opt, output, file }) => {
+            let mut driver = Driver::new(load_config(&cli, &LimitArgs::default()));
+            driver.optimize = *opt;
+            let output = output
+                .clone()
+                .unwrap_or_else(|| Path::new(file).with_extension(loxc::EXTENSION));
+            driver.compile_file(file, &output)
+        }
+        Some(Command::Ast { format, files }) => {
+            let mut driver = Driver::new(load_config(&cli, &LimitArgs::default()));
+            driver.print_ast(*format, files)
+        }
+        Some(Command::CallGraph { format, files }) => {
+            let mut driver = Driver::new(load_config(&cli, &LimitArgs::default()));
+            driver.print_call_graph(*format, files)
+        }
+        Some(Command::RunMd(args)) => {
+            let mut driver = Driver::new(load_config(&cli, &args.limits));
+            driver.run_markdown(&args.files)
+        }
+        Some(Command::Transpile { target, files }) => {
+            let mut driver = Driver::new(load_config(&cli, &LimitArgs::default()));
+            driver.transpile(*target, files)
+        }
+        Some(Command::Doc { format, files }) => {
+            let mut driver = Driver::new(load_config(&cli, &LimitArgs::default()));
+            driver.print_docs(*format, files)
+        }
+        Some(command) => {
+            let config = load_config(&cli, &LimitArgs::default());
+            match command {
+                Command::Fmt { check, files } => fmt(*check, files),
+                Command::Test {
+                    exclude,
+                    filter,
+                    dir,
+                } => test_runner::run(dir, exclude, filter.as_deref(), &config),
+                Command::Bench { repeat, dir } => bench::run(dir, *repeat),
+                Command::Highlight { format, files } => highlighted(*format, files),
+                Command::Tokens { files } => tokens(files),
+                _ => unreachable!(),
+            }
+        }
+    }
+}
@@ -0,0 +1,70 @@
+// Base64 and hex, for the natives scripts encode and decode text with. Lox only
+// has strings, so what gets encoded is the UTF-8 of a string, and what gets
+// decoded has to be UTF-8 to make one.
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// The standard alphabet, padded with `=`
+pub fn base64_encode(text: &str) -> String {
+    let mut out = String::with_capacity(text.len().div_ceil(3) * 4);
+    for chunk in text.as_bytes().chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |bits, (i, &x)| bits | ((x as u32) << (16 - 8 * i)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[((bits >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Takes the URL safe alphabet too, and padding is optional
+pub fn base64_decode(text: &str) -> Result<String, String> {
+    let digits = text.trim_end_matches('=').as_bytes();
+    if digits.len() % 4 == 1 {
+        return Err("Invalid base64.".into());
+    }
+    let mut bytes = Vec::with_capacity(digits.len() / 4 * 3);
+    for chunk in digits.chunks(4) {
+        let mut bits = 0u32;
+        for (i, &digit) in chunk.iter().enumerate() {
+            let value = match digit {
+                b'A'..=b'Z' => digit - b'A',
+                b'a'..=b'z' => digit - b'a' + 26,
+                b'0'..=b'9' => digit - b'0' + 52,
+                b'+' | b'-' => 62,
+                b'/' | b'_' => 63,
+                _ => return Err("Invalid base64.".into()),
+            };
+            bits |= (value as u32) << (18 - 6 * i);
+        }
+        bytes.extend(&bits.to_be_bytes()[1..chunk.len()]);
+    }
+    utf8(bytes)
+}
+
+/// Two lowercase digits a byte
+pub fn hex_encode(text: &str) -> String {
+    text.bytes().map(|x| format!("{:02x}", x)).collect()
+}
+
+/// Either case, but no separators
+pub fn hex_decode(text: &str) -> Result<String, String> {
+    if text.len() % 2 == 1 || !text.bytes().all(|x| x.is_ascii_hexdigit()) {
+        return Err("Invalid hex.".into());
+    }
+    let bytes = (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).expect("Checked above"))
+        .collect();
+    utf8(bytes)
+}
+
+fn utf8(bytes: Vec<u8>) -> Result<String, String> {
+    String::from_utf8(bytes).map_err(|_| "Decoded bytes aren't valid UTF-8.".into())
+}
@@ -1,101 +1,295 @@
-use crate::scanner::{Literal, Token};
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use crate::error::LoxError;
+use crate::gc::{Collect, Trace, Tracer};
+use crate::scanner::Token;
+use crate::shared::{Rc, RefCell};
+use crate::symbol::Symbol;
+use crate::value::Value;
+use std::collections::{HashMap, HashSet};
 
 #[derive(Debug, PartialEq)]
 pub struct Environment {
     enclosing: Option<Rc<RefCell<Environment>>>,
-    values: HashMap<String, Literal>,
+    /// Locals in the order they were declared, which is how the resolver numbers
+    /// them. `None` for variables declared without a value and not assigned since.
+    slots: Vec<Option<Value>>,
+    /// Only used by the global scope, where variables can be used in functions
+    /// before they are declared. They are numbered the first time their name is
+    /// seen instead, `None` until they are actually declared.
+    globals: Vec<Option<Option<Value>>>,
+    indices: HashMap<Symbol, usize>,
+    /// The globals declared with `const`, by index. The resolver already stops
+    /// the assignments it can see to these and to local constants, which leaves
+    /// globals assigned before the resolver knew about them, in functions
+    /// declared earlier or on earlier lines of the REPL.
+    constants: HashSet<usize>,
 }
 
 impl Environment {
     pub fn new() -> Self {
         Environment {
             enclosing: None,
-            values: HashMap::new(),
+            slots: Vec::new(),
+            globals: Vec::new(),
+            indices: HashMap::new(),
+            constants: HashSet::new(),
         }
     }
 
     pub fn nested(enclosing: Rc<RefCell<Self>>) -> Rc<RefCell<Self>> {
         Rc::new(RefCell::new(Environment {
             enclosing: Some(enclosing),
-            values: HashMap::new(),
+            slots: Vec::new(),
+            globals: Vec::new(),
+            indices: HashMap::new(),
+            constants: HashSet::new(),
         }))
     }
 
-    pub fn define(&mut self, key: &str, value: Literal) {
-        self.values.insert(key.into(), value);
+    pub fn define(&mut self, key: &str, value: Value) {
+        self.insert(key, Some(value));
     }
 
-    pub fn get(&self, key: &Token) -> Result<Literal, String> {
-        self.values
-            .get(&key.lexeme)
-            .cloned()
-            .or_else(|| {
-                self.enclosing
-                    .as_ref()
-                    .and_then(|x| x.borrow().get(key).ok())
-            })
-            .ok_or(format!(
-                "[line {}:{}] Undefined variable '{}'.",
-                key.line, key.column, key.lexeme
-            ))
+    /// Defines a variable that can't be assigned to, which only globals need to
+    /// know about
+    pub fn define_constant(&mut self, key: &str, value: Value) {
+        self.insert(key, Some(value));
+        if self.enclosing.is_none() {
+            let index = self.global_index(key);
+            self.constants.insert(index);
+        }
+    }
+
+    /// Defines a variable that can't be read until something is assigned to it
+    pub fn declare(&mut self, key: &str) {
+        self.insert(key, None);
     }
 
-    pub fn get_at(&self, distance: usize, name: &str) -> Result<Literal, String> {
-        if distance > 0 {
-            self.ancestor(distance).borrow().values.get(name).cloned()
+    /// Locals take the next slot, so they have to be defined in the same order
+    /// the resolver declared them
+    fn insert(&mut self, key: &str, value: Option<Value>) {
+        if self.enclosing.is_some() {
+            self.slots.push(value);
         } else {
-            self.values.get(name).cloned()
+            let index = self.global_index(key);
+            self.globals[index] = Some(value);
+            // Declared again, it is whatever the new declaration says
+            self.constants.remove(&index);
         }
-        .ok_or(format!(
-            "Undefined variable '{}' at distance {}.",
-            name, distance
-        ))
     }
 
-    pub fn assign_at(
-        &mut self,
+    /// The index of a global variable in the globals table, for `get_global` and
+    /// `assign_global`, whether it has been declared yet or not
+    pub fn global_index(&mut self, key: &str) -> usize {
+        let key = Symbol::intern(key);
+        if let Some(&index) = self.indices.get(&key) {
+            return index;
+        }
+        self.globals.push(None);
+        self.indices.insert(key, self.globals.len() - 1);
+        self.globals.len() - 1
+    }
+
+    /// Looks up a global variable by name, for code that wasn't resolved
+    pub fn get(&self, key: &Token) -> Result<Value, LoxError> {
+        match self.indices.get(&key.lexeme) {
+            Some(&index) => self.get_global(index, key),
+            None => Err(undefined(key)),
+        }
+    }
+
+    /// A global variable by name, for the host. `None` unless it was declared
+    /// and assigned.
+    pub fn get_by_name(&self, key: &str) -> Option<Value> {
+        let index = *self.indices.get(&Symbol::intern(key))?;
+        self.globals[index].clone().flatten()
+    }
+
+    pub fn get_global(&self, index: usize, key: &Token) -> Result<Value, LoxError> {
+        match &self.globals[index] {
+            Some(value) => assigned(key, value.clone()),
+            None => Err(undefined(key)),
+        }
+    }
+
+    /// Same as `get_at`, for variables named in the source
+    pub fn get_at_token(
+        &self,
         distance: usize,
-        name: &Token,
-        val: Literal,
-    ) -> Result<Literal, String> {
-        if distance > 0 {
-            self.ancestor(distance)
-                .borrow_mut()
-                .values
-                .insert(name.lexeme.clone(), val.clone());
-        } else {
-            self.values.insert(name.lexeme.clone(), val.clone());
+        slot: usize,
+        key: &Token,
+    ) -> Result<Value, LoxError> {
+        match self.slot_at(distance, slot) {
+            Some(value) => assigned(key, value),
+            None => self.get_at(distance, slot),
+        }
+    }
+
+    pub fn get_at(&self, distance: usize, slot: usize) -> Result<Value, LoxError> {
+        self.slot_at(distance, slot)
+            .flatten()
+            .ok_or_else(|| LoxError::RuntimeError {
+                token: None,
+                message: format!(
+                    "Undefined variable in slot {} at distance {}.",
+                    slot, distance
+                ),
+                trace: Vec::new(),
+            })
+    }
+
+    /// Only the value itself gets cloned
+    fn slot_at(&self, distance: usize, slot: usize) -> Option<Option<Value>> {
+        if distance == 0 {
+            return self.slots.get(slot).cloned();
         }
-        Ok(val)
+        self.with_ancestor(distance, |x| x.borrow().slots.get(slot).cloned())
+            .flatten()
     }
 
-    pub fn ancestor(&self, distance: usize) -> Rc<RefCell<Environment>> {
-        let mut current = self.enclosing.clone().expect("No parent environment");
+    pub fn assign_at(&mut self, distance: usize, slot: usize, val: Value) -> Value {
+        if distance == 0 {
+            self.slots[slot] = Some(val.clone());
+            return val;
+        }
+        self.with_ancestor(distance, |x| x.borrow_mut().slots[slot] = Some(val.clone()))
+            .expect("No parent environment");
+        val
+    }
+
+    /// Walks up the scopes in a loop, by reference
+    #[cfg(not(feature = "sync"))]
+    fn with_ancestor<R>(
+        &self,
+        distance: usize,
+        f: impl FnOnce(&RefCell<Environment>) -> R,
+    ) -> Option<R> {
+        let mut scope = self.enclosing.as_ref()?;
+        for _ in 1..distance {
+            scope = unguarded(scope)?.enclosing.as_ref()?;
+        }
+        Some(f(scope))
+    }
 
+    /// Locks the scopes one at a time on the way up, which takes holding on to
+    /// each of them
+    #[cfg(feature = "sync")]
+    fn with_ancestor<R>(
+        &self,
+        distance: usize,
+        f: impl FnOnce(&RefCell<Environment>) -> R,
+    ) -> Option<R> {
+        let mut scope = self.enclosing.clone()?;
         for _ in 1..distance {
-            current = {
-                let borrowed = current.borrow();
-                borrowed
-                    .enclosing
-                    .clone()
-                    .expect("No further parent environment")
-            };
+            let enclosing = scope.borrow().enclosing.clone()?;
+            scope = enclosing;
         }
+        Some(f(&scope))
+    }
 
-        current
+    /// The globals by index, for snapshots
+    pub fn global_values(&self) -> &[Option<Option<Value>>] {
+        &self.globals
     }
 
-    pub fn assign(&mut self, name: &Token, val: Literal) -> Result<Literal, String> {
-        if self.values.contains_key(&name.lexeme) {
-            self.values.insert(name.lexeme.clone(), val.clone());
-            return Ok(val);
+    /// Puts back the globals a snapshot saw. The ones numbered since stay
+    /// numbered, for the code resolved since, but undeclared.
+    pub fn restore_globals(&mut self, mut globals: Vec<Option<Option<Value>>>) {
+        globals.resize(self.globals.len().max(globals.len()), None);
+        self.globals = globals;
+    }
+
+    /// Assigns a global variable by name, for code that wasn't resolved
+    pub fn assign(&mut self, name: &Token, val: Value) -> Result<Value, LoxError> {
+        match self.indices.get(&name.lexeme) {
+            Some(&index) => self.assign_global(index, name, val),
+            None => Err(undefined(name)),
         }
+    }
 
-        if let Some(x) = &mut self.enclosing {
-            return x.borrow_mut().assign(name, val);
+    pub fn assign_global(
+        &mut self,
+        index: usize,
+        name: &Token,
+        val: Value,
+    ) -> Result<Value, LoxError> {
+        match &mut self.globals[index] {
+            Some(_) if self.constants.contains(&index) => Err(LoxError::runtime(
+                name,
+                format!("Can't assign to constant '{}'.", name.lexeme),
+            )),
+            Some(value) => {
+                *value = Some(val.clone());
+                Ok(val)
+            }
+            None => Err(undefined(name)),
         }
+    }
+}
 
-        Err(format!("Undefined variable '{}'.", name.lexeme))
+impl Default for Environment {
+    fn default() -> Self {
+        Self::new()
     }
 }
+
+impl Trace for RefCell<Environment> {
+    fn trace(&self, tracer: &mut Tracer) {
+        let environment = self.borrow();
+        if let Some(enclosing) = &environment.enclosing {
+            tracer.mark(enclosing);
+        }
+        let globals = environment.globals.iter().flatten();
+        for value in environment.slots.iter().chain(globals).flatten() {
+            value.trace(tracer);
+        }
+    }
+}
+
+impl Collect for RefCell<Environment> {
+    fn clear(&self) {
+        let mut environment = self.borrow_mut();
+        environment.enclosing = None;
+        environment.slots.clear();
+        environment.globals.clear();
+    }
+
+    fn describe(&self) -> String {
+        "environment".to_string()
+    }
+
+    fn size(&self) -> usize {
+        let environment = self.borrow();
+        let slots = environment.slots.iter().flatten();
+        let globals = environment.globals.iter().flatten().flatten();
+        size_of::<Self>()
+            + environment.indices.len() * size_of::<(Symbol, usize)>()
+            + slots.chain(globals).map(Value::size).sum::<usize>()
+    }
+}
+
+/// Borrows an enclosing scope for as long as its child is, without the guard
+/// `borrow` would need to be kept alive, so that the chain can be walked in a
+/// loop. Fails if the scope is being changed.
+#[cfg(not(feature = "sync"))]
+fn unguarded(scope: &RefCell<Environment>) -> Option<&Environment> {
+    // SAFETY: the child holds on to the scope, so it stays alive for as long as
+    // the child is borrowed. Reading or assigning a variable only walks the
+    // chain while that borrow lasts, and nothing borrows a scope mutably in
+    // between, which `try_borrow_unguarded` checks for anyway.
+    unsafe { scope.try_borrow_unguarded() }.ok()
+}
+
+fn undefined(key: &Token) -> LoxError {
+    LoxError::runtime(key, format!("Undefined variable '{}'.", key.lexeme))
+}
+
+/// Reading a variable before anything was assigned to it is an error, rather
+/// than a silent `nil`
+fn assigned(key: &Token, value: Option<Value>) -> Result<Value, LoxError> {
+    value.ok_or_else(|| {
+        LoxError::runtime(
+            key,
+            format!("Variable '{}' used before being assigned.", key.lexeme),
+        )
+    })
+}
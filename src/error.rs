@@ -0,0 +1,425 @@
+use crate::scanner::{Token, TokenType};
+use crate::shared::Rc;
+use clap::ValueEnum;
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// How diagnostics are printed, decided once from `ErrorFormat`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Style {
+    Short,
+    /// Quoting the source they point at
+    Rich,
+    /// One JSON object per line, see `Diagnostic`
+    Json,
+}
+
+static STYLE: AtomicU8 = AtomicU8::new(Style::Short as u8);
+
+pub fn set_style(style: Style) {
+    STYLE.store(style as u8, Ordering::Relaxed);
+}
+
+pub fn style() -> Style {
+    match STYLE.load(Ordering::Relaxed) {
+        x if x == Style::Rich as u8 => Style::Rich,
+        x if x == Style::Json as u8 => Style::Json,
+        _ => Style::Short,
+    }
+}
+
+/// Where something is in the source, as reported by all diagnostics
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    /// Only present when more than one script is being run
+    pub file: Option<Rc<str>>,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "[{} line {}:{}]", file, self.line, self.column),
+            None => write!(f, "[line {}:{}]", self.line, self.column),
+        }
+    }
+}
+
+/// A call that was still running when a runtime error happened
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub function: String,
+    /// Where the call was made from
+    pub file: Rc<str>,
+    pub line: usize,
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "in {} at {}:{}", self.function, self.file, self.line)
+    }
+}
+
+/// Everything that can go wrong with a script, by the stage that found it
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoxError {
+    ScanError {
+        location: Location,
+        message: String,
+    },
+    ParseError {
+        token: Box<Token>,
+        message: String,
+    },
+    ResolveError {
+        token: Box<Token>,
+        message: String,
+    },
+    /// Errors raised by native functions don't know where they were called from.
+    /// The `trace` lists the calls in progress, innermost first.
+    RuntimeError {
+        token: Option<Box<Token>>,
+        message: String,
+        trace: Vec<Frame>,
+    },
+    /// The fuel a host gave the interpreter ran out, at the statement that
+    /// needed more
+    OutOfFuel {
+        token: Option<Box<Token>>,
+    },
+}
+
+const OUT_OF_FUEL: &str = "Out of fuel.";
+
+impl LoxError {
+    pub fn runtime(token: &Token, message: impl Into<String>) -> Self {
+        LoxError::RuntimeError {
+            token: Some(Box::new(token.clone())),
+            message: message.into(),
+            trace: Vec::new(),
+        }
+    }
+
+    /// Records the calls in progress, outermost first, unless an inner call
+    /// already did so on the way out
+    pub fn in_frames(mut self, frames: impl DoubleEndedIterator<Item = Frame>) -> Self {
+        if let LoxError::RuntimeError { trace, .. } = &mut self {
+            if trace.is_empty() {
+                *trace = frames.rev().collect();
+            }
+        }
+        self
+    }
+
+    /// The calls that were running when a runtime error happened
+    pub fn trace(&self) -> &[Frame] {
+        match self {
+            LoxError::RuntimeError { trace, .. } => trace,
+            _ => &[],
+        }
+    }
+
+    pub fn location(&self) -> Option<Location> {
+        match self {
+            LoxError::ScanError { location, .. } => Some(location.clone()),
+            LoxError::ParseError { token, .. } | LoxError::ResolveError { token, .. } => {
+                Some(token.location())
+            }
+            LoxError::RuntimeError { token, .. } | LoxError::OutOfFuel { token } => {
+                token.as_ref().map(|x| x.location())
+            }
+        }
+    }
+
+    /// The line of `source` the error points at, with a `^^^` underline below
+    /// the offending part, ready to be printed after the error itself:
+    ///
+    ///    --> test.lox:3:9
+    ///     |
+    ///   3 | var a = ;
+    ///     |         ^
+    pub fn snippet(&self, filename: &str, source: &str) -> Option<String> {
+        quote(&self.location()?, width(self.token()), filename, source)
+    }
+
+    fn token(&self) -> Option<&Token> {
+        match self {
+            LoxError::ScanError { .. } => None,
+            LoxError::ParseError { token, .. } | LoxError::ResolveError { token, .. } => {
+                Some(token)
+            }
+            LoxError::RuntimeError { token, .. } | LoxError::OutOfFuel { token } => {
+                token.as_deref()
+            }
+        }
+    }
+
+    /// The error for tools, `file` being the script it happened in when the
+    /// location doesn't say
+    pub fn diagnostic<'a>(&'a self, file: &'a str) -> Diagnostic<'a> {
+        let (code, message): (_, &str) = match self {
+            LoxError::ScanError { message, .. } => ("scan", message),
+            LoxError::ParseError { message, .. } => ("parse", message),
+            LoxError::ResolveError { message, .. } => ("resolve", message),
+            LoxError::RuntimeError { message, .. } => ("runtime", message),
+            LoxError::OutOfFuel { .. } => ("fuel", OUT_OF_FUEL),
+        };
+        let location = self.location();
+        Diagnostic {
+            file,
+            line: location.as_ref().map(|x| x.line),
+            column: location.as_ref().map(|x| x.column),
+            length: width(self.token()),
+            severity: Severity::Error,
+            code,
+            message,
+        }
+    }
+}
+
+/// How many columns an underline spans, the whole token when there is one
+fn width(token: Option<&Token>) -> usize {
+    token
+        .and_then(|x| x.lexeme.lines().next())
+        .map_or(1, |x| x.chars().count().max(1))
+}
+
+/// The line of `source` at `location`, underlined from there for `width` columns
+fn quote(location: &Location, width: usize, filename: &str, source: &str) -> Option<String> {
+    let line = source.lines().nth(location.line.checked_sub(1)?)?;
+    // Keep tabs so that the underline lines up however they are displayed
+    let padding: String = line
+        .chars()
+        .take(location.column.saturating_sub(1))
+        .map(|x| if x == '\t' { '\t' } else { ' ' })
+        .collect();
+    let gutter = " ".repeat(location.line.to_string().len());
+    Some(format!(
+        "{gutter}--> {}:{}:{}\n{gutter} |\n{} | {}\n{gutter} | {}{}",
+        filename,
+        location.line,
+        location.column,
+        location.line,
+        line,
+        padding,
+        "^".repeat(width).red()
+    ))
+}
+
+impl fmt::Display for LoxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoxError::ScanError { location, message } => {
+                write!(f, "{} Error: {}", location, message)
+            }
+            LoxError::ParseError { token, message } | LoxError::ResolveError { token, message } => {
+                if token.token == TokenType::Eof {
+                    write!(f, "{} Error at end: {}", token.location(), message)
+                } else {
+                    write!(
+                        f,
+                        "{} Error at '{}': {}",
+                        token.location(),
+                        token.lexeme,
+                        message
+                    )
+                }
+            }
+            LoxError::RuntimeError {
+                token: Some(token),
+                message,
+                ..
+            } => write!(f, "{} RuntimeError: {}", token.location(), message),
+            LoxError::RuntimeError {
+                token: None,
+                message,
+                ..
+            } => write!(f, "RuntimeError: {}", message),
+            LoxError::OutOfFuel { token: Some(token) } => {
+                write!(f, "{} RuntimeError: {}", token.location(), OUT_OF_FUEL)
+            }
+            LoxError::OutOfFuel { token: None } => write!(f, "RuntimeError: {}", OUT_OF_FUEL),
+        }
+    }
+}
+
+impl Error for LoxError {}
+
+/// The kinds of warnings, named as on the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Lint {
+    /// All of the ones below
+    Warnings,
+    /// A local variable that is never read
+    Unused,
+    /// Statements after a `return`
+    Unreachable,
+    /// A `loop` with no `return` to leave it by
+    Endless,
+    /// A local variable hiding one from an enclosing scope
+    Shadow,
+    /// A number added to a string, found while running the script
+    Concat,
+    /// A `print` in one of the files configured as libraries
+    Print,
+    /// A name not in the configured style, or a class not in PascalCase
+    Naming,
+    /// A function with more statements than configured
+    FunctionLength,
+    /// A global variable, function or class named with a single letter
+    ShortGlobal,
+}
+
+impl Lint {
+    pub fn name(self) -> &'static str {
+        match self {
+            Lint::Warnings => "warnings",
+            Lint::Unused => "unused",
+            Lint::Unreachable => "unreachable",
+            Lint::Endless => "endless",
+            Lint::Shadow => "shadow",
+            Lint::Concat => "concat",
+            Lint::Print => "print",
+            Lint::Naming => "naming",
+            Lint::FunctionLength => "function-length",
+            Lint::ShortGlobal => "short-global",
+        }
+    }
+
+    /// Whether a setting for `self` applies to warnings of the `lint` kind
+    pub fn covers(self, lint: Lint) -> bool {
+        self == Lint::Warnings || self == lint
+    }
+
+    /// Shadowing is often done on purpose, and the rest are matters of
+    /// style, so they have to be asked for
+    pub fn default_level(self) -> Level {
+        match self {
+            Lint::Shadow
+            | Lint::Print
+            | Lint::Naming
+            | Lint::FunctionLength
+            | Lint::ShortGlobal => Level::Allow,
+            _ => Level::Warn,
+        }
+    }
+}
+
+/// What happens to the warnings of a kind
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    Allow,
+    Warn,
+    /// Reported like the rest, but the script fails before running
+    Deny,
+}
+
+/// Something suspicious in a script that doesn't stop it from running
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub lint: Lint,
+    pub token: Box<Token>,
+    pub message: String,
+    pub note: Option<Note>,
+}
+
+/// Points at another place in the source that explains a warning
+#[derive(Debug, Clone, PartialEq)]
+pub struct Note {
+    pub token: Box<Token>,
+    pub message: String,
+}
+
+impl Warning {
+    /// Same as `LoxError::snippet`
+    pub fn snippet(&self, filename: &str, source: &str) -> Option<String> {
+        quote(
+            &self.token.location(),
+            width(Some(&self.token)),
+            filename,
+            source,
+        )
+    }
+
+    /// Same as `LoxError::diagnostic`, the note comes as a diagnostic of its own
+    pub fn diagnostics<'a>(&'a self, file: &'a str) -> Vec<Diagnostic<'a>> {
+        let mut diagnostics = vec![Diagnostic {
+            file,
+            line: Some(self.token.line),
+            column: Some(self.token.column),
+            length: width(Some(&self.token)),
+            severity: Severity::Warning,
+            code: self.lint.name(),
+            message: &self.message,
+        }];
+        if let Some(note) = &self.note {
+            diagnostics.push(Diagnostic {
+                file,
+                line: Some(note.token.line),
+                column: Some(note.token.column),
+                length: width(Some(&note.token)),
+                severity: Severity::Note,
+                code: self.lint.name(),
+                message: &note.message,
+            });
+        }
+        diagnostics
+    }
+}
+
+impl Note {
+    pub fn snippet(&self, filename: &str, source: &str) -> Option<String> {
+        quote(
+            &self.token.location(),
+            width(Some(&self.token)),
+            filename,
+            source,
+        )
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} Warning at '{}': {}",
+            self.token.location(),
+            self.token.lexeme,
+            self.message
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+/// An error or a warning the way editors and graders want it, printed as JSON.
+/// The `code` is the stage that failed for errors, and the lint for warnings.
+#[derive(Debug, Serialize)]
+pub struct Diagnostic<'a> {
+    pub file: &'a str,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub length: usize,
+    pub severity: Severity,
+    pub code: &'static str,
+    pub message: &'a str,
+}
+
+impl fmt::Display for Diagnostic<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let json = serde_json::to_string(self).map_err(|_| fmt::Error)?;
+        write!(f, "{}", json)
+    }
+}
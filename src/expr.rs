@@ -1,40 +1,89 @@
 use crate::scanner::Literal;
 use crate::scanner::Token;
+use crate::shared::Rc;
+use serde::{Deserialize, Deserializer, Serialize};
 use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-#[derive(Debug, Clone, PartialEq)]
+/// Tells apart the expressions the resolver binds to a scope, whatever they look
+/// like and wherever they are in the source
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub struct ExprId(usize);
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+impl ExprId {
+    /// A new id, never handed out before in this process
+    pub fn fresh() -> Self {
+        ExprId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// Expressions loaded back get fresh ids, as the ones saved may have been
+/// handed out again since, in this process or another
+impl<'de> Deserialize<'de> for ExprId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        serde::de::IgnoredAny::deserialize(deserializer)?;
+        Ok(ExprId::fresh())
+    }
+}
+
+/// Subexpressions are shared, so that the interpreter can keep hold of the ones
+/// it still has to evaluate without borrowing the whole tree
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Expr {
-    Assign(Token, Box<Expr>),
-    Binary(Box<Expr>, Token, Box<Expr>),
-    Call(Box<Expr>, Token, Vec<Expr>),
-    Get(Box<Expr>, Token),
-    Set(Box<Expr>, Token, Box<Expr>),
-    Super(Token, Token),
-    This(Token),
-    Grouping(Box<Expr>),
+    Assign(ExprId, Token, Rc<Expr>),
+    Binary(Rc<Expr>, Token, Rc<Expr>),
+    Call(Rc<Expr>, Token, Vec<Rc<Expr>>),
+    Get(ExprId, Rc<Expr>, Token),
+    Set(Rc<Expr>, Token, Rc<Expr>),
+    Super(ExprId, Token, Token),
+    This(ExprId, Token),
+    Grouping(Rc<Expr>),
     Literal(Literal),
-    Logical(Box<Expr>, Token, Box<Expr>),
-    Unary(Token, Box<Expr>),
-    Variable(Token),
+    Logical(Rc<Expr>, Token, Rc<Expr>),
+    Unary(Token, Rc<Expr>),
+    Variable(ExprId, Token),
+}
+
+impl Expr {
+    /// The leftmost token of the expression, if it has one. Literals don't keep
+    /// their tokens around, so an expression made only of literals has none.
+    pub fn token(&self) -> Option<&Token> {
+        match self {
+            Expr::Assign(_, name, _) => Some(name),
+            Expr::Binary(left, op, _) => left.token().or(Some(op)),
+            Expr::Call(callee, paren, _) => callee.token().or(Some(paren)),
+            Expr::Get(_, obj, name) => obj.token().or(Some(name)),
+            Expr::Set(obj, name, _) => obj.token().or(Some(name)),
+            Expr::Super(_, keyword, _) => Some(keyword),
+            Expr::This(_, keyword) => Some(keyword),
+            Expr::Grouping(expr) => expr.token(),
+            Expr::Literal(_) => None,
+            Expr::Logical(left, op, _) => left.token().or(Some(op)),
+            Expr::Unary(op, _) => Some(op),
+            Expr::Variable(_, name) => Some(name),
+        }
+    }
 }
 
 impl fmt::Display for Expr {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Expr::Assign(name, value) => write!(f, "(= {} {})", name.lexeme, value),
+            Expr::Assign(_, name, value) => write!(f, "(= {} {})", name.lexeme, value),
             Expr::Binary(left, op, right) => write!(f, "({} {} {})", op.lexeme, left, right),
             Expr::Call(callee, _paren, arguments) => {
                 write!(f, "(call {} ({}))", callee, vec_to_string(arguments))
             }
-            Expr::Get(obj, name) => write!(f, "(. {} {})", obj, name),
+            Expr::Get(_, obj, name) => write!(f, "(. {} {})", obj, name),
             Expr::Set(obj, name, val) => write!(f, "(.= {} {} {})", obj, name, val),
-            Expr::Super(keyword, method) => write!(f, "({} {})", keyword, method),
-            Expr::This(keyword) => write!(f, "{}", keyword),
+            Expr::Super(_, keyword, method) => write!(f, "({} {})", keyword, method),
+            Expr::This(_, keyword) => write!(f, "{}", keyword),
             Expr::Grouping(expr) => write!(f, "(group {})", expr),
             Expr::Literal(lit) => write!(f, "{}", lit),
             Expr::Logical(left, op, right) => write!(f, "({} {} {})", op.lexeme, left, right),
             Expr::Unary(op, expr) => write!(f, "({} {})", op.lexeme, expr),
-            Expr::Variable(ident) => write!(f, "{}", ident.lexeme),
+            Expr::Variable(_, ident) => write!(f, "{}", ident.lexeme),
         }
     }
 }
@@ -0,0 +1,102 @@
+// Native extensions: shared libraries adding natives to the interpreter, found
+// through the `rjlox_register` function they export:
+//
+//     #[no_mangle]
+//     pub extern "C" fn rjlox_register(registry: &mut Registry) {
+//         registry.define_native("answer", 0, |_, _| Ok(Value::Double(42.0)));
+//     }
+//
+// Rust has no stable ABI, so extensions have to be built against the same
+// version of rjlox, with the same compiler and features, as the interpreter.
+// Even then they have their own copy of it, with their own symbols, which the
+// interpreter's never compare equal to. So natives are defined by calling back
+// into the interpreter, and should only work with the values they are given.
+
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+
+use crate::interpreter::Interpreter;
+use crate::lox_callable::{Native, NativeFunction};
+use crate::sandbox::Capability;
+use crate::shared::Shareable;
+use crate::value::Value;
+
+#[link(name = "dl")]
+extern "C" {
+    fn dlopen(filename: *const c_char, flags: c_int) -> *mut c_void;
+    fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+    fn dlerror() -> *const c_char;
+}
+
+/// Resolves every symbol of the library when it is loaded
+const RTLD_NOW: c_int = 2;
+
+const REGISTER: &CStr = c"rjlox_register";
+
+type Register = extern "C" fn(&mut Registry);
+
+/// What extensions define their natives with
+pub struct Registry<'a> {
+    interpreter: &'a mut Interpreter,
+    /// Always the interpreter's `define`, even when called from an extension
+    define: fn(&mut Interpreter, &str, usize, Box<Native>),
+}
+
+impl Registry<'_> {
+    /// Same as `Interpreter::define_native`
+    pub fn define_native(
+        &mut self,
+        name: &str,
+        arity: usize,
+        callable: impl Fn(&mut Interpreter, &[Value]) -> Result<Value, String> + Shareable + 'static,
+    ) {
+        (self.define)(self.interpreter, name, arity, Box::new(callable));
+    }
+}
+
+fn define(interpreter: &mut Interpreter, name: &str, arity: usize, callable: Box<Native>) {
+    interpreter.define_native(name, arity, callable);
+}
+
+/// `loadExtension(path)`, for scripts the sandbox lets load native code
+pub fn native() -> NativeFunction {
+    NativeFunction::new("loadExtension", 1, |interpreter, arguments| {
+        interpreter.policy().require(Capability::Extensions)?;
+        match &arguments[0] {
+            Value::String(path) => load(interpreter, path).map(|_| Value::None),
+            _ => Err("Extension paths must be strings.".into()),
+        }
+    })
+}
+
+/// Loads the library at `path`, looked up like `dlopen` does when it has no
+/// slash, and lets it define its natives. Libraries are never unloaded, as
+/// the natives they defined point into them.
+pub fn load(interpreter: &mut Interpreter, path: &str) -> Result<(), String> {
+    let name = CString::new(path).map_err(|_| format!("Can't load extension '{}'.", path))?;
+    // Safe, as both strings end with a nul and the library is trusted to
+    // export the function with the signature it is documented to have
+    unsafe {
+        let library = dlopen(name.as_ptr(), RTLD_NOW);
+        if library.is_null() {
+            return Err(format!(
+                "Can't load extension '{}': {}.",
+                path,
+                CStr::from_ptr(dlerror()).to_string_lossy()
+            ));
+        }
+        let register = dlsym(library, REGISTER.as_ptr());
+        if register.is_null() {
+            return Err(format!(
+                "Extension '{}' doesn't export '{}'.",
+                path,
+                REGISTER.to_string_lossy()
+            ));
+        }
+        let register: Register = std::mem::transmute(register);
+        register(&mut Registry {
+            interpreter,
+            define,
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,53 @@
+// Natives looking at the filesystem, which give their paths as lists and so
+// only exist in the tree-walker. They need the sandbox to allow access to
+// files, checked on every call.
+
+use std::fs;
+
+use crate::collections::List;
+use crate::interpreter::Interpreter;
+use crate::lox_callable::NativeFunction;
+use crate::sandbox::Capability;
+use crate::shared::Rc;
+use crate::value::Value;
+
+/// `listDir(path)` and `glob(pattern)`
+pub fn natives() -> Vec<NativeFunction> {
+    vec![
+        NativeFunction::new("listDir", 1, |interpreter, arguments| {
+            let path = allowed(interpreter, &arguments[0])?;
+            let entries =
+                fs::read_dir(path).map_err(|e| format!("Can't list '{}': {}.", path, e))?;
+            let mut paths = entries
+                .map(|entry| entry.map(|x| x.path().to_string_lossy().into_owned()))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("Can't list '{}': {}.", path, e))?;
+            paths.sort();
+            Ok(list(paths))
+        }),
+        NativeFunction::new("glob", 1, |interpreter, arguments| {
+            let pattern = allowed(interpreter, &arguments[0])?;
+            let paths = glob::glob(pattern)
+                .map_err(|e| format!("Invalid pattern '{}': {}.", pattern, e.msg))?;
+            let paths = paths
+                .map(|path| path.map(|x| x.to_string_lossy().into_owned()))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("Can't read '{}': {}.", e.path().display(), e.error()))?;
+            Ok(list(paths))
+        }),
+    ]
+}
+
+/// The path or pattern a native was called with, once the sandbox allows it
+fn allowed<'a>(interpreter: &Interpreter, argument: &'a Value) -> Result<&'a str, String> {
+    interpreter.policy().require(Capability::Filesystem)?;
+    match argument {
+        Value::String(path) => Ok(path),
+        _ => Err("Paths must be strings.".into()),
+    }
+}
+
+fn list(paths: Vec<String>) -> Value {
+    let paths = paths.into_iter().map(|x| Value::String(x.into())).collect();
+    Value::Foreign(Rc::new(List::new(paths)))
+}
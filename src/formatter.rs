@@ -0,0 +1,530 @@
+use crate::error::LoxError;
+use crate::expr::Expr;
+use crate::parser::Parser;
+use crate::scanner::{Comment, Literal, Scanner, Token, TokenType as TT};
+use crate::shared::Rc;
+use crate::stmt::Stmt;
+
+const INDENT: &str = "  ";
+
+/// Turns a parsed program back into canonical Lox source: two space indents,
+/// opening braces on the same line, single spaces around binary operators and
+/// a blank line around function and class declarations. Comments are put back
+/// before the statement that follows them, or at the end of the statement
+/// they trailed, always in the block they were written in, which the braces
+/// among `tokens` tell. Runs of `blank_lines` between statements are kept as a
+/// single one.
+pub fn format(
+    statements: &[Stmt],
+    tokens: &[Token],
+    comments: &[Comment],
+    blank_lines: &[usize],
+) -> String {
+    // Each block is written when its opening brace comes up, so the pairs go
+    // in the order of those, whichever closes first
+    let mut braces = Vec::new();
+    let mut open = Vec::new();
+    for token in tokens {
+        match token.token {
+            TT::LeftBrace => {
+                open.push(braces.len());
+                braces.push((token.line, token.line));
+            }
+            TT::RightBrace => {
+                if let Some(i) = open.pop() {
+                    braces[i].1 = token.line;
+                }
+            }
+            _ => {}
+        }
+    }
+    let mut formatter = Formatter {
+        comments,
+        next_comment: 0,
+        blank_lines,
+        braces: braces.into_iter(),
+        last_line: 0,
+        out: String::new(),
+        depth: 0,
+    };
+    formatter.statements(statements, usize::MAX);
+    formatter.leading_comments(usize::MAX);
+    formatter.out
+}
+
+struct Formatter<'a> {
+    comments: &'a [Comment],
+    next_comment: usize,
+    blank_lines: &'a [usize],
+    /// The lines of the opening and closing braces of each block in the
+    /// source, in the order the blocks are written in
+    braces: std::vec::IntoIter<(usize, usize)>,
+    /// The last source line that made it into the output
+    last_line: usize,
+    out: String,
+    depth: usize,
+}
+
+impl Formatter<'_> {
+    fn indent(&mut self) {
+        self.out.push_str(&INDENT.repeat(self.depth));
+    }
+
+    /// Writes all the comments that come before `line` on lines of their own,
+    /// but for one trailing code not written yet, like the `{` of a statement
+    /// whose first token is further down
+    fn leading_comments(&mut self, line: usize) {
+        while let Some(comment) = self.comments.get(self.next_comment) {
+            if comment.line >= line || (comment.trailing && comment.line > self.last_line) {
+                break;
+            }
+            self.indent();
+            self.out.push_str(&comment.text);
+            self.out.push('\n');
+            self.last_line = self.last_line.max(comment.line);
+            self.next_comment += 1;
+        }
+    }
+
+    /// Appends the comments trailing code on the lines up to `line`, which
+    /// statements spanning several lines can have more than one of
+    fn trailing_comments(&mut self, line: usize) {
+        while let Some(comment) = self.comments.get(self.next_comment) {
+            if !comment.trailing || comment.line > line {
+                break;
+            }
+            self.out.push(' ');
+            self.out.push_str(&comment.text);
+            self.next_comment += 1;
+        }
+    }
+
+    /// Whether a comment comes before `line`
+    fn comment_before(&self, line: usize) -> bool {
+        self.comments
+            .get(self.next_comment)
+            .is_some_and(|x| x.line < line)
+    }
+
+    /// Whether the source had a blank line between what was last written and
+    /// the statement at `line`, including the comments leading it
+    fn blank_before(&self, line: usize) -> bool {
+        let start = match self.comments.get(self.next_comment) {
+            Some(comment) if comment.line < line => comment.line,
+            _ => line,
+        };
+        self.blank_lines
+            .iter()
+            .any(|&x| x > self.last_line && x < start)
+    }
+
+    /// Statements of a block ending at the line `end`, of its closing brace
+    fn statements(&mut self, statements: &[Stmt], end: usize) {
+        for (i, stmt) in statements.iter().enumerate() {
+            let line = self.first_line(stmt);
+            if i > 0
+                && (is_declaration(stmt)
+                    || is_declaration(&statements[i - 1])
+                    || line.is_some_and(|x| self.blank_before(x)))
+            {
+                self.out.push('\n');
+            }
+            let next = statements[i + 1..].iter().find_map(|x| x.token());
+            self.statement_line(stmt, next.map_or(end, |x| x.line));
+        }
+    }
+
+    /// The line `stmt` starts at. A block's token is that of the first
+    /// statement in it, so its `{` is looked at instead, being the next one.
+    fn first_line(&self, stmt: &Stmt) -> Option<usize> {
+        match stmt {
+            Stmt::Block(_) => self.braces.as_slice().first().map(|x| x.0),
+            _ => stmt.token().map(|x| x.line),
+        }
+    }
+
+    /// Writes `stmt` on a line of its own, with the comments trailing code on
+    /// the lines before `next`, where the next statement or the block's closing
+    /// brace is, as they can't belong to anything else
+    fn statement_line(&mut self, stmt: &Stmt, next: usize) {
+        let line = self.first_line(stmt);
+        if let Some(line) = line {
+            self.leading_comments(line);
+            self.last_line = self.last_line.max(line);
+        }
+        self.indent();
+        self.statement(stmt);
+        if line.is_some() {
+            self.last_line = self.last_line.max(last_line(stmt));
+        }
+        self.trailing_comments(next.saturating_sub(1));
+        self.out.push('\n');
+    }
+
+    /// A block without the newline after the closing brace. The comments up to
+    /// the closing brace are written inside.
+    fn block(&mut self, statements: &[Stmt]) {
+        let (open, close) = self.braces.next().unzip();
+        let commented = close.is_some_and(|x| self.comment_before(x));
+        if statements.is_empty() && !commented {
+            self.out.push_str("{}");
+            if let Some(close) = close {
+                self.last_line = self.last_line.max(close);
+            }
+            return;
+        }
+        self.open(open, close);
+        self.statements(statements, close.unwrap_or(usize::MAX));
+        self.depth -= 1;
+        self.close(close);
+    }
+
+    /// Starts a block whose braces are at the lines `open` and `close`. A
+    /// comment after the `{` stays there, unless the `}` is on the same line,
+    /// as then it comes after that.
+    fn open(&mut self, open: Option<usize>, close: Option<usize>) {
+        self.out.push('{');
+        if let (Some(open), Some(close)) = (open, close) {
+            if open < close {
+                self.trailing_comments(open);
+            }
+        }
+        self.out.push('\n');
+        self.depth += 1;
+    }
+
+    /// Ends a block on a line of its own, once the comments before the closing
+    /// brace at `line` are written inside it
+    fn close(&mut self, line: Option<usize>) {
+        if let Some(line) = line {
+            if self.comment_before(line) && self.blank_before(line) {
+                self.out.push('\n');
+            }
+            self.depth += 1;
+            self.leading_comments(line);
+            self.depth -= 1;
+            self.last_line = self.last_line.max(line);
+        }
+        self.indent();
+        self.out.push('}');
+    }
+
+    /// The body of a loop or a branch, after its header
+    fn body(&mut self, stmt: &Stmt) {
+        self.out.push(' ');
+        match stmt {
+            Stmt::Block(statements) => self.block(statements),
+            _ => self.statement(stmt),
+        }
+    }
+
+    fn function(&mut self, stmt: &Stmt) {
+        if let Stmt::Function(name, params, body, _) = stmt {
+            let params = params
+                .iter()
+                .map(|x| &*x.lexeme)
+                .collect::<Vec<_>>()
+                .join(", ");
+            self.out.push_str(&format!("{}({}) ", name.lexeme, params));
+            self.block(body);
+        }
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Block(statements) => self.block(statements),
+            Stmt::Class(name, superclass, methods, _) => {
+                self.out.push_str(&format!("class {} ", name.lexeme));
+                if let Some(superclass) = superclass {
+                    self.out.push_str(&format!("< {} ", expr(superclass)));
+                }
+                let (open, close) = self.braces.next().unzip();
+                let commented = close.is_some_and(|x| self.comment_before(x));
+                if methods.is_empty() && !commented {
+                    self.out.push_str("{}");
+                    if let Some(close) = close {
+                        self.last_line = self.last_line.max(close);
+                    }
+                    return;
+                }
+                self.open(open, close);
+                for (i, method) in methods.iter().enumerate() {
+                    if i > 0 {
+                        self.out.push('\n');
+                    }
+                    if let Some(line) = method.token().map(|x| x.line) {
+                        self.leading_comments(line);
+                    }
+                    self.indent();
+                    self.function(method);
+                    self.trailing_comments(self.last_line);
+                    self.out.push('\n');
+                }
+                self.depth -= 1;
+                self.close(close);
+            }
+            Stmt::Expression(e) => self.out.push_str(&format!("{};", expr(e))),
+            Stmt::For(keyword, None, None, None, body) if keyword.lexeme == "loop" => {
+                self.out.push_str("loop");
+                self.body(body);
+            }
+            Stmt::For(_, initializer, condition, increment, body) => {
+                self.out.push_str("for (");
+                match initializer {
+                    Some(init) => self.statement(init),
+                    None => self.out.push(';'),
+                }
+                if let Some(cond) = condition {
+                    self.out.push_str(&format!(" {}", expr(cond)));
+                }
+                self.out.push(';');
+                if let Some(inc) = increment {
+                    self.out.push_str(&format!(" {}", expr(inc)));
+                }
+                self.out.push(')');
+                self.body(body);
+            }
+            Stmt::ForIn(_, name, collection, body) => {
+                self.out
+                    .push_str(&format!("for ({} in {})", name.lexeme, expr(collection)));
+                self.body(body);
+            }
+            Stmt::Function(_, _, _, _) => {
+                self.out.push_str("fun ");
+                self.function(stmt);
+            }
+            Stmt::If(cond, then_branch, else_branch) => {
+                self.out.push_str(&format!("if ({})", expr(cond)));
+                self.body(then_branch);
+                if let Some(else_branch) = else_branch {
+                    if matches!(**then_branch, Stmt::Block(_)) {
+                        self.out.push_str(" else");
+                    } else {
+                        self.out.push('\n');
+                        self.indent();
+                        self.out.push_str("else");
+                    }
+                    self.body(else_branch);
+                }
+            }
+            Stmt::Print(_, e) => self.out.push_str(&format!("print {};", expr(e))),
+            Stmt::Return(_, e) if matches!(**e, Expr::Literal(Literal::None)) => {
+                self.out.push_str("return;")
+            }
+            Stmt::Return(_, e) => self.out.push_str(&format!("return {};", expr(e))),
+            Stmt::Test(name, body) => {
+                self.out
+                    .push_str(&format!("test {} ", literal(&name.literal)));
+                self.block(body);
+            }
+            Stmt::Var(name, Some(init), constant) => self.out.push_str(&format!(
+                "{} {} = {};",
+                if *constant { "const" } else { "var" },
+                name.lexeme,
+                operand(init, ASSIGNMENT)
+            )),
+            Stmt::Var(name, None, _) => self.out.push_str(&format!("var {};", name.lexeme)),
+            Stmt::While(_, cond, body) => {
+                self.out.push_str(&format!("while ({})", expr(cond)));
+                self.body(body);
+            }
+        }
+    }
+}
+
+fn is_declaration(stmt: &Stmt) -> bool {
+    matches!(
+        stmt,
+        Stmt::Class(_, _, _, _) | Stmt::Function(_, _, _, _) | Stmt::Test(_, _)
+    )
+}
+
+/// The last line with a token of `stmt` outside of its blocks, whose closing
+/// braces are kept track of by the blocks themselves
+fn last_line(stmt: &Stmt) -> usize {
+    let expr = |x: &Option<Rc<Expr>>| x.as_deref().map_or(0, expr_last_line);
+    match stmt {
+        Stmt::Block(_) | Stmt::Class(_, _, _, _) | Stmt::Test(_, _) => 0,
+        Stmt::Expression(e) | Stmt::Print(_, e) | Stmt::Return(_, e) => expr_last_line(e),
+        Stmt::For(keyword, initializer, condition, increment, body) => keyword
+            .line
+            .max(initializer.as_deref().map_or(0, last_line))
+            .max(expr(condition))
+            .max(expr(increment))
+            .max(last_line(body)),
+        Stmt::ForIn(_, name, collection, body) => name
+            .line
+            .max(expr_last_line(collection))
+            .max(last_line(body)),
+        Stmt::Function(name, params, _, _) => {
+            params.iter().map(|x| x.line).fold(name.line, usize::max)
+        }
+        Stmt::If(cond, then_branch, else_branch) => expr_last_line(cond)
+            .max(last_line(then_branch))
+            .max(else_branch.as_deref().map_or(0, last_line)),
+        Stmt::Var(name, initializer, _) => name.line.max(expr(initializer)),
+        Stmt::While(_, cond, body) => expr_last_line(cond).max(last_line(body)),
+    }
+}
+
+fn expr_last_line(e: &Expr) -> usize {
+    match e {
+        Expr::Assign(_, name, value) => name.line.max(expr_last_line(value)),
+        Expr::Binary(left, op, right) | Expr::Logical(left, op, right) => {
+            expr_last_line(left).max(op.line).max(expr_last_line(right))
+        }
+        Expr::Call(callee, paren, arguments) => arguments
+            .iter()
+            .map(|x| expr_last_line(x))
+            .fold(expr_last_line(callee).max(paren.line), usize::max),
+        Expr::Get(_, obj, name) => expr_last_line(obj).max(name.line),
+        Expr::Set(obj, name, value) => expr_last_line(obj)
+            .max(name.line)
+            .max(expr_last_line(value)),
+        Expr::Super(_, _, method) => method.line,
+        Expr::This(_, keyword) => keyword.line,
+        Expr::Grouping(inner) => expr_last_line(inner),
+        Expr::Literal(_) => 0,
+        Expr::Unary(op, right) => op.line.max(expr_last_line(right)),
+        Expr::Variable(_, name) => name.line,
+    }
+}
+
+fn literal(lit: &Literal) -> String {
+    match lit {
+        // Whole doubles keep a decimal point, or they'd read back as integers
+        Literal::Double(num) if num.fract() == 0.0 => format!("{}.0", num),
+        Literal::Double(num) => format!("{}", num),
+        // Escaped again, so that they read back the same
+        Literal::String(text) => {
+            let mut escaped = String::from('"');
+            for c in text.chars() {
+                match c {
+                    '"' => escaped.push_str("\\\""),
+                    '\\' => escaped.push_str("\\\\"),
+                    '\n' => escaped.push_str("\\n"),
+                    '\t' => escaped.push_str("\\t"),
+                    '\r' => escaped.push_str("\\r"),
+                    '\0' => escaped.push_str("\\0"),
+                    _ => escaped.push(c),
+                }
+            }
+            escaped.push('"');
+            escaped
+        }
+        _ => lit.to_string(),
+    }
+}
+
+/// How tightly expressions bind, from commas up to literals, in the order of
+/// the grammar
+const COMMA: u8 = 0;
+const ASSIGNMENT: u8 = 1;
+const UNARY: u8 = 8;
+const CALL: u8 = 9;
+const PRIMARY: u8 = 10;
+
+fn precedence(expr_: &Expr) -> u8 {
+    match expr_ {
+        Expr::Assign(_, _, _) | Expr::Set(_, _, _) => ASSIGNMENT,
+        Expr::Binary(_, op, _) | Expr::Logical(_, op, _) => match op.token {
+            TT::Comma => COMMA,
+            TT::Or => 2,
+            TT::And => 3,
+            TT::BangEqual | TT::EqualEqual => 4,
+            TT::Greater | TT::GreaterEqual | TT::Less | TT::LessEqual => 5,
+            TT::Minus | TT::Plus => 6,
+            _ => 7,
+        },
+        // Negative numbers only come out of the optimizer, and read as negations
+        Expr::Literal(Literal::Integer(n)) if *n < 0 => UNARY,
+        Expr::Literal(Literal::Double(n)) if n.is_sign_negative() => UNARY,
+        Expr::Unary(_, _) => UNARY,
+        Expr::Call(_, _, _) | Expr::Get(_, _, _) => CALL,
+        _ => PRIMARY,
+    }
+}
+
+/// `expr_`, in parentheses when it binds less tightly than `min` does
+fn operand(expr_: &Expr, min: u8) -> String {
+    if precedence(expr_) < min {
+        format!("({})", expr(expr_))
+    } else {
+        expr(expr_)
+    }
+}
+
+/// Parsed expressions keep their parentheses as groupings. Trees made some
+/// other way, like with `crate::ast`, get the ones their shape needs, with
+/// operators associating to the left.
+pub fn expr(expr_: &Expr) -> String {
+    match expr_ {
+        Expr::Assign(_, name, value) => {
+            format!("{} = {}", name.lexeme, operand(value, ASSIGNMENT))
+        }
+        Expr::Binary(left, op, right) if op.token == TT::Comma => {
+            format!("{}, {}", expr(left), operand(right, ASSIGNMENT))
+        }
+        Expr::Binary(left, op, right) | Expr::Logical(left, op, right) => {
+            let min = precedence(expr_);
+            format!(
+                "{} {} {}",
+                operand(left, min),
+                op.lexeme,
+                operand(right, min + 1)
+            )
+        }
+        Expr::Call(callee, _, arguments) => format!(
+            "{}({})",
+            operand(callee, CALL),
+            arguments
+                .iter()
+                .map(|x| operand(x, ASSIGNMENT))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        Expr::Get(_, obj, name) => format!("{}.{}", operand(obj, CALL), name.lexeme),
+        Expr::Set(obj, name, value) => {
+            format!(
+                "{}.{} = {}",
+                operand(obj, CALL),
+                name.lexeme,
+                operand(value, ASSIGNMENT)
+            )
+        }
+        Expr::Super(_, _, method) => format!("super.{}", method.lexeme),
+        Expr::This(_, _) => "this".into(),
+        Expr::Grouping(inner) => format!("({})", expr(inner)),
+        Expr::Literal(lit) => literal(lit),
+        Expr::Unary(op, right) => format!("{}{}", op.lexeme, operand(right, UNARY)),
+        Expr::Variable(_, name) => name.lexeme.to_string(),
+    }
+}
+
+/// Lox source for statements with no source of their own, like the ones code
+/// generators build, formatted like `fmt` would
+pub fn to_source(statements: &[Stmt]) -> String {
+    format(statements, &[], &[], &[])
+}
+
+/// Scans and parses `source` and formats it, failing with the syntax errors
+/// when it isn't a valid program
+pub fn format_source(source: &str) -> Result<String, Vec<LoxError>> {
+    let mut scanner = Scanner::new(source);
+    let raw_tokens = scanner.scan_tokens();
+    let errors: Vec<LoxError> = raw_tokens
+        .iter()
+        .filter_map(|x| x.as_ref().err().cloned())
+        .collect();
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let tokens = raw_tokens.iter().flatten().cloned().collect::<Vec<_>>();
+    let statements = Parser::new(tokens.clone()).parse()?;
+    Ok(format(
+        &statements,
+        &tokens,
+        scanner.comments(),
+        scanner.blank_lines(),
+    ))
+}
@@ -0,0 +1,347 @@
+use crate::shared::{Rc, Shareable, Weak};
+use std::collections::{HashMap, HashSet};
+use std::hash::BuildHasherDefault;
+
+use tracing::debug;
+
+use crate::symbol::SymbolHasher;
+
+/// Collections happen once this many objects were registered since the last
+/// one, or twice as many as were still alive then if that is more
+const INITIAL_THRESHOLD: usize = 1024;
+
+type Addresses = BuildHasherDefault<SymbolHasher>;
+
+/// Anything holding on to objects of the heap
+pub trait Trace {
+    fn trace(&self, tracer: &mut Tracer);
+}
+
+/// What can be changed after it was made, and so end up in a reference cycle,
+/// like environments and instances. Every cycle goes through one of them, so
+/// emptying the ones that can't be reached anymore frees all the garbage.
+pub trait Collect: Trace + Shareable {
+    /// Drops whatever it holds on to
+    fn clear(&self);
+    /// What it is, for `--leak-check` to name it
+    fn describe(&self) -> String;
+    /// Roughly how many bytes it takes, the text of its strings included but
+    /// not the objects it leads to
+    fn size(&self) -> usize;
+}
+
+/// What hosts can pin, see [`Heap::pin`]
+pub trait Root: Trace + Shareable {}
+
+impl<T: Trace + Shareable + ?Sized> Root for T {}
+
+/// A value the host holds on to, which keeps everything it leads to alive for
+/// as long as any clone of it is around, however unreachable from the scripts
+pub struct Pinned<T>(Rc<T>);
+
+impl<T> Clone for Pinned<T> {
+    fn clone(&self) -> Self {
+        Pinned(self.0.clone())
+    }
+}
+
+impl<T> std::ops::Deref for Pinned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: std::fmt::Debug> std::fmt::Debug for Pinned<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Goes through everything reachable, without recursing on the Rust stack for
+/// the objects of the heap, which can be chained very long
+pub struct Tracer {
+    /// By address, which hashes just as fast as symbols do
+    visited: HashSet<*const (), Addresses>,
+    pending: Vec<Rc<dyn Collect>>,
+    /// Taken by everything reachable gone through so far
+    bytes: usize,
+}
+
+impl Tracer {
+    /// Whether `rc` is seen for the first time, for things shared all over
+    /// like functions and classes to only be traced once
+    pub fn visit<T: ?Sized>(&mut self, rc: &Rc<T>) -> bool {
+        self.visited.insert(Rc::as_ptr(rc) as *const ())
+    }
+
+    /// Keeps an object alive, tracing it later if it wasn't already
+    pub fn mark<T: Collect + 'static>(&mut self, object: &Rc<T>) {
+        if self.visit(object) {
+            self.pending.push(object.clone());
+        }
+    }
+
+    /// Counts what a root takes, for the roots that aren't objects of the
+    /// heap, like the values on a stack
+    pub fn count(&mut self, bytes: usize) {
+        self.bytes += bytes;
+    }
+
+    fn new(capacity: usize) -> Self {
+        Tracer {
+            visited: HashSet::with_capacity_and_hasher(capacity, Default::default()),
+            pending: Vec::new(),
+            bytes: 0,
+        }
+    }
+}
+
+/// The objects that could end up in reference cycles, which `Rc` alone never
+/// frees. The interpreter and the VM each have their own, and register what
+/// they make in it.
+pub struct Heap {
+    objects: Vec<Weak<dyn Collect>>,
+    /// Roots besides the ones of the interpreter, for as long as they live
+    pinned: Vec<Weak<dyn Root>>,
+    /// Registered since the last collection
+    allocated: usize,
+    threshold: usize,
+    /// Roughly how many bytes everything reachable took at the last
+    /// collection, plus what was allocated since
+    bytes: usize,
+    /// Where the objects were made, only kept for `--leak-check`. By address
+    /// as a number, which unlike a pointer can be sent between threads.
+    sites: Option<HashMap<usize, String, Addresses>>,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap {
+            objects: Vec::new(),
+            pinned: Vec::new(),
+            allocated: 0,
+            threshold: INITIAL_THRESHOLD,
+            bytes: 0,
+            sites: None,
+        }
+    }
+
+    /// Remembers where objects are made from now on, for `cycles` to tell
+    pub fn keep_sites(&mut self) {
+        self.sites.get_or_insert_with(HashMap::default);
+    }
+
+    /// Says where the object registered last was made, when sites are kept
+    pub fn record_site(&mut self, site: impl FnOnce() -> String) {
+        if let (Some(sites), Some(object)) = (&mut self.sites, self.objects.last()) {
+            sites.insert(object.as_ptr() as *const () as usize, site());
+        }
+    }
+
+    pub fn register<T: Collect + 'static>(&mut self, object: &Rc<T>) {
+        // Like an environment several functions are declared in in a row
+        let address = Rc::as_ptr(object) as *const ();
+        if self
+            .objects
+            .last()
+            .is_some_and(|x| x.as_ptr() as *const () == address)
+        {
+            return;
+        }
+        self.objects
+            .push(Rc::downgrade(object) as Weak<dyn Collect>);
+        self.allocated += 1;
+    }
+
+    /// Keeps `value` and what it leads to from being emptied as long as the
+    /// handle given back is, for the host to hold on to objects of the heap
+    /// while scripts drop them
+    pub fn pin<T: Root + 'static>(&mut self, value: T) -> Pinned<T> {
+        let pinned = Rc::new(value);
+        self.pinned.push(Rc::downgrade(&pinned) as Weak<dyn Root>);
+        Pinned(pinned)
+    }
+
+    /// Accounts for `bytes` more being taken, until the next collection
+    /// measures what actually still is
+    pub fn allocate(&mut self, bytes: usize) {
+        self.bytes += bytes;
+    }
+
+    /// What `max-memory-bytes` is checked against, only ever an estimate
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Whether enough was made since the last collection for another one
+    pub fn should_collect(&self) -> bool {
+        self.allocated >= self.threshold
+    }
+
+    /// Marks everything `roots` lead to, and empties the rest
+    pub fn collect(&mut self, roots: impl FnOnce(&mut Tracer)) {
+        let mut tracer = Tracer::new(self.objects.len());
+        roots(&mut tracer);
+        self.pinned.retain(|x| match x.upgrade() {
+            Some(pinned) => {
+                pinned.trace(&mut tracer);
+                true
+            }
+            None => false,
+        });
+        while let Some(object) = tracer.pending.pop() {
+            tracer.bytes += object.size();
+            object.trace(&mut tracer);
+        }
+        let mut garbage = Vec::new();
+        let sites = &mut self.sites;
+        self.objects.retain(|x| {
+            let address = x.as_ptr() as *const ();
+            let alive = match x.upgrade() {
+                None => false,
+                Some(_) if tracer.visited.contains(&address) => true,
+                Some(object) => {
+                    garbage.push(object);
+                    false
+                }
+            };
+            if let (false, Some(sites)) = (alive, sites.as_mut()) {
+                sites.remove(&(address as usize));
+            }
+            alive
+        });
+        // Nothing is dropped until all of them are emptied, so that none of
+        // the objects still to go through gets freed from under the loop
+        garbage.iter().for_each(|x| x.clear());
+        debug!(
+            "collect freed={} alive={}",
+            garbage.len(),
+            self.objects.len()
+        );
+        self.allocated = 0;
+        self.bytes = tracer.bytes;
+        self.threshold = INITIAL_THRESHOLD.max(2 * self.objects.len());
+    }
+
+    /// The groups of objects that keep each other alive, each named after what
+    /// it is and where it was made, in the order they were made. Objects that
+    /// were never registered, like the environment binding `this` to a method,
+    /// show up too when they are part of a cycle. The globals are left out, as
+    /// they stay alive anyway.
+    pub fn cycles<T: ?Sized>(&self, globals: &Rc<T>) -> Vec<Vec<String>> {
+        let mut objects: Vec<Rc<dyn Collect>> =
+            self.objects.iter().filter_map(|x| x.upgrade()).collect();
+        let mut indices: HashMap<*const (), usize, Addresses> = objects
+            .iter()
+            .enumerate()
+            .map(|(i, x)| (Rc::as_ptr(x) as *const (), i))
+            .collect();
+        let globals = Rc::as_ptr(globals) as *const ();
+        // What each object holds on to directly, going through functions and
+        // classes, which can't be in cycles of their own
+        let mut edges = Vec::new();
+        while edges.len() < objects.len() {
+            let mut tracer = Tracer::new(0);
+            objects[edges.len()].trace(&mut tracer);
+            let targets = tracer
+                .pending
+                .into_iter()
+                .filter(|x| Rc::as_ptr(x) as *const () != globals)
+                .map(|x| {
+                    let next = objects.len();
+                    let index = *indices.entry(Rc::as_ptr(&x) as *const ()).or_insert(next);
+                    if index == next {
+                        objects.push(x);
+                    }
+                    index
+                })
+                .collect::<Vec<_>>();
+            edges.push(targets);
+        }
+        components(&edges)
+            .into_iter()
+            .filter(|x| x.len() > 1 || edges[x[0]].contains(&x[0]))
+            .map(|x| {
+                x.into_iter()
+                    .map(|i| {
+                        let object = &objects[i];
+                        let address = Rc::as_ptr(object) as *const () as usize;
+                        match self.sites.as_ref().and_then(|x| x.get(&address)) {
+                            Some(site) => format!("{} {}", object.describe(), site),
+                            None => object.describe(),
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// The strongly connected components of a graph, with Tarjan's algorithm, each
+/// sorted and in the order of their first node. Done with a stack of its own, as
+/// chains of objects can be very long.
+fn components(edges: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    let mut order: Vec<Option<usize>> = vec![None; edges.len()];
+    let mut low = vec![0; edges.len()];
+    let mut on_stack = vec![false; edges.len()];
+    let mut stack = Vec::new();
+    let mut visits: Vec<(usize, usize)> = Vec::new();
+    let mut count = 0;
+    let mut components = Vec::new();
+    for root in 0..edges.len() {
+        if order[root].is_some() {
+            continue;
+        }
+        order[root] = Some(count);
+        low[root] = count;
+        count += 1;
+        stack.push(root);
+        on_stack[root] = true;
+        visits.push((root, 0));
+        while let Some((node, next)) = visits.last_mut() {
+            let node = *node;
+            if let Some(&target) = edges[node].get(*next) {
+                *next += 1;
+                match order[target] {
+                    None => {
+                        order[target] = Some(count);
+                        low[target] = count;
+                        count += 1;
+                        stack.push(target);
+                        on_stack[target] = true;
+                        visits.push((target, 0));
+                    }
+                    Some(seen) if on_stack[target] => low[node] = low[node].min(seen),
+                    Some(_) => (),
+                }
+                continue;
+            }
+            visits.pop();
+            if let Some((parent, _)) = visits.last() {
+                low[*parent] = low[*parent].min(low[node]);
+            }
+            if Some(low[node]) == order[node] {
+                let mut component = Vec::new();
+                while let Some(member) = stack.pop() {
+                    on_stack[member] = false;
+                    component.push(member);
+                    if member == node {
+                        break;
+                    }
+                }
+                component.sort_unstable();
+                components.push(component);
+            }
+        }
+    }
+    components.sort_unstable_by_key(|x| x[0]);
+    components
+}
@@ -0,0 +1,234 @@
+// Syntax trees as Graphviz graphs, for `rjlox ast --format dot`, to see how a
+// script parses:
+//
+//     rjlox ast --format dot script.lox | dot -Tsvg > script.svg
+//
+// Statements are boxes and expressions ellipses, labeled with their keyword,
+// operator, name or value. Edges go from each node to its children, in order,
+// named when the part a child plays isn't clear from the order alone.
+
+use std::fmt::Write;
+
+use crate::expr::Expr;
+use crate::stmt::Stmt;
+
+/// One graph for all of `statements`, under a node for the whole script
+pub fn dot(statements: &[Stmt]) -> String {
+    let mut graph = Graph {
+        out: String::from("digraph ast {\n  node [fontname=\"monospace\"];\n"),
+        nodes: 0,
+    };
+    let root = graph.node("script", "box");
+    for statement in statements {
+        let child = graph.stmt(statement);
+        graph.edge(root, child, None);
+    }
+    graph.out.push_str("}\n");
+    graph.out
+}
+
+struct Graph {
+    out: String,
+    nodes: usize,
+}
+
+impl Graph {
+    fn node(&mut self, label: &str, shape: &str) -> usize {
+        let id = self.nodes;
+        self.nodes += 1;
+        let _ = writeln!(
+            self.out,
+            "  n{} [label=\"{}\", shape={}];",
+            id,
+            escape(label),
+            shape
+        );
+        id
+    }
+
+    fn edge(&mut self, from: usize, to: usize, label: Option<&str>) {
+        let _ = match label {
+            Some(label) => writeln!(self.out, "  n{} -> n{} [label=\"{}\"];", from, to, label),
+            None => writeln!(self.out, "  n{} -> n{};", from, to),
+        };
+    }
+
+    fn stmts(&mut self, parent: usize, statements: &[Stmt]) {
+        for statement in statements {
+            let child = self.stmt(statement);
+            self.edge(parent, child, None);
+        }
+    }
+
+    fn stmt(&mut self, statement: &Stmt) -> usize {
+        match statement {
+            Stmt::Block(statements) => {
+                let id = self.node("block", "box");
+                self.stmts(id, statements);
+                id
+            }
+            Stmt::Class(name, superclass, methods, _) => {
+                let id = self.node(&format!("class {}", name.lexeme), "box");
+                if let Some(superclass) = superclass {
+                    let child = self.expr(superclass);
+                    self.edge(id, child, Some("superclass"));
+                }
+                self.stmts(id, methods);
+                id
+            }
+            Stmt::Expression(expr) => {
+                let id = self.node("expression", "box");
+                let child = self.expr(expr);
+                self.edge(id, child, None);
+                id
+            }
+            Stmt::For(_, initializer, condition, increment, body) => {
+                let id = self.node("for", "box");
+                if let Some(initializer) = initializer {
+                    let child = self.stmt(initializer);
+                    self.edge(id, child, Some("initializer"));
+                }
+                if let Some(condition) = condition {
+                    let child = self.expr(condition);
+                    self.edge(id, child, Some("condition"));
+                }
+                if let Some(increment) = increment {
+                    let child = self.expr(increment);
+                    self.edge(id, child, Some("increment"));
+                }
+                let child = self.stmt(body);
+                self.edge(id, child, Some("body"));
+                id
+            }
+            Stmt::ForIn(_, name, collection, body) => {
+                let id = self.node(&format!("for {} in", name.lexeme), "box");
+                let child = self.expr(collection);
+                self.edge(id, child, Some("collection"));
+                let child = self.stmt(body);
+                self.edge(id, child, Some("body"));
+                id
+            }
+            Stmt::Function(name, params, body, _) => {
+                let params = params.iter().map(|x| &*x.lexeme).collect::<Vec<_>>();
+                let label = format!("fun {}({})", name.lexeme, params.join(", "));
+                let id = self.node(&label, "box");
+                self.stmts(id, body);
+                id
+            }
+            Stmt::If(condition, then_branch, else_branch) => {
+                let id = self.node("if", "box");
+                let child = self.expr(condition);
+                self.edge(id, child, Some("condition"));
+                let child = self.stmt(then_branch);
+                self.edge(id, child, Some("then"));
+                if let Some(else_branch) = else_branch {
+                    let child = self.stmt(else_branch);
+                    self.edge(id, child, Some("else"));
+                }
+                id
+            }
+            Stmt::Print(_, expr) => {
+                let id = self.node("print", "box");
+                let child = self.expr(expr);
+                self.edge(id, child, None);
+                id
+            }
+            Stmt::Return(_, value) => {
+                let id = self.node("return", "box");
+                let child = self.expr(value);
+                self.edge(id, child, None);
+                id
+            }
+            Stmt::Test(name, body) => {
+                let id = self.node(&format!("test {}", name.literal), "box");
+                self.stmts(id, body);
+                id
+            }
+            Stmt::Var(name, initializer, constant) => {
+                let keyword = if *constant { "const" } else { "var" };
+                let id = self.node(&format!("{} {}", keyword, name.lexeme), "box");
+                if let Some(initializer) = initializer {
+                    let child = self.expr(initializer);
+                    self.edge(id, child, None);
+                }
+                id
+            }
+            Stmt::While(_, condition, body) => {
+                let id = self.node("while", "box");
+                let child = self.expr(condition);
+                self.edge(id, child, Some("condition"));
+                let child = self.stmt(body);
+                self.edge(id, child, Some("body"));
+                id
+            }
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) -> usize {
+        match expr {
+            Expr::Assign(_, name, value) => {
+                let id = self.node(&format!("{} =", name.lexeme), "ellipse");
+                let child = self.expr(value);
+                self.edge(id, child, None);
+                id
+            }
+            Expr::Binary(left, op, right) | Expr::Logical(left, op, right) => {
+                let id = self.node(&op.lexeme, "ellipse");
+                let child = self.expr(left);
+                self.edge(id, child, None);
+                let child = self.expr(right);
+                self.edge(id, child, None);
+                id
+            }
+            Expr::Call(callee, _, arguments) => {
+                let id = self.node("call", "ellipse");
+                let child = self.expr(callee);
+                self.edge(id, child, Some("callee"));
+                for argument in arguments {
+                    let child = self.expr(argument);
+                    self.edge(id, child, None);
+                }
+                id
+            }
+            Expr::Get(_, object, name) => {
+                let id = self.node(&format!(".{}", name.lexeme), "ellipse");
+                let child = self.expr(object);
+                self.edge(id, child, None);
+                id
+            }
+            Expr::Set(object, name, value) => {
+                let id = self.node(&format!(".{} =", name.lexeme), "ellipse");
+                let child = self.expr(object);
+                self.edge(id, child, Some("object"));
+                let child = self.expr(value);
+                self.edge(id, child, Some("value"));
+                id
+            }
+            Expr::Super(_, _, method) => self.node(&format!("super.{}", method.lexeme), "ellipse"),
+            Expr::This(_, _) => self.node("this", "ellipse"),
+            Expr::Grouping(expr) => {
+                let id = self.node("group", "ellipse");
+                let child = self.expr(expr);
+                self.edge(id, child, None);
+                id
+            }
+            Expr::Literal(literal) => self.node(&literal.to_string(), "ellipse"),
+            Expr::Unary(op, right) => {
+                let id = self.node(&op.lexeme, "ellipse");
+                let child = self.expr(right);
+                self.edge(id, child, None);
+                id
+            }
+            Expr::Variable(_, name) => self.node(&name.lexeme, "ellipse"),
+        }
+    }
+}
+
+/// Labels are quoted, so only quotes, backslashes and the line breaks of
+/// strings need escaping
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,163 @@
+// Syntax highlighting, for the `highlight` subcommand, the prompt and the
+// playground page. It goes by the tokens and comments the scanner finds, so it
+// never disagrees with the interpreter about what a script is made of, and
+// scripts that don't parse are highlighted all the same.
+
+use std::fmt::Write;
+
+use serde::Serialize;
+
+use crate::doc::escape;
+use crate::scanner::{Scanner, Span, TokenType};
+
+/// What a part of a script is highlighted as
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenKind {
+    Keyword,
+    /// `true`, `false` and `nil`
+    Constant,
+    Number,
+    String,
+    Identifier,
+    Operator,
+    /// Parentheses, braces, commas, dots and semicolons
+    Punctuation,
+    Comment,
+    /// What the scanner couldn't make a token of
+    Error,
+}
+
+impl TokenKind {
+    fn of(token: TokenType) -> Self {
+        match token {
+            TokenType::LeftParen
+            | TokenType::RightParen
+            | TokenType::LeftBrace
+            | TokenType::RightBrace
+            | TokenType::Comma
+            | TokenType::Dot
+            | TokenType::Semicolon => TokenKind::Punctuation,
+            TokenType::Minus
+            | TokenType::Plus
+            | TokenType::Slash
+            | TokenType::Star
+            | TokenType::Bang
+            | TokenType::BangEqual
+            | TokenType::Equal
+            | TokenType::EqualEqual
+            | TokenType::Greater
+            | TokenType::GreaterEqual
+            | TokenType::Less
+            | TokenType::LessEqual => TokenKind::Operator,
+            TokenType::Identifier => TokenKind::Identifier,
+            TokenType::String => TokenKind::String,
+            TokenType::Number => TokenKind::Number,
+            TokenType::False | TokenType::Nil | TokenType::True => TokenKind::Constant,
+            TokenType::And
+            | TokenType::Class
+            | TokenType::Else
+            | TokenType::Fun
+            | TokenType::For
+            | TokenType::If
+            | TokenType::Or
+            | TokenType::Print
+            | TokenType::Return
+            | TokenType::Super
+            | TokenType::This
+            | TokenType::Var
+            | TokenType::While => TokenKind::Keyword,
+            // Never highlighted, being empty
+            TokenType::Eof => TokenKind::Punctuation,
+        }
+    }
+
+    /// The SGR parameters of the color, for the kinds that have one
+    fn ansi(self) -> Option<&'static str> {
+        match self {
+            TokenKind::Keyword => Some("35"),
+            TokenKind::Constant | TokenKind::Number => Some("33"),
+            TokenKind::String => Some("32"),
+            TokenKind::Comment => Some("90"),
+            TokenKind::Error => Some("4;31"),
+            TokenKind::Identifier | TokenKind::Operator | TokenKind::Punctuation => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            TokenKind::Keyword => "keyword",
+            TokenKind::Constant => "constant",
+            TokenKind::Number => "number",
+            TokenKind::String => "string",
+            TokenKind::Identifier => "identifier",
+            TokenKind::Operator => "operator",
+            TokenKind::Punctuation => "punctuation",
+            TokenKind::Comment => "comment",
+            TokenKind::Error => "error",
+        }
+    }
+}
+
+/// The parts of `source` to highlight, in order. Whitespace is left out.
+pub fn highlight(source: &str) -> Vec<(Span, TokenKind)> {
+    let mut scanner = Scanner::new(source);
+    let kinds = scanner
+        .scan_tokens()
+        .iter()
+        .map(|x| match x {
+            Ok(token) => TokenKind::of(token.token),
+            Err(_) => TokenKind::Error,
+        })
+        .collect::<Vec<_>>();
+    let mut parts = scanner
+        .spans()
+        .iter()
+        .copied()
+        .zip(kinds)
+        .filter(|(span, _)| span.start < span.end)
+        .collect::<Vec<_>>();
+    let comments = scanner.comments().iter();
+    parts.extend(comments.map(|x| (x.span, TokenKind::Comment)));
+    parts.sort_by_key(|(span, _)| span.start);
+    parts
+}
+
+/// `source` colored with escape codes, for terminals
+pub fn ansi(source: &str) -> String {
+    render(source, |out, text, kind| match kind.ansi() {
+        Some(color) => {
+            let _ = write!(out, "\x1b[{}m{}\x1b[0m", color, text);
+        }
+        None => out.push_str(text),
+    })
+}
+
+/// `source` in a `pre` element to put in a page, its parts in `span`
+/// elements with a class of `lox-` and the name of their kind
+pub fn html(source: &str) -> String {
+    let mut out = String::from("<pre class=\"lox\"><code>");
+    out += &render(source, |out, text, kind| {
+        let _ = write!(
+            out,
+            "<span class=\"lox-{}\">{}</span>",
+            kind.name(),
+            escape(text)
+        );
+    });
+    out.push_str("</code></pre>\n");
+    out
+}
+
+/// Writes out `source`, each highlighted part through `part`
+fn render(source: &str, mut part: impl FnMut(&mut String, &str, TokenKind)) -> String {
+    let mut out = String::new();
+    let mut at = 0;
+    for (span, kind) in highlight(source) {
+        out.push_str(&source[at..span.start]);
+        part(&mut out, &source[span.start..span.end], kind);
+        at = span.end;
+    }
+    out.push_str(&source[at..]);
+    out
+}
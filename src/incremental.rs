@@ -0,0 +1,420 @@
+use std::ops::Range;
+
+use crate::error::{Location, LoxError};
+use crate::expr::Expr;
+use crate::parser::Parser;
+use crate::scanner::{Scanner, Token, TokenType};
+use crate::shared::Rc;
+use crate::stmt::Stmt;
+
+/// A place in a script, counted like the positions of tokens: lines from 1,
+/// and characters within the line from 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Replaces the text from `start` up to, but not including, `end`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edit {
+    pub start: Position,
+    pub end: Position,
+    pub text: String,
+}
+
+/// What an edit made a [`Document`] go through again
+#[derive(Debug, Clone, PartialEq)]
+pub struct Changes {
+    /// The tokens scanned again, by index in the new tokens
+    pub tokens: Range<usize>,
+    /// The top-level statements parsed again, by index in the new statements,
+    /// or `None` when the whole script was
+    pub statements: Option<Range<usize>>,
+}
+
+/// A script open in an editor, kept scanned and parsed as it is edited. An edit
+/// is scanned again from a couple of tokens before it, until the tokens line up
+/// with the ones after it again, and only the top-level statements those tokens
+/// are part of are parsed again. The rest is moved to its new place. Scripts
+/// that didn't parse before the edit, or don't after it, are parsed in full.
+pub struct Document {
+    source: String,
+    file: Option<Rc<str>>,
+    tokens: Vec<Result<Token, LoxError>>,
+    statements: Vec<Stmt>,
+    /// How many tokens each of the statements is made of
+    spans: Vec<usize>,
+    errors: Vec<LoxError>,
+}
+
+impl Document {
+    pub fn new(source: impl Into<String>) -> Self {
+        Document::with_file(source, None)
+    }
+
+    pub fn with_file(source: impl Into<String>, file: Option<Rc<str>>) -> Self {
+        let source = source.into();
+        let tokens = Scanner::with_file(&source, file.clone())
+            .scan_tokens()
+            .to_vec();
+        let mut document = Document {
+            source,
+            file,
+            tokens,
+            statements: Vec::new(),
+            spans: Vec::new(),
+            errors: Vec::new(),
+        };
+        document.parse();
+        document
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// The tokens and lexical errors, as [`Scanner::scan_tokens`] gives them
+    pub fn tokens(&self) -> &[Result<Token, LoxError>] {
+        &self.tokens
+    }
+
+    /// The top-level statements, or every error in the script
+    pub fn statements(&self) -> Result<&[Stmt], &[LoxError]> {
+        if self.errors.is_empty() {
+            Ok(&self.statements)
+        } else {
+            Err(&self.errors)
+        }
+    }
+
+    /// Applies `edit` and brings the tokens and statements up to date, or
+    /// leaves everything as it was if its positions aren't in the script
+    pub fn edit(&mut self, edit: &Edit) -> Option<Changes> {
+        let start = offset(&self.source, edit.start)?;
+        let end = offset(&self.source, edit.end)?;
+        if start > end {
+            return None;
+        }
+
+        // From two tokens before the edit, as the one right before it could
+        // run on into it, and a number even into the one after. Only from a
+        // token, as errors can be in the middle of a string.
+        let before = self.tokens[..self.tokens.len() - 1]
+            .iter()
+            .rposition(|x| position(x) < edit.start);
+        let restart = before.and_then(|x| self.tokens[..x].iter().rposition(|x| x.is_ok()));
+        let from = match restart {
+            Some(x) => position(&self.tokens[x]),
+            None => Position { line: 1, column: 1 },
+        };
+        let restart = restart.unwrap_or(0);
+        let from_offset = offset(&self.source, from)?;
+
+        let mut source = String::with_capacity(self.source.len() - (end - start) + edit.text.len());
+        source.push_str(&self.source[..start]);
+        source.push_str(&edit.text);
+        source.push_str(&self.source[end..]);
+        let moved = Moved::new(edit);
+
+        // Scanning stops at a token found at the same place and the same as
+        // one after the edit, as the rest of the source is the same from there
+        let mut next = self.tokens[restart..]
+            .iter()
+            .position(|x| position(x) >= edit.end)
+            .map_or(self.tokens.len(), |x| x + restart);
+        let old = &self.tokens;
+        let mut scanner = Scanner::resume(
+            &source[from_offset..],
+            from.line,
+            from.column,
+            self.file.clone(),
+        );
+        let resynced = scanner.scan_until(|token| {
+            let at = Position {
+                line: token.line,
+                column: token.column,
+            };
+            while next < old.len() - 1 && moved.position(position(&old[next])) < at {
+                next += 1;
+            }
+            match old.get(next) {
+                Some(Ok(x)) if x.token != TokenType::Eof => {
+                    moved.position(position(&old[next])) == at
+                        && x.token == token.token
+                        && x.lexeme == token.lexeme
+                }
+                _ => false,
+            }
+        });
+        let until = if resynced { next } else { self.tokens.len() };
+        let scanned = scanner.into_tokens();
+        let count = scanned.len();
+        let tail = self.tokens.split_off(until);
+        self.tokens.truncate(restart);
+        self.tokens.extend(scanned);
+        self.tokens.extend(tail.into_iter().map(|x| moved.entry(x)));
+        self.source = source;
+
+        let statements = self.reparse(restart, until, count, &moved);
+        if statements.is_none() {
+            self.parse();
+        }
+        Some(Changes {
+            tokens: restart..restart + count,
+            statements,
+        })
+    }
+
+    /// Parses again the statements with tokens in `restart..until` before they
+    /// were replaced by `count` new ones, if the script parsed before and the
+    /// new tokens parse into whole statements
+    fn reparse(
+        &mut self,
+        restart: usize,
+        until: usize,
+        count: usize,
+        moved: &Moved,
+    ) -> Option<Range<usize>> {
+        if !self.errors.is_empty() || self.tokens.iter().any(|x| x.is_err()) {
+            return None;
+        }
+        let starts: Vec<usize> = self
+            .spans
+            .iter()
+            .scan(0, |start, span| {
+                *start += span;
+                Some(*start - span)
+            })
+            .collect();
+        let first = starts.partition_point(|&x| x <= restart).saturating_sub(1);
+        // The one before too, which an `else` could have been added to
+        let first = first.saturating_sub(1);
+        let last = starts
+            .partition_point(|&x| x < until)
+            .max(first + 1)
+            .min(self.statements.len());
+        let from = starts.get(first).copied().unwrap_or(0);
+        let to = match starts.get(last) {
+            Some(x) => x + count - (until - restart),
+            None => self.tokens.len(),
+        };
+        let tokens = self.tokens[from..to].iter().flatten().cloned().collect();
+        let parsed = Parser::new(tokens).parse_spans().ok()?;
+
+        let kept = self.statements.split_off(last);
+        let kept_spans = self.spans.split_off(last);
+        self.statements.truncate(first);
+        self.spans.truncate(first);
+        let range = first..first + parsed.len();
+        for (stmt, span) in parsed {
+            self.statements.push(stmt);
+            self.spans.push(span);
+        }
+        for (stmt, span) in kept.iter().zip(kept_spans) {
+            self.statements.push(moved.statement(stmt));
+            self.spans.push(span);
+        }
+        Some(range)
+    }
+
+    fn parse(&mut self) {
+        self.errors = self.tokens.iter().filter_map(|x| x.clone().err()).collect();
+        let tokens = self.tokens.iter().flatten().cloned().collect();
+        match Parser::new(tokens).parse_spans() {
+            Ok(parsed) => (self.statements, self.spans) = parsed.into_iter().unzip(),
+            Err(errors) => {
+                self.statements.clear();
+                self.spans.clear();
+                self.errors.extend(errors);
+            }
+        }
+    }
+}
+
+fn position(entry: &Result<Token, LoxError>) -> Position {
+    match entry {
+        Ok(token) => Position {
+            line: token.line,
+            column: token.column,
+        },
+        Err(e) => e
+            .location()
+            .map_or(Position { line: 1, column: 1 }, |x| Position {
+                line: x.line,
+                column: x.column,
+            }),
+    }
+}
+
+/// The byte offset of `at` in `source`, which can be right after the end of a
+/// line but not past it
+fn offset(source: &str, at: Position) -> Option<usize> {
+    let start = match at.line {
+        0 => return None,
+        1 => 0,
+        n => source.match_indices('\n').nth(n - 2)?.0 + 1,
+    };
+    let line = source[start..].split('\n').next().unwrap_or("");
+    match line.char_indices().nth(at.column.checked_sub(1)?) {
+        Some((i, _)) => Some(start + i),
+        None if at.column - 1 == line.chars().count() => Some(start + line.len()),
+        None => None,
+    }
+}
+
+/// Where what came after an edit ended up
+struct Moved {
+    end: Position,
+    /// Where `end` itself is now
+    to: Position,
+}
+
+impl Moved {
+    fn new(edit: &Edit) -> Self {
+        let to = match edit.text.rsplit_once('\n') {
+            Some((before, after)) => Position {
+                line: edit.start.line + before.matches('\n').count() + 1,
+                column: after.chars().count() + 1,
+            },
+            None => Position {
+                line: edit.start.line,
+                column: edit.start.column + edit.text.chars().count(),
+            },
+        };
+        Moved { end: edit.end, to }
+    }
+
+    fn position(&self, at: Position) -> Position {
+        if at.line == self.end.line {
+            Position {
+                line: self.to.line,
+                column: at.column + self.to.column - self.end.column,
+            }
+        } else {
+            Position {
+                line: at.line + self.to.line - self.end.line,
+                column: at.column,
+            }
+        }
+    }
+
+    fn token(&self, token: &Token) -> Token {
+        let at = self.position(Position {
+            line: token.line,
+            column: token.column,
+        });
+        Token {
+            line: at.line,
+            column: at.column,
+            ..token.clone()
+        }
+    }
+
+    fn entry(&self, entry: Result<Token, LoxError>) -> Result<Token, LoxError> {
+        match entry {
+            Ok(token) => Ok(self.token(&token)),
+            Err(LoxError::ScanError { location, message }) => {
+                let at = self.position(Position {
+                    line: location.line,
+                    column: location.column,
+                });
+                Err(LoxError::ScanError {
+                    location: Location {
+                        line: at.line,
+                        column: at.column,
+                        ..location
+                    },
+                    message,
+                })
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn statements(&self, statements: &[Stmt]) -> Rc<[Stmt]> {
+        statements.iter().map(|x| self.statement(x)).collect()
+    }
+
+    fn statement(&self, stmt: &Stmt) -> Stmt {
+        let stmt_rc = |x: &Rc<Stmt>| Rc::new(self.statement(x));
+        match stmt {
+            Stmt::Block(body) => Stmt::Block(self.statements(body)),
+            Stmt::Class(name, superclass, methods, doc) => Stmt::Class(
+                self.token(name),
+                superclass.as_ref().map(|x| self.expression(x)),
+                methods.iter().map(|x| self.statement(x)).collect(),
+                doc.clone(),
+            ),
+            Stmt::Expression(e) => Stmt::Expression(self.expr(e)),
+            Stmt::For(keyword, initializer, condition, increment, body) => Stmt::For(
+                self.token(keyword),
+                initializer.as_ref().map(stmt_rc),
+                condition.as_ref().map(|x| self.expr(x)),
+                increment.as_ref().map(|x| self.expr(x)),
+                stmt_rc(body),
+            ),
+            Stmt::ForIn(keyword, name, collection, body) => Stmt::ForIn(
+                self.token(keyword),
+                self.token(name),
+                self.expr(collection),
+                stmt_rc(body),
+            ),
+            Stmt::Function(name, params, body, doc) => Stmt::Function(
+                self.token(name),
+                params.iter().map(|x| self.token(x)).collect(),
+                self.statements(body),
+                doc.clone(),
+            ),
+            Stmt::If(condition, then_branch, else_branch) => Stmt::If(
+                self.expr(condition),
+                stmt_rc(then_branch),
+                else_branch.as_ref().map(stmt_rc),
+            ),
+            Stmt::Print(keyword, e) => Stmt::Print(self.token(keyword), self.expr(e)),
+            Stmt::Return(keyword, e) => Stmt::Return(self.token(keyword), self.expr(e)),
+            Stmt::Test(name, body) => Stmt::Test(self.token(name), self.statements(body)),
+            Stmt::Var(name, initializer, constant) => Stmt::Var(
+                self.token(name),
+                initializer.as_ref().map(|x| self.expr(x)),
+                *constant,
+            ),
+            Stmt::While(keyword, condition, body) => {
+                Stmt::While(self.token(keyword), self.expr(condition), stmt_rc(body))
+            }
+        }
+    }
+
+    fn expr(&self, expr: &Rc<Expr>) -> Rc<Expr> {
+        Rc::new(self.expression(expr))
+    }
+
+    fn expression(&self, expr: &Expr) -> Expr {
+        match expr {
+            Expr::Assign(id, name, value) => Expr::Assign(*id, self.token(name), self.expr(value)),
+            Expr::Binary(left, op, right) => {
+                Expr::Binary(self.expr(left), self.token(op), self.expr(right))
+            }
+            Expr::Call(callee, paren, arguments) => Expr::Call(
+                self.expr(callee),
+                self.token(paren),
+                arguments.iter().map(|x| self.expr(x)).collect(),
+            ),
+            Expr::Get(id, obj, name) => Expr::Get(*id, self.expr(obj), self.token(name)),
+            Expr::Set(obj, name, value) => {
+                Expr::Set(self.expr(obj), self.token(name), self.expr(value))
+            }
+            Expr::Super(id, keyword, method) => {
+                Expr::Super(*id, self.token(keyword), self.token(method))
+            }
+            Expr::This(id, keyword) => Expr::This(*id, self.token(keyword)),
+            Expr::Grouping(inner) => Expr::Grouping(self.expr(inner)),
+            Expr::Literal(lit) => Expr::Literal(lit.clone()),
+            Expr::Logical(left, op, right) => {
+                Expr::Logical(self.expr(left), self.token(op), self.expr(right))
+            }
+            Expr::Unary(op, right) => Expr::Unary(self.token(op), self.expr(right)),
+            Expr::Variable(id, name) => Expr::Variable(*id, self.token(name)),
+        }
+    }
+}
@@ -1,425 +1,1745 @@
+use crate::call_span::CallSpan;
+use crate::collections;
+use crate::config::{Config, Limits};
+use crate::coverage::Coverage;
+use crate::encoding;
 use crate::environment::Environment;
-use crate::expr::Expr;
-use crate::lox_callable::{LoxCallable, LoxClass, LoxFunction, LoxInstance, NativeFunction};
-use crate::scanner::{Literal as Lit, Literal, Token, TokenType as TT};
+use crate::error::{Frame, Level, Lint, LoxError, Warning};
+use crate::expr::{Expr, ExprId};
+use crate::files;
+use crate::formatter;
+use crate::gc::{Collect, Heap, Pinned, Trace, Tracer};
+#[cfg(feature = "hash")]
+use crate::hash;
+#[cfg(all(feature = "keyboard", unix))]
+use crate::keyboard;
+#[cfg(feature = "async")]
+use crate::lox_callable::NativeFuture;
+use crate::lox_callable::{Call, LoxCallable, LoxClass, LoxFunction, LoxInstance, NativeFunction};
+use crate::lox_object::LoxObject;
+use crate::path;
+use crate::resolver::Resolver;
+use crate::sandbox::{Capability, SandboxPolicy};
+use crate::scanner::{compare_integer, Literal, Token, TokenType as TT};
+use crate::shared::RefCell;
+use crate::shared::{Output, Rc, Shareable, Weak};
+use crate::snapshot::Snapshot;
 use crate::stmt::Stmt;
-use std::cell::RefCell;
+use crate::string_builder;
+use crate::symbol::Symbol;
+use crate::terminal;
+use crate::timers::Timers;
+use crate::value::Value;
+use std::borrow::Cow;
+use std::cmp;
 use std::collections::HashMap;
-use std::rc::Rc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::debug_span;
+
+/// Where the resolver found a variable
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Binding {
+    /// How many scopes up it is, and its slot in that scope
+    Local(usize, usize),
+    /// Its index in the globals table
+    Global(usize),
+}
+
+/// The method a property access or `super` last found, for as long as it is
+/// made on the same class. Classes never change once declared, so it can't get
+/// out of date.
+struct CachedMethod {
+    /// Weak, not to keep the class alive, which also keeps its address from
+    /// being taken by another one while the entry is around
+    class: Weak<LoxClass>,
+    method: Rc<LoxFunction>,
+}
+
+/// A statement the interpreter still has to come back to, wherever it is in
+/// the tree
+#[derive(Clone)]
+enum StmtRef {
+    /// One of a block, a function body or a program
+    InList(Rc<[Stmt]>, usize),
+    /// The initializer of a `for`, a branch of an `if` or the body of a loop
+    Single(Rc<Stmt>),
+}
+
+impl StmtRef {
+    fn get(&self) -> &Stmt {
+        match self {
+            StmtRef::InList(statements, index) => &statements[*index],
+            StmtRef::Single(stmt) => stmt,
+        }
+    }
+}
+
+/// What is left to do. Evaluating an expression leaves its value on the value
+/// stack, where the task coming back to the expression or statement that
+/// needed it takes it from.
+enum Task {
+    Eval(Rc<Expr>),
+    Exec(StmtRef),
+    /// The rest of a block or body, from the given statement on
+    Statements(Rc<[Stmt]>, usize),
+    /// Back to an expression once its operands have been evaluated, or only
+    /// the left one for `and` and `or`
+    Finish(Rc<Expr>),
+    /// Back to `a.b = c` once `a` has been evaluated, to check it before `c` is
+    SetObject(Rc<Expr>),
+    /// Back to a statement once its expression has been evaluated
+    Complete(StmtRef),
+    /// Starts another iteration of a `for` loop
+    Iterate(StmtRef),
+    /// Runs the increment of a `for` loop, after its body
+    Increment(StmtRef),
+    /// Back to a `for`-`in` loop once `iterator()` returned the iterator
+    Iterator(StmtRef),
+    /// Asks the iterator of a `for`-`in` loop whether it is `done()`, before
+    /// every iteration
+    Advance(StmtRef, Value),
+    /// Back from `done()`, to stop or get the `next()` value
+    Done(StmtRef, Value),
+    /// Back from `next()`, to run the body with the value bound
+    Next(StmtRef, Value),
+    /// Drops a value nothing needs
+    Discard,
+    /// Leaves the scope of a block or loop
+    Restore(Rc<RefCell<Environment>>),
+    /// Where a call returns to, with the environment of the caller
+    Return(Rc<LoxFunction>, Rc<RefCell<Environment>>),
+    /// A call the host makes, of the callee with the arguments
+    Call(Box<(Value, Vec<Value>)>),
+    /// Calls back the next timer once it is due, and then the rest, for
+    /// `runEventLoop`
+    Timers,
+}
+
+/// How many steps go by between looks at the clock, for `set_deadline`
+const DEADLINE_STEPS: u64 = 1024;
+
+/// Where calls the host makes come from, as far as errors are concerned
+const HOST: &str = "<host>";
+
+/// A call in progress, only turned into a `Frame` when an error needs it
+struct CallFrame {
+    callee: LoxCallable,
+    file: Rc<str>,
+    line: usize,
+    /// Traces the call until the frame goes
+    _span: CallSpan,
+}
 
 pub struct Interpreter {
     pub globals: Rc<RefCell<Environment>>,
-    locals: HashMap<String, usize>,
+    /// The variables bound by the resolver, by expression id. Anything not in
+    /// here is looked up by name in the globals. Ids are shared by all the
+    /// interpreters of a process, so a table indexed by them would grow with
+    /// the code every other one parsed too.
+    bindings: HashMap<ExprId, Binding>,
+    /// By the ids of property accesses and `super` expressions
+    methods: HashMap<ExprId, CachedMethod>,
+    environment: Rc<RefCell<Environment>>,
+    /// Print every statement to stderr just before it is executed
+    pub trace: bool,
+    /// Counts the lines and branches run, when there is one
+    pub coverage: Option<Coverage>,
+    /// Name of the script being run, used when its tokens don't carry a file name
+    pub script: Rc<str>,
+    pub limits: Limits,
+    policy: SandboxPolicy,
+    /// What `print` writes to
+    pub stdout: Output,
+    /// What traces are written to, and the binary's diagnostics
+    pub stderr: Output,
+    /// The calls in progress, innermost last
+    frames: Vec<CallFrame>,
+    /// What is left to do, the next thing last
+    tasks: Vec<Task>,
+    /// The values of the expressions evaluated, until something takes them
+    values: Vec<Value>,
+    /// The value of the last expression statement run outside of any function,
+    /// for `interpret` to give back
+    last: Option<Value>,
+    steps: u64,
+    /// Set from another thread to stop whatever runs, see `set_interrupt`
+    interrupt: Option<Arc<AtomicBool>>,
+    /// When whatever runs is stopped, see `set_deadline`
+    deadline: Option<Instant>,
+    /// Statements left to execute, when the host set a budget with `set_fuel`
+    fuel: Option<u64>,
+    output_bytes: usize,
+    /// What to do about a number added to a string, `Deny` in strict mode
+    concat: Level,
+    /// Found while running, for the caller to report
+    warnings: Vec<Warning>,
+    /// Set by `setTimeout` and `setInterval`, for `runEventLoop` to call back
+    timers: Timers,
+    /// The environments and instances made while running, for the cycles
+    /// between them to be collected
+    pub heap: Heap,
+    /// Whether async natives can be called, only while `run_async` runs and
+    /// not from natives calling back into Lox
+    #[cfg(feature = "async")]
+    awaiting: bool,
+    /// What the async native called last gives, for `run_async` to await
+    #[cfg(feature = "async")]
+    pending: Option<NativeFuture>,
+}
+
+/// Where a run started, for errors to leave everything as it was
+struct Checkpoint {
+    floor: usize,
+    values: usize,
+    frames: usize,
     environment: Rc<RefCell<Environment>>,
 }
 
+/// A call to `run_async` in progress, which goes back to where it started
+/// however it ends, the future being dropped before it is done included
+#[cfg(feature = "async")]
+struct AsyncRun<'a> {
+    interpreter: &'a mut Interpreter,
+    checkpoint: Checkpoint,
+}
+
+#[cfg(feature = "async")]
+impl<'a> AsyncRun<'a> {
+    fn start(interpreter: &'a mut Interpreter) -> Self {
+        interpreter.awaiting = true;
+        let checkpoint = interpreter.checkpoint();
+        AsyncRun {
+            interpreter,
+            checkpoint,
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl Drop for AsyncRun<'_> {
+    fn drop(&mut self) {
+        let interpreter = &mut *self.interpreter;
+        interpreter.awaiting = false;
+        interpreter.pending = None;
+        interpreter.tasks.truncate(self.checkpoint.floor);
+        interpreter.values.truncate(self.checkpoint.values);
+        interpreter.frames.truncate(self.checkpoint.frames);
+        interpreter.environment = self.checkpoint.environment.clone();
+    }
+}
+
 impl Interpreter {
-    pub fn new() -> Self {
+    pub fn with_config(config: &Config) -> Self {
         let globals = Rc::new(RefCell::new(Environment::new()));
-        let locals = HashMap::new();
         let environment = globals.clone();
 
-        globals.borrow_mut().define(
-            "clock",
-            Lit::Callable(LoxCallable::NativeFunction(Rc::new(NativeFunction::new(
-                "clock",
-                0,
-                |_, _| {
-                    let duration = SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .expect("Time went backwards");
-
-                    Ok(Lit::Double((duration.as_millis() as f64) / 1000.0))
-                },
-            )))),
-        );
+        let natives = Interpreter::natives()
+            .into_iter()
+            .chain(collections::natives())
+            .chain(files::natives())
+            .chain(string_builder::natives());
+        // Only a name when it isn't a keyword
+        let print = NativeFunction::new("print", 1, |interpreter, arguments| {
+            interpreter.print(&arguments[0], true)?;
+            Ok(Value::None)
+        });
+        let natives = natives.chain(config.native_print.then_some(print));
+        #[cfg(all(feature = "extensions", unix))]
+        let natives = natives.chain(std::iter::once(crate::extension::native()));
+        for native in natives {
+            if config.native_enabled(native.name()) {
+                let name = native.name().to_string();
+                globals.borrow_mut().define(
+                    &name,
+                    Value::Callable(LoxCallable::NativeFunction(Rc::new(native))),
+                );
+            }
+        }
 
         Interpreter {
             globals,
-            locals,
+            bindings: HashMap::new(),
             environment,
+            trace: false,
+            coverage: None,
+            script: Rc::from("<script>"),
+            limits: config.effective_limits(),
+            policy: config.sandbox,
+            stdout: Box::new(io::stdout()),
+            stderr: Box::new(io::stderr()),
+            frames: Vec::new(),
+            tasks: Vec::new(),
+            values: Vec::new(),
+            last: None,
+            steps: 0,
+            interrupt: None,
+            deadline: None,
+            fuel: None,
+            output_bytes: 0,
+            concat: if config.strict {
+                Level::Deny
+            } else {
+                config.level(Lint::Concat)
+            },
+            warnings: Vec::new(),
+            timers: Timers::default(),
+            heap: Heap::new(),
+            methods: HashMap::new(),
+            #[cfg(feature = "async")]
+            awaiting: false,
+            #[cfg(feature = "async")]
+            pending: None,
         }
     }
 
-    pub fn evaluate(&mut self, expr: &Expr) -> Result<Lit, String> {
-        match expr {
-            Expr::Assign(name, value) => {
-                let val = self.evaluate(value)?;
-
-                if let Some(distance) = self.locals.get(&format!("{:?}", expr)) {
-                    let mut env = self.environment.borrow_mut();
-                    env.assign_at(*distance, name, val)
+    fn natives() -> Vec<NativeFunction> {
+        vec![
+            NativeFunction::new("clock", 0, |_, _| Ok(Value::Double(clock()))),
+            NativeFunction::new("println", 1, |interpreter, arguments| {
+                interpreter.print(&arguments[0], true)?;
+                Ok(Value::None)
+            }),
+            NativeFunction::new("printRaw", 1, |interpreter, arguments| {
+                interpreter.print(&arguments[0], false)?;
+                Ok(Value::None)
+            }),
+            NativeFunction::new("clearScreen", 0, |interpreter, _| {
+                interpreter.print(&Value::String(terminal::CLEAR_SCREEN.into()), false)?;
+                Ok(Value::None)
+            }),
+            NativeFunction::new("moveCursor", 2, |interpreter, arguments| {
+                let (Some(row), Some(column)) = (arguments[0].number(), arguments[1].number())
+                else {
+                    return Err("Rows and columns must be whole numbers from 1.".into());
+                };
+                let escape = terminal::move_cursor(row, column)?;
+                interpreter.print(&Value::String(escape.into()), false)?;
+                Ok(Value::None)
+            }),
+            NativeFunction::new("setColor", 1, |interpreter, arguments| {
+                let Value::String(name) = &arguments[0] else {
+                    return Err("Color names must be strings.".into());
+                };
+                let escape = terminal::set_color(name)?;
+                interpreter.print(&Value::String(escape.into()), false)?;
+                Ok(Value::None)
+            }),
+            NativeFunction::new("terminalSize", 0, |interpreter, _| {
+                // An instance rather than a host object, as in the VM
+                let (rows, columns) = terminal::size();
+                let class = Rc::new(LoxClass::new("TerminalSize", None, HashMap::new()));
+                let mut instance = LoxInstance::new(class);
+                instance.set_field(Symbol::intern("rows"), Value::Integer(rows.into()));
+                instance.set_field(Symbol::intern("columns"), Value::Integer(columns.into()));
+                let instance = Rc::new(RefCell::new(instance));
+                interpreter.heap.register(&instance);
+                interpreter.heap.allocate(instance.size());
+                Ok(Value::LoxInstance(instance))
+            }),
+            #[cfg(not(target_arch = "wasm32"))]
+            NativeFunction::new("sleep", 1, |interpreter, arguments| {
+                interpreter.policy().require(Capability::Waiting)?;
+                let Some(milliseconds) = arguments[0].number() else {
+                    return Err("Milliseconds must be a number.".into());
+                };
+                terminal::sleep(milliseconds)?;
+                Ok(Value::None)
+            }),
+            #[cfg(all(feature = "keyboard", unix))]
+            NativeFunction::new("pollKey", 0, |_, _| {
+                Ok(keyboard::poll().map_or(Value::None, |x| Value::String(x.into())))
+            }),
+            #[cfg(not(target_arch = "wasm32"))]
+            NativeFunction::new("setTimeout", 2, |interpreter, arguments| {
+                interpreter.set_timer(arguments, false)
+            }),
+            #[cfg(not(target_arch = "wasm32"))]
+            NativeFunction::new("setInterval", 2, |interpreter, arguments| {
+                interpreter.set_timer(arguments, true)
+            }),
+            NativeFunction::new("clearTimer", 1, |interpreter, arguments| {
+                if let Value::Integer(id) = arguments[0] {
+                    interpreter.timers.clear(id as u32);
+                }
+                Ok(Value::None)
+            }),
+            NativeFunction::new("runEventLoop", 0, |interpreter, _| {
+                // Once the call is done, with nil for whatever called it
+                interpreter.tasks.push(Task::Timers);
+                Ok(Value::None)
+            }),
+            text_native("base64Encode", |x| Ok(encoding::base64_encode(x))),
+            text_native("base64Decode", encoding::base64_decode),
+            text_native("hexEncode", |x| Ok(encoding::hex_encode(x))),
+            text_native("hexDecode", encoding::hex_decode),
+            NativeFunction::new("pathJoin", 2, |_, arguments| {
+                match (&arguments[0], &arguments[1]) {
+                    (Value::String(a), Value::String(b)) => {
+                        Ok(Value::String(path::join(a, b).into()))
+                    }
+                    _ => Err("Paths must be strings.".into()),
+                }
+            }),
+            text_native("basename", |x| Ok(path::basename(x))),
+            text_native("dirname", |x| Ok(path::dirname(x))),
+            text_native("extension", |x| Ok(path::extension(x))),
+            NativeFunction::new("absolutePath", 1, |interpreter, arguments| {
+                interpreter.policy().require(Capability::Filesystem)?;
+                match &arguments[0] {
+                    Value::String(text) => Ok(Value::String(path::absolute(text)?.into())),
+                    _ => Err("Argument must be a string.".into()),
+                }
+            }),
+            #[cfg(feature = "hash")]
+            text_native("sha256", |x| Ok(hash::sha256(x))),
+            #[cfg(feature = "hash")]
+            text_native("md5", |x| Ok(hash::md5(x))),
+            NativeFunction::new("assert", 1, |_, arguments| {
+                if Interpreter::is_truthy(&arguments[0]) {
+                    Ok(Value::None)
                 } else {
-                    self.globals.borrow_mut().assign(name, val)
+                    Err("Assertion failed.".into())
                 }
-            }
-            Expr::Binary(left, op, right) => self.eval_binary(left, op, right),
-            Expr::Call(callee, paren, arguments) => self.eval_call(callee, paren, arguments),
-            Expr::Get(obj, name) => self.eval_get(obj, name),
-            Expr::Set(obj, name, val) => self.eval_set(obj, name, val),
-            Expr::Super(keyword, method) => {
-                let distance = *self.locals.get(&format!("{:?}", expr)).unwrap_or(&0);
-                let superclass = self
-                    .environment
-                    .borrow()
-                    .get_at(distance, &keyword.lexeme)?;
-                let instance = self.environment.borrow().get_at(distance - 1, "this")?;
-                let res =
-                    if let (Lit::Callable(LoxCallable::LoxClass(parent)), Lit::LoxInstance(obj)) =
-                        (superclass, instance)
-                    {
-                        parent
-                            .find_method(&method.lexeme)
-                            .map(|m| LoxCallable::LoxFunction(m.bind(obj.clone())))
-                            .map(Lit::Callable)
-                    } else {
-                        None
-                    };
-                res.ok_or(format!(
-                    "[line {}:{}] Undefined property '{}'.",
-                    method.line, method.column, method.lexeme
-                ))
-            }
-            Expr::This(keyword) => self.lookup_variable(keyword, expr),
-            Expr::Grouping(expr) => self.eval_grouping(expr),
-            Expr::Literal(lit) => self.eval_literal(lit),
-            Expr::Logical(left, op, right) if op.token == TT::Or => {
-                let res = self.evaluate(left)?;
-                if Interpreter::is_truthy(&res) {
-                    Ok(res)
+            }),
+            NativeFunction::new("assertEqual", 2, |_, arguments| {
+                let (actual, expected) = (&arguments[0], &arguments[1]);
+                if Interpreter::is_equal(actual, expected) {
+                    Ok(Value::None)
                 } else {
-                    self.evaluate(right)
+                    Err(format!("Expected {} but got {}.", expected, actual))
                 }
+            }),
+        ]
+    }
+
+    /// `setTimeout(callback, ms)` or `setInterval(callback, ms)`, giving the id
+    /// of the timer
+    #[cfg(not(target_arch = "wasm32"))]
+    fn set_timer(&mut self, arguments: &[Value], repeats: bool) -> Result<Value, String> {
+        self.policy.require(Capability::Waiting)?;
+        let Value::Callable(_) = arguments[0] else {
+            return Err("Timer callbacks must be functions.".into());
+        };
+        let Some(milliseconds) = arguments[1].number() else {
+            return Err("Milliseconds must be a number.".into());
+        };
+        // As in browsers, no delay at all when it is less than none
+        let delay = std::time::Duration::try_from_secs_f64(milliseconds.max(0.0) / 1000.0)
+            .map_err(|_| format!("Can't wait for {} milliseconds.", milliseconds))?;
+        let id = self.timers.set(arguments[0].clone(), delay, repeats);
+        Ok(Value::Integer(id.into()))
+    }
+
+    /// Defines a global function for scripts to call, which runs `callable`.
+    /// Unlike the built-in natives, it is there whatever the config says.
+    pub fn define_native(
+        &mut self,
+        name: &str,
+        arity: usize,
+        callable: impl Fn(&mut Interpreter, &[Value]) -> Result<Value, String> + Shareable + 'static,
+    ) {
+        let native = NativeFunction::new(name, arity, callable);
+        self.globals.borrow_mut().define(
+            name,
+            Value::Callable(LoxCallable::NativeFunction(Rc::new(native))),
+        );
+    }
+
+    /// Defines a global variable for scripts to use, like a host object
+    pub fn define_global(&mut self, name: &str, value: Value) {
+        self.globals.borrow_mut().define(name, value);
+    }
+
+    /// A global variable, `None` if it isn't defined
+    pub fn global(&self, name: &str) -> Option<Value> {
+        self.globals.borrow().get_by_name(name)
+    }
+
+    /// Keeps `value` usable for as long as the host holds on to the handle.
+    /// Instances and closures the scripts can't get to anymore are emptied by
+    /// the garbage collector otherwise, even when the host still has them.
+    pub fn pin(&mut self, value: Value) -> Pinned<Value> {
+        self.heap.pin(value)
+    }
+
+    /// Calls a function or class as scripts do, with the same checks. Errors
+    /// and traces say the call came from the host.
+    pub fn call_value(&mut self, callee: &Value, arguments: Vec<Value>) -> Result<Value, LoxError> {
+        self.run(Task::Call(Box::new((callee.clone(), arguments))))?;
+        Ok(self.values.pop().unwrap_or(Value::None))
+    }
+
+    /// Makes an instance of `class`, running its initializer with `arguments`
+    pub fn instantiate(
+        &mut self,
+        class: &Rc<LoxClass>,
+        arguments: Vec<Value>,
+    ) -> Result<Rc<RefCell<LoxInstance>>, LoxError> {
+        let callee = Value::Callable(LoxCallable::LoxClass(class.clone()));
+        match self.call_value(&callee, arguments)? {
+            Value::LoxInstance(instance) => Ok(instance),
+            _ => unreachable!("Classes make instances"),
+        }
+    }
+
+    /// Evaluates an expression on its own, as the REPL does
+    pub fn evaluate(&mut self, expr: &Expr) -> Result<Value, LoxError> {
+        self.run(Task::Eval(Rc::new(expr.clone())))?;
+        Ok(self.values.pop().unwrap_or(Value::None))
+    }
+
+    /// Carries out `task` and everything it leads to. Nothing here recurses on
+    /// the Rust stack, calls included, so only the limits say how deep a
+    /// program can go. On errors, everything is left as it was before.
+    fn run(&mut self, task: Task) -> Result<(), LoxError> {
+        let checkpoint = self.checkpoint();
+        self.tasks.push(task);
+        #[cfg(feature = "async")]
+        let awaiting = std::mem::replace(&mut self.awaiting, false);
+        let ran = self.resume(&checkpoint);
+        #[cfg(feature = "async")]
+        {
+            self.awaiting = awaiting;
+        }
+        ran
+    }
+
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            floor: self.tasks.len(),
+            values: self.values.len(),
+            frames: self.frames.len(),
+            environment: self.environment.clone(),
+        }
+    }
+
+    /// Carries out the tasks pushed since `checkpoint`, stopping early when an
+    /// async native was called, for `run_async` to await it and come back
+    fn resume(&mut self, checkpoint: &Checkpoint) -> Result<(), LoxError> {
+        while self.tasks.len() > checkpoint.floor {
+            #[cfg(feature = "async")]
+            if self.pending.is_some() {
+                break;
             }
-            Expr::Logical(left, _, right) => {
-                let res = self.evaluate(left)?;
-                if !Interpreter::is_truthy(&res) {
-                    Ok(res)
-                } else {
-                    self.evaluate(right)
+            // In between tasks, everything still needed is in the interpreter
+            if self.heap.should_collect() || self.over_memory() {
+                self.collect(&checkpoint.environment);
+            }
+            let Some(task) = self.tasks.pop() else {
+                break;
+            };
+            if let Err(e) = self.perform(task) {
+                return Err(self.unwind(checkpoint, e));
+            }
+        }
+        Ok(())
+    }
+
+    /// Goes back to `checkpoint` after `e`, adding the calls it went through
+    fn unwind(&mut self, checkpoint: &Checkpoint, e: LoxError) -> LoxError {
+        let e = e.in_frames(self.frames.iter().map(|x| Frame {
+            function: x.callee.name().to_string(),
+            file: x.file.clone(),
+            line: x.line,
+        }));
+        self.tasks.truncate(checkpoint.floor);
+        self.values.truncate(checkpoint.values);
+        self.frames.truncate(checkpoint.frames);
+        self.environment = checkpoint.environment.clone();
+        #[cfg(feature = "async")]
+        {
+            self.pending = None;
+        }
+        e
+    }
+
+    fn perform(&mut self, task: Task) -> Result<(), LoxError> {
+        match task {
+            Task::Eval(expr) => self.evaluate_step(expr),
+            Task::Exec(stmt) => self.execute(stmt),
+            Task::Statements(statements, next) => {
+                if next < statements.len() {
+                    self.tasks
+                        .push(Task::Statements(statements.clone(), next + 1));
+                    self.execute(StmtRef::InList(statements, next))?;
+                }
+                Ok(())
+            }
+            Task::Finish(expr) => self.finish(&expr),
+            Task::SetObject(expr) => {
+                let Expr::Set(obj, name, value) = &*expr else {
+                    return Ok(());
+                };
+                let object = self.values.last().cloned().unwrap_or(Value::None);
+                if !matches!(object, Value::LoxInstance(_) | Value::Foreign(_)) {
+                    return Err(LoxError::runtime(
+                        name,
+                        format!("Only instances have fields.{}", nil_hint(obj, &object)),
+                    ));
+                }
+                let value = value.clone();
+                self.tasks.push(Task::Finish(expr));
+                self.tasks.push(Task::Eval(value));
+                Ok(())
+            }
+            Task::Complete(stmt) => self.complete(stmt),
+            Task::Iterate(stmt) => {
+                let Stmt::For(keyword, _, condition, _, body) = stmt.get() else {
+                    return Ok(());
+                };
+                // Every iteration is a step of its own, so that even `for (;;) {}`,
+                // which has nothing else to blame, runs out of them at the loop
+                self.step(Some(keyword))?;
+                match condition {
+                    Some(condition) => {
+                        let condition = condition.clone();
+                        self.tasks.push(Task::Complete(stmt));
+                        self.tasks.push(Task::Eval(condition));
+                    }
+                    None => {
+                        let body = body.clone();
+                        self.tasks.push(Task::Increment(stmt));
+                        self.tasks.push(Task::Exec(StmtRef::Single(body)));
+                    }
+                }
+                Ok(())
+            }
+            Task::Increment(stmt) => {
+                let Stmt::For(_, _, _, increment, _) = stmt.get() else {
+                    return Ok(());
+                };
+                let increment = increment.clone();
+                self.tasks.push(Task::Iterate(stmt));
+                if let Some(increment) = increment {
+                    self.tasks.push(Task::Discard);
+                    self.tasks.push(Task::Eval(increment));
+                }
+                Ok(())
+            }
+            Task::Iterator(stmt) => {
+                let iterator = self.pop();
+                self.tasks.push(Task::Advance(stmt, iterator));
+                Ok(())
+            }
+            Task::Advance(stmt, iterator) => {
+                let Stmt::ForIn(keyword, _, _, _) = stmt.get() else {
+                    return Ok(());
+                };
+                let keyword = keyword.clone();
+                self.step(Some(&keyword))?;
+                let done = self.protocol_method(&iterator, "done", &keyword, None)?;
+                self.tasks.push(Task::Done(stmt, iterator));
+                self.call(done, Vec::new(), &keyword)
+            }
+            Task::Done(stmt, iterator) => {
+                let done = self.pop();
+                let Stmt::ForIn(keyword, _, _, _) = stmt.get() else {
+                    return Ok(());
+                };
+                if Interpreter::is_truthy(&done) {
+                    return Ok(());
+                }
+                let keyword = keyword.clone();
+                let next = self.protocol_method(&iterator, "next", &keyword, None)?;
+                self.tasks.push(Task::Next(stmt, iterator));
+                self.call(next, Vec::new(), &keyword)
+            }
+            Task::Next(stmt, iterator) => {
+                let value = self.pop();
+                let Stmt::ForIn(_, name, _, body) = stmt.get() else {
+                    return Ok(());
+                };
+                let (name, body) = (name.lexeme.clone(), body.clone());
+                self.tasks.push(Task::Advance(stmt, iterator));
+                self.enter_scope();
+                self.heap.allocate(size_of::<Option<Value>>());
+                self.environment.borrow_mut().define(&name, value);
+                self.tasks.push(Task::Exec(StmtRef::Single(body)));
+                Ok(())
+            }
+            Task::Discard => {
+                self.values.pop();
+                Ok(())
+            }
+            Task::Restore(environment) => {
+                self.environment = environment;
+                Ok(())
+            }
+            Task::Return(function, caller) => self.finish_call(&function, caller, None),
+            Task::Call(call) => {
+                let (callee, arguments) = *call;
+                // Stands for the host in errors and traces
+                let paren = Token {
+                    token: TT::RightParen,
+                    lexeme: Symbol::intern(")"),
+                    literal: Literal::None,
+                    line: 0,
+                    column: 0,
+                    file: Some(Rc::from(HOST)),
+                };
+                self.call(callee, arguments, &paren)
+            }
+            Task::Timers => {
+                // Intervals go on forever, so they need to run out of steps
+                self.step(None)?;
+                if let Some(callback) = self.timers.wait() {
+                    self.tasks.push(Task::Timers);
+                    self.tasks.push(Task::Discard);
+                    self.tasks
+                        .push(Task::Call(Box::new((callback, Vec::new()))));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Starts evaluating an expression, leaving its value on the value stack
+    /// right away when it has no operands
+    fn evaluate_step(&mut self, expr: Rc<Expr>) -> Result<(), LoxError> {
+        // Constants have no token to blame, and are no reason to run out of steps anyway
+        if let Some(token) = expr.token() {
+            self.step(Some(token))?;
+        }
+        match &*expr {
+            Expr::Assign(_, _, value) | Expr::Unary(_, value) => {
+                let value = value.clone();
+                self.tasks.push(Task::Finish(expr));
+                self.tasks.push(Task::Eval(value));
+            }
+            Expr::Binary(left, _, right) => {
+                let (left, right) = (left.clone(), right.clone());
+                self.tasks.push(Task::Finish(expr));
+                self.tasks.push(Task::Eval(right));
+                self.tasks.push(Task::Eval(left));
+            }
+            Expr::Logical(left, _, _) | Expr::Get(_, left, _) => {
+                let left = left.clone();
+                self.tasks.push(Task::Finish(expr));
+                self.tasks.push(Task::Eval(left));
+            }
+            Expr::Set(obj, _, _) => {
+                let obj = obj.clone();
+                self.tasks.push(Task::SetObject(expr));
+                self.tasks.push(Task::Eval(obj));
+            }
+            Expr::Call(callee, _, arguments) => {
+                let callee = callee.clone();
+                let arguments = arguments.clone();
+                self.tasks.push(Task::Finish(expr));
+                for argument in arguments.into_iter().rev() {
+                    self.tasks.push(Task::Eval(argument));
                 }
+                self.tasks.push(Task::Eval(callee));
+            }
+            Expr::Grouping(inner) => self.tasks.push(Task::Eval(inner.clone())),
+            Expr::Super(id, _, method) => {
+                let value = self.eval_super(*id, method)?;
+                self.values.push(value);
             }
-            Expr::Unary(op, expr) => self.eval_unary(op, expr),
-            Expr::Variable(name) => self.lookup_variable(name, expr),
+            Expr::This(id, name) | Expr::Variable(id, name) => {
+                let value = self.lookup_variable(name, *id)?;
+                self.values.push(value);
+            }
+            Expr::Literal(lit) => self.values.push(Value::from(lit)),
         }
+        Ok(())
+    }
+
+    /// Comes back to an expression once its operands are on the value stack
+    fn finish(&mut self, expr: &Expr) -> Result<(), LoxError> {
+        let value = match expr {
+            Expr::Assign(id, name, _) => {
+                let val = self.pop();
+                match self.binding(*id) {
+                    Some(Binding::Local(distance, slot)) => {
+                        self.environment.borrow_mut().assign_at(distance, slot, val)
+                    }
+                    Some(Binding::Global(index)) => {
+                        self.globals.borrow_mut().assign_global(index, name, val)?
+                    }
+                    None => self.globals.borrow_mut().assign(name, val)?,
+                }
+            }
+            Expr::Binary(_, op, _) => {
+                let right = self.pop();
+                let left = self.pop();
+                self.binary(&left, op, &right)?
+            }
+            Expr::Logical(_, op, right) => {
+                let left = self.pop();
+                let short = if op.token == TT::Or {
+                    Interpreter::is_truthy(&left)
+                } else {
+                    !Interpreter::is_truthy(&left)
+                };
+                if !short {
+                    self.tasks.push(Task::Eval(right.clone()));
+                    return Ok(());
+                }
+                left
+            }
+            Expr::Unary(op, _) => {
+                let operand = self.pop();
+                Interpreter::unary(op, &operand)?
+            }
+            Expr::Get(id, obj, name) => match self.pop() {
+                Value::LoxInstance(inst) => self.get_property(*id, inst, name)?,
+                Value::Foreign(object) => Interpreter::foreign_property(object, name)?,
+                object => {
+                    return Err(LoxError::runtime(
+                        name,
+                        format!("Only instances have properties.{}", nil_hint(obj, &object)),
+                    ))
+                }
+            },
+            Expr::Set(_, name, _) => {
+                let value = self.pop();
+                match self.pop() {
+                    Value::LoxInstance(inst) => {
+                        self.heap.allocate(size_of::<Symbol>() + value.size());
+                        inst.borrow_mut().set(name, value.clone());
+                    }
+                    Value::Foreign(object) => object
+                        .set(&name.lexeme, value.clone())
+                        .map_err(|message| LoxError::runtime(name, message))?,
+                    _ => (),
+                }
+                value
+            }
+            Expr::Call(_, paren, arguments) => {
+                let args = self.values.split_off(self.values.len() - arguments.len());
+                let callee = self.pop();
+                return self.call(callee, args, paren);
+            }
+            _ => return Ok(()),
+        };
+        self.values.push(value);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Value {
+        self.values.pop().unwrap_or(Value::None)
     }
 
-    fn lookup_variable(&mut self, name: &Token, expr: &Expr) -> Result<Lit, String> {
-        if let Some(distance) = self.locals.get(&format!("{:?}", expr)) {
-            self.environment.borrow().get_at(*distance, &name.lexeme)
+    fn eval_super(&mut self, id: ExprId, method: &Token) -> Result<Value, LoxError> {
+        let Some(Binding::Local(distance, slot)) = self.binding(id) else {
+            unreachable!("The resolver binds 'super' in the class declaring it")
+        };
+        let superclass = self.environment.borrow().get_at(distance, slot)?;
+        // `this` is alone in the scope just inside the one of `super`
+        let instance = self.environment.borrow().get_at(distance - 1, 0)?;
+        let res = if let (Value::Callable(LoxCallable::LoxClass(parent)), Value::LoxInstance(obj)) =
+            (superclass, instance)
+        {
+            self.find_method(id, &parent, &method.lexeme)
+                .map(|m| LoxCallable::LoxFunction(m.bind(obj.clone())))
+                .map(Value::Callable)
         } else {
-            self.globals.borrow().get(name)
+            None
+        };
+        res.ok_or_else(|| {
+            LoxError::runtime(method, format!("Undefined property '{}'.", method.lexeme))
+        })
+    }
+
+    /// Fields first, then methods, bound to the instance
+    fn get_property(
+        &mut self,
+        id: ExprId,
+        instance: Rc<RefCell<LoxInstance>>,
+        name: &Token,
+    ) -> Result<Value, LoxError> {
+        if let Some(value) = instance.borrow().field(&name.lexeme) {
+            return Ok(value);
+        }
+        let class = instance.borrow().class().clone();
+        match self.find_method(id, &class, &name.lexeme) {
+            Some(method) => Ok(Value::Callable(LoxCallable::LoxFunction(
+                method.bind(instance),
+            ))),
+            None => Err(LoxError::runtime(
+                name,
+                format!("Undefined property '{}'.", name.lexeme),
+            )),
         }
     }
 
-    pub fn resolve(&mut self, expr: &Expr, depth: usize) {
-        self.locals.insert(format!("{:?}", expr), depth);
+    /// A property of a host object, or one of its methods bound to it as a
+    /// native, which is made anew every time like bound methods are
+    fn foreign_property(object: Rc<dyn LoxObject>, name: &Token) -> Result<Value, LoxError> {
+        if let Some(value) = object.get(&name.lexeme) {
+            return Ok(value);
+        }
+        let Some(arity) = object.method_arity(&name.lexeme) else {
+            return Err(LoxError::runtime(
+                name,
+                format!("Undefined property '{}'.", name.lexeme),
+            ));
+        };
+        let method = name.lexeme.clone();
+        let native = NativeFunction::new(&name.lexeme, arity, move |interpreter, arguments| {
+            object.call_method(interpreter, &method, arguments)
+        });
+        Ok(Value::Callable(LoxCallable::NativeFunction(Rc::new(
+            native,
+        ))))
     }
 
-    pub fn interpret(&mut self, statements: &[Stmt]) -> Result<Option<Lit>, String> {
-        for statement in statements {
-            self.execute(statement)?;
+    /// `object.name` for the methods a `for`-`in` loop calls, which point their
+    /// errors at its `for`, with a hint when `subject` turns out to be `nil`
+    fn protocol_method(
+        &mut self,
+        object: &Value,
+        name: &str,
+        keyword: &Token,
+        subject: Option<&Expr>,
+    ) -> Result<Value, LoxError> {
+        let name = Token {
+            lexeme: Symbol::intern(name),
+            ..keyword.clone()
+        };
+        match object {
+            Value::LoxInstance(inst) => {
+                if let Some(value) = inst.borrow().field(&name.lexeme) {
+                    return Ok(value);
+                }
+                let class = inst.borrow().class().clone();
+                match class.find_method(&name.lexeme) {
+                    Some(method) => Ok(Value::Callable(LoxCallable::LoxFunction(
+                        method.bind(inst.clone()),
+                    ))),
+                    None => Err(LoxError::runtime(
+                        &name,
+                        format!("Undefined property '{}'.", name.lexeme),
+                    )),
+                }
+            }
+            Value::Foreign(object) => Interpreter::foreign_property(object.clone(), &name),
+            object => Err(LoxError::runtime(
+                &name,
+                format!(
+                    "Only instances have properties.{}",
+                    subject.map(|x| nil_hint(x, object)).unwrap_or_default()
+                ),
+            )),
         }
-        Ok(None)
     }
 
-    pub fn execute_block(
+    /// Only looks in the methods of `class` the first time `id` is evaluated
+    /// on it, or when it was last evaluated on another one
+    fn find_method(
         &mut self,
-        statements: &[Stmt],
-        environment: Rc<RefCell<Environment>>,
-    ) -> Result<Option<Lit>, String> {
-        let previous = self.environment.clone();
-        self.environment = environment;
-        let mut res: Result<Option<Lit>, String> = Ok(None);
-        // this can be replaced in the future with iter().try_find() when added to Rust
-        for stmt in statements {
-            res = self.execute(stmt);
-            if res.is_err() || res.as_ref().is_ok_and(|x| x.is_some()) {
+        id: ExprId,
+        class: &Rc<LoxClass>,
+        name: &Symbol,
+    ) -> Option<Rc<LoxFunction>> {
+        if let Some(cached) = self.methods.get(&id) {
+            if Weak::as_ptr(&cached.class) == Rc::as_ptr(class) {
+                return Some(cached.method.clone());
+            }
+        }
+        let method = class.find_method(name)?;
+        let cached = CachedMethod {
+            class: Rc::downgrade(class),
+            method: method.clone(),
+        };
+        self.methods.insert(id, cached);
+        Some(method)
+    }
+
+    fn lookup_variable(&mut self, name: &Token, id: ExprId) -> Result<Value, LoxError> {
+        match self.binding(id) {
+            Some(Binding::Local(distance, slot)) => {
+                self.environment.borrow().get_at_token(distance, slot, name)
+            }
+            Some(Binding::Global(index)) => self.globals.borrow().get_global(index, name),
+            None => self.globals.borrow().get(name),
+        }
+    }
+
+    fn binding(&self, id: ExprId) -> Option<Binding> {
+        self.bindings.get(&id).copied()
+    }
+
+    fn bind(&mut self, id: ExprId, binding: Binding) {
+        self.bindings.insert(id, binding);
+    }
+
+    pub fn resolve(&mut self, id: ExprId, depth: usize, slot: usize) {
+        self.bind(id, Binding::Local(depth, slot));
+    }
+
+    /// Binds a variable found in no local scope, declared already or not
+    pub fn resolve_global(&mut self, id: ExprId, name: &str) {
+        let index = self.globals.borrow_mut().global_index(name);
+        self.bind(id, Binding::Global(index));
+    }
+
+    /// Runs resolved statements, giving back the value of the last expression
+    /// statement run at the top level, if any
+    pub fn interpret(&mut self, statements: &[Stmt]) -> Result<Option<Value>, LoxError> {
+        self.last = None;
+        let ran = self.run(Task::Statements(statements.into(), 0));
+        let last = self.last.take();
+        ran.map(|_| last)
+    }
+
+    /// Runs the body of a test declared in statements already interpreted, in
+    /// a scope of its own. The globals are put back as they were afterwards,
+    /// so that each test starts from what the script left, whatever the tests
+    /// before it did.
+    pub fn run_test(&mut self, body: &Rc<[Stmt]>) -> Result<(), LoxError> {
+        let snapshot = self.snapshot();
+        let block = Rc::new(Stmt::Block(body.clone()));
+        let ran = self.run(Task::Exec(StmtRef::Single(block)));
+        self.restore(&snapshot);
+        ran
+    }
+
+    /// Runs resolved statements like `interpret`, awaiting the async natives
+    /// they call instead of failing, so that whatever runs the future can get
+    /// on with something else in the meantime. Dropping the future before it
+    /// is done stops the script, as if it failed.
+    #[cfg(feature = "async")]
+    pub async fn run_async(&mut self, statements: &[Stmt]) -> Result<Option<Value>, LoxError> {
+        let run = AsyncRun::start(self);
+        run.interpreter.last = None;
+        run.interpreter
+            .tasks
+            .push(Task::Statements(statements.into(), 0));
+        loop {
+            run.interpreter.resume(&run.checkpoint)?;
+            let Some(future) = run.interpreter.pending.take() else {
                 break;
             };
+            match future.await {
+                Ok(value) => {
+                    run.interpreter.frames.pop();
+                    run.interpreter.values.push(value);
+                }
+                Err(message) => {
+                    let e = NativeFunction::error(message);
+                    return Err(run.interpreter.unwind(&run.checkpoint, e));
+                }
+            }
         }
-        self.environment = previous;
-        res
+        Ok(run.interpreter.last.take())
+    }
+
+    /// Defines a global function giving a future, for scripts run with
+    /// `run_async` to await. `callable` gets the arguments already evaluated,
+    /// and has to copy what it keeps of them.
+    #[cfg(feature = "async")]
+    pub fn define_async_native(
+        &mut self,
+        name: &str,
+        arity: usize,
+        callable: impl Fn(&[Value]) -> NativeFuture + Shareable + 'static,
+    ) {
+        let native = NativeFunction::new_async(name, arity, callable);
+        self.globals.borrow_mut().define(
+            name,
+            Value::Callable(LoxCallable::NativeFunction(Rc::new(native))),
+        );
+    }
+
+    /// Runs a script on its own, for hosts using Lox to work values out. It
+    /// fails with the first error found in it, and nothing is run unless the
+    /// whole script scans, parses and resolves.
+    pub fn eval_source(&mut self, source: &str) -> Result<Value, LoxError> {
+        let first = |mut errors: Vec<LoxError>| errors.swap_remove(0);
+        let tokens = crate::scan(source).map_err(first)?;
+        let statements = crate::parse(tokens).map_err(first)?;
+        Resolver::new(self).resolve(&statements).map_err(first)?;
+        Ok(self.interpret(&statements)?.unwrap_or(Value::None))
+    }
+
+    fn trace_stmt(&mut self, stmt: &Stmt) {
+        let token = stmt.token();
+        let file = token
+            .and_then(|x| x.file.clone())
+            .unwrap_or_else(|| self.script.clone());
+        let line = token.map_or("?".to_string(), |x| x.line.to_string());
+        // Traces are best effort, they mustn't stop the script
+        let _ = writeln!(self.stderr, "[trace] {}:{} {}", file, line, stmt);
     }
 
-    pub fn execute(&mut self, stmt: &Stmt) -> Result<Option<Lit>, String> {
+    /// Starts executing a statement, finishing it right away when it has no
+    /// expression or statements of its own to go through
+    fn execute(&mut self, stmt_ref: StmtRef) -> Result<(), LoxError> {
+        let stmt = stmt_ref.get();
+        // Blocks are only containers, tracing the statements inside is enough
+        if self.trace && !matches!(stmt, Stmt::Block(_)) {
+            self.trace_stmt(stmt);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            if !matches!(stmt, Stmt::Block(_)) {
+                coverage.hit(&self.script, stmt);
+            }
+        }
+        // Like constants, empty blocks have no token to blame, and the loop
+        // running one over and over counts a step of its own each time
+        if let Some(token) = stmt.token() {
+            self.step(Some(token))?;
+        }
+        self.burn(stmt.token())?;
         match stmt {
             Stmt::Block(statements) => {
-                self.execute_block(statements, Environment::nested(self.environment.clone()))
-            }
-            Stmt::Class(name, superclass, class_methods) => {
-                let parent = superclass
-                    .clone()
-                    .map(|x| self.evaluate(&x))
-                    .transpose()?
-                    .map(|x| match x {
-                        Literal::Callable(LoxCallable::LoxClass(class)) => Ok(Rc::clone(&class)),
-                        _ => Err(format!(
-                            "[line {}:{}] Superclass must be a class.",
-                            name.line, name.column
-                        )),
-                    })
-                    .transpose()?;
-
-                self.environment
-                    .borrow_mut()
-                    .define(&name.lexeme, Lit::None);
+                let statements = statements.clone();
+                self.enter_scope();
+                self.tasks.push(Task::Statements(statements, 0));
+            }
+            Stmt::Class(name, superclass, class_methods, _) => {
+                // The parser only ever makes a variable the superclass
+                let parent = match superclass {
+                    Some(Expr::Variable(id, parent)) => {
+                        self.step(Some(parent))?;
+                        match self.lookup_variable(parent, *id)? {
+                            Value::Callable(LoxCallable::LoxClass(class)) => Some(class),
+                            _ => {
+                                return Err(LoxError::runtime(name, "Superclass must be a class."))
+                            }
+                        }
+                    }
+                    _ => None,
+                };
 
+                let enclosing = self.environment.clone();
                 if let Some(super_ref) = &parent {
                     self.environment = Environment::nested(self.environment.clone());
                     self.environment.borrow_mut().define(
                         "super",
-                        Literal::Callable(LoxCallable::LoxClass(super_ref.clone())),
+                        Value::Callable(LoxCallable::LoxClass(super_ref.clone())),
                     );
                 }
 
-                let mut methods: HashMap<String, Rc<LoxFunction>> = HashMap::new();
+                let closure = self.capture(name);
+                let mut methods: HashMap<Symbol, Rc<LoxFunction>> = HashMap::new();
                 for x in class_methods {
-                    if let Stmt::Function(name, params, body) = x {
+                    if let Stmt::Function(name, params, body, _) = x {
                         let method = LoxFunction::new(
                             name.clone(),
-                            params.to_vec(),
-                            body.to_vec(),
-                            self.environment.clone(),
+                            params.clone(),
+                            body.clone(),
+                            closure.clone(),
                             name.lexeme == "init",
                         );
                         methods.insert(name.lexeme.clone(), Rc::new(method));
                     }
                 }
 
-                let klass = Lit::Callable(LoxCallable::LoxClass(Rc::new(LoxClass::new(
+                let klass = Value::Callable(LoxCallable::LoxClass(Rc::new(LoxClass::new(
                     &name.lexeme,
                     parent,
                     methods,
                 ))));
 
-                if superclass.is_some() {
-                    let ancestor = self.environment.borrow().ancestor(0);
-                    self.environment = ancestor;
-                }
-
-                self.environment.borrow_mut().assign(name, klass)?;
-                Ok(None)
-            }
-            Stmt::Expression(expr) => {
-                self.evaluate(expr)?;
-                Ok(None)
+                self.environment = enclosing;
+                // Nothing else is defined in this scope in between, so the class
+                // still gets the slot the resolver gave it
+                self.environment.borrow_mut().define(&name.lexeme, klass);
             }
-            Stmt::Function(name, params, body) => {
+            Stmt::Function(name, params, body, _) => {
+                let function = LoxFunction::new(
+                    name.clone(),
+                    params.clone(),
+                    body.clone(),
+                    self.capture(name),
+                    false,
+                );
                 self.environment.borrow_mut().define(
                     &name.lexeme,
-                    Lit::Callable(LoxCallable::LoxFunction(Rc::new(LoxFunction::new(
-                        name.clone(),
-                        params.to_vec(),
-                        body.to_vec(),
-                        self.environment.clone(),
-                        false,
-                    )))),
+                    Value::Callable(LoxCallable::LoxFunction(Rc::new(function))),
                 );
-                Ok(None)
             }
-            Stmt::If(cond, then_branch, maybe_else) => {
-                if Interpreter::is_truthy(&(self.evaluate(cond)?)) {
-                    self.execute(then_branch)
-                } else if let Some(else_branch) = maybe_else {
-                    self.execute(else_branch)
-                } else {
-                    Ok(None)
+            Stmt::For(_, initializer, _, _, _) => {
+                let initializer = initializer.clone();
+                self.enter_scope();
+                self.tasks.push(Task::Iterate(stmt_ref));
+                if let Some(initializer) = initializer {
+                    self.tasks.push(Task::Exec(StmtRef::Single(initializer)));
                 }
             }
-            Stmt::Print(expr) => {
-                let value = self.evaluate(expr)?;
-                if let Lit::String(val) = value {
-                    println!("{}", val);
+            Stmt::ForIn(_, _, collection, _) => {
+                let collection = collection.clone();
+                self.tasks.push(Task::Complete(stmt_ref));
+                self.tasks.push(Task::Eval(collection));
+            }
+            Stmt::Var(name, None, _) => {
+                self.environment.borrow_mut().declare(&name.lexeme);
+            }
+            // Only run by `run_test`
+            Stmt::Test(_, _) => (),
+            Stmt::Expression(expr)
+            | Stmt::If(expr, _, _)
+            | Stmt::Print(_, expr)
+            | Stmt::Return(_, expr)
+            | Stmt::Var(_, Some(expr), _)
+            | Stmt::While(_, expr, _) => {
+                let expr = expr.clone();
+                self.tasks.push(Task::Complete(stmt_ref));
+                self.tasks.push(Task::Eval(expr));
+            }
+        }
+        Ok(())
+    }
+
+    /// Comes back to a statement once its expression is on the value stack
+    fn complete(&mut self, stmt_ref: StmtRef) -> Result<(), LoxError> {
+        let value = self.pop();
+        if let Some(coverage) = &mut self.coverage {
+            coverage.branch(&self.script, stmt_ref.get(), Interpreter::is_truthy(&value));
+        }
+        match stmt_ref.get() {
+            Stmt::If(_, then_branch, else_branch) => {
+                let branch = if Interpreter::is_truthy(&value) {
+                    Some(then_branch)
                 } else {
-                    println!("{}", value);
+                    else_branch.as_ref()
+                };
+                if let Some(branch) = branch {
+                    self.tasks.push(Task::Exec(StmtRef::Single(branch.clone())));
                 }
-                Ok(None)
-            }
-            Stmt::Return(_, value) => Ok(Some(self.evaluate(value)?)),
-            Stmt::While(cond, body) => {
-                let mut res: Option<Lit> = None;
-                while Interpreter::is_truthy(&(self.evaluate(cond)?)) {
-                    res = self.execute(body)?;
-                    if res.is_some() {
-                        break;
+            }
+            Stmt::Print(keyword, _) => self
+                .print(&value, true)
+                .map_err(|e| LoxError::runtime(keyword, e))?,
+            Stmt::Return(_, _) => {
+                // Whatever the function had left to do is dropped
+                while let Some(task) = self.tasks.pop() {
+                    if let Task::Return(function, caller) = task {
+                        return self.finish_call(&function, caller, Some(value));
                     }
                 }
-                Ok(res)
             }
-            Stmt::Var(name, None) => {
-                self.environment
-                    .borrow_mut()
-                    .define(&name.lexeme, Lit::None);
-                Ok(None)
+            Stmt::Var(name, _, constant) => {
+                self.heap.allocate(size_of::<Option<Value>>());
+                let mut environment = self.environment.borrow_mut();
+                if *constant {
+                    environment.define_constant(&name.lexeme, value);
+                } else {
+                    environment.define(&name.lexeme, value);
+                }
+            }
+            Stmt::Expression(_) if self.frames.is_empty() => self.last = Some(value),
+            Stmt::While(keyword, condition, body) if Interpreter::is_truthy(&value) => {
+                // A step for each iteration, as for `for` loops
+                self.step(Some(keyword))?;
+                let (condition, body) = (condition.clone(), body.clone());
+                self.tasks.push(Task::Complete(stmt_ref));
+                self.tasks.push(Task::Eval(condition));
+                self.tasks.push(Task::Exec(StmtRef::Single(body)));
             }
-            Stmt::Var(name, Some(initializer)) => {
-                let value = self.evaluate(initializer)?;
-                self.environment.borrow_mut().define(&name.lexeme, value);
-                Ok(None)
+            Stmt::For(_, _, _, _, body) if Interpreter::is_truthy(&value) => {
+                let body = body.clone();
+                self.tasks.push(Task::Increment(stmt_ref));
+                self.tasks.push(Task::Exec(StmtRef::Single(body)));
             }
+            Stmt::ForIn(keyword, _, collection, _) => {
+                let iterator =
+                    self.protocol_method(&value, "iterator", keyword, Some(collection))?;
+                self.tasks.push(Task::Iterator(stmt_ref.clone()));
+                self.call(iterator, Vec::new(), keyword)?;
+            }
+            _ => {}
         }
+        Ok(())
     }
 
-    fn eval_binary(&mut self, left: &Expr, op: &Token, right: &Expr) -> Result<Lit, String> {
-        let lval = self.evaluate(left)?;
-        let rval = self.evaluate(right)?;
-        match (&lval, op.token, &rval) {
-            (Lit::Double(lhs), TT::Minus, Lit::Double(rhs)) => Ok(Lit::Double(lhs - rhs)),
-            (Lit::Double(lhs), TT::Slash, Lit::Double(rhs)) => Ok(Lit::Double(lhs / rhs)),
-            (Lit::Double(lhs), TT::Star, Lit::Double(rhs)) => Ok(Lit::Double(lhs * rhs)),
-            (_, TT::Minus, _) => Err(format!(
-                "[line {}:{}] Operands must be numbers.",
-                op.line, op.column
-            )),
-            (_, TT::Slash, _) => Err(format!(
-                "[line {}:{}] Operands must be numbers.",
-                op.line, op.column
-            )),
-            (_, TT::Star, _) => Err(format!(
-                "[line {}:{}] Operands must be numbers.",
-                op.line, op.column
-            )),
-            (Lit::Double(lhs), TT::Plus, Lit::Double(rhs)) => Ok(Lit::Double(lhs + rhs)),
-            (Lit::String(lhs), TT::Plus, Lit::String(rhs)) => {
-                Ok(Lit::String(format!("{}{}", lhs, rhs)))
+    /// Runs what follows in a scope of its own, until the `Restore` pushed here
+    fn enter_scope(&mut self) {
+        let enclosing = self.environment.clone();
+        self.tasks.push(Task::Restore(enclosing.clone()));
+        self.environment = Environment::nested(enclosing);
+        self.heap.allocate(self.environment.size());
+    }
+
+    /// The current environment, for a function to close over. Only then can it
+    /// end up in a cycle, which always goes through an environment captured
+    /// this way or an instance, so only those are registered in the heap.
+    fn capture(&mut self, by: &Token) -> Rc<RefCell<Environment>> {
+        // The globals are never garbage
+        if !Rc::ptr_eq(&self.environment, &self.globals) {
+            self.heap.register(&self.environment);
+            self.heap
+                .record_site(|| format!("closed over by '{}' at {}", by.lexeme, by.location()));
+        }
+        self.environment.clone()
+    }
+
+    /// Frees the environments and instances the program can't get to anymore,
+    /// `saved` being the environment to go back to on errors
+    fn collect(&mut self, saved: &Rc<RefCell<Environment>>) {
+        let Interpreter {
+            globals,
+            environment,
+            frames,
+            tasks,
+            values,
+            last,
+            timers,
+            heap,
+            ..
+        } = self;
+        heap.collect(|tracer: &mut Tracer| {
+            for environment in [&*globals, &*environment, saved] {
+                tracer.mark(environment);
             }
-            (Lit::String(lhs), TT::Plus, Lit::Double(rhs)) => {
-                Ok(Lit::String(format!("{}{}", lhs, rhs)))
+            frames.iter().for_each(|x| x.callee.trace(tracer));
+            timers.trace(tracer);
+            for value in values.iter().chain(last.iter()) {
+                tracer.count(value.size());
+                value.trace(tracer);
             }
-            (Lit::Double(lhs), TT::Plus, Lit::String(rhs)) => {
-                Ok(Lit::String(format!("{}{}", lhs, rhs)))
+            for task in tasks.iter() {
+                match task {
+                    Task::Restore(environment) => tracer.mark(environment),
+                    Task::Return(function, environment) => {
+                        function.trace(tracer);
+                        tracer.mark(environment);
+                    }
+                    Task::Call(call) => {
+                        call.0.trace(tracer);
+                        call.1.iter().for_each(|x| x.trace(tracer));
+                    }
+                    Task::Advance(_, iterator)
+                    | Task::Done(_, iterator)
+                    | Task::Next(_, iterator) => iterator.trace(tracer),
+                    _ => (),
+                }
             }
-            (_, TT::Plus, _) => Err(format!(
-                "[line {}:{}] Operands must be two numbers or two strings.",
-                op.line, op.column
-            )),
-            (Lit::Double(lhs), TT::Greater, Lit::Double(rhs)) => Ok(Lit::Boolean(lhs > rhs)),
-            (Lit::Double(lhs), TT::GreaterEqual, Lit::Double(rhs)) => Ok(Lit::Boolean(lhs >= rhs)),
-            (Lit::Double(lhs), TT::Less, Lit::Double(rhs)) => Ok(Lit::Boolean(lhs < rhs)),
-            (Lit::Double(lhs), TT::LessEqual, Lit::Double(rhs)) => Ok(Lit::Boolean(lhs <= rhs)),
-            (_, TT::Greater, _) => Err(format!(
-                "[line {}:{}] Operands must be numbers.",
-                op.line, op.column
-            )),
-            (_, TT::GreaterEqual, _) => Err(format!(
-                "[line {}:{}] Operands must be numbers.",
-                op.line, op.column
-            )),
-            (_, TT::Less, _) => Err(format!(
-                "[line {}:{}] Operands must be numbers.",
-                op.line, op.column
-            )),
-            (_, TT::LessEqual, _) => Err(format!(
-                "[line {}:{}] Operands must be numbers.",
-                op.line, op.column
-            )),
-            (_, TT::EqualEqual, _) => Ok(Lit::Boolean(Interpreter::is_equal(&lval, &rval))),
-            (_, TT::BangEqual, _) => Ok(Lit::Boolean(!Interpreter::is_equal(&lval, &rval))),
-            _ => Ok(Lit::None),
+        });
+    }
+
+    /// The cycles still alive once all the garbage is collected, for
+    /// `--leak-check` to report
+    pub fn cycles(&mut self) -> Vec<Vec<String>> {
+        let globals = self.globals.clone();
+        self.collect(&globals);
+        self.heap.cycles(&globals)
+    }
+
+    /// Counts every statement executed and every expression evaluated against
+    /// `--max-steps`, so that no loop or recursion can run forever
+    fn step(&mut self, token: Option<&Token>) -> Result<(), LoxError> {
+        self.steps += 1;
+        if self.limits.max_steps.is_some_and(|max| self.steps > max) {
+            return Err(Interpreter::limit_error(token, "Execution limit exceeded."));
+        }
+        if let Some(interrupt) = &self.interrupt {
+            if interrupt.swap(false, Ordering::Relaxed) {
+                return Err(Interpreter::limit_error(token, "Interrupted."));
+            }
+        }
+        // Only now and then, as reading the clock takes longer than most steps
+        if self.steps.is_multiple_of(DEADLINE_STEPS)
+            && self.deadline.is_some_and(|x| Instant::now() >= x)
+        {
+            return Err(Interpreter::limit_error(token, "Time limit exceeded."));
+        }
+        // Still over once collected in between tasks, or it wouldn't be
+        if self.over_memory() {
+            return Err(Interpreter::limit_error(token, "Memory limit exceeded."));
         }
+        Ok(())
     }
 
-    fn eval_call(
-        &mut self,
-        callee: &Expr,
-        paren: &Token,
-        arguments: &[Expr],
-    ) -> Result<Lit, String> {
-        let callable: Lit = self.evaluate(callee)?;
-
-        let mut args: Vec<Lit> = Vec::new();
-        for arg in arguments {
-            let res = self.evaluate(arg)?;
-            args.push(res);
-        }
-
-        if let Lit::Callable(func) = callable {
-            if args.len() != func.arity() {
-                return Err(format!(
-                    "[line {}:{}] Expected {} arguments but got {}.",
-                    paren.line,
-                    paren.column,
-                    func.arity(),
-                    args.len()
-                ));
+    fn over_memory(&self) -> bool {
+        self.limits
+            .max_memory_bytes
+            .is_some_and(|max| self.heap.bytes() > max)
+    }
+
+    /// Saves the globals, to roll back to with `restore`. What they held stays
+    /// alive as long as the snapshot does, whatever scripts do in between.
+    pub fn snapshot(&mut self) -> Snapshot {
+        Snapshot::take(&self.globals.borrow(), &mut self.heap)
+    }
+
+    /// Puts the globals back as they were when `snapshot` was taken
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        snapshot.restore(&mut self.globals.borrow_mut(), &mut self.heap);
+    }
+
+    /// What privileged natives check before doing anything
+    pub fn policy(&self) -> &SandboxPolicy {
+        &self.policy
+    }
+
+    /// Applies to the next call of every privileged native, and caps the
+    /// limits from now on
+    pub fn set_policy(&mut self, policy: SandboxPolicy) {
+        self.policy = policy;
+        self.limits = policy.cap(self.limits);
+    }
+
+    /// Makes setting `interrupt`, from any thread, stop the script running at
+    /// the time with a runtime error. It is cleared again once seen.
+    pub fn set_interrupt(&mut self, interrupt: Arc<AtomicBool>) {
+        self.interrupt = Some(interrupt);
+    }
+
+    /// Stops the script running at `deadline` with a runtime error, as well
+    /// as anything run after it
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        self.deadline = Some(deadline);
+    }
+
+    /// Lets only `fuel` more statements execute, whatever runs them, after which
+    /// running anything fails with `LoxError::OutOfFuel` until more is given.
+    /// Unlike `--max-steps`, which bounds a whole run, this is for hosts to
+    /// preempt scripts and decide whether to go on.
+    pub fn set_fuel(&mut self, fuel: u64) {
+        self.fuel = Some(fuel);
+    }
+
+    /// What is left of the fuel given with `set_fuel`, `None` when unbounded
+    pub fn remaining_fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    fn burn(&mut self, token: Option<&Token>) -> Result<(), LoxError> {
+        match &mut self.fuel {
+            Some(0) => Err(LoxError::OutOfFuel {
+                token: token.cloned().map(Box::new),
+            }),
+            Some(fuel) => {
+                *fuel -= 1;
+                Ok(())
             }
+            None => Ok(()),
+        }
+    }
 
-            func.call(self, &args)
+    /// Prints `value` the way `print` does, on a line of its own when
+    /// `newline`, counting it against the output limit
+    fn print(&mut self, value: &Value, newline: bool) -> Result<(), String> {
+        // Strings are printed straight from the value, without a copy
+        let output = match value {
+            Value::String(val) => Cow::Borrowed(&**val),
+            _ => Cow::Owned(value.to_string()),
+        };
+        self.output_bytes += output.len() + usize::from(newline);
+        if self
+            .limits
+            .max_output_bytes
+            .is_some_and(|max| self.output_bytes > max)
+        {
+            return Err("Output limit exceeded.".into());
+        }
+        let printed = if newline {
+            writeln!(self.stdout, "{}", output)
         } else {
-            Err(format!(
-                "[line {}:{}] Can only call functions and classes.",
-                paren.line, paren.column
-            ))
+            // What comes without a newline is mostly a prompt or a progress
+            // bar, which has to show right away
+            write!(self.stdout, "{}", output).and_then(|_| self.stdout.flush())
+        };
+        printed.map_err(|e| format!("Couldn't print: {}.", e))
+    }
+
+    fn limit_error(token: Option<&Token>, message: &str) -> LoxError {
+        LoxError::RuntimeError {
+            token: token.cloned().map(Box::new),
+            message: message.to_string(),
+            trace: Vec::new(),
         }
     }
 
-    fn eval_get(&mut self, obj: &Expr, name: &Token) -> Result<Lit, String> {
-        let object = self.evaluate(obj)?;
-        if let Lit::LoxInstance(inst) = object {
-            LoxInstance::get(inst, name)
-        } else {
-            Err(format!(
-                "[line {}:{}] Only instances have properties.",
-                name.line, name.column
-            ))
+    /// Integers stay integers as long as the result fits, except in division,
+    /// which gives a double like any arithmetic with a double in it
+    fn binary(&mut self, lval: &Value, op: &Token, rval: &Value) -> Result<Value, LoxError> {
+        if let Some(value) = Interpreter::arithmetic(lval, op.token, rval) {
+            return Ok(value);
+        }
+        match (lval, op.token, rval) {
+            (_, TT::Minus, _) => Err(LoxError::runtime(op, "Operands must be numbers.")),
+            (_, TT::Slash, _) => Err(LoxError::runtime(op, "Operands must be numbers.")),
+            (_, TT::Star, _) => Err(LoxError::runtime(op, "Operands must be numbers.")),
+            (Value::String(lhs), TT::Plus, Value::String(rhs)) => {
+                Ok(self.string(format!("{}{}", lhs, rhs)))
+            }
+            (Value::String(lhs), TT::Plus, Value::Double(rhs)) => {
+                self.concat(op)?;
+                Ok(self.string(format!("{}{}", lhs, rhs)))
+            }
+            (Value::String(lhs), TT::Plus, Value::Integer(rhs)) => {
+                self.concat(op)?;
+                Ok(self.string(format!("{}{}", lhs, rhs)))
+            }
+            (Value::Double(lhs), TT::Plus, Value::String(rhs)) => {
+                self.concat(op)?;
+                Ok(self.string(format!("{}{}", lhs, rhs)))
+            }
+            (Value::Integer(lhs), TT::Plus, Value::String(rhs)) => {
+                self.concat(op)?;
+                Ok(self.string(format!("{}{}", lhs, rhs)))
+            }
+            (_, TT::Plus, _) => Err(LoxError::runtime(
+                op,
+                "Operands must be two numbers or two strings.",
+            )),
+            (_, TT::Greater, _) => Err(LoxError::runtime(op, "Operands must be numbers.")),
+            (_, TT::GreaterEqual, _) => Err(LoxError::runtime(op, "Operands must be numbers.")),
+            (_, TT::Less, _) => Err(LoxError::runtime(op, "Operands must be numbers.")),
+            (_, TT::LessEqual, _) => Err(LoxError::runtime(op, "Operands must be numbers.")),
+            (_, TT::EqualEqual, _) => Ok(Value::Boolean(Interpreter::is_equal(lval, rval))),
+            (_, TT::BangEqual, _) => Ok(Value::Boolean(!Interpreter::is_equal(lval, rval))),
+            (_, TT::Comma, _) => Ok(rval.clone()),
+            _ => Ok(Value::None),
         }
     }
 
-    fn eval_set(&mut self, obj: &Expr, name: &Token, val: &Expr) -> Result<Lit, String> {
-        let object = self.evaluate(obj)?;
-        if let Lit::LoxInstance(inst) = object {
-            let value = self.evaluate(val)?;
-            inst.borrow_mut().set(name, value.clone());
-            Ok(value)
-        } else {
-            Err(format!(
-                "[line {}:{}] Only instances have fields.",
-                name.line, name.column
-            ))
+    /// A string made while running, accounted for against `max-memory-bytes`
+    fn string(&mut self, text: String) -> Value {
+        self.heap.allocate(size_of::<Value>() + text.len());
+        Value::String(text.into())
+    }
+
+    /// Adding a number and a string is an extension to the book, which some
+    /// want to be warned about or to keep out altogether
+    fn concat(&mut self, op: &Token) -> Result<(), LoxError> {
+        match self.concat {
+            Level::Allow => Ok(()),
+            // Once per `+`, however many times it runs
+            Level::Warn if self.warnings.iter().any(|x| *x.token == *op) => Ok(()),
+            Level::Warn => {
+                self.warnings.push(Warning {
+                    lint: Lint::Concat,
+                    token: Box::new(op.clone()),
+                    message: "Number implicitly converted to a string.".into(),
+                    note: None,
+                });
+                Ok(())
+            }
+            Level::Deny => Err(LoxError::runtime(
+                op,
+                "Operands must be two numbers or two strings.",
+            )),
         }
     }
 
-    fn eval_grouping(&mut self, expr: &Expr) -> Result<Lit, String> {
-        self.evaluate(expr)
+    /// The warnings found while running since the last call
+    pub fn take_warnings(&mut self) -> Vec<Warning> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    fn call(&mut self, callable: Value, args: Vec<Value>, paren: &Token) -> Result<(), LoxError> {
+        let Value::Callable(func) = callable else {
+            return Err(LoxError::runtime(
+                paren,
+                "Can only call functions and classes.",
+            ));
+        };
+        if args.len() != func.arity() {
+            let declared = func
+                .declaration()
+                .map(|x| format!(" declared at {}", x.location()))
+                .unwrap_or_default();
+            return Err(LoxError::runtime(
+                paren,
+                format!(
+                    "Expected {} arguments but got {} in call to '{}'{}.",
+                    func.arity(),
+                    args.len(),
+                    func.name(),
+                    declared
+                ),
+            ));
+        }
+
+        if self
+            .limits
+            .max_stack_depth
+            .is_some_and(|max| self.frames.len() >= max)
+        {
+            return Err(LoxError::runtime(paren, "Stack overflow."));
+        }
+        let span = debug_span!(
+            "call",
+            function = %func.name(),
+            depth = self.frames.len() + 1,
+            line = paren.line
+        );
+        self.frames.push(CallFrame {
+            callee: func.clone(),
+            file: paren.file.clone().unwrap_or_else(|| self.script.clone()),
+            line: paren.line,
+            _span: CallSpan::enter(span),
+        });
+        let call = func.call(self, args)?;
+        if let LoxCallable::LoxClass(_) = func {
+            self.heap
+                .record_site(|| format!("made at {}", paren.location()));
+        }
+        match call {
+            Call::Done(value) => {
+                self.frames.pop();
+                self.values.push(value);
+            }
+            Call::Run(function, environment) => {
+                self.heap.allocate(environment.size());
+                let caller = std::mem::replace(&mut self.environment, environment);
+                let body = function.body().clone();
+                self.tasks.push(Task::Return(function, caller));
+                self.tasks.push(Task::Statements(body, 0));
+            }
+            // The frame stays until the future is done
+            #[cfg(feature = "async")]
+            Call::Await(future) => {
+                if !self.awaiting {
+                    return Err(LoxError::runtime(
+                        paren,
+                        format!(
+                            "'{}' is async and can only be called by scripts run with run_async.",
+                            func.name()
+                        ),
+                    ));
+                }
+                self.pending = Some(future);
+            }
+        }
+        Ok(())
     }
 
-    fn eval_literal(&mut self, lit: &Lit) -> Result<Lit, String> {
-        Ok(lit.clone())
+    /// Back in the caller, with what the call gave
+    fn finish_call(
+        &mut self,
+        function: &LoxFunction,
+        caller: Rc<RefCell<Environment>>,
+        value: Option<Value>,
+    ) -> Result<(), LoxError> {
+        self.environment = caller;
+        self.frames.pop();
+        let result = function.result(value)?;
+        self.values.push(result);
+        Ok(())
     }
 
-    fn eval_unary(&mut self, op: &Token, expr: &Expr) -> Result<Lit, String> {
-        let lit = self.evaluate(expr)?;
-        match (op.token, &lit) {
-            (TT::Minus, Lit::Double(n)) => Ok(Lit::Double(-n)),
-            (TT::Minus, _) => Err(format!(
-                "[line {}:{}] Operand must be a number.",
-                op.line, op.column
-            )),
-            (TT::Bang, _) => Ok(Lit::Boolean(!Interpreter::is_truthy(&lit))),
-            _ => Ok(Lit::None),
+    /// Integers stay integers as long as the result fits, except in division,
+    /// which gives a double like any arithmetic with a double in it. `None`
+    /// when the operands aren't both numbers or it isn't arithmetic.
+    pub fn arithmetic(lval: &Value, op: TT, rval: &Value) -> Option<Value> {
+        let test: fn(cmp::Ordering) -> bool = match op {
+            TT::Greater => cmp::Ordering::is_gt,
+            TT::GreaterEqual => cmp::Ordering::is_ge,
+            TT::Less => cmp::Ordering::is_lt,
+            TT::LessEqual => cmp::Ordering::is_le,
+            _ => return Interpreter::calculate(lval, op, rval),
+        };
+        // Exactly, even where doubles can't tell integers apart
+        let ordering = match (lval, rval) {
+            (Value::Integer(a), Value::Integer(b)) => Some(a.cmp(b)),
+            (Value::Integer(a), Value::Double(b)) => compare_integer(*a, *b),
+            (Value::Double(a), Value::Integer(b)) => {
+                compare_integer(*b, *a).map(cmp::Ordering::reverse)
+            }
+            (Value::Double(a), Value::Double(b)) => a.partial_cmp(b),
+            _ => return None,
+        };
+        Some(Value::Boolean(ordering.is_some_and(test)))
+    }
+
+    /// `arithmetic` with the operators giving numbers
+    fn calculate(lval: &Value, op: TT, rval: &Value) -> Option<Value> {
+        if let (Value::Integer(lhs), Value::Integer(rhs)) = (lval, rval) {
+            let exact = match op {
+                TT::Minus => lhs.checked_sub(*rhs).map(Value::Integer),
+                TT::Star => lhs.checked_mul(*rhs).map(Value::Integer),
+                TT::Plus => lhs.checked_add(*rhs).map(Value::Integer),
+                _ => None,
+            };
+            if exact.is_some() {
+                return exact;
+            }
         }
+        let (lhs, rhs) = (lval.number()?, rval.number()?);
+        Some(match op {
+            TT::Minus => Value::Double(lhs - rhs),
+            TT::Slash => Value::Double(lhs / rhs),
+            TT::Star => Value::Double(lhs * rhs),
+            TT::Plus => Value::Double(lhs + rhs),
+            _ => return None,
+        })
     }
 
-    fn is_truthy(lit: &Lit) -> bool {
+    fn unary(op: &Token, lit: &Value) -> Result<Value, LoxError> {
+        match (op.token, lit) {
+            (TT::Minus, Value::Integer(n)) => Ok(n
+                .checked_neg()
+                .map_or(Value::Double(-(*n as f64)), Value::Integer)),
+            (TT::Minus, Value::Double(n)) => Ok(Value::Double(-n)),
+            (TT::Minus, _) => Err(LoxError::runtime(op, "Operand must be a number.")),
+            (TT::Bang, _) => Ok(Value::Boolean(!Interpreter::is_truthy(lit))),
+            _ => Ok(Value::None),
+        }
+    }
+
+    fn is_truthy(lit: &Value) -> bool {
         match lit {
-            Lit::Boolean(x) => *x,
-            Lit::None => false,
+            Value::Boolean(x) => *x,
+            Value::None => false,
             _ => true,
         }
     }
 
-    fn is_equal(left: &Lit, right: &Lit) -> bool {
+    fn is_equal(left: &Value, right: &Value) -> bool {
         match (left, right) {
-            (Lit::Boolean(a), Lit::Boolean(b)) => a == b,
-            (Lit::String(a), Lit::String(b)) => a == b,
-            (Lit::Double(a), Lit::Double(b)) => a == b,
-            (Lit::None, Lit::None) => true,
-            (Lit::None, _) => false,
-            (Lit::Callable(a), Lit::Callable(b)) => a == b,
-            (Lit::LoxInstance(a), Lit::LoxInstance(b)) => a == b,
+            (Value::Boolean(a), Value::Boolean(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Integer(_) | Value::Double(_), Value::Integer(_) | Value::Double(_)) => {
+                left == right
+            }
+            (Value::None, Value::None) => true,
+            (Value::None, _) => false,
+            (Value::Callable(a), Value::Callable(b)) => a == b,
+            (Value::LoxInstance(a), Value::LoxInstance(b)) => a == b,
+            (Value::Foreign(a), Value::Foreign(b)) => Rc::ptr_eq(a, b),
             (_, _) => false,
         }
     }
 }
+
+/// Seconds since the epoch, for the `clock` native
+#[cfg(not(target_arch = "wasm32"))]
+pub fn clock() -> f64 {
+    let duration = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Time went backwards");
+    (duration.as_millis() as f64) / 1000.0
+}
+
+/// Browsers don't let wasm see the time, it asks the page
+#[cfg(target_arch = "wasm32")]
+pub fn clock() -> f64 {
+    #[wasm_bindgen::prelude::wasm_bindgen]
+    extern "C" {
+        /// Milliseconds since the epoch
+        #[wasm_bindgen(js_namespace = Date)]
+        fn now() -> f64;
+    }
+    now() / 1000.0
+}
+
+/// A native turning one string into another, like the encodings
+fn text_native(name: &str, convert: fn(&str) -> Result<String, String>) -> NativeFunction {
+    NativeFunction::new(name, 1, move |_, arguments| match &arguments[0] {
+        Value::String(text) => Ok(Value::String(convert(text)?.into())),
+        _ => Err("Argument must be a string.".into()),
+    })
+}
+
+/// Most properties looked up on something that isn't an instance are looked up
+/// on a `nil` by mistake, which deserves saying where it came from
+fn nil_hint(obj: &Expr, object: &Value) -> String {
+    match (obj, object) {
+        (Expr::Literal(_), _) => String::new(),
+        (_, Value::None) => format!(
+            " '{}' is nil; did you forget to initialize it?",
+            formatter::expr(obj)
+        ),
+        _ => String::new(),
+    }
+}
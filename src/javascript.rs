@@ -0,0 +1,693 @@
+// Scripts translated to JavaScript, for `rjlox transpile --target js`, to run
+// them with Node.js or in a browser where the interpreter isn't available.
+//
+// Classes become classes, functions nested in others arrow functions, so that
+// they close over `this` like in Lox, and `print` becomes `console.log`. What
+// JavaScript does differently, truthiness, `and` and `or`, calling classes,
+// binding methods and printing values, goes through the few functions of a
+// prelude, whose names start with a `$` so that no Lox name can clash with
+// them. Lox names JavaScript reserves get a `$` after them instead, and local
+// variables shadowing others a `$` and a number, as JavaScript would otherwise
+// take the ones closed over before their declaration for them.
+//
+// Only what a correct program does is kept: where Lox would stop with a
+// runtime error, for a wrong number of arguments or adding a string to nil,
+// JavaScript goes on as it does with its own values.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+use crate::expr::Expr;
+use crate::scanner::{Literal, Token, TokenType};
+use crate::stmt::Stmt;
+
+const INDENT: &str = "  ";
+
+const PRELUDE: &str = r#"function $truthy(value) {
+  return value !== null && value !== undefined && value !== false;
+}
+
+function $and(left, right) {
+  return $truthy(left) ? right() : left;
+}
+
+function $or(left, right) {
+  return $truthy(left) ? left : right();
+}
+
+function $isClass(value) {
+  return typeof value === "function" && /^class\b/.test(Function.prototype.toString.call(value));
+}
+
+function $new(cls, ...args) {
+  const instance = new cls();
+  if (instance.init) {
+    instance.init(...args);
+  }
+  return instance;
+}
+
+// A method is bound each time it is taken out of its instance, so that it
+// keeps the instance and, like in Lox, isn't the same function the next time
+function $get(object, name) {
+  const value = object[name];
+  if (typeof value === "function" && !Object.prototype.hasOwnProperty.call(object, name)) {
+    return value.bind(object);
+  }
+  return value;
+}
+
+function $call(callee, ...args) {
+  return $isClass(callee) ? $new(callee, ...args) : callee(...args);
+}
+
+// The values a `for`-`in` loop goes through, asked of the iterator of
+// `collection` with `done()` and `next()`
+function* $iterate(collection) {
+  const iterator = collection.iterator();
+  while (!$truthy(iterator.done())) {
+    yield iterator.next();
+  }
+}
+
+function $name(value) {
+  return value.name.replace(/^bound /, "").replace(/\$\d*$/, "");
+}
+
+function $str(value) {
+  if (value === null || value === undefined) {
+    return "nil";
+  } else if (value === Infinity) {
+    return "inf";
+  } else if (value === -Infinity) {
+    return "-inf";
+  } else if ($isClass(value)) {
+    return "<class " + $name(value) + ">";
+  } else if ([clock, print, println, printRaw].includes(value)) {
+    return "<native fn " + value.name + ">";
+  } else if (typeof value === "function") {
+    return "<fn " + $name(value) + ">";
+  } else if (typeof value === "object") {
+    return "<class " + $name(value.constructor) + "> instance";
+  }
+  return String(value);
+}
+
+function clock() {
+  return Date.now() / 1000;
+}
+
+// Only a name with `--native-print`
+function print(value) {
+  console.log($str(value));
+}
+
+function println(value) {
+  console.log($str(value));
+}
+
+function printRaw(value) {
+  if (typeof process === "undefined") {
+    console.log($str(value));
+  } else {
+    process.stdout.write($str(value));
+  }
+}
+"#;
+
+/// Words JavaScript keeps for itself, and the globals the prelude uses
+const RESERVED: &[&str] = &[
+    "arguments",
+    "await",
+    "break",
+    "case",
+    "catch",
+    "console",
+    "const",
+    "continue",
+    "Date",
+    "debugger",
+    "default",
+    "delete",
+    "do",
+    "enum",
+    "eval",
+    "export",
+    "extends",
+    "finally",
+    "Function",
+    "function",
+    "globalThis",
+    "implements",
+    "import",
+    "in",
+    "Infinity",
+    "instanceof",
+    "interface",
+    "let",
+    "NaN",
+    "new",
+    "Object",
+    "package",
+    "private",
+    "protected",
+    "public",
+    "static",
+    "String",
+    "switch",
+    "throw",
+    "try",
+    "typeof",
+    "undefined",
+    "void",
+    "with",
+    "yield",
+];
+
+/// What a name was declared as, to call classes with `$new` and functions
+/// directly, leaving `$call` for what can't be told
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Class,
+    Function,
+    Variable,
+}
+
+/// What a Lox name is called in JavaScript, and what it was declared as
+#[derive(Debug, Clone)]
+struct Name {
+    js: String,
+    kind: Kind,
+}
+
+/// The whole program, after the prelude. Tests are left out, as they are when
+/// scripts are run.
+pub fn transpile(statements: &[Stmt]) -> String {
+    let natives = ["clock", "print", "println", "printRaw"].map(|x| {
+        let js = x.to_string();
+        (
+            js.clone(),
+            Name {
+                js,
+                kind: Kind::Function,
+            },
+        )
+    });
+    let mut globals = HashMap::from(natives);
+    let mut redeclared = HashSet::new();
+    for statement in statements {
+        let (name, kind) = match statement {
+            Stmt::Class(name, _, _, _) => (name, Kind::Class),
+            Stmt::Function(name, _, _, _) => (name, Kind::Function),
+            Stmt::Var(name, _, _) => (name, Kind::Variable),
+            _ => continue,
+        };
+        let kind = match globals.get(&*name.lexeme) {
+            Some(declared) => {
+                redeclared.insert(name.lexeme.to_string());
+                if declared.kind == kind {
+                    kind
+                } else {
+                    Kind::Variable
+                }
+            }
+            None => kind,
+        };
+        let js = name_of(name);
+        globals.insert(name.lexeme.to_string(), Name { js, kind });
+    }
+    let mut js = Js {
+        out: format!("{}\n", PRELUDE),
+        depth: 0,
+        scopes: vec![globals],
+        redeclared,
+        init: false,
+    };
+    js.statements(statements);
+    js.out
+}
+
+struct Js {
+    out: String,
+    depth: usize,
+    /// The names declared in each scope, the globals first
+    scopes: Vec<HashMap<String, Name>>,
+    /// The globals declared more than once, which are assigned rather than
+    /// declared, since JavaScript would only keep the last of the declarations
+    redeclared: HashSet<String>,
+    /// Whether the function being written is an `init` method, which returns
+    /// `this`
+    init: bool,
+}
+
+impl Js {
+    fn line(&mut self, line: &str) {
+        self.out.push_str(&INDENT.repeat(self.depth));
+        self.out.push_str(line);
+        self.out.push('\n');
+    }
+
+    /// Declares a local in the innermost scope, returning what it is called
+    fn declare(&mut self, name: &Token, kind: Kind) -> String {
+        let shadowed = self
+            .scopes
+            .iter()
+            .filter(|x| x.contains_key(&*name.lexeme))
+            .count();
+        let js = match shadowed {
+            0 => name_of(name),
+            _ => format!("{}${}", name.lexeme, shadowed),
+        };
+        let scope = self.scopes.last_mut().expect("There are always globals");
+        let declared = Name {
+            js: js.clone(),
+            kind,
+        };
+        scope.insert(name.lexeme.to_string(), declared);
+        js
+    }
+
+    /// What `name` refers to where it is used, as far as declared by then
+    fn lookup(&self, name: &Token) -> Name {
+        let declared = self.scopes.iter().rev().find_map(|x| x.get(&*name.lexeme));
+        declared.cloned().unwrap_or_else(|| Name {
+            js: name_of(name),
+            kind: Kind::Variable,
+        })
+    }
+
+    fn global(&self) -> bool {
+        self.scopes.len() == 1
+    }
+
+    /// Classes and functions get a blank line around them, as when formatted
+    fn statements(&mut self, statements: &[Stmt]) {
+        for (i, statement) in statements.iter().enumerate() {
+            if i > 0 && (declaration(statement) || declaration(&statements[i - 1])) {
+                self.out.push('\n');
+            }
+            self.statement(statement);
+        }
+    }
+
+    /// `statements` in braces, after whatever the line already has
+    fn block(&mut self, statements: &[Stmt]) {
+        self.out.push_str("{\n");
+        self.depth += 1;
+        self.scopes.push(HashMap::new());
+        self.statements(statements);
+        self.scopes.pop();
+        self.depth -= 1;
+        self.out.push_str(&INDENT.repeat(self.depth));
+        self.out.push('}');
+    }
+
+    /// The body of an `if` or a loop, braced even when it's a single statement
+    fn body(&mut self, body: &Stmt) {
+        self.out.push(' ');
+        match body {
+            Stmt::Block(statements) => self.block(statements),
+            _ => self.block(std::slice::from_ref(body)),
+        }
+    }
+
+    fn statement(&mut self, statement: &Stmt) {
+        match statement {
+            Stmt::Block(statements) => {
+                self.out.push_str(&INDENT.repeat(self.depth));
+                self.block(statements);
+                self.out.push('\n');
+            }
+            Stmt::Class(name, superclass, methods, _) => self.class(name, superclass, methods),
+            Stmt::Expression(expr) => {
+                let expr = self.expr(expr);
+                self.line(&format!("{};", expr));
+            }
+            Stmt::For(_, initializer, condition, increment, body) => {
+                let clauses = (condition.as_deref(), increment.as_deref());
+                // A variable declared by the loop is shared by all its
+                // iterations in Lox, but not in a JavaScript `for`
+                match initializer.as_deref() {
+                    Some(initializer @ Stmt::Var(..)) => {
+                        self.line("{");
+                        self.depth += 1;
+                        self.scopes.push(HashMap::new());
+                        self.statement(initializer);
+                        self.for_loop("", clauses, body);
+                        self.scopes.pop();
+                        self.depth -= 1;
+                        self.line("}");
+                    }
+                    Some(Stmt::Expression(expr)) => {
+                        let initializer = self.expr(expr);
+                        self.for_loop(&initializer, clauses, body);
+                    }
+                    _ => self.for_loop("", clauses, body),
+                }
+            }
+            Stmt::ForIn(_, name, collection, body) => {
+                let collection = self.expr(collection);
+                self.scopes.push(HashMap::new());
+                let js = self.declare(name, Kind::Variable);
+                self.out.push_str(&INDENT.repeat(self.depth));
+                let _ = write!(self.out, "for (let {} of $iterate({}))", js, collection);
+                self.body(body);
+                self.out.push('\n');
+                self.scopes.pop();
+            }
+            Stmt::Function(name, params, body, _) => {
+                let global = self.global();
+                let declared = match global {
+                    true => self.lookup(name),
+                    false => Name {
+                        js: self.declare(name, Kind::Function),
+                        kind: Kind::Function,
+                    },
+                };
+                let list = params.iter().map(name_of).collect::<Vec<_>>().join(", ");
+                self.out.push_str(&INDENT.repeat(self.depth));
+                let hoisted = global
+                    && declared.kind == Kind::Function
+                    && !self.redeclared.contains(&*name.lexeme);
+                if hoisted {
+                    let _ = write!(self.out, "function {}({}) ", declared.js, list);
+                } else {
+                    let keyword = if global { "var" } else { "let" };
+                    let _ = write!(self.out, "{} {} = ({}) => ", keyword, declared.js, list);
+                }
+                self.function(params, body, false);
+                self.out.push_str(if hoisted { "\n" } else { ";\n" });
+            }
+            Stmt::If(condition, then_branch, else_branch) => {
+                let condition = self.condition(condition);
+                self.out.push_str(&INDENT.repeat(self.depth));
+                self.if_chain(&condition, then_branch, else_branch.as_deref());
+                self.out.push('\n');
+            }
+            Stmt::Print(_, expr) => {
+                let expr = match &**expr {
+                    Expr::Literal(Literal::String(_)) => self.expr(expr),
+                    _ => format!("$str({})", self.expr(expr)),
+                };
+                self.line(&format!("console.log({});", expr));
+            }
+            Stmt::Return(_, value) => {
+                let line = match &**value {
+                    Expr::Literal(Literal::None) if self.init => "return this;".to_string(),
+                    value => format!("return {};", self.expr(value)),
+                };
+                self.line(&line);
+            }
+            Stmt::Test(_, _) => (),
+            Stmt::Var(name, initializer, constant) => {
+                let value = match initializer {
+                    Some(initializer) => self.expr(initializer),
+                    None => "null".to_string(),
+                };
+                // Globals can be declared again in Lox, constants included
+                let (keyword, js) = match (self.global(), constant) {
+                    (true, _) => ("var", name_of(name)),
+                    (false, true) => ("const", self.declare(name, Kind::Variable)),
+                    (false, false) => ("let", self.declare(name, Kind::Variable)),
+                };
+                self.line(&format!("{} {} = {};", keyword, js, value));
+            }
+            Stmt::While(_, condition, body) => {
+                let condition = self.condition(condition);
+                self.out.push_str(&INDENT.repeat(self.depth));
+                let _ = write!(self.out, "while ({})", condition);
+                self.body(body);
+                self.out.push('\n');
+            }
+        }
+    }
+
+    fn for_loop(
+        &mut self,
+        initializer: &str,
+        clauses: (Option<&Expr>, Option<&Expr>),
+        body: &Stmt,
+    ) {
+        let condition = clauses.0.map(|x| self.condition(x)).unwrap_or_default();
+        let increment = clauses.1.map(|x| self.expr(x)).unwrap_or_default();
+        self.out.push_str(&INDENT.repeat(self.depth));
+        let _ = write!(
+            self.out,
+            "for ({}; {}; {})",
+            initializer, condition, increment
+        );
+        self.body(body);
+        self.out.push('\n');
+    }
+
+    /// An `if` without the newline after it, `else if` following on from the
+    /// closing brace
+    fn if_chain(&mut self, condition: &str, then_branch: &Stmt, else_branch: Option<&Stmt>) {
+        let _ = write!(self.out, "if ({})", condition);
+        self.body(then_branch);
+        match else_branch {
+            Some(Stmt::If(condition, then_branch, else_branch)) => {
+                let condition = self.condition(condition);
+                self.out.push_str(" else ");
+                self.if_chain(&condition, then_branch, else_branch.as_deref());
+            }
+            Some(else_branch) => {
+                self.out.push_str(" else");
+                self.body(else_branch);
+            }
+            None => (),
+        }
+    }
+
+    /// The parameters and body of a function, in braces after whatever the
+    /// line already has
+    fn function(&mut self, params: &[Token], body: &[Stmt], init: bool) {
+        let outer = std::mem::replace(&mut self.init, init);
+        // Nothing can be used before the parameters, so they keep their names
+        let params = params.iter().map(|x| {
+            let js = name_of(x);
+            let kind = Kind::Variable;
+            (x.lexeme.to_string(), Name { js, kind })
+        });
+        self.scopes.push(params.collect());
+        self.out.push_str("{\n");
+        self.depth += 1;
+        self.statements(body);
+        if init && !matches!(body.last(), Some(Stmt::Return(..))) {
+            self.line("return this;");
+        }
+        self.depth -= 1;
+        self.out.push_str(&INDENT.repeat(self.depth));
+        self.out.push('}');
+        self.scopes.pop();
+        self.init = outer;
+    }
+
+    fn class(&mut self, name: &Token, superclass: &Option<Expr>, methods: &[Stmt]) {
+        let js = match self.global() {
+            true => name_of(name),
+            false => self.declare(name, Kind::Class),
+        };
+        let extends = match superclass {
+            Some(superclass) => format!(" extends {}", self.expr(superclass)),
+            None => String::new(),
+        };
+        let assigned = self.global() && self.redeclared.contains(&*name.lexeme);
+        if assigned {
+            self.line(&format!("var {0} = class {0}{1} {{", js, extends));
+        } else {
+            self.line(&format!("class {}{} {{", js, extends));
+        }
+        self.depth += 1;
+        for (i, method) in methods.iter().enumerate() {
+            let Stmt::Function(name, params, body, _) = method else {
+                continue;
+            };
+            if i > 0 {
+                self.out.push('\n');
+            }
+            let list = params.iter().map(name_of).collect::<Vec<_>>().join(", ");
+            self.out.push_str(&INDENT.repeat(self.depth));
+            let _ = write!(self.out, "{}({}) ", property(name), list);
+            self.function(params, body, &*name.lexeme == "init");
+            self.out.push('\n');
+        }
+        self.depth -= 1;
+        self.line(if assigned { "};" } else { "}" });
+    }
+
+    /// A condition, left as it is when it can only be true or false
+    fn condition(&mut self, expr: &Expr) -> String {
+        let js = self.expr(expr);
+        if boolean(expr) {
+            js
+        } else {
+            format!("$truthy({})", js)
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) -> String {
+        match expr {
+            Expr::Assign(_, name, value) => {
+                let value = self.expr(value);
+                format!("{} = {}", self.lookup(name).js, value)
+            }
+            Expr::Binary(left, op, right) => {
+                let op = match op.token {
+                    TokenType::EqualEqual if nil(left) || nil(right) => "==",
+                    TokenType::BangEqual if nil(left) || nil(right) => "!=",
+                    TokenType::EqualEqual => "===",
+                    TokenType::BangEqual => "!==",
+                    TokenType::Comma => {
+                        return format!("({}, {})", self.expr(left), self.expr(right));
+                    }
+                    _ => &op.lexeme,
+                };
+                format!("{} {} {}", self.expr(left), op, self.expr(right))
+            }
+            Expr::Call(callee, _, arguments) => {
+                let arguments = arguments
+                    .iter()
+                    .map(|x| self.expr(x))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                let kind = match &**callee {
+                    Expr::Variable(_, name) => self.lookup(name).kind,
+                    Expr::Get(..) | Expr::Super(..) => Kind::Function,
+                    _ => Kind::Variable,
+                };
+                // Methods called right away need no binding
+                let callee = match &**callee {
+                    Expr::Get(_, object, name) => {
+                        format!("{}.{}", self.operand(object), property(name))
+                    }
+                    _ => self.expr(callee),
+                };
+                let callee = callee.strip_suffix(".bind(this)").unwrap_or(&callee);
+                match kind {
+                    Kind::Class if arguments.is_empty() => format!("$new({})", callee),
+                    Kind::Class => format!("$new({}, {})", callee, arguments),
+                    Kind::Function => format!("{}({})", callee, arguments),
+                    Kind::Variable if arguments.is_empty() => format!("$call({})", callee),
+                    Kind::Variable => format!("$call({}, {})", callee, arguments),
+                }
+            }
+            Expr::Get(_, object, name) => {
+                format!("$get({}, \"{}\")", self.expr(object), property(name))
+            }
+            Expr::Set(object, name, value) => format!(
+                "{}.{} = {}",
+                self.operand(object),
+                property(name),
+                self.expr(value)
+            ),
+            Expr::Super(_, _, method) => format!("super.{}.bind(this)", property(method)),
+            Expr::This(_, _) => "this".to_string(),
+            Expr::Grouping(expr) => format!("({})", self.expr(expr)),
+            Expr::Literal(literal) => match literal {
+                Literal::Integer(number) => number.to_string(),
+                Literal::Double(number) => number.to_string(),
+                Literal::String(string) => {
+                    serde_json::to_string(&**string).expect("Strings always serialize")
+                }
+                Literal::Boolean(boolean) => boolean.to_string(),
+                Literal::None => "null".to_string(),
+            },
+            Expr::Logical(left, op, right) if boolean(left) && boolean(right) => {
+                let op = if op.token == TokenType::And {
+                    "&&"
+                } else {
+                    "||"
+                };
+                format!("{} {} {}", self.operand(left), op, self.operand(right))
+            }
+            Expr::Logical(left, op, right) => {
+                let helper = if op.token == TokenType::And {
+                    "$and"
+                } else {
+                    "$or"
+                };
+                format!(
+                    "{}({}, () => {})",
+                    helper,
+                    self.expr(left),
+                    self.expr(right)
+                )
+            }
+            Expr::Unary(op, right) if op.token == TokenType::Bang => {
+                if boolean(right) {
+                    format!("!{}", self.operand(right))
+                } else {
+                    format!("!$truthy({})", self.expr(right))
+                }
+            }
+            Expr::Unary(op, right) => match &**right {
+                // `- -x` would be a decrement without the space
+                Expr::Unary(..) => format!("{}({})", op.lexeme, self.expr(right)),
+                _ => format!("{}{}", op.lexeme, self.operand(right)),
+            },
+            Expr::Variable(_, name) => self.lookup(name).js,
+        }
+    }
+
+    /// An expression that has to bind tighter than the operator applied to it
+    fn operand(&mut self, expr: &Expr) -> String {
+        match expr {
+            Expr::Assign(..) | Expr::Binary(..) | Expr::Set(..) => {
+                format!("({})", self.expr(expr))
+            }
+            Expr::Logical(left, _, right) if boolean(left) && boolean(right) => {
+                format!("({})", self.expr(expr))
+            }
+            _ => self.expr(expr),
+        }
+    }
+}
+
+fn declaration(statement: &Stmt) -> bool {
+    matches!(statement, Stmt::Class(..) | Stmt::Function(..))
+}
+
+/// Whether `expr` can only be true or false, so that JavaScript takes it the
+/// same way as Lox
+fn boolean(expr: &Expr) -> bool {
+    match expr {
+        Expr::Binary(_, op, _) => matches!(
+            op.token,
+            TokenType::EqualEqual
+                | TokenType::BangEqual
+                | TokenType::Greater
+                | TokenType::GreaterEqual
+                | TokenType::Less
+                | TokenType::LessEqual
+        ),
+        Expr::Grouping(expr) => boolean(expr),
+        Expr::Literal(literal) => matches!(literal, Literal::Boolean(_)),
+        Expr::Logical(left, _, right) => boolean(left) && boolean(right),
+        Expr::Unary(op, _) => op.token == TokenType::Bang,
+        _ => false,
+    }
+}
+
+/// Functions may return `undefined` where Lox returns nil, so comparisons with
+/// nil take either
+fn nil(expr: &Expr) -> bool {
+    matches!(expr, Expr::Literal(Literal::None))
+}
+
+fn name_of(name: &Token) -> String {
+    if RESERVED.contains(&&*name.lexeme) {
+        format!("{}$", name.lexeme)
+    } else {
+        name.lexeme.to_string()
+    }
+}
+
+/// Properties can be named anything but these, which mean something to every
+/// JavaScript object
+fn property(name: &Token) -> String {
+    match &*name.lexeme {
+        "constructor" | "__proto__" => format!("{}$", name.lexeme),
+        _ => name.lexeme.to_string(),
+    }
+}
@@ -0,0 +1,115 @@
+// Keys pressed while a script runs, for `pollKey()` in terminal games, built
+// with the `keyboard` feature on Unix. The first poll puts the terminal in raw
+// mode, for keys to come without Enter and without being echoed, and it is put
+// back the way it was when the process exits or is interrupted.
+
+use std::collections::VecDeque;
+use std::sync::{Mutex, Once, OnceLock};
+
+/// Read but not yet taken apart into keys, as one read can get several
+static PENDING: Mutex<VecDeque<u8>> = Mutex::new(VecDeque::new());
+/// How the terminal was before raw mode, to restore at exit. No lock, as a
+/// signal handler reads it.
+static ORIGINAL: OnceLock<libc::termios> = OnceLock::new();
+static RAW: Once = Once::new();
+
+/// The next key pressed, `None` if there is none waiting. Keys with no text
+/// of their own are named: `up`, `down`, `left`, `right`, `enter`, `tab`,
+/// `backspace` and `escape`.
+pub fn poll() -> Option<String> {
+    RAW.call_once(raw_mode);
+    let mut pending = PENDING.lock().unwrap_or_else(|e| e.into_inner());
+    if pending.is_empty() {
+        pending.extend(available());
+    }
+    let (key, length) = key(pending.make_contiguous())?;
+    pending.drain(..length);
+    Some(key)
+}
+
+/// The first key of `bytes`, and how many bytes it took
+pub fn key(bytes: &[u8]) -> Option<(String, usize)> {
+    let named = |name: &str, length| Some((name.to_string(), length));
+    match bytes {
+        [] => None,
+        [0x1b, b'[' | b'O', arrow @ b'A'..=b'D', ..] => {
+            let names = ["up", "down", "right", "left"];
+            named(names[(arrow - b'A') as usize], 3)
+        }
+        [0x1b, ..] => named("escape", 1),
+        [b'\r' | b'\n', ..] => named("enter", 1),
+        [b'\t', ..] => named("tab", 1),
+        [0x7f | 0x08, ..] => named("backspace", 1),
+        [first, ..] => {
+            // As many bytes as the UTF-8 of one character takes
+            let length = match first.leading_ones() {
+                2..=4 => first.leading_ones() as usize,
+                _ => 1,
+            };
+            let length = length.min(bytes.len());
+            Some((
+                String::from_utf8_lossy(&bytes[..length]).into_owned(),
+                length,
+            ))
+        }
+    }
+}
+
+/// Whatever stdin has ready, without waiting
+fn available() -> Vec<u8> {
+    let mut ready = libc::pollfd {
+        fd: libc::STDIN_FILENO,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let mut buffer = [0u8; 64];
+    // Safe, as both only write to what they are given, as much as it holds
+    unsafe {
+        if libc::poll(&mut ready, 1, 0) <= 0 || ready.revents & libc::POLLIN == 0 {
+            return Vec::new();
+        }
+        let read = libc::read(libc::STDIN_FILENO, buffer.as_mut_ptr().cast(), buffer.len());
+        buffer[..read.max(0) as usize].to_vec()
+    }
+}
+
+/// Turns off line buffering and echo, if stdin is a terminal at all
+fn raw_mode() {
+    // Safe, as a termios is plain data and the calls only fill in or read the
+    // one they are given
+    unsafe {
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+            return;
+        }
+        let _ = ORIGINAL.set(termios);
+        termios.c_lflag &= !(libc::ICANON | libc::ECHO);
+        termios.c_cc[libc::VMIN] = 0;
+        termios.c_cc[libc::VTIME] = 0;
+        libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios);
+        libc::atexit(restore);
+        libc::signal(
+            libc::SIGINT,
+            interrupted as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+}
+
+extern "C" fn restore() {
+    if let Some(termios) = ORIGINAL.get() {
+        // Safe, as it was what tcgetattr gave
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, termios);
+        }
+    }
+}
+
+/// Restores the terminal and is interrupted as if it never had been there
+extern "C" fn interrupted(signal: libc::c_int) {
+    restore();
+    // Safe, as both may be called from a signal handler
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+}
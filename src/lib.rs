@@ -0,0 +1,143 @@
+pub mod ast;
+pub mod bench;
+pub mod call_span;
+pub mod callgraph;
+pub mod chunk;
+pub mod cli;
+pub mod collections;
+pub mod compiler;
+pub mod config;
+pub mod coverage;
+pub mod csv;
+pub mod doc;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod driver;
+pub mod encoding;
+pub mod environment;
+pub mod error;
+pub mod expr;
+#[cfg(all(feature = "extensions", unix))]
+pub mod extension;
+pub mod files;
+pub mod formatter;
+pub mod gc;
+pub mod graphviz;
+#[cfg(feature = "hash")]
+pub mod hash;
+pub mod highlight;
+pub mod incremental;
+pub mod interpreter;
+pub mod javascript;
+#[cfg(all(feature = "keyboard", unix))]
+pub mod keyboard;
+pub mod linter;
+pub mod lox_callable;
+pub mod lox_object;
+pub mod loxc;
+pub mod notebook;
+pub mod optimizer;
+pub mod parser;
+pub mod path;
+pub mod playground;
+pub mod resolver;
+#[cfg(feature = "sync")]
+pub mod runtime;
+pub mod sandbox;
+pub mod scanner;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod server;
+pub mod shared;
+pub mod snapshot;
+pub mod stmt;
+pub mod string_builder;
+pub mod symbol;
+pub mod terminal;
+pub mod test_runner;
+pub mod timers;
+pub mod value;
+pub mod vm;
+
+use config::Config;
+use error::LoxError;
+use interpreter::Interpreter;
+use parser::Parser;
+use resolver::Resolver;
+use scanner::{Scanner, Token};
+use shared::{Rc, RefCell};
+use stmt::Stmt;
+use value::Value;
+
+// The stages a script goes through, one at a time, for benchmarks and hosts
+// that want to time or drive them separately. The binary does the same with
+// diagnostics, timings and warnings on top.
+
+/// The tokens of a script, or every lexical error in it
+pub fn scan(source: &str) -> Result<Vec<Token>, Vec<LoxError>> {
+    let mut scanner = Scanner::new(source);
+    let (tokens, errors): (Vec<_>, Vec<_>) = scanner
+        .scan_tokens()
+        .iter()
+        .cloned()
+        .partition(|x| x.is_ok());
+    if errors.is_empty() {
+        Ok(tokens.into_iter().flatten().collect())
+    } else {
+        Err(errors.into_iter().filter_map(|x| x.err()).collect())
+    }
+}
+
+pub fn parse(tokens: Vec<Token>) -> Result<Vec<Stmt>, Vec<LoxError>> {
+    Parser::new(tokens).parse()
+}
+
+/// Binds the variables of `statements` to their scopes in `interpreter`, which
+/// has to be the one running them afterwards
+pub fn resolve(
+    statements: &[Stmt],
+    interpreter: &Rc<RefCell<Interpreter>>,
+) -> Result<(), Vec<LoxError>> {
+    Resolver::new(&mut interpreter.borrow_mut()).resolve(statements)
+}
+
+/// Runs resolved statements, printing to stdout
+pub fn interpret(
+    statements: &[Stmt],
+    interpreter: &Rc<RefCell<Interpreter>>,
+) -> Result<(), LoxError> {
+    interpreter.borrow_mut().interpret(statements).map(|_| ())
+}
+
+/// An interpreter for hosts embedding Lox. The globals a script defines are
+/// kept for the ones run after it, like the files given to the binary.
+pub struct Lox {
+    interpreter: Rc<RefCell<Interpreter>>,
+}
+
+impl Default for Lox {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Lox {
+    pub fn new() -> Self {
+        Self::with_config(&Config::default())
+    }
+
+    /// With the runtime limits and lints of `config`
+    pub fn with_config(config: &Config) -> Self {
+        Lox {
+            interpreter: Rc::new(RefCell::new(Interpreter::with_config(config))),
+        }
+    }
+
+    pub fn interpreter(&self) -> &Rc<RefCell<Interpreter>> {
+        &self.interpreter
+    }
+
+    /// Runs a script like [`Interpreter::eval_source`], giving back the value
+    /// of its last expression statement. Warnings are left to the binary.
+    pub fn run(&mut self, source: &str) -> Result<Value, LoxError> {
+        self.interpreter.borrow_mut().eval_source(source)
+    }
+}
@@ -0,0 +1,168 @@
+// Lints about style rather than mistakes, all of them off unless asked for
+// with `warn` and set up by the `[lint]` section of the config file. They only
+// look at the syntax tree, so unlike the resolver's they need no scopes.
+
+use crate::config::{LintConfig, Naming};
+use crate::error::{Lint, Warning};
+use crate::scanner::Token;
+use crate::stmt::Stmt;
+
+/// The style warnings of a script, in source order. `library` tells whether
+/// it is one of the files configured as libraries.
+pub fn lint(statements: &[Stmt], config: &LintConfig, library: bool) -> Vec<Warning> {
+    let mut linter = Linter {
+        config,
+        library,
+        warnings: Vec::new(),
+    };
+    linter.lint_all(statements, true);
+    linter.warnings
+}
+
+struct Linter<'a> {
+    config: &'a LintConfig,
+    library: bool,
+    warnings: Vec<Warning>,
+}
+
+impl Linter<'_> {
+    /// `global` is for the statements at the top of the script, outside of
+    /// any block
+    fn lint_all(&mut self, statements: &[Stmt], global: bool) {
+        for statement in statements {
+            self.lint_stmt(statement, global);
+        }
+    }
+
+    fn lint_stmt(&mut self, statement: &Stmt, global: bool) {
+        match statement {
+            Stmt::Block(statements) | Stmt::Test(_, statements) => self.lint_all(statements, false),
+            Stmt::Class(name, _, methods, _) => {
+                if global {
+                    self.short_global(name);
+                }
+                if !pascal_case(&name.lexeme) {
+                    self.warn(Lint::Naming, name, "Class names should be PascalCase.");
+                }
+                for method in methods {
+                    if let Stmt::Function(name, params, body, _) = method {
+                        self.function(name, params, body, "Method");
+                    }
+                }
+            }
+            Stmt::Expression(_) | Stmt::Return(_, _) => (),
+            Stmt::For(_, initializer, _, _, body) => {
+                if let Some(initializer) = initializer {
+                    self.lint_stmt(initializer, false);
+                }
+                self.lint_stmt(body, false);
+            }
+            Stmt::ForIn(_, name, _, body) => {
+                self.name(name, "Variable");
+                self.lint_stmt(body, false);
+            }
+            Stmt::Function(name, params, body, _) => {
+                if global {
+                    self.short_global(name);
+                }
+                self.function(name, params, body, "Function");
+            }
+            Stmt::If(_, then_branch, else_branch) => {
+                self.lint_stmt(then_branch, false);
+                if let Some(else_branch) = else_branch {
+                    self.lint_stmt(else_branch, false);
+                }
+            }
+            Stmt::Print(keyword, _) => {
+                if self.library {
+                    self.warn(Lint::Print, keyword, "Library files shouldn't print.");
+                }
+            }
+            Stmt::Var(name, _, _) => {
+                if global {
+                    self.short_global(name);
+                }
+                self.name(name, "Variable");
+            }
+            Stmt::While(_, _, body) => self.lint_stmt(body, false),
+        }
+    }
+
+    fn function(&mut self, name: &Token, params: &[Token], body: &[Stmt], kind: &str) {
+        self.name(name, kind);
+        for param in params {
+            self.name(param, "Parameter");
+        }
+        let length = length(body);
+        if length > self.config.max_function_length {
+            let message = format!(
+                "{} has {} statements, more than the {} allowed.",
+                kind, length, self.config.max_function_length
+            );
+            self.warn(Lint::FunctionLength, name, &message);
+        }
+        self.lint_all(body, false);
+    }
+
+    fn name(&mut self, name: &Token, kind: &str) {
+        // Leading underscores are for names meant to go unused, whatever the style
+        let bare = name.lexeme.trim_start_matches('_');
+        let styled = match self.config.naming {
+            Naming::CamelCase => !bare.contains('_') && !bare.starts_with(uppercase),
+            Naming::SnakeCase => !bare.contains(uppercase),
+        };
+        if !styled {
+            let message = format!("{} names should be {}.", kind, self.config.naming);
+            self.warn(Lint::Naming, name, &message);
+        }
+    }
+
+    fn short_global(&mut self, name: &Token) {
+        if name.lexeme.chars().count() == 1 {
+            self.warn(
+                Lint::ShortGlobal,
+                name,
+                "Globals should have names longer than a letter.",
+            );
+        }
+    }
+
+    fn warn(&mut self, lint: Lint, token: &Token, message: &str) {
+        self.warnings.push(Warning {
+            lint,
+            token: Box::new(token.clone()),
+            message: message.into(),
+            note: None,
+        });
+    }
+}
+
+fn uppercase(c: char) -> bool {
+    c.is_uppercase()
+}
+
+fn pascal_case(name: &str) -> bool {
+    name.starts_with(uppercase) && !name.contains('_')
+}
+
+/// The statements in `statements`, counting the ones nested in blocks, loops,
+/// branches and functions
+fn length(statements: &[Stmt]) -> usize {
+    statements.iter().map(stmt_length).sum()
+}
+
+fn stmt_length(statement: &Stmt) -> usize {
+    1 + match statement {
+        Stmt::Block(statements) => length(statements),
+        Stmt::Class(_, _, methods, _) => length(methods),
+        Stmt::For(_, initializer, _, _, body) => {
+            initializer.as_deref().map_or(0, stmt_length) + stmt_length(body)
+        }
+        Stmt::Function(_, _, body, _) | Stmt::Test(_, body) => length(body),
+        Stmt::If(_, then_branch, else_branch) => {
+            stmt_length(then_branch) + else_branch.as_deref().map_or(0, stmt_length)
+        }
+        Stmt::ForIn(_, _, _, body) | Stmt::While(_, _, body) => stmt_length(body),
+        Stmt::Expression(_) | Stmt::Print(_, _) | Stmt::Return(_, _) | Stmt::Var(_, _, _) => 0,
+    }
+}
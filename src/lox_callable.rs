@@ -1,17 +1,24 @@
 use std::fmt;
+#[cfg(feature = "async")]
+use std::future::Future;
 use std::hash::{Hash, Hasher};
+#[cfg(feature = "async")]
+use std::pin::Pin;
 use std::{
-    cell::RefCell,
     collections::HashMap,
     fmt::{Debug, Display},
-    rc::Rc,
 };
 
 use crate::{
     environment::Environment,
+    error::LoxError,
+    gc::{Collect, Heap, Trace, Tracer},
     interpreter::Interpreter,
-    scanner::{Literal, Token},
+    scanner::Token,
+    shared::{Rc, RefCell, Shareable},
     stmt::Stmt,
+    symbol::Symbol,
+    value::Value,
 };
 
 #[derive(Debug, Clone)]
@@ -52,16 +59,56 @@ impl fmt::Display for LoxCallable {
     }
 }
 
+/// What calling something amounts to, for the interpreter to carry out
+pub enum Call {
+    /// Natives and classes without an initializer are done right away
+    Done(Value),
+    /// Lox code still has to run the body of the function in the environment
+    /// with its arguments
+    Run(Rc<LoxFunction>, Rc<RefCell<Environment>>),
+    /// Async natives give their value once the future is done
+    #[cfg(feature = "async")]
+    Await(NativeFuture),
+}
+
+impl Trace for LoxCallable {
+    fn trace(&self, tracer: &mut Tracer) {
+        match self {
+            LoxCallable::NativeFunction(_) => (),
+            LoxCallable::LoxFunction(func) => func.trace(tracer),
+            LoxCallable::LoxClass(class) => class.trace(tracer),
+        }
+    }
+}
+
 impl LoxCallable {
     pub fn call(
         &self,
         interpreter: &mut Interpreter,
-        arguments: &[Literal],
-    ) -> Result<Literal, String> {
+        arguments: Vec<Value>,
+    ) -> Result<Call, LoxError> {
+        match self {
+            LoxCallable::NativeFunction(func) => func.call(interpreter, &arguments),
+            LoxCallable::LoxFunction(func) => Ok(func.call(arguments)),
+            LoxCallable::LoxClass(class) => Ok(class.call(&mut interpreter.heap, arguments)),
+        }
+    }
+
+    pub fn name(&self) -> &str {
         match self {
-            LoxCallable::NativeFunction(func) => func.call(interpreter, arguments),
-            LoxCallable::LoxFunction(func) => func.call(interpreter, arguments),
-            LoxCallable::LoxClass(class) => class.call(interpreter, arguments),
+            LoxCallable::NativeFunction(func) => func.name(),
+            LoxCallable::LoxFunction(func) => &func.name.lexeme,
+            LoxCallable::LoxClass(class) => &class.name,
+        }
+    }
+
+    /// Where the parameters were declared, the initializer's for classes. Natives
+    /// and classes without an initializer have none.
+    pub fn declaration(&self) -> Option<&Token> {
+        match self {
+            LoxCallable::NativeFunction(_) => None,
+            LoxCallable::LoxFunction(func) => Some(&func.name),
+            LoxCallable::LoxClass(class) => class.initializer().map(|x| &x.name),
         }
     }
 
@@ -74,37 +121,103 @@ impl LoxCallable {
     }
 }
 
-#[derive(Debug, PartialEq)]
+/// What natives are made of. They fail with just a message, as they don't know
+/// where they were called from.
+#[cfg(not(feature = "sync"))]
+pub(crate) type Native = dyn Fn(&mut Interpreter, &[Value]) -> Result<Value, String>;
+#[cfg(feature = "sync")]
+pub(crate) type Native = dyn Fn(&mut Interpreter, &[Value]) -> Result<Value, String> + Send + Sync;
+
+/// What async natives give, which can't borrow the interpreter or the arguments
+/// as the script goes on once it is done
+#[cfg(all(feature = "async", not(feature = "sync")))]
+pub type NativeFuture = Pin<Box<dyn Future<Output = Result<Value, String>>>>;
+#[cfg(all(feature = "async", feature = "sync"))]
+pub type NativeFuture = Pin<Box<dyn Future<Output = Result<Value, String>> + Send>>;
+
+#[cfg(all(feature = "async", not(feature = "sync")))]
+pub(crate) type AsyncNative = dyn Fn(&[Value]) -> NativeFuture;
+#[cfg(all(feature = "async", feature = "sync"))]
+pub(crate) type AsyncNative = dyn Fn(&[Value]) -> NativeFuture + Send + Sync;
+
+enum Callable {
+    Sync(Box<Native>),
+    #[cfg(feature = "async")]
+    Async(Box<AsyncNative>),
+}
+
 pub struct NativeFunction {
     name: String,
     arity: usize,
-    callable: fn(&mut Interpreter, &[Literal]) -> Result<Literal, String>,
+    callable: Callable,
 }
 
 impl NativeFunction {
+    /// `callable` can hold on to whatever state the host gives it
     pub fn new(
         name: &str,
         arity: usize,
-        callable: fn(&mut Interpreter, &[Literal]) -> Result<Literal, String>,
+        callable: impl Fn(&mut Interpreter, &[Value]) -> Result<Value, String> + Shareable + 'static,
     ) -> Self {
         Self {
             name: name.into(),
             arity,
-            callable,
+            callable: Callable::Sync(Box::new(callable)),
         }
     }
-    fn call(
-        &self,
-        interpreter: &mut Interpreter,
-        arguments: &[Literal],
-    ) -> Result<Literal, String> {
-        (self.callable)(interpreter, arguments)
+
+    /// `callable` starts whatever the native does, to be awaited by
+    /// `Interpreter::run_async`
+    #[cfg(feature = "async")]
+    pub fn new_async(
+        name: &str,
+        arity: usize,
+        callable: impl Fn(&[Value]) -> NativeFuture + Shareable + 'static,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            arity,
+            callable: Callable::Async(Box::new(callable)),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn call(&self, interpreter: &mut Interpreter, arguments: &[Value]) -> Result<Call, LoxError> {
+        match &self.callable {
+            Callable::Sync(callable) => callable(interpreter, arguments)
+                .map(Call::Done)
+                .map_err(NativeFunction::error),
+            #[cfg(feature = "async")]
+            Callable::Async(callable) => Ok(Call::Await(callable(arguments))),
+        }
+    }
+
+    /// What natives failing with `message` amounts to
+    pub(crate) fn error(message: String) -> LoxError {
+        LoxError::RuntimeError {
+            token: None,
+            message,
+            trace: Vec::new(),
+        }
     }
 
     fn arity(&self) -> usize {
         self.arity
     }
 }
+
+impl Debug for NativeFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NativeFunction")
+            .field("name", &self.name)
+            .field("arity", &self.arity)
+            .finish_non_exhaustive()
+    }
+}
+
 impl Display for NativeFunction {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "<native fn {}>", self.name)
@@ -114,8 +227,8 @@ impl Display for NativeFunction {
 #[derive(Debug, PartialEq)]
 pub struct LoxFunction {
     name: Token,
-    params: Vec<Token>,
-    body: Vec<Stmt>,
+    params: Rc<[Token]>,
+    body: Rc<[Stmt]>,
     closure: Rc<RefCell<Environment>>,
     is_initializer: bool,
 }
@@ -123,8 +236,8 @@ pub struct LoxFunction {
 impl LoxFunction {
     pub fn new(
         name: Token,
-        params: Vec<Token>,
-        body: Vec<Stmt>,
+        params: Rc<[Token]>,
+        body: Rc<[Stmt]>,
         closure: Rc<RefCell<Environment>>,
         is_initializer: bool,
     ) -> Self {
@@ -141,30 +254,35 @@ impl LoxFunction {
         let environment = Environment::nested(self.closure.clone());
         environment
             .borrow_mut()
-            .define("this", Literal::LoxInstance(Rc::clone(&instance)));
+            .define("this", Value::LoxInstance(Rc::clone(&instance)));
         Rc::new(LoxFunction::new(
             self.name.clone(),
-            self.params.to_vec(),
-            self.body.to_vec(),
+            self.params.clone(),
+            self.body.clone(),
             environment,
             self.is_initializer,
         ))
     }
-    fn call(
-        &self,
-        interpreter: &mut Interpreter,
-        arguments: &[Literal],
-    ) -> Result<Literal, String> {
+    /// The arguments are moved into the parameters, not copied
+    fn call(self: &Rc<Self>, arguments: Vec<Value>) -> Call {
         let environment = Environment::nested(self.closure.clone());
-        let it = self.params.iter().zip(arguments.iter());
-        for (param, arg) in it {
-            environment.borrow_mut().define(&param.lexeme, arg.clone());
+        for (param, arg) in self.params.iter().zip(arguments) {
+            environment.borrow_mut().define(&param.lexeme, arg);
         }
-        let res: Option<Literal> = interpreter.execute_block(&self.body, environment)?;
+        Call::Run(self.clone(), environment)
+    }
+
+    pub fn body(&self) -> &Rc<[Stmt]> {
+        &self.body
+    }
+
+    /// What a call gives back once the body has returned `value`, or run to
+    /// its end without returning. Initializers always give the instance.
+    pub fn result(&self, value: Option<Value>) -> Result<Value, LoxError> {
         if self.is_initializer {
-            self.closure.borrow_mut().get_at(0, "this")
+            self.closure.borrow().get_at(0, 0)
         } else {
-            Ok(res.unwrap_or(Literal::None))
+            Ok(value.unwrap_or(Value::None))
         }
     }
 
@@ -172,6 +290,14 @@ impl LoxFunction {
         self.params.len()
     }
 }
+impl Trace for Rc<LoxFunction> {
+    fn trace(&self, tracer: &mut Tracer) {
+        if tracer.visit(self) {
+            tracer.mark(&self.closure);
+        }
+    }
+}
+
 impl Display for LoxFunction {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "<fn {}>", self.name.lexeme)
@@ -181,51 +307,82 @@ impl Display for LoxFunction {
 #[derive(Debug, PartialEq, Clone)]
 pub struct LoxClass {
     name: String,
-    parent: Option<Rc<LoxClass>>,
-    methods: HashMap<String, Rc<LoxFunction>>,
+    /// Only there for `is_subclass_of`, methods are found without it
+    superclass: Option<Rc<LoxClass>>,
+    /// Its own and the inherited ones it doesn't override, so that finding one
+    /// never has to go up the superclasses
+    methods: HashMap<Symbol, Rc<LoxFunction>>,
 }
 
 impl LoxClass {
     pub fn new(
         name: &str,
         parent: Option<Rc<LoxClass>>,
-        methods: HashMap<String, Rc<LoxFunction>>,
+        mut methods: HashMap<Symbol, Rc<LoxFunction>>,
     ) -> Self {
+        if let Some(parent) = &parent {
+            for (name, method) in &parent.methods {
+                methods
+                    .entry(name.clone())
+                    .or_insert_with(|| method.clone());
+            }
+        }
         Self {
             name: name.into(),
-            parent,
+            superclass: parent,
             methods,
         }
     }
 
-    pub fn find_method(&self, name: &str) -> Option<Rc<LoxFunction>> {
-        if self.methods.contains_key(name) {
-            self.methods.get(name).cloned()
-        } else if let Some(parent) = &self.parent {
-            parent.find_method(name)
-        } else {
-            None
-        }
+    pub fn name(&self) -> &str {
+        &self.name
     }
-    fn call(
-        &self,
-        interpreter: &mut Interpreter,
-        arguments: &[Literal],
-    ) -> Result<Literal, String> {
-        let lox = Rc::new(RefCell::new(LoxInstance::new(Rc::new(self.clone()))));
-        if let Some(initializer) = self.find_method("init") {
-            initializer.bind(lox.clone()).call(interpreter, arguments)?;
+
+    /// Whether this is `class` or inherits from it
+    pub fn is_subclass_of(&self, class: &LoxClass) -> bool {
+        std::ptr::eq(self, class)
+            || self
+                .superclass
+                .as_ref()
+                .is_some_and(|x| x.is_subclass_of(class))
+    }
+
+    pub fn find_method(&self, name: &Symbol) -> Option<Rc<LoxFunction>> {
+        self.methods.get(name).cloned()
+    }
+    /// Instances share the class, so that they can share method caches too
+    fn call(self: &Rc<Self>, heap: &mut Heap, arguments: Vec<Value>) -> Call {
+        let lox = Rc::new(RefCell::new(LoxInstance::new(self.clone())));
+        heap.register(&lox);
+        heap.allocate(lox.size());
+        match self.find_method(&Symbol::intern("init")) {
+            Some(initializer) => initializer.bind(lox).call(arguments),
+            None => Call::Done(Value::LoxInstance(lox)),
         }
-        Ok(Literal::LoxInstance(lox))
     }
 
     fn arity(&self) -> usize {
-        if let Some(initializer) = self.find_method("init") {
+        if let Some(initializer) = self.find_method(&Symbol::intern("init")) {
             initializer.arity()
         } else {
             0
         }
     }
+
+    fn initializer(&self) -> Option<&LoxFunction> {
+        self.methods.get(&Symbol::intern("init")).map(|x| &**x)
+    }
+}
+
+impl Trace for Rc<LoxClass> {
+    fn trace(&self, tracer: &mut Tracer) {
+        if tracer.visit(self) {
+            self.methods.values().for_each(|x| x.trace(tracer));
+            if let Some(superclass) = &self.superclass {
+                superclass.trace(tracer);
+            }
+        }
+    }
 }
 
 impl Display for LoxClass {
@@ -237,7 +394,7 @@ impl Display for LoxClass {
 #[derive(Debug, PartialEq, Clone)]
 pub struct LoxInstance {
     klass: Rc<LoxClass>,
-    fields: HashMap<String, Literal>,
+    fields: HashMap<Symbol, Value>,
 }
 
 impl LoxInstance {
@@ -247,27 +404,56 @@ impl LoxInstance {
             fields: HashMap::new(),
         }
     }
-    pub fn get(obj: Rc<RefCell<Self>>, name: &Token) -> Result<Literal, String> {
-        let lambda = || {
-            obj.borrow()
-                .klass
-                .find_method(&name.lexeme)
-                .map(|x| Literal::Callable(LoxCallable::LoxFunction(x.bind(Rc::clone(&obj)))))
-        };
-        obj.borrow()
-            .fields
-            .get(&name.lexeme)
-            .cloned()
-            .or_else(lambda)
-            .ok_or(format!(
-                "[line {}:{}] Undefined property '{}'.",
-                name.line, name.column, name.lexeme
-            ))
-    }
-
-    pub fn set(&mut self, name: &Token, val: Literal) {
+    pub fn field(&self, name: &Symbol) -> Option<Value> {
+        self.fields.get(name).cloned()
+    }
+
+    pub fn class(&self) -> &Rc<LoxClass> {
+        &self.klass
+    }
+
+    /// Whether this was made by `class` or one of its subclasses
+    pub fn is_instance_of(&self, class: &LoxClass) -> bool {
+        self.klass.is_subclass_of(class)
+    }
+
+    pub fn set(&mut self, name: &Token, val: Value) {
         self.fields.insert(name.lexeme.clone(), val);
     }
+
+    pub fn set_field(&mut self, name: Symbol, val: Value) {
+        self.fields.insert(name, val);
+    }
+
+    pub fn fields(&self) -> impl Iterator<Item = (&Symbol, &Value)> {
+        self.fields.iter()
+    }
+}
+
+impl Trace for RefCell<LoxInstance> {
+    fn trace(&self, tracer: &mut Tracer) {
+        let instance = self.borrow();
+        instance.klass.trace(tracer);
+        instance.fields.values().for_each(|x| x.trace(tracer));
+    }
+}
+
+/// The class is left alone, only the fields can make cycles
+impl Collect for RefCell<LoxInstance> {
+    fn clear(&self) {
+        self.borrow_mut().fields.clear();
+    }
+
+    fn describe(&self) -> String {
+        format!("{} instance", self.borrow().klass.name)
+    }
+
+    fn size(&self) -> usize {
+        let fields = &self.borrow().fields;
+        size_of::<Self>()
+            + fields.len() * size_of::<Symbol>()
+            + fields.values().map(Value::size).sum::<usize>()
+    }
 }
 
 impl Display for LoxInstance {
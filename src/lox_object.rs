@@ -0,0 +1,87 @@
+// Host types scripts use like instances of Lox classes: they read and write
+// properties and call methods, which the host gives the meaning of.
+//
+//     #[derive(Debug)]
+//     struct Counter(Cell<f64>);
+//
+//     impl LoxObject for Counter {
+//         fn class_name(&self) -> &str {
+//             "Counter"
+//         }
+//
+//         fn get(&self, name: &str) -> Option<Value> {
+//             (name == "count").then(|| Value::Double(self.0.get()))
+//         }
+//
+//         fn method_arity(&self, name: &str) -> Option<usize> {
+//             (name == "increment").then_some(0)
+//         }
+//
+//         fn call_method(&self, _: &mut Interpreter, _: &str, _: &[Value]) -> Result<Value, String> {
+//             self.0.set(self.0.get() + 1.0);
+//             Ok(Value::None)
+//         }
+//     }
+//
+//     interpreter.define_global("counter", Value::Foreign(Rc::new(Counter(Cell::new(0.0)))));
+
+use std::any::Any;
+use std::fmt::Debug;
+
+use crate::gc::Tracer;
+use crate::interpreter::Interpreter;
+use crate::shared::Shareable;
+use crate::value::Value;
+
+/// What `Value::Foreign` holds. Scripts share the object, so everything takes
+/// `&self`, and what changes has to be in a `Cell` or a `RefCell`.
+pub trait LoxObject: Debug + Shareable {
+    /// What it prints as, followed by `instance` like instances of classes
+    fn class_name(&self) -> &str;
+
+    /// A property, `None` when there is none by that name. Properties win
+    /// over methods, as fields do in instances of classes.
+    fn get(&self, name: &str) -> Option<Value>;
+
+    /// Fails with the message of the runtime error scripts get when the
+    /// property can't be set, which is always unless implemented
+    fn set(&self, name: &str, value: Value) -> Result<(), String> {
+        let _ = value;
+        Err(format!(
+            "Can't set property '{}' of {} instances.",
+            name,
+            self.class_name()
+        ))
+    }
+
+    /// How many arguments method `name` takes, `None` when there is no method
+    /// by that name
+    fn method_arity(&self, name: &str) -> Option<usize> {
+        let _ = name;
+        None
+    }
+
+    /// Runs method `name`, only ever called with as many arguments as
+    /// `method_arity` said it takes
+    fn call_method(
+        &self,
+        interpreter: &mut Interpreter,
+        name: &str,
+        arguments: &[Value],
+    ) -> Result<Value, String> {
+        let _ = (interpreter, arguments);
+        Err(format!("Undefined property '{}'.", name))
+    }
+
+    /// Marks the values it holds on to, or the collector could empty the
+    /// instances and closures only the object keeps
+    fn trace(&self, tracer: &mut Tracer) {
+        let _ = tracer;
+    }
+
+    /// The object itself, for natives taking objects of a host type to
+    /// downcast them to it. `None` unless implemented.
+    fn as_any(&self) -> Option<&dyn Any> {
+        None
+    }
+}
@@ -0,0 +1,499 @@
+use crate::shared::Rc;
+
+use crate::chunk::{Chunk, Op, Site};
+use crate::scanner::{Literal, Token, TokenType as TT};
+use crate::symbol::Symbol;
+use crate::vm::{Capture, Function, Value, Vm};
+
+/// What compiled scripts are saved as, and recognized by when run
+pub const EXTENSION: &str = "loxc";
+
+/// The first bytes of every compiled script
+const MAGIC: &[u8; 4] = b"LOXC";
+
+/// Bumped whenever the layout below or the instructions change, files of any
+/// other version have to be compiled again
+const VERSION: u32 = 3;
+
+/// Token types are saved as their index in here
+const TOKEN_TYPES: [TT; 39] = [
+    TT::LeftParen,
+    TT::RightParen,
+    TT::LeftBrace,
+    TT::RightBrace,
+    TT::Comma,
+    TT::Dot,
+    TT::Minus,
+    TT::Plus,
+    TT::Semicolon,
+    TT::Slash,
+    TT::Star,
+    TT::Bang,
+    TT::BangEqual,
+    TT::Equal,
+    TT::EqualEqual,
+    TT::Greater,
+    TT::GreaterEqual,
+    TT::Less,
+    TT::LessEqual,
+    TT::Identifier,
+    TT::String,
+    TT::Number,
+    TT::And,
+    TT::Class,
+    TT::Else,
+    TT::False,
+    TT::Fun,
+    TT::For,
+    TT::If,
+    TT::Nil,
+    TT::Or,
+    TT::Print,
+    TT::Return,
+    TT::Super,
+    TT::This,
+    TT::True,
+    TT::Var,
+    TT::While,
+    TT::Eof,
+];
+
+/// Whether a script is a compiled one, going by its name
+pub fn is_compiled(filename: &str) -> bool {
+    filename.ends_with(&format!(".{}", EXTENSION))
+}
+
+/// Saves a script compiled for `vm`, along with the name of the source it was
+/// compiled from, for diagnostics. Everything is little-endian, strings are
+/// prefixed with their length in bytes:
+///
+/// - the magic bytes `LOXC` and the version, as a `u32`
+/// - a checksum of everything after it, as a `u64`
+/// - the name of the source
+/// - the names of the globals, which are numbered differently by every VM
+/// - the script, as a function, functions nesting in their constants
+pub fn write(script: &Function, vm: &Vm, source: &str) -> Vec<u8> {
+    let mut writer = Writer::default();
+    writer.str(source);
+    let globals = vm.global_names();
+    writer.u32(globals.len() as u32);
+    globals.iter().for_each(|x| writer.str(x));
+    writer.function(script);
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend(VERSION.to_le_bytes());
+    bytes.extend(checksum(&writer.bytes).to_le_bytes());
+    bytes.extend(writer.bytes);
+    bytes
+}
+
+/// Loads a script saved by `write` into `vm`, numbering its globals there, and
+/// naming its source in diagnostics when `named`. Gives the script and the
+/// name of its source, or what is wrong with the file, which is checked to
+/// only refer to constants, names, upvalues and instructions that are there.
+pub fn read(bytes: &[u8], vm: &mut Vm, named: bool) -> Result<(Rc<Function>, Rc<str>), String> {
+    let mut reader = Reader {
+        bytes,
+        offset: 0,
+        file: None,
+        globals: Vec::new(),
+    };
+    if !bytes.starts_with(MAGIC) {
+        return Err("not a compiled Lox script".to_string());
+    }
+    reader.offset = MAGIC.len();
+    let version = reader.u32()?;
+    if version != VERSION {
+        return Err(format!(
+            "compiled for version {} of the format, this is version {}, compile it again",
+            version, VERSION
+        ));
+    }
+    let expected = reader.u64()?;
+    if checksum(&bytes[reader.offset..]) != expected {
+        return Err("the file is corrupted".to_string());
+    }
+    let source: Rc<str> = reader.str()?.into();
+    reader.file = named.then(|| source.clone());
+    for _ in 0..reader.u32()? {
+        let name = Symbol::intern(reader.str()?);
+        reader.globals.push(vm.global_index(&name) as u32);
+    }
+    let script = reader.function(0)?;
+    if reader.offset != bytes.len() {
+        return Err("unexpected bytes after the script".to_string());
+    }
+    Ok((script, source))
+}
+
+/// FNV-1a, to tell truncated or damaged files from the ones `write` made. The
+/// checks done while reading only keep them from referring to what isn't
+/// there, the VM still trusts the code itself to be what the compiler emits.
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &x| {
+        (hash ^ x as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[derive(Default)]
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, n: u8) {
+        self.bytes.push(n);
+    }
+
+    fn u32(&mut self, n: u32) {
+        self.bytes.extend(n.to_le_bytes());
+    }
+
+    fn str(&mut self, s: &str) {
+        self.u32(s.len() as u32);
+        self.bytes.extend(s.as_bytes());
+    }
+
+    fn token(&mut self, token: &Token) {
+        let kind = TOKEN_TYPES.iter().position(|x| *x == token.token);
+        self.u8(kind.expect("Every token type is listed") as u8);
+        self.str(&token.lexeme);
+        self.u32(token.line as u32);
+        self.u32(token.column as u32);
+    }
+
+    fn optional<T>(&mut self, value: Option<&T>, write: impl Fn(&mut Self, &T)) {
+        match value {
+            Some(value) => {
+                self.u8(1);
+                write(self, value);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    fn function(&mut self, function: &Function) {
+        self.optional(function.name.as_ref(), Self::token);
+        self.u32(function.arity as u32);
+        self.u32(function.captures.len() as u32);
+        for capture in &function.captures {
+            self.u8(capture.local as u8);
+            self.u32(capture.index);
+        }
+        self.chunk(&function.chunk);
+    }
+
+    fn chunk(&mut self, chunk: &Chunk) {
+        self.u32(chunk.constants.len() as u32);
+        for constant in &chunk.constants {
+            match constant {
+                Value::Number(n) => {
+                    self.u8(0);
+                    self.bytes.extend(n.to_le_bytes());
+                }
+                Value::String(s) => {
+                    self.u8(1);
+                    self.str(s);
+                }
+                Value::Function(function) => {
+                    self.u8(2);
+                    self.function(function);
+                }
+                Value::Integer(n) => {
+                    self.u8(3);
+                    self.bytes.extend(n.to_le_bytes());
+                }
+                _ => unreachable!("Only numbers, strings and functions are constants"),
+            }
+        }
+        self.u32(chunk.names.len() as u32);
+        chunk.names.iter().for_each(|x| self.str(x));
+        self.u32(chunk.sites().len() as u32);
+        for site in chunk.sites() {
+            self.token(&site.token);
+            self.optional(site.subject.as_ref(), |w, x| w.str(x));
+            self.optional(site.paren.as_ref(), Self::token);
+        }
+        self.u32(chunk.code.len() as u32);
+        for (op, &location) in chunk.code.iter().zip(chunk.locations()) {
+            self.op(*op);
+            self.u32(location);
+        }
+    }
+
+    fn op(&mut self, op: Op) {
+        let (code, operands): (u8, &[u32]) = match op {
+            Op::Constant(i) => (0, &[i]),
+            Op::Nil => (1, &[]),
+            Op::True => (2, &[]),
+            Op::False => (3, &[]),
+            Op::Unassigned => (4, &[]),
+            Op::Pop => (5, &[]),
+            Op::GetLocal(i) => (6, &[i]),
+            Op::SetLocal(i) => (7, &[i]),
+            Op::GetGlobal(i) => (8, &[i]),
+            Op::DefineGlobal(i) => (9, &[i]),
+            Op::SetGlobal(i) => (10, &[i]),
+            Op::GetUpvalue(i) => (11, &[i]),
+            Op::SetUpvalue(i) => (12, &[i]),
+            Op::GetProperty(i) => (13, &[i]),
+            Op::SetProperty(i) => (14, &[i]),
+            Op::GetSuper(i) => (15, &[i]),
+            Op::Equal => (16, &[]),
+            Op::Greater => (17, &[]),
+            Op::GreaterEqual => (18, &[]),
+            Op::Less => (19, &[]),
+            Op::LessEqual => (20, &[]),
+            Op::Add => (21, &[]),
+            Op::Subtract => (22, &[]),
+            Op::Multiply => (23, &[]),
+            Op::Divide => (24, &[]),
+            Op::Not => (25, &[]),
+            Op::Negate => (26, &[]),
+            Op::Print => (27, &[]),
+            Op::Jump(to) => (28, &[to]),
+            Op::JumpIfFalse(to) => (29, &[to]),
+            Op::JumpIfTrue(to) => (30, &[to]),
+            Op::Call(argc) => (31, &[argc]),
+            Op::Invoke(name, argc) => (32, &[name, argc]),
+            Op::SuperInvoke(name, argc) => (33, &[name, argc]),
+            Op::Closure(i) => (34, &[i]),
+            Op::CloseUpvalue => (35, &[]),
+            Op::Return => (36, &[]),
+            Op::Class(name) => (37, &[name]),
+            Op::Inherit => (38, &[]),
+            Op::Method(name) => (39, &[name]),
+            Op::DefineConstant(i) => (40, &[i]),
+        };
+        self.u8(code);
+        operands.iter().for_each(|x| self.u32(*x));
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    /// What the tokens are put in
+    file: Option<Rc<str>>,
+    /// The index in the VM of every global of the file
+    globals: Vec<u32>,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self.offset.saturating_add(len);
+        let bytes = self
+            .bytes
+            .get(self.offset..end)
+            .ok_or("unexpected end of file")?;
+        self.offset = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?.try_into().expect("Took 4 bytes");
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        let bytes = self.take(8)?.try_into().expect("Took 8 bytes");
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn f64(&mut self) -> Result<f64, String> {
+        let bytes = self.take(8)?.try_into().expect("Took 8 bytes");
+        Ok(f64::from_le_bytes(bytes))
+    }
+
+    fn str(&mut self) -> Result<&'a str, String> {
+        let len = self.u32()? as usize;
+        std::str::from_utf8(self.take(len)?).map_err(|_| "invalid UTF-8 in a string".to_string())
+    }
+
+    fn flag(&mut self) -> Result<bool, String> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            x => Err(format!("invalid flag {}", x)),
+        }
+    }
+
+    fn token(&mut self) -> Result<Token, String> {
+        let kind = self.u8()?;
+        let token = *TOKEN_TYPES
+            .get(kind as usize)
+            .ok_or_else(|| format!("invalid token type {}", kind))?;
+        Ok(Token {
+            token,
+            lexeme: Symbol::intern(self.str()?),
+            literal: Literal::None,
+            line: self.u32()? as usize,
+            column: self.u32()? as usize,
+            file: self.file.clone(),
+        })
+    }
+
+    /// A function nested in one with `enclosing` upvalues
+    fn function(&mut self, enclosing: usize) -> Result<Rc<Function>, String> {
+        let name = if self.flag()? {
+            Some(self.token()?)
+        } else {
+            None
+        };
+        let arity = self.u32()? as usize;
+        let mut captures = Vec::new();
+        for _ in 0..self.u32()? {
+            let capture = Capture {
+                local: self.flag()?,
+                index: self.u32()?,
+            };
+            if !capture.local && capture.index as usize >= enclosing {
+                return Err(format!("invalid upvalue {}", capture.index));
+            }
+            captures.push(capture);
+        }
+        let chunk = self.chunk(captures.len())?;
+        Ok(Rc::new(Function {
+            name,
+            arity,
+            chunk,
+            captures,
+        }))
+    }
+
+    /// The chunk of a function with `upvalues` upvalues
+    fn chunk(&mut self, upvalues: usize) -> Result<Chunk, String> {
+        let mut chunk = Chunk::default();
+        for _ in 0..self.u32()? {
+            let constant = match self.u8()? {
+                0 => Value::Number(self.f64()?),
+                1 => Value::String(self.str()?.into()),
+                2 => Value::Function(self.function(upvalues)?),
+                3 => Value::Integer(self.u64()? as i64),
+                x => return Err(format!("invalid constant kind {}", x)),
+            };
+            chunk.add_constant(constant);
+        }
+        for _ in 0..self.u32()? {
+            chunk.names.push(Symbol::intern(self.str()?));
+        }
+        let mut sites = Vec::new();
+        for _ in 0..self.u32()? {
+            let token = self.token()?;
+            let subject = if self.flag()? {
+                Some(self.str()?.into())
+            } else {
+                None
+            };
+            let paren = if self.flag()? {
+                Some(self.token()?)
+            } else {
+                None
+            };
+            sites.push(Site {
+                token,
+                subject,
+                paren,
+            });
+        }
+        let len = self.u32()? as usize;
+        for _ in 0..len {
+            let op = self.op()?;
+            let op = self.check(op, &chunk, upvalues, len)?;
+            let location = self.u32()?;
+            let site = sites
+                .get(location as usize)
+                .ok_or_else(|| format!("invalid site {}", location))?;
+            chunk.write(op, site.clone());
+        }
+        if chunk.code.last() != Some(&Op::Return) {
+            return Err("a function doesn't end with a return".to_string());
+        }
+        Ok(chunk)
+    }
+
+    fn op(&mut self) -> Result<Op, String> {
+        let op = match self.u8()? {
+            0 => Op::Constant(self.u32()?),
+            1 => Op::Nil,
+            2 => Op::True,
+            3 => Op::False,
+            4 => Op::Unassigned,
+            5 => Op::Pop,
+            6 => Op::GetLocal(self.u32()?),
+            7 => Op::SetLocal(self.u32()?),
+            8 => Op::GetGlobal(self.u32()?),
+            9 => Op::DefineGlobal(self.u32()?),
+            10 => Op::SetGlobal(self.u32()?),
+            11 => Op::GetUpvalue(self.u32()?),
+            12 => Op::SetUpvalue(self.u32()?),
+            13 => Op::GetProperty(self.u32()?),
+            14 => Op::SetProperty(self.u32()?),
+            15 => Op::GetSuper(self.u32()?),
+            16 => Op::Equal,
+            17 => Op::Greater,
+            18 => Op::GreaterEqual,
+            19 => Op::Less,
+            20 => Op::LessEqual,
+            21 => Op::Add,
+            22 => Op::Subtract,
+            23 => Op::Multiply,
+            24 => Op::Divide,
+            25 => Op::Not,
+            26 => Op::Negate,
+            27 => Op::Print,
+            28 => Op::Jump(self.u32()?),
+            29 => Op::JumpIfFalse(self.u32()?),
+            30 => Op::JumpIfTrue(self.u32()?),
+            31 => Op::Call(self.u32()?),
+            32 => Op::Invoke(self.u32()?, self.u32()?),
+            33 => Op::SuperInvoke(self.u32()?, self.u32()?),
+            34 => Op::Closure(self.u32()?),
+            35 => Op::CloseUpvalue,
+            36 => Op::Return,
+            37 => Op::Class(self.u32()?),
+            38 => Op::Inherit,
+            39 => Op::Method(self.u32()?),
+            40 => Op::DefineConstant(self.u32()?),
+            x => return Err(format!("invalid instruction {}", x)),
+        };
+        Ok(op)
+    }
+
+    /// Checks the operands of an instruction of a chunk with `len` of them,
+    /// and renumbers its global for the VM
+    fn check(&self, op: Op, chunk: &Chunk, upvalues: usize, len: usize) -> Result<Op, String> {
+        let valid = match op {
+            Op::Constant(i) => (i as usize) < chunk.constants.len(),
+            Op::Closure(i) => matches!(chunk.constants.get(i as usize), Some(Value::Function(_))),
+            Op::GetGlobal(i) | Op::DefineGlobal(i) | Op::DefineConstant(i) | Op::SetGlobal(i) => {
+                let Some(&index) = self.globals.get(i as usize) else {
+                    return Err(format!("invalid global {}", i));
+                };
+                return Ok(match op {
+                    Op::GetGlobal(_) => Op::GetGlobal(index),
+                    Op::DefineGlobal(_) => Op::DefineGlobal(index),
+                    Op::DefineConstant(_) => Op::DefineConstant(index),
+                    _ => Op::SetGlobal(index),
+                });
+            }
+            Op::GetUpvalue(i) | Op::SetUpvalue(i) => (i as usize) < upvalues,
+            Op::GetProperty(i)
+            | Op::SetProperty(i)
+            | Op::GetSuper(i)
+            | Op::Invoke(i, _)
+            | Op::SuperInvoke(i, _)
+            | Op::Class(i)
+            | Op::Method(i) => (i as usize) < chunk.names.len(),
+            Op::Jump(to) | Op::JumpIfFalse(to) | Op::JumpIfTrue(to) => (to as usize) < len,
+            _ => true,
+        };
+        if !valid {
+            return Err(format!("invalid operand in {:?}", op));
+        }
+        Ok(op)
+    }
+}
@@ -1,209 +1,2826 @@
-use log::debug;
-use std::cell::RefCell;
-use std::env;
-use std::fs;
+use std::io::{self, IsTerminal};
 use std::process::exit;
-use std::rc::Rc;
 
-mod environment;
-mod expr;
-mod interpreter;
-mod lox_callable;
-mod parser;
-mod resolver;
-mod scanner;
-mod stmt;
+use rjlox::cli::exit_code;
+use rjlox::config::Config;
+use rjlox::driver::{run_cli, STACK_SIZE};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
 
-use interpreter::Interpreter;
-use parser::Parser;
-use rustyline::error::ReadlineError;
-use rustyline::DefaultEditor;
-use stmt::Stmt;
+fn main() {
+    let interpreter = std::thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(|| run_cli(init_tracing))
+        .expect("Something went wrong with starting the interpreter...");
+    let code = interpreter.join().unwrap_or(exit_code::SOFTWARE);
+    // Looked up again, as usage errors are found before the config is loaded
+    let exit_codes = Config::load().map(|x| x.exit_codes).unwrap_or_default();
+    exit(exit_codes.map(code));
+}
 
-use colored::Colorize;
+/// Each `-v` traces more of what rjlox does, on top of what RUST_LOG asks for.
+/// Spans are written to stderr as they close, with how long they took.
+fn init_tracing(verbose: u8) {
+    let mut filter = EnvFilter::from_default_env();
+    let level = match verbose {
+        0 => None,
+        1 => Some("rjlox=info"),
+        2 => Some("rjlox=debug"),
+        _ => Some("rjlox=trace"),
+    };
+    if let Some(level) = level {
+        filter = filter.add_directive(level.parse().expect("Levels are valid directives"));
+    }
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal())
+        .init();
+}
 
-use crate::resolver::Resolver;
+#[cfg(test)]
+mod tests {
+    use assert_cmd::Command;
+    use regex::Regex;
+    use rjlox::config::Config;
+    use rjlox::error::LoxError;
+    use rjlox::interpreter::Interpreter;
+    use rjlox::lox_callable::LoxCallable;
+    use rjlox::lox_object::LoxObject;
+    use rjlox::resolver::Resolver;
+    use rjlox::sandbox::{Capability, SandboxPolicy};
+    use rjlox::scanner::Literal;
+    use rjlox::shared::{Rc, RefCell};
+    use rjlox::stmt::Stmt;
+    use rjlox::symbol::Symbol;
+    use rjlox::test_runner::Expected;
+    use rjlox::value::Value;
+    use rstest::*;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::Instant;
 
-struct Lox {
-    interpreter: Rc<RefCell<Interpreter>>,
-}
+    #[test]
+    fn test_multiple_files_share_globals() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["test/_my/modules/lib.lox", "test/_my/modules/main.lox"])
+            .assert()
+            .success()
+            .stdout("Hello, modules!\n3\n");
+    }
 
-impl Lox {
-    pub fn new() -> Self {
-        Lox {
-            interpreter: Rc::new(RefCell::new(Interpreter::new())),
+    #[test]
+    fn test_multiple_files_errors_name_the_file() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["test/_my/modules/lib.lox", "test/_my/modules/error.lox"])
+            .assert()
+            .failure()
+            .code(70)
+            .stderr(
+                "[test/_my/modules/lib.lox line 6:12] RuntimeError: Operands must be numbers.\n",
+            );
+    }
+
+    #[test]
+    fn test_later_files_fail_after_earlier_ones_ran() {
+        let files = ["test/_my/modules/lib.lox", "test/_my/modules/main.lox"];
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(files)
+            .args(["test/_my/errors/lexical.lox", "missing.lox"])
+            .assert()
+            .code(65)
+            .stdout("Hello, modules!\n3\n")
+            .stderr(
+                "[test/_my/errors/lexical.lox line 1:10] Error: Unexpected character.\n\
+                 [test/_my/errors/lexical.lox line 2:10] Error: Unexpected character.\n\
+                 [test/_my/errors/lexical.lox line 3:7] Error: Unterminated string.\n\
+                 [test/_my/errors/lexical.lox line 4:1] Error at end: Expect expression.\n",
+            );
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(files)
+            .arg("missing.lox")
+            .assert()
+            .code(66)
+            .stdout("Hello, modules!\n3\n");
+    }
+
+    #[test]
+    fn test_trace_prints_statements() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["--trace", "test/_my/modules/main.lox"])
+            .assert()
+            .failure()
+            .code(70)
+            .stderr(
+                "[trace] test/_my/modules/main.lox:1 (print (call greet (\"modules\")))\n\
+                 [line 1:7] RuntimeError: Undefined variable 'greet'.\n",
+            );
+    }
+
+    #[rstest]
+    #[case::stages(&["-v"], &["script{file=test/_my/modules/lib.lox}: rjlox::driver: close time.busy=", "script{file=test/_my/modules/lib.lox}:scan{tokens=30 errors=0}: rjlox::driver: close time.busy=", "parse{statements=2 errors=0}: rjlox::driver: close", "resolve{warnings=0}: rjlox::driver: close", "interpret{ok=true}: rjlox::driver: close"], &["call"])]
+    #[case::calls(&["-vv"], &["script{file=test/_my/modules/main.lox}:interpret:call{function=add depth=1 line=2}: rjlox::interpreter: close time.busy="], &["token Ok("])]
+    #[case::vm_calls(&["-vv", "--vm"], &["interpret:call{function=greet depth=1 line=1}: rjlox::vm: close time.busy="], &[])]
+    #[case::statements(&["-vvv"], &["statement (fun add (a b) ((return (- a (- b)))))"], &[])]
+    fn test_verbose_logging(
+        #[case] args: &[&str],
+        #[case] logged: &[&str],
+        #[case] not_logged: &[&str],
+    ) {
+        let assert = Command::cargo_bin("rjlox")
+            .unwrap()
+            .env_remove("RUST_LOG")
+            .args(args)
+            .args(["test/_my/modules/lib.lox", "test/_my/modules/main.lox"])
+            .assert()
+            .success()
+            .stdout("Hello, modules!\n3\n");
+        let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+        for line in logged {
+            assert!(stderr.contains(line), "{} not in {}", line, stderr);
+        }
+        for line in not_logged {
+            assert!(!stderr.contains(line), "{} in {}", line, stderr);
         }
     }
 
-    pub fn run_file(&mut self, filename: &str) -> i32 {
-        let contents =
-            fs::read_to_string(filename).expect("Something went wrong reading the file...");
-        match self.run(&contents) {
-            Ok(()) => 0,
-            Err(err) => err,
+    #[test]
+    fn test_nothing_is_logged_by_default() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .env_remove("RUST_LOG")
+            .arg("test/_my/modules/lib.lox")
+            .assert()
+            .success()
+            .stderr("");
+    }
+
+    #[test]
+    fn test_resolve_dump() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["--resolve-dump", "test/_my/resolve/closure.lox"])
+            .assert()
+            .success()
+            .stdout("global\nglobal\nblock\n")
+            .stderr(
+                "[resolve] test/_my/resolve/closure.lox:4:11 a: global\n\
+                 [resolve] test/_my/resolve/closure.lox:7:3 showA: depth 0, slot 0\n\
+                 [resolve] test/_my/resolve/closure.lox:9:3 showA: depth 0, slot 0\n\
+                 [resolve] test/_my/resolve/closure.lox:10:9 a: depth 0, slot 1\n\
+                 [resolve] test/_my/resolve/closure.lox:15:12 this: depth 1, slot 0\n\
+                 [resolve] test/_my/resolve/closure.lox:15:26 name: depth 0, slot 0\n",
+            );
+    }
+
+    #[test]
+    fn test_for_loops_point_at_their_keyword() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["--trace", "--max-steps=3", "test/_my/limits/empty_for.lox"])
+            .assert()
+            .failure()
+            .code(70)
+            .stderr(
+                "[trace] test/_my/limits/empty_for.lox:1 (for nil nil nil (body (block )))\n\
+                 [line 1:1] RuntimeError: Execution limit exceeded.\n",
+            );
+    }
+
+    #[rstest]
+    fn test_empty_loops_run_out_of_steps_at_their_keyword(
+        #[values("empty_for", "empty_while")] script: &str,
+        #[values(None, Some("--vm"))] flag: Option<&str>,
+    ) {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(flag)
+            .arg("--max-steps=100")
+            .arg(format!("test/_my/limits/{}.lox", script))
+            .assert()
+            .failure()
+            .code(70)
+            .stderr("[line 1:1] RuntimeError: Execution limit exceeded.\n");
+    }
+
+    #[test]
+    fn test_time_reports_every_stage() {
+        let stages = Regex::new(
+            r"^\[time\] scanning: .*\n\[time\] parsing: .*\n\[time\] resolving: .*\n\[time\] interpreting: .*\n\[time\] total: .*\n$",
+        )
+        .unwrap();
+        let assert = Command::cargo_bin("rjlox")
+            .unwrap()
+            .args([
+                "--time",
+                "test/_my/modules/lib.lox",
+                "test/_my/modules/main.lox",
+            ])
+            .assert()
+            .success()
+            .stdout("Hello, modules!\n3\n");
+        let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+        assert!(stages.is_match(&stderr), "{}", stderr);
+    }
+
+    #[rstest]
+    #[case(&["--color=always"], "\u{1b}[31m[line 1:7] RuntimeError: Undefined variable 'greet'.\u{1b}[0m\n")]
+    #[case(&["--color=never"], "[line 1:7] RuntimeError: Undefined variable 'greet'.\n")]
+    #[case(&["--color=auto"], "[line 1:7] RuntimeError: Undefined variable 'greet'.\n")]
+    fn test_color_choice(#[case] flags: &[&str], #[case] error: &str) {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(flags)
+            .arg("test/_my/modules/main.lox")
+            .assert()
+            .failure()
+            .code(70)
+            .stderr(error.to_string());
+    }
+
+    #[test]
+    fn test_no_color_wins_over_auto() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .env("NO_COLOR", "1")
+            .arg("test/_my/modules/main.lox")
+            .assert()
+            .failure()
+            .stderr("[line 1:7] RuntimeError: Undefined variable 'greet'.\n");
+    }
+
+    #[test]
+    fn test_unused_locals_are_warned_about() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .arg("test/_my/errors/unused.lox")
+            .assert()
+            .success()
+            .stdout("1\n")
+            .stderr("[line 3:7] Warning at 'unused': Local variable is never used.\n");
+    }
+
+    #[test]
+    fn test_unreachable_code_is_warned_about() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .arg("test/_my/errors/unreachable.lox")
+            .assert()
+            .success()
+            .stdout("1\n")
+            .stderr("[line 3:3] Warning at 'print': Unreachable code.\n");
+    }
+
+    #[test]
+    fn test_loops_without_a_return_are_warned_about() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .arg("test/_my/errors/endless.lox")
+            .assert()
+            .success()
+            .stdout("1\n")
+            .stderr("[line 2:3] Warning at 'loop': Loop has no 'return' to leave it by.\n");
+    }
+
+    #[test]
+    fn test_shadowing_is_only_warned_about_on_demand() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .arg("test/_my/errors/shadow.lox")
+            .assert()
+            .success()
+            .stderr("");
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["--warn", "shadow", "test/_my/errors/shadow.lox"])
+            .assert()
+            .success()
+            .stdout("2\n")
+            .stderr("[line 4:9] Warning at 'a': Shadows a variable declared at [line 3:7].\n");
+    }
+
+    #[test]
+    fn test_json_diagnostics() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["--diagnostics=json", "test/unexpected_character.lox"])
+            .assert()
+            .failure()
+            .code(65)
+            .stderr(
+                "{\"file\":\"test/unexpected_character.lox\",\"line\":3,\"column\":7,\"length\":1,\
+                 \"severity\":\"error\",\"code\":\"scan\",\"message\":\"Unexpected character.\"}\n\
+                 {\"file\":\"test/unexpected_character.lox\",\"line\":3,\"column\":9,\"length\":1,\
+                 \"severity\":\"error\",\"code\":\"parse\",\"message\":\"Expect ')' after arguments. \
+                 Last valid lexeme was 'a' at [line 3:5].\"}\n",
+            );
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["--diagnostics=json", "test/_my/errors/unused.lox"])
+            .assert()
+            .success()
+            .stderr(
+                "{\"file\":\"test/_my/errors/unused.lox\",\"line\":3,\"column\":7,\"length\":6,\
+                 \"severity\":\"warning\",\"code\":\"unused\",\"message\":\"Local variable is never used.\"}\n",
+            );
+    }
+
+    #[test]
+    fn test_deny_warnings() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["--deny", "warnings", "test/_my/errors/unused.lox"])
+            .assert()
+            .failure()
+            .code(65)
+            .stdout("")
+            .stderr("[line 3:7] Warning at 'unused': Local variable is never used.\n");
+    }
+
+    #[rstest]
+    #[case(&["--allow", "unused"], 0, "")]
+    #[case(&["--allow", "warnings"], 0, "")]
+    #[case(
+        &["--deny", "unused", "--allow", "warnings"],
+        65,
+        "[line 3:7] Warning at 'unused': Local variable is never used.\n"
+    )]
+    #[case(&["--deny", "shadow"], 0, "[line 3:7] Warning at 'unused': Local variable is never used.\n")]
+    fn test_warning_levels(#[case] args: &[&str], #[case] code: i32, #[case] stderr: &str) {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(args)
+            .arg("test/_my/errors/unused.lox")
+            .assert()
+            .code(code)
+            .stderr(stderr.to_string());
+    }
+
+    #[rstest]
+    #[case(&[], 0, "a1\n2b\ni0\ni1\ncd\n", "[line 1:11] Warning at '+': Number implicitly converted to a string.\n\
+         [line 2:9] Warning at '+': Number implicitly converted to a string.\n\
+         [line 3:45] Warning at '+': Number implicitly converted to a string.\n")]
+    #[case(&["--allow=concat"], 0, "a1\n2b\ni0\ni1\ncd\n", "")]
+    #[case(&["--strict"], 70, "", "[line 1:11] RuntimeError: Operands must be two numbers or two strings.\n")]
+    fn test_adding_numbers_to_strings(
+        #[case] args: &[&str],
+        #[case] code: i32,
+        #[case] stdout: &str,
+        #[case] stderr: &str,
+    ) {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(args)
+            .arg("test/_my/errors/concat.lox")
+            .assert()
+            .code(code)
+            .stdout(stdout.to_string())
+            .stderr(stderr.to_string());
+    }
+
+    #[rstest]
+    fn test_print_natives(#[values(None, Some("--vm"))] flag: Option<&str>) {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(flag)
+            .arg("test/_my/print/natives.lox")
+            .assert()
+            .success()
+            .stdout("Loading... done\n42\nnil\n<native fn println>\n");
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(flag)
+            .args(["--max-output-bytes=10", "test/_my/print/natives.lox"])
+            .assert()
+            .code(70)
+            .stdout("Loading...")
+            .stderr("RuntimeError: Output limit exceeded.\n");
+    }
+
+    #[rstest]
+    fn test_native_print(#[values(None, Some("--vm"))] flag: Option<&str>) {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(flag)
+            .args(["--native-print", "test/_my/print/native_print.lox"])
+            .assert()
+            .success()
+            .stdout("hello\n3\n<native fn print>\n");
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(flag)
+            .arg("test/_my/print/native_print.lox")
+            .assert()
+            .code(65)
+            .stderr("[line 1:11] Error at 'print': Expect expression.\n");
+    }
+
+    #[rstest]
+    fn test_encoding_natives(#[values(None, Some("--vm"))] flag: Option<&str>) {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(flag)
+            .arg("test/_my/encoding/encoding.lox")
+            .assert()
+            .success()
+            .stdout(concat!(
+                "SGVsbG8sIHdvcmxkIQ==\nHello, world!\nHi\naMOpbGxv\nhéllo\n",
+                "4c6f78\nLox\n✓\n\n"
+            ));
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(flag)
+            .arg("test/_my/encoding/invalid.lox")
+            .assert()
+            .code(70)
+            .stderr("RuntimeError: Invalid hex.\n");
+    }
+
+    #[rstest]
+    fn test_terminal_natives(#[values(None, Some("--vm"))] flag: Option<&str>) {
+        // Not a terminal, so the size comes from the environment
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(flag)
+            .arg("test/_my/terminal/terminal.lox")
+            .env("LINES", "30")
+            .env("COLUMNS", "100")
+            .assert()
+            .success()
+            .stdout(
+                "\x1b[2J\x1b[H\x1b[2;5H\x1b[32mok\x1b[0m\n<class TerminalSize> instance\n3000\n",
+            );
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(flag)
+            .arg("-")
+            .write_stdin("setColor(\"pink\");")
+            .assert()
+            .code(70)
+            .stderr("RuntimeError: Unknown color 'pink'.\n");
+    }
+
+    #[rstest]
+    fn test_sleep(#[values(None, Some("--vm"))] flag: Option<&str>) {
+        let start = std::time::Instant::now();
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(flag)
+            .arg("-")
+            .write_stdin("sleep(100);\nprint \"awake\";\nsleep(\"long\");\n")
+            .assert()
+            .code(70)
+            .stdout("awake\n")
+            .stderr("RuntimeError: Milliseconds must be a number.\n");
+        assert!(start.elapsed() >= std::time::Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_event_loop() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["test", "test/_my/timers"])
+            .assert()
+            .success()
+            .stdout("2 passed, 0 failed.\n");
+        // Intervals never end, but the steps do
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["--max-steps=1000", "-"])
+            .write_stdin("fun f() {}\nsetInterval(f, 0);\nrunEventLoop();\n")
+            .assert()
+            .code(70)
+            .stderr("RuntimeError: Execution limit exceeded.\n");
+    }
+
+    #[test]
+    fn test_serve() {
+        use std::io::{BufRead, BufReader, Read, Write};
+        use std::net::TcpStream;
+
+        let mut server = std::process::Command::new(assert_cmd::cargo::cargo_bin("rjlox"))
+            .args(["serve", "--port", "0"])
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::null())
+            .spawn()
+            .unwrap();
+        let mut listening = String::new();
+        BufReader::new(server.stdout.take().unwrap())
+            .read_line(&mut listening)
+            .unwrap();
+        let address = listening.trim().replace("Listening on http://", "");
+        let request = |request: String| {
+            let mut stream = TcpStream::connect(&address).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            stream.shutdown(std::net::Shutdown::Write).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let source = "print 1 + 2;\nprint nil.x;";
+        let ran = request(format!(
+            "POST /run HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            source.len(),
+            source
+        ));
+        let page = request("GET / HTTP/1.1\r\n\r\n".to_string());
+        let missing = request("GET /nowhere HTTP/1.1\r\n\r\n".to_string());
+        let huge = request(format!(
+            "GET / HTTP/1.1\r\nX: {}\r\n\r\n",
+            "a".repeat(10_000)
+        ));
+        // Connections that send nothing keep their threads until they time out
+        let idle: Vec<_> = (0..64)
+            .map(|_| TcpStream::connect(&address).unwrap())
+            .collect();
+        // Turned away before it could send anything
+        let mut busy = String::new();
+        TcpStream::connect(&address)
+            .unwrap()
+            .read_to_string(&mut busy)
+            .unwrap();
+        drop(idle);
+        server.kill().unwrap();
+        server.wait().unwrap();
+
+        assert!(ran.starts_with("HTTP/1.1 200 OK\r\n"), "{}", ran);
+        assert!(
+            ran.ends_with(r#"{"stdout":"3\n","errors":["[line 2:11] RuntimeError: Only instances have properties."]}"#),
+            "{}",
+            ran
+        );
+        assert!(page.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(page.contains("<textarea"));
+        assert!(missing.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(huge.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+        assert!(busy.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+    }
+
+    #[cfg(all(feature = "keyboard", unix))]
+    #[test]
+    fn test_keys_pressed() {
+        use rjlox::keyboard::key;
+
+        assert_eq!(key(b""), None);
+        assert_eq!(key(b"ab"), Some(("a".to_string(), 1)));
+        assert_eq!(key("éa".as_bytes()), Some(("é".to_string(), 2)));
+        assert_eq!(key(b"\x1b[Dx"), Some(("left".to_string(), 3)));
+        assert_eq!(key(b"\x1bx"), Some(("escape".to_string(), 1)));
+        assert_eq!(key(b"\r"), Some(("enter".to_string(), 1)));
+        assert_eq!(key(b"\x7f"), Some(("backspace".to_string(), 1)));
+    }
+
+    #[test]
+    fn test_listing_files() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["test", "test/_my/files"])
+            .assert()
+            .success()
+            .stdout("1 passed, 0 failed.\n");
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["--sandbox", "test/_my/files/files.lox"])
+            .assert()
+            .code(70)
+            .stderr("RuntimeError: The sandbox doesn't allow access to files.\n");
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .arg("-")
+            .write_stdin("glob(\"a[\");")
+            .assert()
+            .code(70)
+            .stderr("RuntimeError: Invalid pattern 'a[': invalid range pattern.\n");
+    }
+
+    #[rstest]
+    fn test_path_natives(#[values(None, Some("--vm"))] flag: Option<&str>) {
+        let cwd = std::env::current_dir().unwrap();
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(flag)
+            .arg("test/_my/path/path.lox")
+            .assert()
+            .success()
+            .stdout(concat!(
+                "notes/day1.lox\n/tmp/x.lox\nday1.lox\ntrue\nnotes\n.\n/\ngz\ntrue\n",
+                "day1.lox\n/tmp/x.lox\n"
+            ));
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(flag)
+            .arg("-")
+            .write_stdin("print absolutePath(\"day1.lox\");")
+            .assert()
+            .success()
+            .stdout(format!("{}\n", cwd.join("day1.lox").display()));
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(flag)
+            .args(["--sandbox", "test/_my/path/path.lox"])
+            .assert()
+            .code(70)
+            .stderr("RuntimeError: The sandbox doesn't allow access to files.\n");
+    }
+
+    #[test]
+    fn test_csv_natives() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["test", "test/_my/csv"])
+            .assert()
+            .success()
+            .stdout("2 passed, 0 failed.\n");
+    }
+
+    #[rstest]
+    fn test_integers(#[values(None, Some("--opt"), Some("--vm"))] flag: Option<&str>) {
+        let path = "test/_my/integers/integers.lox";
+        let Expected::Output(output) = Expected::parse(&fs::read_to_string(path).unwrap()) else {
+            panic!("{} only prints", path);
+        };
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(flag)
+            .arg(path)
+            .assert()
+            .success()
+            .stdout(output);
+    }
+
+    #[rstest]
+    fn test_constants_assigned_before_the_resolver_saw_them(
+        #[files("test/_my/const/*.lox")] path: PathBuf,
+        #[values(None, Some("--opt"), Some("--vm"))] flag: Option<&str>,
+    ) {
+        let mut cmd = Command::cargo_bin("rjlox").unwrap();
+        cmd.args(flag).arg(&path);
+        match Expected::parse(&fs::read_to_string(&path).unwrap()) {
+            Expected::RuntimeError(error) => cmd.assert().failure().code(70).stderr(error),
+            Expected::Output(output) => cmd.assert().success().stdout(output),
+            Expected::CompileError(_) => panic!("{} should compile", path.display()),
+        };
+    }
+
+    #[test]
+    fn test_string_builder() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["test", "test/_my/string_builder"])
+            .assert()
+            .success()
+            .stdout("1 passed, 0 failed.\n");
+    }
+
+    #[cfg(feature = "hash")]
+    #[test]
+    fn test_hash_natives() {
+        // In-process, as `cargo test` doesn't build the binary with features
+        let mut interpreter = Interpreter::with_config(&Config::default());
+        let mut digest = |source: &str| interpreter.eval_source(source).unwrap().to_string();
+        assert_eq!(
+            digest("sha256(\"\");"),
+            "\"e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855\""
+        );
+        assert_eq!(
+            digest("sha256(\"abc\");"),
+            "\"ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad\""
+        );
+        assert_eq!(digest("md5(\"\");"), "\"d41d8cd98f00b204e9800998ecf8427e\"");
+        assert_eq!(
+            digest("md5(\"The quick brown fox jumps over the lazy dog\");"),
+            "\"9e107d9d372bb6826bd81d3542a419d6\""
+        );
+        assert_eq!(
+            digest("md5(\"✓\");"),
+            "\"900eba0c97923d74bd8d9e6190e07264\""
+        );
+    }
+
+    #[test]
+    fn test_shadowing_notes_the_shadowed_variable() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args([
+                "--warn=shadow",
+                "--diagnostics=json",
+                "test/_my/errors/shadow.lox",
+            ])
+            .assert()
+            .success()
+            .stderr(
+                "{\"file\":\"test/_my/errors/shadow.lox\",\"line\":4,\"column\":9,\"length\":1,\
+                 \"severity\":\"warning\",\"code\":\"shadow\",\
+                 \"message\":\"Shadows a variable declared at [line 3:7].\"}\n\
+                 {\"file\":\"test/_my/errors/shadow.lox\",\"line\":3,\"column\":7,\"length\":1,\
+                 \"severity\":\"note\",\"code\":\"shadow\",\
+                 \"message\":\"The shadowed variable is declared here.\"}\n",
+            );
+    }
+
+    #[test]
+    fn test_all_lexical_errors_are_reported_before_giving_up() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .arg("test/_my/errors/lexical.lox")
+            .assert()
+            .failure()
+            .code(65)
+            .stdout("")
+            .stderr(
+                "[line 1:10] Error: Unexpected character.\n\
+                 [line 2:10] Error: Unexpected character.\n\
+                 [line 3:7] Error: Unterminated string.\n\
+                 [line 4:1] Error at end: Expect expression.\n",
+            );
+    }
+
+    #[test]
+    fn test_missing_semicolons_are_reported_where_they_belong() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .arg("test/_my/errors/missing_semicolon.lox")
+            .assert()
+            .failure()
+            .code(65)
+            .stderr(
+                "[line 1:9] Error at '1': Expect ';' after variable declaration.\n\
+                 [line 2:7] Error at 'a': Expect ';' after value.\n\
+                 [line 5:1] Error at 'print': Expect expression.\n",
+            );
+    }
+
+    #[rstest]
+    #[case(
+        "test/_my/modules/main.lox",
+        70,
+        "[line 1:7] RuntimeError: Undefined variable 'greet'.\n \
+         --> test/_my/modules/main.lox:1:7\n  \
+         |\n\
+         1 | print greet(\"modules\");\n  \
+         |       ^^^^^\n"
+    )]
+    #[case(
+        "test/string/unterminated.lox",
+        65,
+        "[line 2:1] Error: Unterminated string.\n \
+         --> test/string/unterminated.lox:2:1\n  \
+         |\n\
+         2 | \"this string has no close quote\n  \
+         | ^\n"
+    )]
+    #[case(
+        "test/_my/errors/trace.lox",
+        70,
+        "[line 2:12] RuntimeError: Operands must be numbers.\n \
+         --> test/_my/errors/trace.lox:2:12\n  \
+         |\n\
+         2 |   return x - nil;\n  \
+         |            ^\n  \
+         in inner at test/_my/errors/trace.lox:5\n  \
+         in outer at test/_my/errors/trace.lox:8\n  \
+         in A at test/_my/errors/trace.lox:10\n"
+    )]
+    fn test_rich_errors_quote_the_source(
+        #[case] file: &str,
+        #[case] code: i32,
+        #[case] error: &str,
+    ) {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["--error-format=rich", file])
+            .assert()
+            .failure()
+            .code(code)
+            .stderr(error.to_string());
+    }
+
+    fn config_dir(name: &str, config: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rjlox-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(".rjloxrc"), config).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_config_file_defaults() {
+        let dir = config_dir("defaults", "natives = []\n[limits]\nmax-steps = 3000\n");
+        let script = fs::canonicalize("test/_my/limits/runaway.lox").unwrap();
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .current_dir(&dir)
+            .arg(&script)
+            .assert()
+            .failure()
+            .code(70)
+            .stderr("[line 10:7] RuntimeError: Execution limit exceeded.\n");
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .current_dir(&dir)
+            .arg("--max-steps=10")
+            .arg(&script)
+            .assert()
+            .failure()
+            .code(70)
+            .stderr("[line 2:25] RuntimeError: Execution limit exceeded.\n");
+        fs::write(dir.join("clock.lox"), "clock();").unwrap();
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .current_dir(&dir)
+            .arg("clock.lox")
+            .assert()
+            .failure()
+            .code(70)
+            .stderr("[line 1:1] RuntimeError: Undefined variable 'clock'.\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[rstest]
+    #[case(
+        "",
+        "[line 4:7] Warning at '_ignored': Local variable is never used.\n"
+    )]
+    #[case(
+        "warn = [\"short-global\", \"naming\"]\n",
+        "[line 1:5] Warning at 'x': Globals should have names longer than a letter.\n\
+         [line 2:5] Warning at 'max_count': Variable names should be camelCase.\n\
+         [line 3:5] Warning at 'Area': Function names should be camelCase.\n\
+         [line 3:17] Warning at 'Height': Parameter names should be camelCase.\n\
+         [line 4:7] Warning at '_ignored': Local variable is never used.\n\
+         [line 7:7] Warning at 'point': Class names should be PascalCase.\n\
+         [line 13:3] Warning at 'long_method': Method names should be camelCase.\n"
+    )]
+    #[case(
+        "warn = [\"naming\"]\nallow = [\"unused\"]\n[lint]\nnaming = \"snake-case\"\n",
+        "[line 3:5] Warning at 'Area': Function names should be snake_case.\n\
+         [line 3:17] Warning at 'Height': Parameter names should be snake_case.\n\
+         [line 7:7] Warning at 'point': Class names should be PascalCase.\n"
+    )]
+    #[case(
+        "deny = [\"function-length\"]\n[lint]\nmax-function-length = 5\n",
+        "[line 4:7] Warning at '_ignored': Local variable is never used.\n\
+         [line 13:3] Warning at 'long_method': Method has 8 statements, more than the 5 allowed.\n"
+    )]
+    fn test_style_lints(#[case] config: &str, #[case] stderr: &str) {
+        let name = format!("lint-{}", stderr.len());
+        let dir = config_dir(&name, config);
+        let script = fs::canonicalize("test/_my/lint/style.lox").unwrap();
+        let code = if config.starts_with("deny") { 65 } else { 0 };
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .current_dir(&dir)
+            .arg(&script)
+            .assert()
+            .code(code)
+            .stderr(stderr.to_string());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_libraries_shouldnt_print() {
+        let dir = config_dir(
+            "libraries",
+            "warn = [\"print\"]\n[lint]\nlibraries = [\"lib\"]\n",
+        );
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::copy(
+            "test/_my/lint/lib/greeting.lox",
+            dir.join("lib/greeting.lox"),
+        )
+        .unwrap();
+        fs::write(dir.join("main.lox"), "print greeting(\"you\");").unwrap();
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .current_dir(&dir)
+            .args(["lib/greeting.lox", "main.lox"])
+            .assert()
+            .success()
+            .stdout("Hello, you\nHello, you\n")
+            .stderr(
+                "[lib/greeting.lox line 2:3] Warning at 'print': Library files shouldn't print.\n",
+            );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_exit_codes_can_be_remapped() {
+        let dir = config_dir("exit-codes", "[exit-codes]\ndata-error = 2\nsoftware = 3\n");
+        fs::write(dir.join("syntax.lox"), "print;").unwrap();
+        fs::write(dir.join("runtime.lox"), "print -nil;").unwrap();
+        for (script, code) in [("syntax.lox", 2), ("runtime.lox", 3), ("missing.lox", 66)] {
+            Command::cargo_bin("rjlox")
+                .unwrap()
+                .current_dir(&dir)
+                .arg(script)
+                .assert()
+                .code(code);
         }
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .current_dir(&dir)
+            .arg("--no-such-flag")
+            .assert()
+            .code(64);
+        fs::remove_dir_all(dir).unwrap();
     }
 
-    pub fn run_prompt(&mut self) {
-        let mut rl = DefaultEditor::new().expect("Something went wrong with starting rustyline...");
-        loop {
-            let readline = rl.readline(">>> ");
-            match readline {
-                Ok(line) => {
-                    let _ = rl.add_history_entry(line.as_str());
-                    let _ = self.run_repl(&line);
-                }
-                Err(ReadlineError::Interrupted) => {
-                    println!("^C");
-                    break;
-                }
-                Err(ReadlineError::Eof) => {
-                    println!("^D");
-                    break;
-                }
-                Err(err) => {
-                    eprintln!("{}", format!("Error: {:?}", err).red());
-                    break;
-                }
+    #[test]
+    fn test_compiled_scripts_run_like_their_source() {
+        let dir = std::env::temp_dir().join(format!("rjlox-loxc-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let lib = dir.join("lib.loxc");
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["compile", "test/_my/modules/lib.lox", "-o"])
+            .arg(&lib)
+            .assert()
+            .success();
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .arg(&lib)
+            .arg("test/_my/modules/main.lox")
+            .assert()
+            .success()
+            .stdout("Hello, modules!\n3\n");
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .arg(&lib)
+            .arg("test/_my/modules/error.lox")
+            .assert()
+            .failure()
+            .code(70)
+            .stderr(
+                "[test/_my/modules/lib.lox line 6:12] RuntimeError: Operands must be numbers.\n",
+            );
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .arg("--trace")
+            .arg(&lib)
+            .assert()
+            .failure()
+            .code(64);
+        // Integers are saved as they are, not as doubles
+        let integers = dir.join("integers.loxc");
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["compile", "test/_my/integers/integers.lox", "-o"])
+            .arg(&integers)
+            .assert()
+            .success();
+        let source = fs::read_to_string("test/_my/integers/integers.lox").unwrap();
+        let Expected::Output(output) = Expected::parse(&source) else {
+            panic!("integers.lox only prints");
+        };
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .arg(&integers)
+            .assert()
+            .success()
+            .stdout(output);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_compiled_scripts() {
+        let dir = std::env::temp_dir().join(format!("rjlox-bad-loxc-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("source.loxc"), "print 1;").unwrap();
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .current_dir(&dir)
+            .arg("source.loxc")
+            .assert()
+            .failure()
+            .code(65)
+            .stderr("Error: Can't load 'source.loxc': not a compiled Lox script\n");
+        fs::write(dir.join("future.loxc"), b"LOXC\x04\x00\x00\x00").unwrap();
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .current_dir(&dir)
+            .arg("future.loxc")
+            .assert()
+            .failure()
+            .code(65)
+            .stderr(
+                "Error: Can't load 'future.loxc': compiled for version 4 of the format, \
+                this is version 3, compile it again\n",
+            );
+        let compiled = dir.join("main.loxc");
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .arg("compile")
+            .arg("test/_my/modules/main.lox")
+            .arg("--output")
+            .arg(&compiled)
+            .assert()
+            .success();
+        let bytes = fs::read(&compiled).unwrap();
+        fs::write(&compiled, &bytes[..bytes.len() - 1]).unwrap();
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .current_dir(&dir)
+            .arg("main.loxc")
+            .assert()
+            .failure()
+            .code(65)
+            .stderr("Error: Can't load 'main.loxc': the file is corrupted\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_config_file() {
+        let dir = config_dir("invalid", "colour = \"never\"\n");
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .current_dir(&dir)
+            .arg("main.lox")
+            .assert()
+            .failure()
+            .code(78);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_test_subcommand() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args([
+                "test",
+                "--exclude=test/closure/nested_closure.lox",
+                "test/closure",
+            ])
+            .assert()
+            .success()
+            .stdout("12 passed, 0 failed.\n");
+        let assert = Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["test", "test/_my/interpreter"])
+            .assert()
+            .failure()
+            .code(1);
+        let stdout = String::from_utf8_lossy(&assert.get_output().stdout);
+        assert!(
+            stdout.starts_with("FAIL test/_my/interpreter/"),
+            "{}",
+            stdout
+        );
+        assert!(stdout.ends_with(" passed, 5 failed.\n"), "{}", stdout);
+    }
+
+    #[test]
+    fn test_test_blocks() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["test", "test/_my/tests"])
+            .assert()
+            .failure()
+            .code(1)
+            .stdout(
+                "FAIL test/_my/tests/math.lox: subtraction is not addition\n\
+                 RuntimeError: Expected 2 but got 4.\n  \
+                 in assertEqual at test/_my/tests/math.lox:31\n\
+                 3 passed, 1 failed.\n",
+            );
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["test", "--filter=starts", "test/_my/tests"])
+            .assert()
+            .success()
+            .stdout("1 passed, 0 failed, 3 filtered out.\n");
+        // Scripts run as usual skip them
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .arg("test/_my/tests/math.lox")
+            .assert()
+            .success()
+            .stdout("");
+        let error = rjlox::Lox::new().run("{ test \"nested\" {} }").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "[line 1:8] Error at 'nested': Tests can only be declared at the top level."
+        );
+    }
+
+    #[rstest]
+    fn test_assertions(#[values(None, Some("--vm"))] flag: Option<&str>) {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(flag)
+            .arg("-")
+            .write_stdin("assert(1 < 2);\nassertEqual(\"a\" + \"b\", \"ab\");\nassert(nil);\n")
+            .assert()
+            .code(70)
+            .stderr("RuntimeError: Assertion failed.\n");
+    }
+
+    #[rstest]
+    fn test_cycles_are_collected(#[values(None, Some("--opt"), Some("--vm"))] flag: Option<&str>) {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(flag)
+            .arg("test/_my/gc/cycles.lox")
+            .assert()
+            .success()
+            .stdout("100000\n100001\n");
+    }
+
+    #[rstest]
+    fn test_streaming_scripts(#[values(None, Some("--vm"))] flag: Option<&str>) {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(flag)
+            .args(["--stream", "test/_my/gc/cycles.lox"])
+            .assert()
+            .success()
+            .stdout("100000\n100001\n");
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(flag)
+            .arg("-")
+            .write_stdin("var a = \"piped\";\nprint a;\nprint b;\n")
+            .assert()
+            .code(70)
+            .stdout("piped\n")
+            .stderr("[line 3:7] RuntimeError: Undefined variable 'b'.\n");
+    }
+
+    #[rstest]
+    #[case(&["test/_my/encoding/broken.lox"])]
+    #[case(&["--stream", "test/_my/encoding/broken.lox"])]
+    #[case(&["--vm", "test/_my/encoding/broken.lox"])]
+    #[case(&["-"])]
+    fn test_broken_utf8_reads_the_same_every_way(#[case] args: &[&str]) {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(args)
+            .write_stdin(fs::read("test/_my/encoding/broken.lox").unwrap())
+            .assert()
+            .success()
+            .stdout("a\u{fffd}b\n1\n");
+    }
+
+    #[test]
+    fn test_fmt_leaves_broken_utf8_alone() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["fmt", "--check", "test/_my/encoding/broken.lox"])
+            .assert()
+            .code(65)
+            .stderr("Error: Can't format 'test/_my/encoding/broken.lox': it isn't valid UTF-8.\n");
+    }
+
+    #[rstest]
+    fn test_reading_scripts_as_they_are_scanned(#[values(1, 2, 3, 4, 4096)] capacity: usize) {
+        let bytes = "print \"héllo wörld 😀\";\n// ünïcode\nvar π = 3.14;\n\"\u{ff}"
+            .bytes()
+            .chain([0xe2, 0x28, 0xa1, b';', 0xf0, 0x9f, 0x98])
+            .collect::<Vec<u8>>();
+        let source = String::from_utf8_lossy(&bytes);
+        let reader = std::io::BufReader::with_capacity(capacity, &bytes[..]);
+        assert_eq!(
+            rjlox::scanner::Scanner::from_reader(reader, None).scan_tokens(),
+            rjlox::scanner::Scanner::new(&source).scan_tokens()
+        );
+    }
+
+    #[rstest]
+    fn test_method_caches(#[values(None, Some("--opt"), Some("--vm"))] flag: Option<&str>) {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(flag)
+            .arg("test/_my/interpreter/method_cache.lox")
+            .assert()
+            .success()
+            .stdout(
+                "A says A\nA says B via B\nA says B via B\nA says A\n\
+                 A says field via B\nA says B via B\n",
+            );
+    }
+
+    #[test]
+    fn test_running_out_of_fuel() {
+        let interpreter = Rc::new(RefCell::new(Interpreter::with_config(&Config::default())));
+        let tokens = rjlox::scan("var i = 0;\nwhile (true) i = i + 1;").unwrap();
+        let statements = rjlox::parse(tokens).unwrap();
+        rjlox::resolve(&statements, &interpreter).unwrap();
+        interpreter.borrow_mut().set_fuel(10);
+        match rjlox::interpret(&statements, &interpreter) {
+            Err(e @ LoxError::OutOfFuel { .. }) => {
+                assert_eq!(e.to_string(), "[line 2:14] RuntimeError: Out of fuel.")
             }
+            other => panic!("Expected to run out of fuel, got {:?}", other),
         }
+        assert_eq!(interpreter.borrow().remaining_fuel(), Some(0));
+    }
+
+    #[test]
+    fn test_embedding() {
+        let mut lox = rjlox::Lox::new();
+        assert!(lox.run("var a = 1;").is_ok());
+        assert_eq!(lox.run("a = a + 1;").unwrap().to_string(), "2");
+        assert!(matches!(
+            lox.run("var b = ;"),
+            Err(LoxError::ParseError { .. })
+        ));
+        assert!(matches!(lox.run("b;"), Err(LoxError::RuntimeError { .. })));
+        assert!(matches!(
+            lox.run("a();"),
+            Err(LoxError::RuntimeError { .. })
+        ));
     }
 
-    pub fn run_repl(&mut self, source: &str) -> Result<(), i32> {
-        // scan tokens and print them
-        let mut scan = scanner::Scanner::new(source);
-        let raw_tokens = scan.scan_tokens();
-        debug!("-------- Scanner results ------");
-        for token in raw_tokens {
-            debug!("{:?}", token);
-            if let Err(e) = token {
-                eprintln!("{}", e.red());
+    #[rstest]
+    #[case("1 + 2;", "3")]
+    #[case("var a = \"a\"; a + \"b\";", "\"ab\"")]
+    #[case("1; 2; var c = 3;", "2")]
+    #[case("if (false) 1; else { 2; }", "2")]
+    #[case("fun f() { 1; } f();", "nil")]
+    #[case("fun f() { 1; } 2; f();", "nil")]
+    #[case("var d = 4;", "nil")]
+    fn test_eval_source(#[case] source: &str, #[case] value: &str) {
+        let mut interpreter = Interpreter::with_config(&Config::default());
+        let result = interpreter.eval_source(source).unwrap();
+        assert_eq!(result.to_string(), value);
+    }
+
+    /// A host type, which scripts see as an instance
+    #[derive(Debug)]
+    struct Account {
+        owner: String,
+        balance: RefCell<f64>,
+    }
+
+    impl LoxObject for Account {
+        fn class_name(&self) -> &str {
+            "Account"
+        }
+
+        fn get(&self, name: &str) -> Option<Value> {
+            match name {
+                "owner" => Some(Value::String(Rc::from(self.owner.as_str()))),
+                "balance" => Some(Value::Double(*self.balance.borrow())),
+                _ => None,
             }
         }
-        debug!("-------- Parser results (expr) ------");
-        let tokens = raw_tokens.iter().flatten().cloned().collect::<Vec<_>>();
-        let mut parser = Parser::new(tokens);
-        if let Ok(expr) = parser.parse_expr() {
-            let res = self.interpreter.borrow_mut().evaluate(&expr);
-            return match res {
-                Ok(val) => {
-                    println!("{}", val);
+
+        fn set(&self, name: &str, value: Value) -> Result<(), String> {
+            match (name, value.number()) {
+                ("balance", Some(n)) => {
+                    *self.balance.borrow_mut() = n;
                     Ok(())
                 }
-                Err(e) => {
-                    eprintln!("{}", e.red());
-                    Err(70)
+                ("balance", None) => Err("Balances are numbers.".to_string()),
+                _ => Err(format!("Can't set '{}' of accounts.", name)),
+            }
+        }
+
+        fn method_arity(&self, name: &str) -> Option<usize> {
+            (name == "deposit").then_some(1)
+        }
+
+        fn call_method(
+            &self,
+            _: &mut Interpreter,
+            _: &str,
+            arguments: &[Value],
+        ) -> Result<Value, String> {
+            let Some(amount) = arguments[0].number() else {
+                return Err("Can only deposit numbers.".to_string());
+            };
+            *self.balance.borrow_mut() += amount;
+            Ok(Value::Double(*self.balance.borrow()))
+        }
+    }
+
+    #[rstest]
+    #[case("account.deposit(5) + account.balance;", Ok("20"))]
+    #[case("account.owner + \" has \" + account.balance;", Ok("\"Ada has 5\""))]
+    #[case("account.balance = 1; account.balance;", Ok("1"))]
+    #[case("var deposit = account.deposit; deposit(2);", Ok("7"))]
+    #[case("account;", Ok("Account instance"))]
+    #[case("account == account;", Ok("true"))]
+    #[case(
+        "account.missing;",
+        Err("[line 1:9] RuntimeError: Undefined property 'missing'.")
+    )]
+    #[case(
+        "account.owner = \"Bob\";",
+        Err("[line 1:9] RuntimeError: Can't set 'owner' of accounts.")
+    )]
+    #[case(
+        "account.balance = nil;",
+        Err("[line 1:9] RuntimeError: Balances are numbers.")
+    )]
+    #[case(
+        "account.deposit(\"all\");",
+        Err("RuntimeError: Can only deposit numbers.")
+    )]
+    #[case(
+        "account.deposit();",
+        Err("[line 1:17] RuntimeError: Expected 1 arguments but got 0 in call to 'deposit'.")
+    )]
+    fn test_host_objects(#[case] source: &str, #[case] expected: Result<&str, &str>) {
+        let mut interpreter = Interpreter::with_config(&Config::default());
+        let account = Account {
+            owner: "Ada".to_string(),
+            balance: RefCell::new(5.0),
+        };
+        interpreter.define_global("account", Value::Foreign(Rc::new(account)));
+        let result = interpreter
+            .eval_source(source)
+            .map(|x| x.to_string())
+            .map_err(|e| e.to_string());
+        assert_eq!(result, expected.map(String::from).map_err(String::from));
+    }
+
+    #[test]
+    fn test_host_side_instances() {
+        let mut interpreter = Interpreter::with_config(&Config::default());
+        interpreter
+            .eval_source(
+                "class Point { init(x, y) { this.x = x; this.y = y; } }
+                 class Point3 < Point {}
+                 class Other {}
+                 fun sum(point) { return point.x + point.y + point.z; }",
+            )
+            .unwrap();
+        let class = |name| match interpreter.global(name) {
+            Some(Value::Callable(LoxCallable::LoxClass(class))) => class,
+            _ => panic!("{} should be a class", name),
+        };
+        let (point, point3, other) = (class("Point"), class("Point3"), class("Other"));
+        let instance = interpreter
+            .instantiate(&point3, vec![Value::Double(1.0), Value::Double(2.0)])
+            .unwrap();
+        assert!(instance.borrow().is_instance_of(&point));
+        assert!(instance.borrow().is_instance_of(&point3));
+        assert!(!instance.borrow().is_instance_of(&other));
+        assert!(!point.is_subclass_of(&point3));
+        let x = Symbol::intern("x");
+        assert_eq!(instance.borrow().field(&x), Some(Value::Double(1.0)));
+        instance
+            .borrow_mut()
+            .set_field(Symbol::intern("z"), Value::Double(3.0));
+        let sum = interpreter.global("sum").unwrap();
+        let result = interpreter.call_value(&sum, vec![Value::LoxInstance(instance)]);
+        assert_eq!(result, Ok(Value::Double(6.0)));
+
+        let error = interpreter.instantiate(&point, vec![]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "[<host> line 0:0] RuntimeError: Expected 2 arguments but got 0 in call to 'Point' declared at [line 1:15]."
+        );
+        let error = interpreter.call_value(&Value::None, vec![]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "[<host> line 0:0] RuntimeError: Can only call functions and classes."
+        );
+        assert_eq!(interpreter.global("missing"), None);
+    }
+
+    #[rstest]
+    #[case("print 1 + 2;\nprint \"a\";", "3\na\n", &[])]
+    #[case("print 1;\nprint nope;", "1\n", &["[line 2:7] RuntimeError: Undefined variable 'nope'."])]
+    #[case(
+        "print 1;\nprint 2 +;\nvar = 3;",
+        "",
+        &["[line 2:10] Error at ';': Expect expression.", "[line 3:5] Error at '=': Expect variable name. Last valid lexeme was 'var' at [line 3:1]."]
+    )]
+    #[case("fun f() { f(); }\nf();", "", &["[line 1:13] RuntimeError: Stack overflow."])]
+    fn test_playground(#[case] source: &str, #[case] stdout: &str, #[case] errors: &[&str]) {
+        let outcome = rjlox::playground::run(source);
+        assert_eq!(outcome.stdout, stdout);
+        assert_eq!(outcome.errors, errors);
+    }
+
+    #[test]
+    fn test_conversions_between_lox_and_rust() {
+        assert_eq!(Literal::from(1.5), Literal::Double(1.5));
+        assert_eq!(Literal::from("a"), Literal::String(Rc::from("a")));
+        assert_eq!(f64::try_from(Literal::Integer(2)), Ok(2.0));
+        assert_eq!(String::try_from(Literal::from("a")), Ok("a".to_string()));
+        assert_eq!(bool::try_from(Literal::from(true)), Ok(true));
+        assert_eq!(bool::try_from(Literal::None), Err(Literal::None));
+
+        assert_eq!(f64::try_from(Value::Integer(2)), Ok(2.0));
+        assert_eq!(String::try_from(Value::from("a")), Ok("a".to_string()));
+        assert_eq!(bool::try_from(Value::from(false)), Ok(false));
+        assert_eq!(f64::try_from(Value::from("a")), Err(Value::from("a")));
+
+        let mut interpreter = Interpreter::with_config(&Config::default());
+        let list = vec![Value::from(1.0), Value::from("two")];
+        let map = HashMap::from([
+            ("b".to_string(), Value::from(true)),
+            ("a".to_string(), list.clone().into()),
+        ]);
+        interpreter.define_global("list", list.into());
+        interpreter.define_global("map", map.into());
+        let result = interpreter
+            .eval_source("list.push(map.get(\"a\").length); map.set(\"c\", list); map.keys();")
+            .unwrap();
+        let keys: Vec<String> = Vec::try_from(result)
+            .unwrap()
+            .into_iter()
+            .map(|x| String::try_from(x).unwrap())
+            .collect();
+        assert_eq!(keys, ["a", "b", "c"]);
+        let map = HashMap::try_from(interpreter.global("map").unwrap()).unwrap();
+        let list = Vec::try_from(map["c"].clone()).unwrap();
+        assert_eq!(
+            list,
+            [Value::from(1.0), Value::from("two"), Value::Integer(2)]
+        );
+        assert!(Vec::try_from(map["b"].clone()).is_err());
+        assert!(HashMap::try_from(Value::None).is_err());
+    }
+
+    #[test]
+    fn test_natives_defined_by_the_host() {
+        let mut lox = rjlox::Lox::new();
+        let recorded = Rc::new(RefCell::new(Vec::new()));
+        let record = recorded.clone();
+        lox.interpreter()
+            .borrow_mut()
+            .define_native("record", 1, move |_, arguments| {
+                match arguments[0].number() {
+                    Some(n) => {
+                        record.borrow_mut().push(n);
+                        Ok(Value::None)
+                    }
+                    None => Err("Can only record numbers.".to_string()),
                 }
+            });
+        lox.run("record(1); record(1 + 1);").unwrap();
+        assert_eq!(*recorded.borrow(), vec![1.0, 2.0]);
+        let error = lox.run("record(\"three\");").unwrap_err();
+        assert_eq!(error.to_string(), "RuntimeError: Can only record numbers.");
+    }
+
+    #[test]
+    fn test_privileged_natives_follow_the_policy() {
+        let mut lox = rjlox::Lox::new();
+        lox.interpreter()
+            .borrow_mut()
+            .define_native("home", 0, |interpreter, _| {
+                interpreter.policy().require(Capability::Environment)?;
+                Ok(Value::String(Rc::from("/home/lox")))
+            });
+        assert_eq!(lox.run("home();").unwrap().to_string(), "\"/home/lox\"");
+        lox.interpreter()
+            .borrow_mut()
+            .set_policy(SandboxPolicy::sandboxed());
+        let error = lox.run("home();").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "RuntimeError: The sandbox doesn't allow access to the environment."
+        );
+        assert_eq!(
+            lox.interpreter().borrow().limits.max_steps,
+            Some(10_000_000)
+        );
+    }
+
+    #[test]
+    fn test_sandbox_caps_the_limits() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["--sandbox", "--max-output-bytes=1000000000"])
+            .arg("test/_my/sandbox/output.lox")
+            .assert()
+            .failure()
+            .code(70)
+            .stdout("")
+            .stderr("[line 3:1] RuntimeError: Output limit exceeded.\n");
+        let dir = config_dir("sandbox", "[sandbox]\nmax-steps = 3000\n");
+        let script = fs::canonicalize("test/_my/limits/runaway.lox").unwrap();
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .current_dir(&dir)
+            .arg("--max-steps=1000000")
+            .arg(&script)
+            .assert()
+            .failure()
+            .code(70)
+            .stderr("[line 10:7] RuntimeError: Execution limit exceeded.\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[rstest]
+    #[case(None, "sleep(1);")]
+    #[case(Some("--vm"), "sleep(1);")]
+    #[case(None, "fun f() {} setTimeout(f, 1);")]
+    #[case(None, "fun f() {} setInterval(f, 1);")]
+    fn test_sandbox_doesnt_wait(#[case] flag: Option<&str>, #[case] source: &str) {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(flag)
+            .args(["--sandbox", "-"])
+            .write_stdin(source)
+            .assert()
+            .code(70)
+            .stderr("RuntimeError: The sandbox doesn't allow waiting.\n");
+    }
+
+    #[test]
+    fn test_playground_stops_at_the_deadline() {
+        let outcome = rjlox::playground::run_until("print 1;\nwhile (true) {}", Instant::now());
+        assert_eq!(outcome.stdout, "1\n");
+        assert_eq!(
+            outcome.errors,
+            ["[line 2:1] RuntimeError: Time limit exceeded."]
+        );
+        let outcome = rjlox::playground::run("print 1;");
+        assert_eq!(outcome.errors, Vec::<String>::new());
+    }
+
+    /// Output shared with the test, for it to look at after the script ran
+    #[derive(Clone, Default)]
+    struct Captured(Rc<RefCell<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_output_can_be_captured() {
+        let mut lox = rjlox::Lox::new();
+        let (stdout, stderr) = (Captured::default(), Captured::default());
+        {
+            let mut interpreter = lox.interpreter().borrow_mut();
+            interpreter.stdout = Box::new(stdout.clone());
+            interpreter.stderr = Box::new(stderr.clone());
+            interpreter.trace = true;
+        }
+        lox.run("print 1 + 2;\nprint \"a\";").unwrap();
+        assert_eq!(*stdout.0.borrow(), b"3\na\n");
+        assert_eq!(
+            String::from_utf8_lossy(&stderr.0.borrow()),
+            "[trace] <script>:1 (print (+ 1 2))\n[trace] <script>:2 (print \"a\")\n"
+        );
+    }
+
+    #[test]
+    fn test_snapshots_roll_back_globals() {
+        let interpreter = Rc::new(RefCell::new(Interpreter::with_config(&Config::default())));
+        let run = |source: &str| {
+            let statements = rjlox::parse(rjlox::scan(source).unwrap()).unwrap();
+            rjlox::resolve(&statements, &interpreter).unwrap();
+            rjlox::interpret(&statements, &interpreter).unwrap();
+        };
+        let eval = |source: &str| {
+            let tokens = rjlox::scan(source).unwrap();
+            let expr = rjlox::parser::Parser::new(tokens).parse_expr().unwrap();
+            let value = interpreter.borrow_mut().evaluate(&expr);
+            value.map(|x| x.to_string()).map_err(|x| x.to_string())
+        };
+        run("class P {} var p = P(); p.x = 1; p.me = p; var n = 1; fun get() { return n; }");
+        let snapshot = interpreter.borrow_mut().snapshot();
+        run("p.x = 2; p.me = nil; n = 2; var added = true;");
+        assert_eq!(eval("get()"), Ok("2".into()));
+        interpreter.borrow_mut().restore(&snapshot);
+        assert_eq!(eval("p.me.me.x"), Ok("1".into()));
+        assert_eq!(eval("get()"), Ok("1".into()));
+        assert_eq!(
+            eval("added"),
+            Err("[line 1:1] RuntimeError: Undefined variable 'added'.".into())
+        );
+        // Restoring copies the instances again, leaving the snapshot as it was
+        run("p.x = 3;");
+        interpreter.borrow_mut().restore(&snapshot);
+        assert_eq!(eval("p.x"), Ok("1".into()));
+    }
+
+    /// Enough garbage for the collector to run
+    const CHURN: &str =
+        "class Junk {} for (var i = 0; i < 5000; i = i + 1) { var j = Junk(); j.me = j; }";
+
+    #[test]
+    fn test_pinned_values_outlive_collections() {
+        let mut interpreter = Interpreter::with_config(&Config::default());
+        interpreter
+            .eval_source("class P { init(x) { this.x = x; } } var p = P(1);")
+            .unwrap();
+        let class = match interpreter.global("P") {
+            Some(Value::Callable(LoxCallable::LoxClass(class))) => class,
+            _ => panic!("P should be a class"),
+        };
+        let global = interpreter.pin(interpreter.global("p").unwrap());
+        let made = interpreter
+            .instantiate(&class, vec![Value::Double(2.0)])
+            .unwrap();
+        let made = interpreter.pin(Value::LoxInstance(made));
+        interpreter.eval_source("p = nil;").unwrap();
+        interpreter.eval_source(CHURN).unwrap();
+        let x = Symbol::intern("x");
+        for (pinned, x_value) in [(&global, 1.0), (&made, 2.0)] {
+            let Value::LoxInstance(instance) = &**pinned else {
+                panic!("{:?} should be an instance", pinned);
             };
+            assert_eq!(instance.borrow().field(&x), Some(Value::Double(x_value)));
         }
-        Err(65)
     }
 
-    pub fn run(&mut self, source: &str) -> Result<(), i32> {
-        let mut res: Result<(), i32> = Ok(());
-        // scan tokens and print them
-        let mut scan = scanner::Scanner::new(source);
-        let raw_tokens = scan.scan_tokens();
-        debug!("-------- Scanner results ------");
-        for token in raw_tokens {
-            debug!("{:?}", token);
-            if let Err(e) = token {
-                eprintln!("{}", e.red());
-                res = Err(65);
+    #[test]
+    fn test_snapshots_keep_closures_alive() {
+        let mut interpreter = Interpreter::with_config(&Config::default());
+        interpreter
+            .eval_source(
+                "fun make() { var n = 1; fun get() { return n; } return get; } var get = make();",
+            )
+            .unwrap();
+        let snapshot = interpreter.snapshot();
+        interpreter.eval_source("get = nil;").unwrap();
+        interpreter.eval_source(CHURN).unwrap();
+        interpreter.restore(&snapshot);
+        assert_eq!(interpreter.eval_source("get();"), Ok(Value::Double(1.0)));
+    }
+
+    #[test]
+    fn test_incremental_edits() {
+        use rjlox::incremental::{Document, Edit, Position};
+        let at = |line, column| Position { line, column };
+        let mut document = Document::new("var a = 1;\nprint a;\nif (a) print a;\nprint a + 2;\n");
+        let edits = [
+            // Within a line, only the statement it is in is parsed again
+            (at(2, 7), at(2, 8), "(a)", Some(0..2)),
+            // The statements after it move down
+            (
+                at(1, 11),
+                at(1, 11),
+                "\nvar b = \"two\nlines\";",
+                Some(0..2),
+            ),
+            // An `else` joins the statement before
+            (at(5, 16), at(5, 16), " else print b;", Some(2..4)),
+            // Breaking a statement parses everything, as does fixing it after
+            (at(6, 11), at(6, 12), "", None),
+            (at(6, 11), at(6, 11), "2", None),
+        ];
+        for (start, end, text, statements) in edits {
+            let edit = Edit {
+                start,
+                end,
+                text: text.into(),
+            };
+            assert_eq!(document.edit(&edit).unwrap().statements, statements);
+            let fresh = Document::new(document.source());
+            assert_eq!(document.tokens(), fresh.tokens());
+            let shown = |x: &Document| {
+                x.statements()
+                    .map(|x| x.iter().map(|x| x.to_string()).collect::<Vec<_>>())
+                    .map_err(|x| x.iter().map(|x| x.to_string()).collect::<Vec<_>>())
+            };
+            assert_eq!(shown(&document), shown(&fresh));
+        }
+        assert_eq!(
+            document.source(),
+            "var a = 1;\nvar b = \"two\nlines\";\nprint (a);\nif (a) print a; else print b;\nprint a + 2;\n"
+        );
+        let edit = Edit {
+            start: at(9, 1),
+            end: at(9, 1),
+            text: "".into(),
+        };
+        assert_eq!(document.edit(&edit), None);
+
+        // Scanning again doesn't start from an error in the middle of a string
+        let mut document = Document::new("var a = \"one\ntwo \\x\";\n// ...\n");
+        let edit = Edit {
+            start: at(3, 4),
+            end: at(3, 7),
+            text: "done".into(),
+        };
+        document.edit(&edit).unwrap();
+        let fresh = Document::new(document.source());
+        assert_eq!(document.tokens(), fresh.tokens());
+        assert_eq!(
+            document.statements().unwrap_err()[0].to_string(),
+            "[line 2:5] Error: Invalid escape sequence."
+        );
+    }
+
+    /// Polls `future` on this thread until it is done, the least an async
+    /// runtime does
+    #[cfg(feature = "async")]
+    fn block_on<T>(future: impl std::future::Future<Output = T>) -> T {
+        use std::sync::Arc;
+        use std::task::{Context, Poll, Wake, Waker};
+        struct Unpark(std::thread::Thread);
+        impl Wake for Unpark {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+        let waker = Waker::from(Arc::new(Unpark(std::thread::current())));
+        let mut context = Context::from_waker(&waker);
+        let mut future = std::pin::pin!(future);
+        loop {
+            match future.as_mut().poll(&mut context) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => std::thread::park(),
             }
         }
-        debug!("-------- Parser results (stmt) ------");
-        let tokens = raw_tokens.iter().flatten().cloned().collect::<Vec<_>>();
-        let mut parser = Parser::new(tokens);
-        let parsed: Result<Vec<Stmt>, String> = parser.parse();
+    }
 
-        if let Err(e) = &parsed {
-            eprintln!("{}", e.red());
-            return Err(65);
+    /// Pending the first time it is polled, like I/O that has to wait
+    #[cfg(feature = "async")]
+    struct Later(bool);
+
+    #[cfg(feature = "async")]
+    impl std::future::Future for Later {
+        type Output = ();
+
+        fn poll(
+            mut self: std::pin::Pin<&mut Self>,
+            context: &mut std::task::Context,
+        ) -> std::task::Poll<()> {
+            if self.0 {
+                return std::task::Poll::Ready(());
+            }
+            self.0 = true;
+            context.waker().wake_by_ref();
+            std::task::Poll::Pending
         }
+    }
 
-        let statements: Vec<Stmt> = parsed.unwrap_or_default();
-        for x in &statements {
-            debug!("{}", x);
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_async_natives() {
+        let mut interpreter = Interpreter::with_config(&Config::default());
+        interpreter.define_async_native("fetch", 1, |arguments| {
+            let key = arguments[0].clone();
+            Box::pin(async move {
+                Later(false).await;
+                match key {
+                    Value::String(key) => Ok(Value::String(format!("{}'s value", key).into())),
+                    _ => Err("Keys must be strings.".to_string()),
+                }
+            })
+        });
+        let mut run = |source: &str, awaiting: bool| {
+            let statements = rjlox::parse(rjlox::scan(source).unwrap()).unwrap();
+            Resolver::new(&mut interpreter)
+                .resolve(&statements)
+                .unwrap();
+            let ran = if awaiting {
+                block_on(interpreter.run_async(&statements))
+            } else {
+                interpreter.interpret(&statements)
+            };
+            ran.map(|x| x.unwrap_or(Value::None).to_string())
+                .map_err(|e| e.to_string())
+        };
+        assert_eq!(
+            run(
+                "fun get(key) { return fetch(key) + \"!\"; }\nget(\"a\") + get(\"b\");",
+                true
+            ),
+            Ok("\"a's value!b's value!\"".to_string())
+        );
+        assert_eq!(
+            run("get(1);", true),
+            Err("RuntimeError: Keys must be strings.".to_string())
+        );
+        assert_eq!(
+            run("fetch(\"a\");", false),
+            Err("[line 1:10] RuntimeError: 'fetch' is async and can only be called by scripts run with run_async.".to_string())
+        );
+        assert_eq!(run("1 + 2;", true), Ok("3".to_string()));
+        // Given up on while waiting, as when a request times out
+        let statements = rjlox::parse(rjlox::scan("fetch(\"c\");").unwrap()).unwrap();
+        Resolver::new(&mut interpreter)
+            .resolve(&statements)
+            .unwrap();
+        let mut context = std::task::Context::from_waker(std::task::Waker::noop());
+        let pending = std::future::Future::poll(
+            std::pin::pin!(interpreter.run_async(&statements)),
+            &mut context,
+        )
+        .is_pending();
+        assert!(pending);
+        assert_eq!(
+            interpreter.eval_source("1 + 2;").unwrap(),
+            Value::Double(3.0)
+        );
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn test_runtime_shared_between_threads() {
+        use rjlox::runtime::LoxRuntime;
+        use std::sync::Arc;
+        let runtime = Arc::new(LoxRuntime::with_setup(&Config::default(), |interpreter| {
+            interpreter.define_global("greeting", Value::String(Rc::from("Hello")));
+        }));
+        runtime
+            .run("var calls = 0; fun greet(name) { calls = calls + 1; return greeting + \", \" + name; }")
+            .unwrap();
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let runtime = runtime.clone();
+                std::thread::spawn(move || {
+                    runtime.call("greet", vec![Value::String(Rc::from(format!("#{}", i)))])
+                })
+            })
+            .collect();
+        for (i, thread) in threads.into_iter().enumerate() {
+            let greeting = Value::String(Rc::from(format!("Hello, #{}", i)));
+            assert_eq!(thread.join().unwrap(), Ok(greeting));
         }
+        assert_eq!(runtime.run("calls;"), Ok(Value::Double(4.0)));
+        assert_eq!(
+            runtime.call("missing", vec![]).unwrap_err().to_string(),
+            "RuntimeError: Undefined variable 'missing'."
+        );
 
-        debug!("-------- Resolver results ------");
-        let mut resolver = Resolver::new(self.interpreter.clone());
-        if let Err(e) = resolver.resolve(&statements) {
-            eprintln!("{}", e.red());
-            return Err(65);
+        let looping = runtime.clone();
+        let thread = std::thread::spawn(move || looping.run("var i = 0;\nwhile (true) i = i + 1;"));
+        // Until the loop has started, as interrupting before has no effect
+        while !thread.is_finished() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            runtime.interrupt();
         }
-        debug!("-------- Interpreter results ------");
-        if let Err(e) = self.interpreter.borrow_mut().interpret(&statements) {
-            eprintln!("{}", e.red());
-            return Err(70);
+        // Wherever in the loop it was at the time
+        let error = thread.join().unwrap().unwrap_err().to_string();
+        assert!(error.starts_with("[line 2:"), "{}", error);
+        assert!(error.ends_with("RuntimeError: Interrupted."), "{}", error);
+        assert_eq!(runtime.run("calls;"), Ok(Value::Double(4.0)));
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn test_interpreter_moves_between_threads() {
+        let interpreter = Rc::new(RefCell::new(Interpreter::with_config(&Config::default())));
+        let tokens = rjlox::scan("var i = 0;\nwhile (i < 10) i = i + 1;").unwrap();
+        let statements = rjlox::parse(tokens).unwrap();
+        rjlox::resolve(&statements, &interpreter).unwrap();
+        interpreter.borrow_mut().set_fuel(1000);
+        let shared = Rc::clone(&interpreter);
+        std::thread::spawn(move || rjlox::interpret(&statements, &shared))
+            .join()
+            .unwrap()
+            .unwrap();
+        assert!(interpreter.borrow().remaining_fuel() < Some(1000));
+    }
+
+    #[cfg(all(feature = "extensions", unix))]
+    #[test]
+    fn test_extensions_have_to_be_allowed() {
+        let run = |args: &[&str]| {
+            let output = Command::cargo_bin("rjlox")
+                .unwrap()
+                .args(args)
+                .arg("test/_my/extensions/missing.lox")
+                .output()
+                .unwrap();
+            String::from_utf8(output.stderr).unwrap()
         };
-        res
+        assert_eq!(
+            run(&[]),
+            "RuntimeError: The sandbox doesn't allow loading native extensions.\n"
+        );
+        assert!(run(&["--allow-extensions"])
+            .starts_with("RuntimeError: Can't load extension 'nope.so': "));
     }
-}
 
-fn main() {
-    env_logger::init();
-    let args: Vec<String> = env::args().collect();
-    let mut lox = Lox::new();
-    match args.len() {
-        1 => lox.run_prompt(),
-        2 => exit(lox.run_file(&args[1])),
-        _ => {
-            println!("Usage: rjlox [script]");
-            exit(64);
+    #[test]
+    fn test_leak_check_reports_cycles() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["--leak-check", "test/_my/gc/leaks.lox"])
+            .assert()
+            .success()
+            .stderr(
+                "[leak-check] Reference cycle between 2 objects:\n  \
+                 Node instance made at [line 16:15]\n  \
+                 Node instance made at [line 17:15]\n\
+                 [leak-check] Reference cycle between 2 objects:\n  \
+                 Node instance made at [line 20:15]\n  \
+                 environment\n\
+                 [leak-check] Object referring to itself:\n  \
+                 environment closed over by 'increment' at [line 9:7]\n\
+                 [leak-check] 3 cycles found.\n",
+            );
+    }
+
+    #[test]
+    fn test_coverage() {
+        let lcov = std::env::temp_dir().join(format!("rjlox-{}.info", std::process::id()));
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["--coverage", "--lcov"])
+            .arg(&lcov)
+            .arg("test/_my/coverage/counter.lox")
+            .assert()
+            .success()
+            .stdout("1\n3\n")
+            .stderr(
+                "[coverage] test/_my/coverage/counter.lox: 12/14 lines, 5/8 branches\n     \
+                 1  | fun sign(n) {\n     \
+                 1! |   if (n < 0) {\n \
+                 #####  |     return -1;\n     \
+                 1! |   } else if (n == 0) {\n \
+                 #####  |     return 0;\n        \
+                 |   }\n     \
+                 1  |   return 1;\n        \
+                 | }\n        \
+                 | \n     \
+                 1  | class Counter {\n        \
+                 |   init() {\n     \
+                 1  |     this.count = 0;\n        \
+                 |   }\n        \
+                 | \n        \
+                 |   add(n) {\n     \
+                 5  |     for (var i = 0; i < n; i = i + 1) this.count = this.count + 1;\n        \
+                 |   }\n        \
+                 | }\n        \
+                 | \n     \
+                 1  | print sign(5);\n     \
+                 1  | var counter = Counter();\n     \
+                 1  | counter.add(3);\n     \
+                 1  | print counter.count;\n     \
+                 1! | while (false) print \"never\";\n",
+            );
+        let report = fs::read_to_string(&lcov).unwrap();
+        fs::remove_file(lcov).unwrap();
+        assert_eq!(
+            report,
+            "TN:\nSF:test/_my/coverage/counter.lox\n\
+             BRDA:2,0,0,0\nBRDA:2,0,1,1\nBRDA:4,0,0,0\nBRDA:4,0,1,1\n\
+             BRDA:16,0,0,3\nBRDA:16,0,1,1\nBRDA:24,0,0,0\nBRDA:24,0,1,1\n\
+             BRF:8\nBRH:5\n\
+             DA:1,1\nDA:2,1\nDA:3,0\nDA:4,1\nDA:5,0\nDA:7,1\nDA:10,1\nDA:12,1\n\
+             DA:16,5\nDA:20,1\nDA:21,1\nDA:22,1\nDA:23,1\nDA:24,1\n\
+             LF:14\nLH:12\nend_of_record\n"
+        );
+    }
+
+    #[rstest]
+    #[case(&["--version"], 0)]
+    #[case(&["--help"], 0)]
+    #[case(&["--no-such-flag"], 64)]
+    #[case(&["--max-steps=many", "a.lox"], 64)]
+    #[case(&["fmt"], 64)]
+    #[case(&["no/such/file.lox"], 66)]
+    #[case(&["check", "test/_my/modules/lib.lox", "test/_my/modules/main.lox"], 0)]
+    #[case(&["check", "test/unexpected_character.lox"], 65)]
+    #[case(&["run", "test/_my/modules/main.lox"], 70)]
+    #[case(&["--vm", "--coverage", "test/_my/coverage/counter.lox"], 64)]
+    fn test_exit_codes(#[case] args: &[&str], #[case] code: i32) {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(args)
+            .assert()
+            .code(code);
+    }
+
+    #[test]
+    fn test_ast_and_tokens_subcommands() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["ast", "test/_my/modules/main.lox"])
+            .assert()
+            .success()
+            .stdout("(print (call greet (\"modules\")))\n(print (call add (1 2)))\n");
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["tokens", "test/_my/modules/error.lox"])
+            .assert()
+            .success()
+            .stdout(
+                "1:1 Identifier add\n1:4 LeftParen (\n1:5 String one\n1:10 Comma ,\n\
+                 1:12 Number 2\n1:13 RightParen )\n1:14 Semicolon ;\n2:1 Eof \n",
+            );
+    }
+
+    #[test]
+    fn test_highlight_subcommand() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["highlight", "test/_my/modules/main.lox"])
+            .assert()
+            .success()
+            .stdout(
+                "\x1b[35mprint\x1b[0m greet(\x1b[32m\"modules\"\x1b[0m);\n\
+                 \x1b[35mprint\x1b[0m add(\x1b[33m1\x1b[0m, \x1b[33m2\x1b[0m);\n",
+            );
+        let assert = Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["highlight", "--format=html", "test/_my/modules/lib.lox"])
+            .assert()
+            .success();
+        let stdout = String::from_utf8_lossy(&assert.get_output().stdout);
+        assert!(
+            stdout.starts_with(
+                "<pre class=\"lox\"><code><span class=\"lox-keyword\">fun</span> \
+                 <span class=\"lox-identifier\">greet</span>"
+            ),
+            "{}",
+            stdout
+        );
+        assert!(stdout.ends_with("<span class=\"lox-punctuation\">}</span>\n</code></pre>\n"));
+    }
+
+    #[test]
+    fn test_highlighting_goes_by_bytes_and_keeps_errors() {
+        use rjlox::highlight::{highlight, TokenKind};
+        let source = "var é = \"ü\"; // ok\n@ \"open";
+        let parts = highlight(source)
+            .into_iter()
+            .map(|(span, kind)| (&source[span.start..span.end], kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            parts,
+            [
+                ("var", TokenKind::Keyword),
+                ("é", TokenKind::Identifier),
+                ("=", TokenKind::Operator),
+                ("\"ü\"", TokenKind::String),
+                (";", TokenKind::Punctuation),
+                ("// ok", TokenKind::Comment),
+                ("@", TokenKind::Error),
+                ("\"open", TokenKind::Error),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_run_markdown() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["run-md", "test/_my/notebook/tutorial.md"])
+            .assert()
+            .success()
+            .stderr("")
+            .stdout(
+                "# Counting\n\n\
+                 Variables declared in one block stay around for the next ones.\n\n\
+                 ```lox\nvar count = 0;\nfun increment() {\n  count = count + 1;\n  \
+                 return count;\n}\nprint increment();\n```\n```text\n1\n```\n\n\
+                 Blocks in other languages are left alone:\n\n\
+                 ```js\nconsole.log(\"not run\");\n```\n\n\
+                 So are blocks that print nothing:\n\n\
+                 ```lox\nincrement();\n```\n\n\
+                 ~~~lox\nprint count;\n~~~\n```text\n2\n```\n",
+            );
+    }
+
+    #[test]
+    fn test_run_markdown_stops_at_errors_in_the_lines_of_the_document() {
+        let assert = Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["run-md", "test/_my/notebook/error.md"])
+            .assert()
+            .code(70);
+        let output = assert.get_output();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.ends_with("print nope;\n```\n```text\nbefore\n```\n"));
+        assert!(!stdout.contains("never"));
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("[line 5:7] RuntimeError: Undefined variable 'nope'."));
+    }
+
+    #[test]
+    fn test_transpile_to_javascript() {
+        let assert = Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["transpile", "test/_my/transpile/counter.lox"])
+            .assert()
+            .success();
+        let stdout = String::from_utf8_lossy(&assert.get_output().stdout);
+        assert!(stdout.starts_with("function $truthy(value) {\n"));
+        for expected in [
+            "class Loud extends Counter {\n  increment() {\n    console.log(\"up\");\n    \
+             return super.increment();\n  }\n}\n",
+            "  init(start) {\n    this.count = start;\n    return this;\n  }\n",
+            "function makeAdder(n) {\n  let add = (x) => {\n    return x + n;\n  };\n",
+            // Declared after the closure, so the closure keeps the global
+            "    console.log($str(count));\n  };\n\n  let count$1 = \"local\";\n",
+            "var counter = $new(Loud, 1);\n",
+            "{\n  let i = 0;\n  for (; i < 2; i = i + 1) {\n    \
+             console.log($str($call(increment)));\n  }\n}\n",
+            "console.log($str($or(null, () => \"default\")));\n",
+        ] {
+            assert!(stdout.contains(expected), "{} not in {}", expected, stdout);
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use assert_cmd::Command;
-    use regex::Regex;
-    use rstest::*;
-    use std::fs;
-    use std::path::{Path, PathBuf};
+    #[test]
+    fn test_transpile_comma() {
+        let assert = Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["transpile", "test/_my/transpile/comma.lox"])
+            .assert()
+            .success();
+        let stdout = String::from_utf8_lossy(&assert.get_output().stdout);
+        assert!(stdout.ends_with("var i = 10;\nconsole.log($str((i, 3)));\n"));
+    }
 
-    fn expected(path: &Path) -> String {
-        let contents = fs::read_to_string(path).unwrap();
+    #[test]
+    fn test_transpile_binds_methods_when_read() {
+        let assert = Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["transpile", "test/operator/equals_method.lox"])
+            .assert()
+            .success();
+        let stdout = String::from_utf8_lossy(&assert.get_output().stdout);
+        assert!(stdout.ends_with(
+            "var fooMethod = $get(foo, \"method\");\n\
+             console.log($str(fooMethod === fooMethod));\n\
+             console.log($str($get(foo, \"method\") === $get(foo, \"method\")));\n"
+        ));
+    }
 
-        let expected: String = contents
-            .lines()
-            .filter(|line| line.contains("// expect: "))
-            .map(|line| line.split("// expect: ").nth(1).unwrap().to_string() + "\n")
-            .collect();
-        expected
-    }
-    fn expected_runtime_error(path: &Path) -> String {
-        let contents = fs::read_to_string(path).unwrap();
-
-        let expected: String = contents
-            .lines()
-            .filter(|line| line.contains("// expect runtime error: "))
-            .map(|line| {
-                line.split("// expect runtime error: ")
-                    .nth(1)
-                    .unwrap()
-                    .to_string()
-                    + "\n"
+    #[test]
+    fn test_doc_subcommand() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["doc", "test/_my/doc/shapes.lox"])
+            .assert()
+            .success()
+            .stdout(
+                "# test/_my/doc/shapes.lox\n\n\
+                 ## `class Shape`\n\nSomething with an area.\n\n\
+                 ### `area()`\n\nHow much of the plane it covers.\n\n\
+                 ## `class Square < Shape`\n\nA square, with sides of `side`.\n\n\
+                 Its corners are all right angles.\n\n\
+                 ### `init(side)`\n\n### `area()`\n\n\
+                 ## `fun total(first, second)`\n",
+            );
+        let assert = Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["doc", "--format=html", "test/_my/doc/shapes.lox"])
+            .assert()
+            .success();
+        let stdout = String::from_utf8_lossy(&assert.get_output().stdout);
+        assert!(
+            stdout.contains(
+                "<h2><code>class Square &lt; Shape</code></h2>\n\
+                 <p>A square, with sides of `side`.</p>\n\
+                 <p>Its corners are all right angles.</p>\n"
+            ),
+            "{}",
+            stdout
+        );
+        // The docs are part of the syntax tree
+        let output = Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["ast", "--format=json", "test/_my/doc/shapes.lox"])
+            .output()
+            .unwrap();
+        let statements: Vec<Stmt> = serde_json::from_slice(&output.stdout).unwrap();
+        let Stmt::Class(_, _, methods, doc) = &statements[0] else {
+            panic!("Expected a class, got {}", statements[0]);
+        };
+        assert_eq!(doc.as_deref(), Some("Something with an area."));
+        let Stmt::Function(_, _, _, doc) = &methods[0] else {
+            panic!("Expected a method, got {}", methods[0]);
+        };
+        assert_eq!(doc.as_deref(), Some("How much of the plane it covers."));
+    }
+
+    #[test]
+    fn test_ast_as_a_graph() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["ast", "--format=dot", "test/_my/modules/main.lox"])
+            .assert()
+            .success()
+            .stdout(
+                "digraph ast {\n  node [fontname=\"monospace\"];\n  \
+                 n0 [label=\"script\", shape=box];\n  \
+                 n1 [label=\"print\", shape=box];\n  \
+                 n2 [label=\"call\", shape=ellipse];\n  \
+                 n3 [label=\"greet\", shape=ellipse];\n  \
+                 n2 -> n3 [label=\"callee\"];\n  \
+                 n4 [label=\"\\\"modules\\\"\", shape=ellipse];\n  \
+                 n2 -> n4;\n  n1 -> n2;\n  n0 -> n1;\n  \
+                 n5 [label=\"print\", shape=box];\n  \
+                 n6 [label=\"call\", shape=ellipse];\n  \
+                 n7 [label=\"add\", shape=ellipse];\n  \
+                 n6 -> n7 [label=\"callee\"];\n  \
+                 n8 [label=\"1\", shape=ellipse];\n  n6 -> n8;\n  \
+                 n9 [label=\"2\", shape=ellipse];\n  n6 -> n9;\n  \
+                 n5 -> n6;\n  n0 -> n5;\n}\n",
+            );
+    }
+
+    #[test]
+    fn test_call_graph() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["call-graph", "test/_my/calls/program.lox"])
+            .assert()
+            .success()
+            .stdout(
+                "digraph calls {\n  node [fontname=\"monospace\", shape=box];\n  \
+                 n0 [label=\"<script>\"];\n  \
+                 n1 [label=\"fib\", peripheries=2];\n  \
+                 n2 [label=\"isEven\", peripheries=2];\n  \
+                 n3 [label=\"isOdd\", peripheries=2];\n  \
+                 n4 [label=\"unused\", style=dashed];\n  \
+                 n5 [label=\"Counter.init\"];\n  \
+                 n6 [label=\"Counter.reset\"];\n  \
+                 n7 [label=\"Counter.add\"];\n  \
+                 n8 [label=\"main\"];\n  \
+                 n9 [label=\"test \\\"parity\\\"\"];\n  \
+                 n1 -> n1;\n  n2 -> n3;\n  n3 -> n2;\n  n4 -> n1;\n  n5 -> n6;\n  \
+                 n8 -> n5;\n  n8 -> n7;\n  n8 -> n1;\n  n0 -> n8;\n  n9 -> n2;\n}\n",
+            );
+        let output = Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["call-graph", "--format=json", "test/_my/calls/program.lox"])
+            .output()
+            .unwrap();
+        let graph: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+        assert_eq!(
+            graph["functions"][4],
+            serde_json::json!({
+                "name": "unused",
+                "line": 16,
+                "root": false,
+                "reachable": false,
+                "recursive": false
             })
-            .collect();
-        expected
+        );
+        assert_eq!(
+            graph["calls"][0],
+            serde_json::json!({ "from": "fib", "to": "fib" })
+        );
+    }
+
+    #[test]
+    fn test_call_graph_follows_scopes_and_classes() {
+        let source = "fun helper() {}\n\
+                      fun outer(helper) {\n  \
+                        fun inner() { return helper(); }\n  \
+                        return inner();\n\
+                      }\n\
+                      class A { name() { return \"A\"; } }\n\
+                      class B < A { name() { return super.name() + \"B\"; } }\n\
+                      print B().name();\n\
+                      outer(nil);";
+        let statements = rjlox::parse(rjlox::scan(source).unwrap()).unwrap();
+        let graph = rjlox::callgraph::CallGraph::new(&statements);
+        let calls = graph
+            .calls
+            .iter()
+            .map(|&(from, to)| (&*graph.functions[from].name, &*graph.functions[to].name))
+            .collect::<Vec<_>>();
+        // The parameter shadows the function, and `name` could be either method
+        assert_eq!(
+            calls,
+            [
+                ("outer", "outer.inner"),
+                ("B.name", "A.name"),
+                ("<script>", "A.name"),
+                ("<script>", "B.name"),
+                ("<script>", "outer"),
+            ]
+        );
+        assert!(!graph.functions[1].reachable);
+    }
+
+    #[test]
+    fn test_syntax_trees_load_back_from_json() {
+        let mut interpreter = Interpreter::with_config(&Config::default());
+        let stdout = Captured::default();
+        interpreter.stdout = Box::new(stdout.clone());
+        for file in ["test/_my/modules/lib.lox", "test/_my/modules/main.lox"] {
+            let output = Command::cargo_bin("rjlox")
+                .unwrap()
+                .args(["ast", "--format", "json", file])
+                .output()
+                .unwrap();
+            assert!(output.status.success());
+            let statements: Vec<Stmt> = serde_json::from_slice(&output.stdout).unwrap();
+            Resolver::new(&mut interpreter)
+                .resolve(&statements)
+                .unwrap();
+            interpreter.interpret(&statements).unwrap();
+        }
+        assert_eq!(*stdout.0.borrow(), b"Hello, modules!\n3\n");
+    }
+
+    #[test]
+    fn test_building_syntax_trees() {
+        use rjlox::ast::*;
+        use rjlox::scanner::TokenType as TT;
+
+        let source = "class Counter { init(n) { this.n = n; } next() { this.n = this.n + 1; return this.n; } }
+            class Twice < Counter { next() { super.next(); return super.next(); } }
+            var c = Twice(0);
+            for (var i = 0; i < 3; i = i + 1) print c.next();
+            if (!(c.n >= 6) or false) print \"no\"; else { print -c.n; }
+            while (c.n != 0) c.n = c.n - 2;
+            fun f() { return; }
+            print f() == nil;";
+        let built = vec![
+            class(
+                "Counter",
+                None,
+                vec![
+                    function("init", &["n"], vec![expression(set(this(), "n", var("n")))]),
+                    function(
+                        "next",
+                        &[],
+                        vec![
+                            expression(set(
+                                this(),
+                                "n",
+                                binary(get(this(), "n"), TT::Plus, number(1.0)),
+                            )),
+                            return_(get(this(), "n")),
+                        ],
+                    ),
+                ],
+            ),
+            class(
+                "Twice",
+                Some("Counter"),
+                vec![function(
+                    "next",
+                    &[],
+                    vec![
+                        expression(call(super_("next"), vec![])),
+                        return_(call(super_("next"), vec![])),
+                    ],
+                )],
+            ),
+            var_decl("c", Some(call(var("Twice"), vec![number(0.0)]))),
+            for_(
+                Some(var_decl("i", Some(number(0.0)))),
+                Some(binary(var("i"), TT::Less, number(3.0))),
+                Some(assign("i", binary(var("i"), TT::Plus, number(1.0)))),
+                print(call(get(var("c"), "next"), vec![])),
+            ),
+            if_(
+                logical(
+                    unary(
+                        TT::Bang,
+                        grouping(binary(get(var("c"), "n"), TT::GreaterEqual, number(6.0))),
+                    ),
+                    TT::Or,
+                    boolean(false),
+                ),
+                print(string("no")),
+                Some(block(vec![print(unary(TT::Minus, get(var("c"), "n")))])),
+            ),
+            while_(
+                binary(get(var("c"), "n"), TT::BangEqual, number(0.0)),
+                expression(set(
+                    var("c"),
+                    "n",
+                    binary(get(var("c"), "n"), TT::Minus, number(2.0)),
+                )),
+            ),
+            function("f", &[], vec![return_(nil())]),
+            print(binary(call(var("f"), vec![]), TT::EqualEqual, nil())),
+        ];
+        let parsed = rjlox::parser::parse_bytes(source.as_bytes()).unwrap();
+        let printed =
+            |statements: &[Stmt]| statements.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        assert_eq!(printed(&built), printed(&parsed));
+
+        let mut interpreter = Interpreter::with_config(&Config::default());
+        let stdout = Captured::default();
+        interpreter.stdout = Box::new(stdout.clone());
+        Resolver::new(&mut interpreter).resolve(&built).unwrap();
+        interpreter.interpret(&built).unwrap();
+        assert_eq!(*stdout.0.borrow(), b"2\n4\n6\n-6\ntrue\n");
+    }
+
+    #[rstest]
+    fn test_syntax_trees_round_trip_through_json(#[files("test/**/*.lox")] path: PathBuf) {
+        let source = fs::read(&path).unwrap();
+        let Ok(statements) = rjlox::parser::parse_bytes(&source) else {
+            return;
+        };
+        let json = serde_json::to_string(&statements).unwrap();
+        let loaded: Vec<Stmt> = serde_json::from_str(&json).unwrap();
+        let printed =
+            |statements: &[Stmt]| statements.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        assert_eq!(printed(&loaded), printed(&statements));
+    }
+
+    #[rstest]
+    fn test_syntax_trees_print_back_to_source(#[files("test/**/*.lox")] path: PathBuf) {
+        let source = fs::read(&path).unwrap();
+        let Ok(statements) = rjlox::parser::parse_bytes(&source) else {
+            return;
+        };
+        let printed = rjlox::formatter::to_source(&statements);
+        let reparsed = rjlox::parser::parse_bytes(printed.as_bytes()).unwrap();
+        let sexprs =
+            |statements: &[Stmt]| statements.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        assert_eq!(sexprs(&reparsed), sexprs(&statements));
+    }
+
+    #[test]
+    fn test_built_syntax_trees_get_the_parentheses_they_need() {
+        use rjlox::ast::*;
+        use rjlox::scanner::TokenType as TT;
+
+        let (a, b, c) = (|| var("a"), || var("b"), || var("c"));
+        let cases = [
+            (
+                binary(binary(a(), TT::Plus, b()), TT::Star, c()),
+                "(a + b) * c",
+            ),
+            (
+                binary(a(), TT::Plus, binary(b(), TT::Star, c())),
+                "a + b * c",
+            ),
+            (
+                binary(binary(a(), TT::Minus, b()), TT::Minus, c()),
+                "a - b - c",
+            ),
+            (
+                binary(a(), TT::Minus, binary(b(), TT::Minus, c())),
+                "a - (b - c)",
+            ),
+            (
+                logical(logical(a(), TT::Or, b()), TT::And, c()),
+                "(a or b) and c",
+            ),
+            (
+                binary(binary(a(), TT::Less, b()), TT::EqualEqual, c()),
+                "a < b == c",
+            ),
+            (unary(TT::Minus, binary(a(), TT::Plus, b())), "-(a + b)"),
+            (unary(TT::Bang, unary(TT::Bang, a())), "!!a"),
+            (get(call(a(), vec![b()]), "x"), "a(b).x"),
+            (get(assign("a", b()), "x"), "(a = b).x"),
+            (call(unary(TT::Minus, a()), vec![]), "(-a)()"),
+            (assign("a", assign("b", c())), "a = b = c"),
+            (
+                set(grouping(a()), "x", binary(b(), TT::Slash, c())),
+                "(a).x = b / c",
+            ),
+            (binary(assign("a", b()), TT::Comma, c()), "a = b, c"),
+            (assign("a", binary(b(), TT::Comma, c())), "a = (b, c)"),
+            (call(a(), vec![binary(b(), TT::Comma, c())]), "a((b, c))"),
+            (
+                binary(a(), TT::Comma, binary(b(), TT::Comma, c())),
+                "a, (b, c)",
+            ),
+        ];
+        for (expr, expected) in cases {
+            assert_eq!(rjlox::formatter::expr(&expr), expected);
+        }
+        let program = vec![
+            var_decl("i", Some(integer(0))),
+            while_(
+                binary(var("i"), TT::Less, integer(3)),
+                block(vec![
+                    print(var("i")),
+                    expression(assign("i", binary(var("i"), TT::Plus, integer(1)))),
+                ]),
+            ),
+            for_in("x", var("xs"), print(var("x"))),
+            loop_(vec![return_(nil())]),
+            const_decl("n", integer(1)),
+            function("f", &["x"], vec![return_(var("x"))]),
+        ];
+        assert_eq!(
+            rjlox::formatter::to_source(&program),
+            "var i = 0;\nwhile (i < 3) {\n  print i;\n  i = i + 1;\n}\nfor (x in xs) print x;\nloop {\n  return;\n}\nconst n = 1;\n\n\
+             fun f(x) {\n  return x;\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_columns_count_characters() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["tokens", "test/_my/scanner/columns.lox"])
+            .assert()
+            .success()
+            .stdout(
+                "1:1 Var var\n1:5 Identifier π\n1:7 Equal =\n1:9 String two\nlïnes\n\
+                 2:7 Semicolon ;\n2:9 Print print\n2:15 Identifier π\n2:16 Semicolon ;\n\
+                 3:2 Var var\n3:6 Identifier été\n3:10 Equal =\n3:12 Number 1\n\
+                 3:13 Semicolon ;\n4:1 Eof \n",
+            );
+    }
+
+    #[test]
+    fn test_fmt_subcommand() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["fmt", "--check", "test/_my/fmt/formatted.lox"])
+            .assert()
+            .success()
+            .stdout("");
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["fmt", "--check", "test/_my/fmt/messy.lox"])
+            .assert()
+            .failure()
+            .code(1)
+            .stdout("Would reformat test/_my/fmt/messy.lox\n");
+
+        let dir = std::env::temp_dir().join(format!("rjlox-fmt-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("messy.lox");
+        fs::copy("test/_my/fmt/messy.lox", &file).unwrap();
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .arg("fmt")
+            .arg(&file)
+            .assert()
+            .success();
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            fs::read_to_string("test/_my/fmt/formatted.lox").unwrap()
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_fmt_keeps_comments_in_their_block() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["fmt", "--check", "test/_my/fmt/comments.lox"])
+            .assert()
+            .success()
+            .stdout("");
+    }
+
+    #[test]
+    fn test_bench_prints_table() {
+        let table = Regex::new(
+            r"^\| Benchmark \| Runs \| Min \(s\) \| Mean \(s\) \|\n\|-+\|-+\|-+\|-+\|\n\| non-trivial.lox \| 2 \| \d+\.\d\d \| \d+\.\d\d \|\n$",
+        )
+        .unwrap();
+        let assert = Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["bench", "--repeat=2", "test/_my/programs"])
+            .assert()
+            .success();
+        let stdout = String::from_utf8_lossy(&assert.get_output().stdout);
+        assert!(table.is_match(&stdout), "{}", stdout);
+    }
+
+    #[rstest]
+    #[case("--max-stack-depth=100", "[line 2:38] RuntimeError: Stack overflow.\n")]
+    #[case(
+        "--max-steps=3000",
+        "[line 10:7] RuntimeError: Execution limit exceeded.\n"
+    )]
+    #[case(
+        "--max-output-bytes=20",
+        "[line 9:3] RuntimeError: Output limit exceeded.\n"
+    )]
+    fn test_runtime_limits(#[case] flag: &str, #[case] error: &str) {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args([flag, "test/_my/limits/runaway.lox"])
+            .assert()
+            .failure()
+            .code(70)
+            .stderr(error.to_string());
+    }
+
+    #[rstest]
+    fn test_memory_limit(#[values(None, Some("--vm"))] flag: Option<&str>) {
+        let run = |script: &str| {
+            Command::cargo_bin("rjlox")
+                .unwrap()
+                .args(flag)
+                .args(["--max-memory-bytes=1000000", script])
+                .output()
+                .unwrap()
+        };
+        let hoard = run("test/_my/limits/hoard.lox");
+        assert_eq!(hoard.status.code(), Some(70));
+        assert!(String::from_utf8_lossy(&hoard.stderr)
+            .ends_with("RuntimeError: Memory limit exceeded.\n"));
+        // Garbage is collected before it counts against the limit
+        let churn = run("test/_my/limits/churn.lox");
+        assert!(churn.status.success());
+        assert_eq!(churn.stdout, b"false\n");
+    }
+
+    #[test]
+    fn test_memory_limit_leaves_the_interpreter_usable() {
+        let config = Config {
+            limits: rjlox::config::Limits {
+                max_memory_bytes: Some(100_000),
+                ..Default::default()
+            },
+            ..Config::default()
+        };
+        let mut lox = rjlox::Lox::with_config(&config);
+        let error = lox
+            .run("var s = \"x\"; while (true) s = s + s;")
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "[line 1:14] RuntimeError: Memory limit exceeded."
+        );
+        lox.run("s = nil;").unwrap();
+        assert_eq!(lox.run("1 + 2;").unwrap(), Value::Double(3.0));
     }
 
-    fn expected_error_at(path: &Path) -> String {
-        let contents = fs::read_to_string(path).unwrap();
-        let re = Regex::new(r"//.* Error.*").unwrap();
-        // Skip all errors supposed to be generated by clox
-        let re_drop = Regex::new(r"//.*\[c line.*").unwrap();
+    #[test]
+    fn test_stack_depth_is_not_bound_by_the_native_stack() {
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(["--max-stack-depth=1000000", "test/_my/limits/deep.lox"])
+            .assert()
+            .success()
+            .stdout("500000\n");
+    }
 
-        let expected: String = contents
-            .lines()
-            .filter(|line| !re_drop.is_match(line))
-            .filter(|line| re.is_match(line))
-            .map(|line| line.split("// ").nth(1).unwrap().to_string() + "\n")
+    #[rstest]
+    #[case("print 2 * 3 + x;", "(print (+ 6 x))")]
+    #[case("print \"a\" + \"b\" == \"ab\" and !nil;", "(print true)")]
+    #[case("print (1 + 2) * -3 < x or \"no\";", "(print (or (< -9 x) \"no\"))")]
+    #[case("print true and x;", "(print x)")]
+    #[case("print false and x;", "(print (and false x))")]
+    #[case("print 1 + \"a\";", "(print (+ 1 \"a\"))")]
+    #[case("print -\"a\";", "(print (- \"a\"))")]
+    fn test_optimizer_folds_constants(#[case] source: &str, #[case] folded: &str) {
+        let statements = rjlox::parser::parse_bytes(source.as_bytes()).unwrap();
+        let optimized = rjlox::optimizer::optimize(&statements);
+        assert_eq!(optimized[0].to_string(), folded);
+    }
+
+    #[test]
+    fn test_errors_are_structured() {
+        let tokens = rjlox::scanner::Scanner::new("var a = ;")
+            .scan_tokens()
+            .iter()
+            .flatten()
+            .cloned()
             .collect();
-        expected
+        let errors = rjlox::parser::Parser::new(tokens).parse().unwrap_err();
+        assert!(matches!(
+            &errors[..],
+            [LoxError::ParseError { token, message }]
+                if token.lexeme == ";" && message == "Expect expression."
+        ));
+        assert_eq!(
+            errors[0].to_string(),
+            "[line 1:9] Error at ';': Expect expression."
+        );
+    }
+
+    #[rstest]
+    #[case(b"")]
+    #[case(b"(")]
+    #[case(b"}")]
+    #[case(b"class")]
+    #[case(b"fun f(")]
+    #[case(b"print \"")]
+    #[case(b"for (;;")]
+    #[case(b"a.b.c = ")]
+    #[case(b"\xff\xfe var \x00 = 1;")]
+    fn test_truncated_programs_are_parse_errors(#[case] bytes: &[u8]) {
+        let parsed = rjlox::parser::parse_bytes(bytes);
+        assert_eq!(parsed.is_err(), !bytes.is_empty());
+    }
+
+    /// Unoptimized builds take a lot of stack for each nested expression, more
+    /// than the 2MB test threads have, so the parser gets enough to reach its
+    /// nesting limit
+    fn with_main_stack(test: impl FnOnce() + Send + 'static) {
+        std::thread::Builder::new()
+            .stack_size(32 << 20)
+            .spawn(test)
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_parser_stays_within_its_nesting_limit() {
+        with_main_stack(test_nesting_limit);
+    }
+
+    fn test_nesting_limit() {
+        let deep = format!("print {}1{};", "(".repeat(100_000), ")".repeat(100_000));
+        let errors = rjlox::parser::parse_bytes(deep.as_bytes()).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].to_string().ends_with("Too much nesting."));
+        let shallow = format!("print {}1{};", "(".repeat(900), ")".repeat(900));
+        assert!(rjlox::parser::parse_bytes(shallow.as_bytes()).is_ok());
+    }
+
+    #[rstest]
+    fn test_long_else_if_chains_and_deep_parentheses(
+        #[values(None, Some("--opt"), Some("--vm"))] flag: Option<&str>,
+    ) {
+        let mut source = String::from("var x = 150;\nif (x == 0) print 0;\n");
+        for i in 1..200 {
+            source.push_str(&format!("else if (x == {}) print {};\n", i, i));
+        }
+        source.push_str("else print \"none\";\n");
+        source.push_str(&format!("print {}1{};\n", "(".repeat(200), ")".repeat(200)));
+        Command::cargo_bin("rjlox")
+            .unwrap()
+            .args(flag)
+            .arg("-")
+            .write_stdin(source)
+            .assert()
+            .success()
+            .stdout("150\n1\n");
+    }
+
+    /// Random bytes, mostly made of pieces of Lox
+    const PIECES: &[&[u8]] = &[
+        b"(",
+        b")",
+        b"{",
+        b"}",
+        b";",
+        b",",
+        b".",
+        b"=",
+        b"==",
+        b"!",
+        b"-",
+        b"+",
+        b"*",
+        b"/",
+        b"<",
+        b"\"",
+        b"\n",
+        b" ",
+        b"1",
+        b"1.5",
+        b"a",
+        b"var",
+        b"fun",
+        b"class",
+        b"if",
+        b"else",
+        b"for",
+        b"while",
+        b"return",
+        b"print",
+        b"this",
+        b"super",
+        b"nil",
+        b"and",
+        b"//",
+        b"\xff",
+        b"\xe2\x82",
+    ];
+
+    /// xorshift, so that any failure can be reproduced
+    fn random(seed: u64) -> impl FnMut() -> usize {
+        let mut state = seed;
+        move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as usize
+        }
+    }
+
+    #[test]
+    fn test_front_end_never_panics() {
+        with_main_stack(|| {
+            let mut next = random(0x2545_f491_4f6c_dd1d);
+            for _ in 0..2000 {
+                let input: Vec<u8> = (0..next() % 64)
+                    .flat_map(|_| PIECES[next() % PIECES.len()].iter().copied())
+                    .collect();
+                let _ = rjlox::parser::parse_bytes(&input);
+            }
+        });
+    }
+
+    #[rstest]
+    fn test_front_end_never_panics_on_broken_scripts(#[files("test/**/*.lox")] path: PathBuf) {
+        let source = fs::read(&path).unwrap();
+        with_main_stack(move || {
+            let mut next = random(source.len() as u64 + 1);
+            for _ in 0..8 {
+                let mut input = source[..next() % (source.len() + 1)].to_vec();
+                if !input.is_empty() {
+                    let at = next() % input.len();
+                    input[at] = PIECES[next() % PIECES.len()][0];
+                }
+                let _ = rjlox::parser::parse_bytes(&input);
+            }
+        });
     }
 
     #[rstest]
@@ -214,23 +2831,22 @@ mod tests {
         #[exclude("test/benchmark")] // this is benchmark tests
         #[exclude("test/expressions")] // this is for the expressions eval
         #[exclude("test/scanning")] // this is just for the scanner
-        #[exclude("test/limit")] // this is for the compiler
+        #[exclude("test/limit/(loop|no_reuse|too_many)")] // these are for the compiler
         path: PathBuf,
+        #[values(None, Some("--opt"), Some("--vm"))] flag: Option<&str>,
     ) {
         let mut cmd = Command::cargo_bin("rjlox").unwrap();
-        let successful = expected(&path);
-        let runtime_error = expected_runtime_error(&path);
-        let error = expected_error_at(&path);
-        if runtime_error.len() > 0 {
-            cmd.arg(&path)
-                .assert()
-                .failure()
-                .code(70)
-                .stderr(runtime_error);
-        } else if error.len() > 0 {
-            cmd.arg(&path).assert().failure().code(65).stderr(error);
-        } else {
-            cmd.arg(&path).assert().success().stdout(successful);
+        cmd.args(flag);
+        match Expected::parse(&fs::read_to_string(&path).unwrap()) {
+            Expected::RuntimeError(error) => {
+                cmd.arg(&path).assert().failure().code(70).stderr(error);
+            }
+            Expected::CompileError(error) => {
+                cmd.arg(&path).assert().failure().code(65).stderr(error);
+            }
+            Expected::Output(output) => {
+                cmd.arg(&path).assert().success().stdout(output);
+            }
         }
     }
 }
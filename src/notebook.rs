@@ -0,0 +1,89 @@
+// Literate scripts, for `rjlox run-md`: Markdown whose ```lox code blocks are
+// run in order as one program, each block's output following it in a ```text
+// block of its own, so that tutorials and notes show what their code prints.
+
+use std::io::{self, Write};
+
+use crate::shared::{Rc, RefCell};
+
+/// A ```lox code block of a Markdown document
+#[derive(Debug, PartialEq)]
+pub struct Block {
+    /// The code, preceded by as many empty lines as come before it in the
+    /// document, so that diagnostics point at the lines of the document
+    pub code: String,
+    /// Where the line closing the block ends, or the document if it is left
+    /// open
+    pub end: usize,
+}
+
+/// The ```lox blocks of `markdown`, in order. Fences have to start their line
+/// and be at least three backticks or tildes long, and the blocks they open
+/// end at the first line of at least as many of the same, as in CommonMark.
+pub fn blocks(markdown: &str) -> Vec<Block> {
+    let mut blocks = Vec::new();
+    let mut open: Option<(&str, String)> = None;
+    let mut at = 0;
+    for (number, line) in markdown.split_inclusive('\n').enumerate() {
+        at += line.len();
+        let text = line.trim_end();
+        match &mut open {
+            Some((fence, code)) => {
+                if text.starts_with(*fence) && text.trim_start_matches(&fence[..1]).is_empty() {
+                    blocks.push(Block {
+                        code: std::mem::take(code),
+                        end: at,
+                    });
+                    open = None;
+                } else {
+                    code.push_str(line);
+                }
+            }
+            None => {
+                let Some(fence) = fence(text) else {
+                    continue;
+                };
+                if text[fence.len()..].trim() == "lox" {
+                    open = Some((fence, "\n".repeat(number + 1)));
+                }
+            }
+        }
+    }
+    if let Some((_, code)) = open {
+        blocks.push(Block {
+            code,
+            end: markdown.len(),
+        });
+    }
+    blocks
+}
+
+/// The backticks or tildes opening a code block on `line`, if it does
+fn fence(line: &str) -> Option<&str> {
+    let mark = line.chars().next().filter(|x| *x == '`' || *x == '~')?;
+    let length = line.len() - line.trim_start_matches(mark).len();
+    (length >= 3).then(|| &line[..length])
+}
+
+/// Where the interpreter prints while a block runs, to be taken out after it
+#[derive(Clone, Default)]
+pub struct Captured(Rc<RefCell<Vec<u8>>>);
+
+impl Captured {
+    /// What was printed since the last time
+    pub fn take(&self) -> String {
+        let printed = std::mem::take(&mut *self.0.borrow_mut());
+        String::from_utf8_lossy(&printed).into_owned()
+    }
+}
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,172 @@
+use crate::shared::Rc;
+
+use crate::expr::Expr;
+use crate::interpreter::Interpreter;
+use crate::scanner::{Literal, Token, TokenType as TT};
+use crate::stmt::Stmt;
+use crate::value::Value;
+
+/// Folds the expressions made only of constants into the constant they evaluate
+/// to, like `2 * 3 + x` into `6 + x`. Runs before the resolver, so nothing that
+/// mentions a variable is ever dropped, and leaves alone what would fail or warn
+/// at runtime, for it to still be reported there. Fewer steps are counted
+/// against `--max-steps`, but the program otherwise does just the same.
+pub fn optimize(statements: &[Stmt]) -> Vec<Stmt> {
+    statements.iter().map(statement).collect()
+}
+
+fn statements(statements: &[Stmt]) -> Rc<[Stmt]> {
+    statements.iter().map(statement).collect()
+}
+
+fn statement(stmt: &Stmt) -> Stmt {
+    match stmt {
+        Stmt::Block(body) => Stmt::Block(statements(body)),
+        Stmt::Class(name, superclass, methods, doc) => Stmt::Class(
+            name.clone(),
+            superclass.clone(),
+            methods.iter().map(statement).collect(),
+            doc.clone(),
+        ),
+        Stmt::Expression(e) => Stmt::Expression(fold(e)),
+        Stmt::For(keyword, initializer, condition, increment, body) => Stmt::For(
+            keyword.clone(),
+            initializer.as_deref().map(statement).map(Rc::new),
+            condition.as_ref().map(fold),
+            increment.as_ref().map(fold),
+            Rc::new(statement(body)),
+        ),
+        Stmt::ForIn(keyword, name, collection, body) => Stmt::ForIn(
+            keyword.clone(),
+            name.clone(),
+            fold(collection),
+            Rc::new(statement(body)),
+        ),
+        Stmt::Function(name, params, body, doc) => {
+            Stmt::Function(name.clone(), params.clone(), statements(body), doc.clone())
+        }
+        Stmt::If(condition, then_branch, else_branch) => Stmt::If(
+            fold(condition),
+            Rc::new(statement(then_branch)),
+            else_branch.as_deref().map(statement).map(Rc::new),
+        ),
+        Stmt::Print(keyword, e) => Stmt::Print(keyword.clone(), fold(e)),
+        Stmt::Return(keyword, e) => {
+            let folded = fold(e);
+            // The resolver takes a `nil` for a bare `return`, the only one allowed
+            // in initializers, so nothing else may be folded into one
+            if matches!(*folded, Expr::Literal(Literal::None)) {
+                Stmt::Return(keyword.clone(), e.clone())
+            } else {
+                Stmt::Return(keyword.clone(), folded)
+            }
+        }
+        Stmt::Test(name, body) => Stmt::Test(name.clone(), statements(body)),
+        Stmt::Var(name, initializer, constant) => {
+            Stmt::Var(name.clone(), initializer.as_ref().map(fold), *constant)
+        }
+        Stmt::While(keyword, condition, body) => {
+            Stmt::While(keyword.clone(), fold(condition), Rc::new(statement(body)))
+        }
+    }
+}
+
+fn fold(expr: &Rc<Expr>) -> Rc<Expr> {
+    let folded = match &**expr {
+        Expr::Assign(id, name, value) => Expr::Assign(*id, name.clone(), fold(value)),
+        Expr::Binary(left, op, right) => {
+            let (left, right) = (fold(left), fold(right));
+            match (&*left, &*right) {
+                // A constant on the left of a comma does nothing
+                (Expr::Literal(_), _) if op.token == TT::Comma => return right,
+                (Expr::Literal(l), Expr::Literal(r)) => match binary(l, op, r) {
+                    Some(lit) => Expr::Literal(lit),
+                    None => Expr::Binary(left, op.clone(), right),
+                },
+                _ => Expr::Binary(left, op.clone(), right),
+            }
+        }
+        Expr::Call(callee, paren, arguments) => Expr::Call(
+            fold(callee),
+            paren.clone(),
+            arguments.iter().map(fold).collect(),
+        ),
+        Expr::Get(id, obj, name) => Expr::Get(*id, fold(obj), name.clone()),
+        Expr::Set(obj, name, value) => Expr::Set(fold(obj), name.clone(), fold(value)),
+        Expr::Grouping(inner) => {
+            let inner = fold(inner);
+            if matches!(*inner, Expr::Literal(_)) {
+                return inner;
+            }
+            Expr::Grouping(inner)
+        }
+        Expr::Logical(left, op, right) => {
+            let (left, right) = (fold(left), fold(right));
+            match (&*left, &*right) {
+                // Whichever operand it gives, the other one is a constant as well
+                (Expr::Literal(l), Expr::Literal(_)) if short_circuits(l, op) => {
+                    return left;
+                }
+                (Expr::Literal(_), Expr::Literal(_)) => return right,
+                // Only the left one being constant, it can still go when the
+                // right one is what the expression gives anyway
+                (Expr::Literal(l), _) if !short_circuits(l, op) => return right,
+                _ => Expr::Logical(left, op.clone(), right),
+            }
+        }
+        Expr::Unary(op, right) => {
+            let right = fold(right);
+            match (op.token, &*right) {
+                (TT::Minus, Expr::Literal(Literal::Integer(n))) => Expr::Literal(
+                    n.checked_neg()
+                        .map_or(Literal::Double(-(*n as f64)), Literal::Integer),
+                ),
+                (TT::Minus, Expr::Literal(Literal::Double(n))) => {
+                    Expr::Literal(Literal::Double(-n))
+                }
+                (TT::Bang, Expr::Literal(lit)) => Expr::Literal(Literal::Boolean(!is_truthy(lit))),
+                _ => Expr::Unary(op.clone(), right),
+            }
+        }
+        Expr::Literal(_) | Expr::Super(_, _, _) | Expr::This(_, _) | Expr::Variable(_, _) => {
+            return expr.clone()
+        }
+    };
+    Rc::new(folded)
+}
+
+/// Whether `left op right` gives `left` without looking at `right`
+fn short_circuits(left: &Literal, op: &Token) -> bool {
+    if op.token == TT::Or {
+        is_truthy(left)
+    } else {
+        !is_truthy(left)
+    }
+}
+
+/// What the interpreter would compute for two constants, unless it would be an
+/// error or a number added to a string, which can be warned about
+fn binary(left: &Literal, op: &Token, right: &Literal) -> Option<Literal> {
+    use Literal::Boolean;
+    let lit = match (left, op.token, right) {
+        (Literal::String(l), TT::Plus, Literal::String(r)) => {
+            Literal::String(format!("{}{}", l, r).into())
+        }
+        // Constants are only ever numbers, strings, booleans and nil, which
+        // compare the same way as in the interpreter
+        (_, TT::EqualEqual, _) => Boolean(left == right),
+        (_, TT::BangEqual, _) => Boolean(left != right),
+        // Numbers are promoted by the interpreter's own rules
+        _ => match Interpreter::arithmetic(&left.into(), op.token, &right.into())? {
+            Value::Integer(n) => Literal::Integer(n),
+            Value::Double(n) => Literal::Double(n),
+            Value::Boolean(b) => Boolean(b),
+            _ => return None,
+        },
+    };
+    Some(lit)
+}
+
+fn is_truthy(lit: &Literal) -> bool {
+    !matches!(lit, Literal::Boolean(false) | Literal::None)
+}
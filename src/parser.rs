@@ -1,24 +1,44 @@
-use crate::expr::Expr;
-use crate::scanner::{Literal, Token, TokenType};
+use crate::error::LoxError;
+use crate::expr::{Expr, ExprId};
+use crate::scanner::{Comment, Literal, Token, TokenType};
+use crate::shared::Rc;
 use crate::stmt::Stmt;
+use crate::symbol::Symbol;
 use itertools::peek_nth;
 use itertools::structs::PeekNth;
-use log::debug;
+use std::collections::BTreeMap;
 use std::vec::IntoIter;
+use tracing::trace;
 
 pub struct Parser {
     tokens: PeekNth<IntoIter<Token>>,
     prev: Option<Token>,
-    errors: Vec<String>,
+    /// Stands in for the tokens after the last one, whatever they were given
+    eof: Token,
+    errors: Vec<LoxError>,
+    /// How many statements and expressions are being parsed inside each other
+    depth: usize,
+    /// How many tokens were consumed so far
+    taken: usize,
+    /// The text of the `///` comments given with `with_docs`, by line
+    docs: BTreeMap<usize, String>,
 }
 
+/// Deeper programs are rejected rather than overflowing the stack of the parser,
+/// or of the resolver and the interpreter after it. Far more than code written
+/// by hand needs, and still within the threads of `driver::STACK_SIZE`, which
+/// unoptimized builds take most of.
+const MAX_NESTING: usize = 1000;
+
 /****************************************************************
 Parser grammar:
 
     program        → declaration* EOF ;
 
     declaration    → classDecl
+                   | constDecl
                    | funDecl
+                   | testDecl
                    | varDecl
                    | statement ;
 
@@ -28,11 +48,14 @@ Parser grammar:
     function       → IDENTIFIER "(" parameters? ")" block ;
     parameters     → IDENTIFIER ( "," IDENTIFIER )* ;
 
-    varDecl        → "var" IDENTIFIER ( "=" expression )? ";" ;
+    testDecl       → "test" STRING block ;
+    varDecl        → "var" IDENTIFIER ( "=" assignment )? ";" ;
+    constDecl      → "const" IDENTIFIER "=" assignment ";" ;
 
     statement      → exprStmt
                    | forStmt
                    | ifStmt
+                   | loopStmt
                    | printStmt
                    | returnStmt
                    | whileStmt
@@ -42,9 +65,11 @@ Parser grammar:
 
     forStmt        → "for" "(" ( varDecl | exprStmt | ";" )
                    expression? ";"
-                   expression? ")" statement ;
+                   expression? ")" statement
+                   | "for" "(" IDENTIFIER "in" expression ")" statement ;
 
     whileStmt      → "while" "(" expression ")" statement ;
+    loopStmt       → "loop" block ;
 
     ifStmt         → "if" "(" expression ")" statement
                    ( "else" statement )? ;
@@ -54,7 +79,8 @@ Parser grammar:
     exprStmt       → expression ";" ;
     printStmt      → "print" expression ";" ;
 
-    expression     → assignment ;
+    expression     → comma ;
+    comma          → assignment ( "," assignment )* ;
     assignment     → ( call "." )? IDENTIFIER "=" assignment
                    | logic_or ;
     logic_or       → logic_and ( "or" logic_and )* ;
@@ -65,33 +91,99 @@ Parser grammar:
     factor         → unary ( ( "/" | "*" ) unary )* ;
     unary          → ( "!" | "-" ) unary | call ;
     call           → primary ( "(" arguments? ")" | "." IDENTIFIER )* ;
-    arguments      → expression ( "," expression )* ;
+    arguments      → assignment ( "," assignment )* ;
 
     primary        → "true" | "false" | "nil" | "this"
                    | NUMBER | STRING | IDENTIFIER | "(" expression ")"
                    | "super" "." IDENTIFIER ;
 
+`test` is only a keyword when a string follows it, so that it can still be
+used as a name.
+
+The comma operator evaluates both its operands and gives the right one, as in
+C. It binds the loosest of all, so commas separating arguments, and values
+assigned or declared, need parentheses around them to be operators.
+
 *****************************************************************/
 impl Parser {
     pub fn new(tokens: Vec<Token>) -> Self {
+        let eof = match tokens.last() {
+            Some(last) if last.token == TokenType::Eof => last.clone(),
+            Some(last) => Token {
+                token: TokenType::Eof,
+                lexeme: Symbol::intern(""),
+                literal: Literal::None,
+                column: last.column + last.lexeme.chars().count(),
+                ..last.clone()
+            },
+            None => Token {
+                token: TokenType::Eof,
+                lexeme: Symbol::intern(""),
+                literal: Literal::None,
+                line: 1,
+                column: 1,
+                file: None,
+            },
+        };
         Parser {
             tokens: peek_nth(tokens),
             prev: None,
+            eof,
             errors: Vec::new(),
-        }
+            depth: 0,
+            taken: 0,
+            docs: BTreeMap::new(),
+        }
+    }
+
+    /// Attaches the `///` comments among `comments` to the classes, methods
+    /// and functions they come right before, the scanner keeping them apart
+    /// from the tokens
+    pub fn with_docs(mut self, comments: &[Comment]) -> Self {
+        self.docs = comments
+            .iter()
+            .filter(|x| !x.trailing && x.text.starts_with("///") && !x.text.starts_with("////"))
+            .map(|x| {
+                let text = &x.text[3..];
+                (x.line, text.strip_prefix(' ').unwrap_or(text).to_string())
+            })
+            .collect();
+        self
+    }
+
+    /// Takes `print` for a name, that of the native function it then is,
+    /// rather than the keyword of a statement
+    pub fn with_native_print(mut self) -> Self {
+        let tokens = std::mem::replace(&mut self.tokens, peek_nth(Vec::new()));
+        let tokens = tokens.map(|mut x| {
+            if x.token == TokenType::Print {
+                x.token = TokenType::Identifier;
+            }
+            x
+        });
+        self.tokens = peek_nth(tokens.collect::<Vec<_>>());
+        self
     }
 
-    pub fn parse_expr(&mut self) -> Result<Expr, String> {
+    pub fn parse_expr(&mut self) -> Result<Expr, LoxError> {
         self.expression()
     }
 
-    pub fn parse(&mut self) -> Result<Vec<Stmt>, String> {
-        let mut statements: Vec<Stmt> = Vec::new();
+    pub fn parse(&mut self) -> Result<Vec<Stmt>, Vec<LoxError>> {
+        self.parse_spans()
+            .map(|x| x.into_iter().map(|(stmt, _)| stmt).collect())
+    }
+
+    /// Parses like [`Parser::parse`], along with how many tokens each of the
+    /// statements is made of
+    pub fn parse_spans(&mut self) -> Result<Vec<(Stmt, usize)>, Vec<LoxError>> {
+        let mut statements = Vec::new();
         while !self.is_at_end() {
+            let start = self.taken;
             let stmt = self.declaration();
-            debug!("{}", format!("Debug {:?}", stmt));
+            trace!("declaration {:?}", stmt);
             match stmt {
-                Ok(x) => statements.push(x),
+                Ok(x) => statements.push((x, self.taken - start)),
                 Err(e) => {
                     self.errors.push(e);
                     self.synchronize();
@@ -102,29 +194,71 @@ impl Parser {
         if self.errors.is_empty() {
             Ok(statements)
         } else {
-            Err(self
-                .errors
-                .iter()
-                .map(|s| s.to_string())
-                .collect::<Vec<_>>()
-                .join("\n"))
+            Err(std::mem::take(&mut self.errors))
         }
     }
 
-    fn declaration(&mut self) -> Result<Stmt, String> {
-        if self.munch(&[TokenType::Class]) {
-            return self.class_declaration();
-        }
-        if self.munch(&[TokenType::Fun]) {
-            return self.function("function");
+    fn declaration(&mut self) -> Result<Stmt, LoxError> {
+        if self.check(TokenType::Class) || self.check(TokenType::Fun) {
+            let doc = self.doc();
+            if self.munch(&[TokenType::Class]) {
+                return self.class_declaration(doc);
+            }
+            self.advance();
+            return self.function("function", doc);
         }
         if self.munch(&[TokenType::Var]) {
             return self.var_declaration();
         }
+        // Another keyword only before what it declares
+        if self.check(TokenType::Identifier)
+            && self.peek().lexeme == "const"
+            && self
+                .tokens
+                .peek_nth(1)
+                .is_some_and(|x| x.token == TokenType::Identifier)
+        {
+            self.advance();
+            return self.const_declaration();
+        }
+        if self.check(TokenType::Identifier)
+            && self.peek().lexeme == "test"
+            && self
+                .tokens
+                .peek_nth(1)
+                .is_some_and(|x| x.token == TokenType::String)
+        {
+            self.advance();
+            return self.test_declaration();
+        }
         self.statement()
     }
 
-    fn function(&mut self, kind: &str) -> Result<Stmt, String> {
+    fn test_declaration(&mut self) -> Result<Stmt, LoxError> {
+        let name = self.advance();
+        self.consume(TokenType::LeftBrace, "Expect '{' before test body.")?;
+        let body = self.block()?;
+        Ok(Stmt::Test(name, body.into()))
+    }
+
+    /// Each statement nests one level deeper, so that bodies and branches are counted too
+    fn statement(&mut self) -> Result<Stmt, LoxError> {
+        self.nested(Parser::any_statement)
+    }
+
+    /// The doc comment on the lines right above the current token
+    fn doc(&mut self) -> Option<Rc<str>> {
+        let line = self.peek().line;
+        let lines = (1..line).rev().map_while(|x| self.docs.get(&x));
+        let mut doc = lines.map(|x| x.as_str()).collect::<Vec<_>>();
+        if doc.is_empty() {
+            return None;
+        }
+        doc.reverse();
+        Some(Rc::from(doc.join("\n")))
+    }
+
+    fn function(&mut self, kind: &str, doc: Option<Rc<str>>) -> Result<Stmt, LoxError> {
         let name = self.consume(
             TokenType::Identifier,
             format!("Expect {} name.", kind).as_str(),
@@ -139,7 +273,7 @@ impl Parser {
             loop {
                 if parameters.len() >= 255 {
                     return Parser::error::<Stmt>(
-                        &self.peek(),
+                        self.peek(),
                         "Can't have more than 255 parameters.",
                     );
                 }
@@ -159,15 +293,15 @@ impl Parser {
         )?;
 
         let body = self.block()?;
-        Ok(Stmt::Function(name, parameters, body))
+        Ok(Stmt::Function(name, parameters.into(), body.into(), doc))
     }
 
-    fn class_declaration(&mut self) -> Result<Stmt, String> {
+    fn class_declaration(&mut self, doc: Option<Rc<str>>) -> Result<Stmt, LoxError> {
         let name = self.consume(TokenType::Identifier, "Expect class name.")?;
 
         let superclass = if self.munch(&[TokenType::Less]) {
             self.consume(TokenType::Identifier, "Expect superclass name.")?;
-            Some(Expr::Variable(self.previous()))
+            Some(Expr::Variable(ExprId::fresh(), self.previous().clone()))
         } else {
             None
         };
@@ -176,17 +310,18 @@ impl Parser {
 
         let mut methods = Vec::new();
         while !self.check(TokenType::RightBrace) && !self.is_at_end() {
-            methods.push(self.function("method")?);
+            let doc = self.doc();
+            methods.push(self.function("method", doc)?);
         }
 
         self.consume(TokenType::RightBrace, "Expect '}' after class body.")?;
-        Ok(Stmt::Class(name, superclass, methods))
+        Ok(Stmt::Class(name, superclass, methods, doc))
     }
 
-    fn var_declaration(&mut self) -> Result<Stmt, String> {
+    fn var_declaration(&mut self) -> Result<Stmt, LoxError> {
         let name = self.consume(TokenType::Identifier, "Expect variable name.")?;
         let initializer: Option<Expr> = if self.munch(&[TokenType::Equal]) {
-            Some(self.expression()?)
+            Some(self.nested(Parser::assignment)?)
         } else {
             None
         };
@@ -195,10 +330,21 @@ impl Parser {
             TokenType::Semicolon,
             "Expect ';' after variable declaration.",
         )?;
-        Ok(Stmt::Var(name, initializer))
+        Ok(Stmt::Var(name, initializer.map(Rc::new), false))
+    }
+
+    fn const_declaration(&mut self) -> Result<Stmt, LoxError> {
+        let name = self.advance();
+        self.consume(TokenType::Equal, "Expect '=' after constant name.")?;
+        let value = self.nested(Parser::assignment)?;
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after constant declaration.",
+        )?;
+        Ok(Stmt::Var(name, Some(Rc::new(value)), true))
     }
 
-    fn statement(&mut self) -> Result<Stmt, String> {
+    fn any_statement(&mut self) -> Result<Stmt, LoxError> {
         if self.munch(&[TokenType::For]) {
             return self.for_statement();
         }
@@ -215,15 +361,47 @@ impl Parser {
             return self.while_statement();
         }
         if self.munch(&[TokenType::LeftBrace]) {
-            return Ok(Stmt::Block(self.block()?));
+            return Ok(Stmt::Block(self.block()?.into()));
+        }
+        // Only a keyword before a block, nothing else can start with a name and
+        // a brace
+        if self.check(TokenType::Identifier)
+            && self.peek().lexeme == "loop"
+            && self
+                .tokens
+                .peek_nth(1)
+                .is_some_and(|x| x.token == TokenType::LeftBrace)
+        {
+            return self.loop_statement();
         }
 
         self.expression_statement()
     }
 
-    fn for_statement(&mut self) -> Result<Stmt, String> {
+    fn for_statement(&mut self) -> Result<Stmt, LoxError> {
+        let keyword = self.previous().clone();
         self.consume(TokenType::LeftParen, "Expect '(' after 'for'.")?;
 
+        // `in` is only a keyword here, like `test` is before a string
+        if self.check(TokenType::Identifier)
+            && self
+                .tokens
+                .peek_nth(1)
+                .is_some_and(|x| x.token == TokenType::Identifier && x.lexeme == "in")
+        {
+            let name = self.advance();
+            self.advance();
+            let collection = self.expression()?;
+            self.consume(TokenType::RightParen, "Expect ')' after for-in collection.")?;
+            let body = self.statement()?;
+            return Ok(Stmt::ForIn(
+                keyword,
+                name,
+                Rc::new(collection),
+                Rc::new(body),
+            ));
+        }
+
         let initializer = if self.munch(&[TokenType::Semicolon]) {
             None
         } else if self.munch(&[TokenType::Var]) {
@@ -233,9 +411,9 @@ impl Parser {
         };
 
         let cond = if !self.check(TokenType::Semicolon) {
-            self.expression()?
+            Some(self.expression()?)
         } else {
-            Expr::Literal(Literal::Boolean(true))
+            None
         };
         self.consume(TokenType::Semicolon, "Expect ';' after loop condition.")?;
 
@@ -246,65 +424,87 @@ impl Parser {
         };
         self.consume(TokenType::RightParen, "Expect ')' after for clauses.")?;
 
-        let mut body = self.statement()?;
+        let body = self.statement()?;
 
-        // Desugaring a for loop into a while loop
-        if let Some(inc) = increment {
-            body = Stmt::Block(vec![body, Stmt::Expression(inc)])
-        }
-        body = Stmt::While(cond, Box::new(body));
-        if let Some(init) = initializer {
-            body = Stmt::Block(vec![init, body])
-        }
+        Ok(Stmt::For(
+            keyword,
+            initializer.map(Rc::new),
+            cond.map(Rc::new),
+            increment.map(Rc::new),
+            Rc::new(body),
+        ))
+    }
 
-        Ok(body)
+    /// `loop { ... }` is `for (;;) { ... }` pointing at the `loop`
+    fn loop_statement(&mut self) -> Result<Stmt, LoxError> {
+        let keyword = self.advance();
+        self.advance();
+        let body = Stmt::Block(self.block()?.into());
+        Ok(Stmt::For(keyword, None, None, None, Rc::new(body)))
     }
 
-    fn if_statement(&mut self) -> Result<Stmt, String> {
-        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.")?;
-        let cond = self.expression()?;
-        self.consume(TokenType::RightParen, "Expect ')' after if condition.")?;
-        let then_branch = Box::new(self.statement()?);
-        let else_branch: Option<Box<Stmt>> = if self.munch(&[TokenType::Else]) {
-            Some(Box::new(self.statement()?))
-        } else {
-            None
-        };
+    /// The arms of an `else if` chain are read in a loop, as they follow each
+    /// other in the source, and only nest in the tree made of them
+    fn if_statement(&mut self) -> Result<Stmt, LoxError> {
+        let mut arms = Vec::new();
+        let mut else_branch = None;
+        loop {
+            self.consume(TokenType::LeftParen, "Expect '(' after 'if'.")?;
+            let cond = self.expression()?;
+            self.consume(TokenType::RightParen, "Expect ')' after if condition.")?;
+            arms.push((Rc::new(cond), Rc::new(self.statement()?)));
+            if !self.munch(&[TokenType::Else]) {
+                break;
+            }
+            if !self.munch(&[TokenType::If]) {
+                else_branch = Some(Rc::new(self.statement()?));
+                break;
+            }
+        }
+        let (cond, then_branch) = arms.remove(0);
+        let else_branch =
+            arms.into_iter()
+                .rev()
+                .fold(else_branch, |else_branch, (cond, then_branch)| {
+                    Some(Rc::new(Stmt::If(cond, then_branch, else_branch)))
+                });
         Ok(Stmt::If(cond, then_branch, else_branch))
     }
 
-    fn print_statement(&mut self) -> Result<Stmt, String> {
+    fn print_statement(&mut self) -> Result<Stmt, LoxError> {
+        let keyword = self.previous().clone();
         let value = self.expression()?;
         self.consume(TokenType::Semicolon, "Expect ';' after value.")?;
-        Ok(Stmt::Print(value))
+        Ok(Stmt::Print(keyword, Rc::new(value)))
     }
 
-    fn return_statement(&mut self) -> Result<Stmt, String> {
-        let keyword = self.previous();
+    fn return_statement(&mut self) -> Result<Stmt, LoxError> {
+        let keyword = self.previous().clone();
         let mut value = Expr::Literal(Literal::None);
         if !self.check(TokenType::Semicolon) {
             value = self.expression()?;
         }
         self.consume(TokenType::Semicolon, "Expect ';' after return value.")?;
-        Ok(Stmt::Return(keyword, value))
+        Ok(Stmt::Return(keyword, Rc::new(value)))
     }
 
-    fn while_statement(&mut self) -> Result<Stmt, String> {
+    fn while_statement(&mut self) -> Result<Stmt, LoxError> {
+        let keyword = self.previous().clone();
         self.consume(TokenType::LeftParen, "Expect '(' after 'while'.")?;
         let cond = self.expression()?;
         self.consume(TokenType::RightParen, "Expect ')' after condition.")?;
         let body = self.statement()?;
 
-        Ok(Stmt::While(cond, Box::new(body)))
+        Ok(Stmt::While(keyword, Rc::new(cond), Rc::new(body)))
     }
 
-    fn expression_statement(&mut self) -> Result<Stmt, String> {
+    fn expression_statement(&mut self) -> Result<Stmt, LoxError> {
         let expr = self.expression()?;
         self.consume(TokenType::Semicolon, "Expect ';' after expression.")?;
-        Ok(Stmt::Expression(expr))
+        Ok(Stmt::Expression(Rc::new(expr)))
     }
 
-    fn block(&mut self) -> Result<Vec<Stmt>, String> {
+    fn block(&mut self) -> Result<Vec<Stmt>, LoxError> {
         let mut statements: Vec<Stmt> = Vec::new();
         while !self.check(TokenType::RightBrace) && !self.is_at_end() {
             match self.declaration() {
@@ -320,20 +520,30 @@ impl Parser {
         Ok(statements)
     }
 
-    fn expression(&mut self) -> Result<Expr, String> {
-        self.assignment()
+    fn expression(&mut self) -> Result<Expr, LoxError> {
+        self.nested(Parser::comma)
+    }
+
+    fn comma(&mut self) -> Result<Expr, LoxError> {
+        let mut expr: Expr = self.assignment()?;
+        while self.munch(&[TokenType::Comma]) {
+            let operator: Token = self.previous().clone();
+            let right: Expr = self.assignment()?;
+            expr = Expr::Binary(Rc::new(expr), operator, Rc::new(right));
+        }
+        Ok(expr)
     }
 
-    fn assignment(&mut self) -> Result<Expr, String> {
+    fn assignment(&mut self) -> Result<Expr, LoxError> {
         let expr = self.or()?;
         if self.munch(&[TokenType::Equal]) {
-            let equals = self.previous();
-            let value = self.assignment()?;
+            let equals = self.previous().clone();
+            let value = self.nested(Parser::assignment)?;
 
-            if let Expr::Variable(name) = expr {
-                return Ok(Expr::Assign(name, Box::new(value)));
-            } else if let Expr::Get(obj, name) = expr {
-                return Ok(Expr::Set(obj, name, Box::new(value)));
+            if let Expr::Variable(id, name) = expr {
+                return Ok(Expr::Assign(id, name, Rc::new(value)));
+            } else if let Expr::Get(_, obj, name) = expr {
+                return Ok(Expr::Set(obj, name, Rc::new(value)));
             }
 
             return Parser::error::<Expr>(&equals, "Invalid assignment target.");
@@ -341,38 +551,38 @@ impl Parser {
         Ok(expr)
     }
 
-    fn or(&mut self) -> Result<Expr, String> {
+    fn or(&mut self) -> Result<Expr, LoxError> {
         let mut expr: Expr = self.and()?;
         while self.munch(&[TokenType::Or]) {
-            let operator = self.previous();
+            let operator = self.previous().clone();
             let right = self.and()?;
-            expr = Expr::Logical(Box::new(expr), operator, Box::new(right));
+            expr = Expr::Logical(Rc::new(expr), operator, Rc::new(right));
         }
         Ok(expr)
     }
 
-    fn and(&mut self) -> Result<Expr, String> {
+    fn and(&mut self) -> Result<Expr, LoxError> {
         let mut expr: Expr = self.equality()?;
         while self.munch(&[TokenType::And]) {
-            let operator = self.previous();
+            let operator = self.previous().clone();
             let right = self.equality()?;
-            expr = Expr::Logical(Box::new(expr), operator, Box::new(right));
+            expr = Expr::Logical(Rc::new(expr), operator, Rc::new(right));
         }
         Ok(expr)
     }
 
-    fn equality(&mut self) -> Result<Expr, String> {
+    fn equality(&mut self) -> Result<Expr, LoxError> {
         let mut expr: Expr = self.comparison()?;
 
         while self.munch(&[TokenType::BangEqual, TokenType::EqualEqual]) {
-            let operator: Token = self.previous();
+            let operator: Token = self.previous().clone();
             let right: Expr = self.comparison()?;
-            expr = Expr::Binary(Box::new(expr), operator, Box::new(right));
+            expr = Expr::Binary(Rc::new(expr), operator, Rc::new(right));
         }
         Ok(expr)
     }
 
-    fn comparison(&mut self) -> Result<Expr, String> {
+    fn comparison(&mut self) -> Result<Expr, LoxError> {
         let mut expr: Expr = self.term()?;
 
         while self.munch(&[
@@ -381,45 +591,45 @@ impl Parser {
             TokenType::Less,
             TokenType::LessEqual,
         ]) {
-            let operator: Token = self.previous();
+            let operator: Token = self.previous().clone();
             let right: Expr = self.term()?;
-            expr = Expr::Binary(Box::new(expr), operator, Box::new(right));
+            expr = Expr::Binary(Rc::new(expr), operator, Rc::new(right));
         }
         Ok(expr)
     }
 
-    fn term(&mut self) -> Result<Expr, String> {
+    fn term(&mut self) -> Result<Expr, LoxError> {
         let mut expr: Expr = self.factor()?;
 
         while self.munch(&[TokenType::Minus, TokenType::Plus]) {
-            let operator: Token = self.previous();
+            let operator: Token = self.previous().clone();
             let right: Expr = self.factor()?;
-            expr = Expr::Binary(Box::new(expr), operator, Box::new(right));
+            expr = Expr::Binary(Rc::new(expr), operator, Rc::new(right));
         }
         Ok(expr)
     }
 
-    fn factor(&mut self) -> Result<Expr, String> {
+    fn factor(&mut self) -> Result<Expr, LoxError> {
         let mut expr: Expr = self.unary()?;
 
         while self.munch(&[TokenType::Slash, TokenType::Star]) {
-            let operator: Token = self.previous();
+            let operator: Token = self.previous().clone();
             let right: Expr = self.unary()?;
-            expr = Expr::Binary(Box::new(expr), operator, Box::new(right));
+            expr = Expr::Binary(Rc::new(expr), operator, Rc::new(right));
         }
         Ok(expr)
     }
 
-    fn unary(&mut self) -> Result<Expr, String> {
+    fn unary(&mut self) -> Result<Expr, LoxError> {
         if self.munch(&[TokenType::Bang, TokenType::Minus]) {
-            let operator: Token = self.previous();
-            let right: Expr = self.unary()?;
-            return Ok(Expr::Unary(operator, Box::new(right)));
+            let operator: Token = self.previous().clone();
+            let right: Expr = self.nested(Parser::unary)?;
+            return Ok(Expr::Unary(operator, Rc::new(right)));
         }
         self.call_expr()
     }
 
-    fn call_expr(&mut self) -> Result<Expr, String> {
+    fn call_expr(&mut self) -> Result<Expr, LoxError> {
         let mut expr: Expr = self.primary()?;
 
         loop {
@@ -428,7 +638,7 @@ impl Parser {
             } else if self.munch(&[TokenType::Dot]) {
                 let name: Token =
                     self.consume(TokenType::Identifier, "Expect property name after '.'.")?;
-                expr = Expr::Get(Box::new(expr), name);
+                expr = Expr::Get(ExprId::fresh(), Rc::new(expr), name);
             } else {
                 break;
             }
@@ -437,17 +647,18 @@ impl Parser {
         Ok(expr)
     }
 
-    fn finish_call(&mut self, callee: Expr) -> Result<Expr, String> {
-        let mut arguments: Vec<Expr> = Vec::new();
+    fn finish_call(&mut self, callee: Expr) -> Result<Expr, LoxError> {
+        let mut arguments: Vec<Rc<Expr>> = Vec::new();
         if !self.check(TokenType::RightParen) {
             loop {
                 if arguments.len() >= 255 {
                     return Parser::error::<Expr>(
-                        &self.peek(),
+                        self.peek(),
                         "Can't have more than 255 arguments.",
                     );
                 }
-                arguments.push(self.expression()?);
+                // Not `expression`, which would take the commas between them
+                arguments.push(Rc::new(self.nested(Parser::assignment)?));
                 if !self.munch(&[TokenType::Comma]) {
                     break;
                 }
@@ -456,10 +667,10 @@ impl Parser {
 
         let paren: Token = self.consume(TokenType::RightParen, "Expect ')' after arguments.")?;
 
-        Ok(Expr::Call(Box::new(callee), paren, arguments))
+        Ok(Expr::Call(Rc::new(callee), paren, arguments))
     }
 
-    fn primary(&mut self) -> Result<Expr, String> {
+    fn primary(&mut self) -> Result<Expr, LoxError> {
         if self.munch(&[TokenType::False]) {
             return Ok(Expr::Literal(Literal::Boolean(false)));
         }
@@ -471,80 +682,71 @@ impl Parser {
         }
 
         if self.munch(&[TokenType::Number, TokenType::String]) {
-            return Ok(Expr::Literal(self.previous().literal));
+            return Ok(Expr::Literal(self.previous().literal.clone()));
         }
 
         if self.munch(&[TokenType::Super]) {
-            let keyword: Token = self.previous();
+            let keyword: Token = self.previous().clone();
             self.consume(TokenType::Dot, "Expect '.' after 'super'.")?;
             let method: Token =
                 self.consume(TokenType::Identifier, "Expect superclass method name.")?;
-            return Ok(Expr::Super(keyword, method));
+            return Ok(Expr::Super(ExprId::fresh(), keyword, method));
         }
 
         if self.munch(&[TokenType::This]) {
-            return Ok(Expr::This(self.previous()));
+            return Ok(Expr::This(ExprId::fresh(), self.previous().clone()));
         }
 
         if self.munch(&[TokenType::Identifier]) {
-            return Ok(Expr::Variable(self.previous()));
+            return Ok(Expr::Variable(ExprId::fresh(), self.previous().clone()));
         }
 
         if self.munch(&[TokenType::LeftParen]) {
             let expr: Expr = self.expression()?;
             self.consume(TokenType::RightParen, "Expect ')' after expression.")?;
-            return Ok(Expr::Grouping(Box::new(expr)));
+            return Ok(Expr::Grouping(Rc::new(expr)));
         }
 
-        Parser::error::<Expr>(&self.peek(), "Expect expression.")
+        Parser::error::<Expr>(self.peek(), "Expect expression.")
     }
 
-    fn consume(&mut self, types: TokenType, message: &str) -> Result<Token, String> {
+    fn consume(&mut self, types: TokenType, message: &str) -> Result<Token, LoxError> {
         if self.check(types) {
             return Ok(self.advance());
         }
         let prev = self.previous();
+        // A missing ';' belongs to the end of the statement, not to whatever follows it
+        if types == TokenType::Semicolon {
+            return Parser::error::<Token>(prev, message);
+        }
         let msg = format!(
             "{} Last valid lexeme was '{}' at [line {}:{}].",
             message, prev.lexeme, prev.line, prev.column
         );
-        Parser::error::<Token>(&self.peek(), &msg)
+        Parser::error::<Token>(self.peek(), &msg)
     }
 
-    pub fn error<T>(token: &Token, message: &str) -> Result<T, String> {
-        if token.token == TokenType::Eof {
-            Err(format!(
-                "[line {}:{}] Error at end: {}",
-                token.line, token.column, message
-            ))
-        } else {
-            Err(format!(
-                "[line {}:{}] Error at '{}': {}",
-                token.line, token.column, token.lexeme, message
-            ))
-        }
+    pub fn error<T>(token: &Token, message: &str) -> Result<T, LoxError> {
+        Err(LoxError::ParseError {
+            token: Box::new(token.clone()),
+            message: message.into(),
+        })
     }
 
     fn synchronize(&mut self) {
-        self.advance();
+        // A keyword opening a new line most likely starts the next statement,
+        // e.g. after a missing ';', so it isn't skipped with the broken one
+        let prev_line = self.prev.as_ref().map_or(0, |x| x.line);
+        if !(self.peek().line > prev_line && self.starts_statement()) {
+            self.advance();
+        }
 
         while !self.is_at_end() {
             if self.previous().token == TokenType::Semicolon {
                 return;
             }
 
-            if [
-                TokenType::Class,
-                TokenType::Fun,
-                TokenType::Var,
-                TokenType::For,
-                TokenType::If,
-                TokenType::While,
-                TokenType::Print,
-                TokenType::Return,
-            ]
-            .contains(&(self.peek().token))
-            {
+            if self.starts_statement() {
                 return;
             }
 
@@ -552,6 +754,20 @@ impl Parser {
         }
     }
 
+    fn starts_statement(&mut self) -> bool {
+        [
+            TokenType::Class,
+            TokenType::Fun,
+            TokenType::Var,
+            TokenType::For,
+            TokenType::If,
+            TokenType::While,
+            TokenType::Print,
+            TokenType::Return,
+        ]
+        .contains(&(self.peek().token))
+    }
+
     fn munch(&mut self, types: &[TokenType]) -> bool {
         for token in types {
             if self.check(*token) {
@@ -569,28 +785,64 @@ impl Parser {
         self.peek().token == token
     }
 
+    /// Only the tokens that end up in the syntax tree are cloned, the others are
+    /// just looked at
     fn advance(&mut self) -> Token {
         if !self.is_at_end() {
             self.prev = self.tokens.next();
+            self.taken += 1;
         }
-        self.previous()
+        self.previous().clone()
     }
 
     fn is_at_end(&mut self) -> bool {
         self.peek().token == TokenType::Eof
     }
 
-    fn peek(&mut self) -> Token {
-        self.tokens
-            .peek()
-            .expect("No more tokens to be processed...")
-            .clone()
+    fn peek(&mut self) -> &Token {
+        match self.tokens.peek() {
+            Some(token) => token,
+            None => &self.eof,
+        }
     }
 
-    fn previous(&mut self) -> Token {
-        self.prev
-            .clone()
-            .expect("No previous token to be processed...")
-            .clone()
+    /// The current token when nothing has been consumed yet
+    fn previous(&mut self) -> &Token {
+        if self.prev.is_none() {
+            return self.peek();
+        }
+        self.prev.as_ref().unwrap_or(&self.eof)
+    }
+
+    fn nested<T>(&mut self, parse: fn(&mut Self) -> Result<T, LoxError>) -> Result<T, LoxError> {
+        if self.depth >= MAX_NESTING {
+            return Parser::error(self.peek(), "Too much nesting.");
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+}
+
+/// Scans and parses arbitrary bytes, broken UTF-8 included, which is all that a
+/// fuzzer needs to check that the front end reports errors instead of panicking
+pub fn parse_bytes(bytes: &[u8]) -> Result<Vec<Stmt>, Vec<LoxError>> {
+    let source = String::from_utf8_lossy(bytes);
+    let mut scanner = crate::scanner::Scanner::new(&source);
+    let (tokens, mut errors): (Vec<_>, Vec<_>) = scanner
+        .scan_tokens()
+        .iter()
+        .cloned()
+        .partition(|x| x.is_ok());
+    let tokens = tokens.into_iter().flatten().collect();
+    let mut errors: Vec<LoxError> = errors.drain(..).filter_map(|x| x.err()).collect();
+    match Parser::new(tokens).parse() {
+        Ok(statements) if errors.is_empty() => Ok(statements),
+        Ok(_) => Err(errors),
+        Err(parsed) => {
+            errors.extend(parsed);
+            Err(errors)
+        }
     }
 }
@@ -0,0 +1,42 @@
+// Paths, for the natives scripts take apart and put together file names with.
+// They go through `std::path`, so separators are the ones of the OS the
+// interpreter runs on, and only `absolute` looks at anything but the text.
+
+use std::path::Path;
+
+/// `path` followed by `other`, or `other` alone when it is absolute
+pub fn join(path: &str, other: &str) -> String {
+    Path::new(path).join(other).to_string_lossy().into_owned()
+}
+
+/// The last component, empty when there is none, as for `/` or `..`
+pub fn basename(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map_or(String::new(), |x| x.to_string_lossy().into_owned())
+}
+
+/// All but the last component, `.` when that is all there is
+pub fn dirname(path: &str) -> String {
+    match Path::new(path).parent() {
+        Some(parent) if parent.as_os_str().is_empty() => ".".into(),
+        Some(parent) => parent.to_string_lossy().into_owned(),
+        None => path.into(),
+    }
+}
+
+/// What follows the last dot of the last component, without it, and empty
+/// when there is no dot but at the start
+pub fn extension(path: &str) -> String {
+    Path::new(path)
+        .extension()
+        .map_or(String::new(), |x| x.to_string_lossy().into_owned())
+}
+
+/// `path` from the root, relative to the current directory when it isn't
+/// already. Neither needs to exist, and `..` is left as it is.
+pub fn absolute(path: &str) -> Result<String, String> {
+    std::path::absolute(path)
+        .map(|x| x.to_string_lossy().into_owned())
+        .map_err(|e| format!("Can't make '{}' absolute: {}.", path, e))
+}
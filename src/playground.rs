@@ -0,0 +1,95 @@
+// Runs scripts for a web page, which can't see stdout or stderr and can't stop
+// a script that never ends. Built for wasm32-unknown-unknown and passed through
+// wasm-bindgen, the page loads it with web/lox.js.
+
+use std::io::{self, Write};
+use std::time::Instant;
+
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::error::LoxError;
+use crate::interpreter::Interpreter;
+use crate::resolver::Resolver;
+use crate::sandbox::SandboxPolicy;
+use crate::shared::{Rc, RefCell};
+
+/// What running a script in the page gave
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Outcome {
+    pub stdout: String,
+    /// All the errors found before running, or the one that stopped it
+    pub errors: Vec<String>,
+}
+
+/// Where the interpreter prints to, shared with `run` to read it back
+#[derive(Clone, Default)]
+struct Captured(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub fn run(source: &str) -> Outcome {
+    run_with(source, None)
+}
+
+/// Like `run`, but stopping the script at `deadline` if it is still going
+pub fn run_until(source: &str, deadline: Instant) -> Outcome {
+    run_with(source, Some(deadline))
+}
+
+fn run_with(source: &str, deadline: Option<Instant>) -> Outcome {
+    let config = Config {
+        sandbox: SandboxPolicy::sandboxed(),
+        ..Config::default()
+    };
+    let mut interpreter = Interpreter::with_config(&config);
+    if let Some(deadline) = deadline {
+        interpreter.set_deadline(deadline);
+    }
+    let stdout = Captured::default();
+    interpreter.stdout = Box::new(stdout.clone());
+    interpreter.stderr = Box::new(io::sink());
+    let errors = match interpret(source, &mut interpreter) {
+        Ok(()) => Vec::new(),
+        Err(errors) => errors.iter().map(|x| x.to_string()).collect(),
+    };
+    drop(interpreter);
+    let stdout = String::from_utf8_lossy(&stdout.0.borrow()).into_owned();
+    Outcome { stdout, errors }
+}
+
+fn interpret(source: &str, interpreter: &mut Interpreter) -> Result<(), Vec<LoxError>> {
+    let statements = crate::parse(crate::scan(source)?)?;
+    Resolver::new(interpreter).resolve(&statements)?;
+    interpreter.interpret(&statements).map_err(|e| vec![e])?;
+    Ok(())
+}
+
+/// The exports the page calls, through the glue `wasm-bindgen` writes for it.
+/// Outcomes and highlighted parts go over as JSON, for `web/lox.js` to parse.
+#[cfg(target_arch = "wasm32")]
+mod wasm {
+    use wasm_bindgen::prelude::*;
+
+    /// Runs the script, giving back its outcome
+    #[wasm_bindgen]
+    pub fn run(source: &str) -> String {
+        serde_json::to_string(&super::run(source)).expect("Outcomes are plain data")
+    }
+
+    /// The parts of `highlight::highlight`, with their spans in bytes of UTF-8
+    #[wasm_bindgen]
+    pub fn highlight(source: &str) -> String {
+        let parts = crate::highlight::highlight(source);
+        serde_json::to_string(&parts).expect("Spans are plain data")
+    }
+}
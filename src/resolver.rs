@@ -1,12 +1,12 @@
-use std::cell::RefCell;
-use std::collections::HashMap;
-use std::rc::Rc;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 
-use crate::expr::Expr;
+use crate::error::{Lint, LoxError, Note, Warning};
+use crate::expr::{Expr, ExprId};
 use crate::interpreter::Interpreter;
-use crate::parser::Parser;
 use crate::scanner::{Literal, Token};
 use crate::stmt::Stmt;
+use crate::symbol::Symbol;
 
 #[derive(Debug, Clone, PartialEq, Copy)]
 enum FunctionType {
@@ -14,6 +14,19 @@ enum FunctionType {
     Function,
     Initializer,
     Method,
+    /// Called on the class itself, so there is no instance for `this` or `super`.
+    /// Nothing declares these yet, but the checks below already handle them.
+    #[allow(dead_code)]
+    StaticMethod,
+}
+
+impl FunctionType {
+    fn is_method(self) -> bool {
+        matches!(
+            self,
+            FunctionType::Initializer | FunctionType::Method | FunctionType::StaticMethod
+        )
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Copy)]
@@ -23,56 +36,172 @@ enum ClassType {
     SubClass,
 }
 
-pub struct Resolver {
-    interpreter: Rc<RefCell<Interpreter>>,
-    scopes: Vec<HashMap<String, bool>>,
+/// What the resolver knows about a variable in a local scope
+struct Local {
+    /// Where it was declared, `None` for `this` and `super`
+    name: Option<Token>,
+    defined: bool,
+    used: bool,
+    /// Declared with `const`, so never assigned to
+    constant: bool,
+    /// Where the interpreter keeps it in its scope
+    slot: usize,
+}
+
+impl Local {
+    /// Alone in a scope of its own, so always in the first slot
+    fn implicit() -> Self {
+        Local {
+            name: None,
+            defined: true,
+            used: true,
+            constant: false,
+            slot: 0,
+        }
+    }
+}
+
+/// Where a reference to a variable was found to point
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resolution {
+    /// How many scopes up from the reference it is, and its slot there
+    Local {
+        depth: usize,
+        slot: usize,
+    },
+    Global,
+}
+
+impl fmt::Display for Resolution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Resolution::Local { depth, slot } => write!(f, "depth {}, slot {}", depth, slot),
+            Resolution::Global => write!(f, "global"),
+        }
+    }
+}
+
+/// A variable read or assigned, `this` or `super`, and where it points
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reference {
+    pub name: Token,
+    pub resolution: Resolution,
+}
+
+pub struct Resolver<'a> {
+    interpreter: &'a mut Interpreter,
+    scopes: Vec<HashMap<Symbol, Local>>,
     current_function: FunctionType,
+    /// The innermost method, functions declared inside one are still part of it
+    current_method: FunctionType,
     current_class: ClassType,
+    /// The globals declared with `const` so far, until declared again otherwise
+    constants: HashSet<Symbol>,
+    errors: Vec<LoxError>,
+    warnings: Vec<Warning>,
+    /// Only kept once asked for with `recording_references`
+    references: Option<Vec<Reference>>,
 }
 
-impl Resolver {
-    pub fn new(interpreter: Rc<RefCell<Interpreter>>) -> Self {
+impl<'a> Resolver<'a> {
+    pub fn new(interpreter: &'a mut Interpreter) -> Self {
         Resolver {
             interpreter,
             scopes: Vec::new(),
             current_function: FunctionType::None,
+            current_method: FunctionType::None,
             current_class: ClassType::None,
+            constants: HashSet::new(),
+            errors: Vec::new(),
+            warnings: Vec::new(),
+            references: None,
+        }
+    }
+
+    /// Keeps every reference resolved, for `references` to tell where they
+    /// all point
+    pub fn recording_references(mut self) -> Self {
+        self.references = Some(Vec::new());
+        self
+    }
+
+    pub fn resolve(&mut self, statements: &[Stmt]) -> Result<(), Vec<LoxError>> {
+        self.resolve_all(statements);
+        self.warnings
+            .sort_by_key(|x| (x.token.line, x.token.column));
+        if let Some(references) = &mut self.references {
+            references.sort_by_key(|x| (x.name.line, x.name.column));
+        }
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(std::mem::take(&mut self.errors))
         }
     }
 
-    pub fn resolve(&mut self, statements: &[Stmt]) -> Result<(), String> {
-        let mut errs: Vec<String> = Vec::new();
+    /// Suspicious code found by `resolve`, in source order
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    /// The references resolved by `resolve`, in source order, if recorded
+    pub fn references(&self) -> &[Reference] {
+        self.references.as_deref().unwrap_or_default()
+    }
+
+    /// Resolves each statement, remembering its errors and carrying on with the next
+    fn resolve_all(&mut self, statements: &[Stmt]) {
         for statement in statements {
             if let Err(e) = self.resolve_stmt(statement) {
-                errs.push(e);
+                self.errors.push(e);
             }
         }
-        if errs.is_empty() {
-            Ok(())
-        } else {
-            Err(errs.join("\n"))
+        // Whatever follows a `return` never runs, one warning per block is enough
+        let unreachable = statements
+            .iter()
+            .skip_while(|x| !matches!(x, Stmt::Return(_, _)))
+            .nth(1)
+            .and_then(|x| x.token());
+        if let Some(token) = unreachable {
+            self.warn(Lint::Unreachable, token, "Unreachable code.");
         }
     }
 
-    fn resolve_stmt(&mut self, statement: &Stmt) -> Result<(), String> {
+    fn warn(&mut self, lint: Lint, token: &Token, message: &str) {
+        self.warnings.push(Warning {
+            lint,
+            token: Box::new(token.clone()),
+            message: message.into(),
+            note: None,
+        });
+    }
+
+    fn error(token: &Token, message: &str) -> Result<(), LoxError> {
+        Err(LoxError::ResolveError {
+            token: Box::new(token.clone()),
+            message: message.into(),
+        })
+    }
+
+    fn resolve_stmt(&mut self, statement: &Stmt) -> Result<(), LoxError> {
         match statement {
             Stmt::Block(statements) => {
                 self.begin_scope();
-                self.resolve(statements)?;
+                self.resolve_all(statements);
                 self.end_scope();
                 Ok(())
             }
-            Stmt::Class(name, superclass, methods) => {
+            Stmt::Class(name, superclass, methods, _) => {
                 let enclosing_class = self.current_class;
                 self.current_class = ClassType::Class;
 
                 self.declare(name)?;
                 self.define(name)?;
 
-                if matches!(superclass, Some(Expr::Variable(parent)) if name.lexeme == parent
+                if matches!(superclass, Some(Expr::Variable(_, parent)) if name.lexeme == parent
                     .lexeme)
                 {
-                    return Parser::error::<()>(name, "A class can't inherit from itself.");
+                    return Resolver::error(name, "A class can't inherit from itself.");
                 }
                 if let Some(parent) = superclass {
                     self.current_class = ClassType::SubClass;
@@ -83,17 +212,27 @@ impl Resolver {
                     self.begin_scope();
                     self.scopes
                         .last_mut()
-                        .map(|x| x.insert("super".to_string(), true));
+                        .map(|x| x.insert(Symbol::intern("super"), Local::implicit()));
                 }
 
                 self.begin_scope();
                 self.scopes
                     .last_mut()
-                    .map(|x| x.insert("this".to_string(), true));
+                    .map(|x| x.insert(Symbol::intern("this"), Local::implicit()));
 
+                let mut names = HashSet::new();
                 for method in methods {
+                    if let Stmt::Function(method_name, _, _, _) = method {
+                        if !names.insert(&method_name.lexeme) {
+                            // A later method would silently replace the earlier one
+                            self.errors.push(LoxError::ResolveError {
+                                token: Box::new(method_name.clone()),
+                                message: "Already a method with this name in this class.".into(),
+                            });
+                        }
+                    }
                     let declaration = match method {
-                        Stmt::Function(method_token, _, _) if method_token.lexeme == "init" => {
+                        Stmt::Function(method_token, _, _, _) if method_token.lexeme == "init" => {
                             FunctionType::Initializer
                         }
                         _ => FunctionType::Method,
@@ -110,14 +249,36 @@ impl Resolver {
                 self.current_class = enclosing_class;
                 Ok(())
             }
-            Stmt::Var(name, initializer) => {
+            // Each test runs in a scope of its own, once the script has run
+            Stmt::Test(name, body) => {
+                if !self.scopes.is_empty() {
+                    return Resolver::error(name, "Tests can only be declared at the top level.");
+                }
+                self.begin_scope();
+                self.resolve_all(body);
+                self.end_scope();
+                Ok(())
+            }
+            Stmt::Var(name, initializer, constant) => {
                 self.declare(name)?;
                 if let Some(init) = initializer {
                     self.resolve_expr(init)?;
                 }
+                if *constant {
+                    match self.scopes.last_mut() {
+                        Some(scope) => {
+                            if let Some(local) = scope.get_mut(&name.lexeme) {
+                                local.constant = true;
+                            }
+                        }
+                        None => {
+                            self.constants.insert(name.lexeme.clone());
+                        }
+                    }
+                }
                 self.define(name)
             }
-            Stmt::Function(name, _, _) => {
+            Stmt::Function(name, _, _, _) => {
                 self.declare(name)?;
                 self.define(name)?;
                 self.resolve_function(statement, FunctionType::Function)
@@ -126,43 +287,100 @@ impl Resolver {
             Stmt::If(condition, then_branch, maybe_else) => {
                 self.resolve_expr(condition)?;
                 self.resolve_stmt(then_branch)?;
-                if let Some(else_branch) = maybe_else {
-                    self.resolve_stmt(else_branch)?;
+                // Down `else if` chains in a loop, however long they are
+                let mut maybe_else = maybe_else;
+                while let Some(else_branch) = maybe_else {
+                    match &**else_branch {
+                        Stmt::If(condition, then_branch, next) => {
+                            self.resolve_expr(condition)?;
+                            self.resolve_stmt(then_branch)?;
+                            maybe_else = next;
+                        }
+                        _ => return self.resolve_stmt(else_branch),
+                    }
                 }
                 Ok(())
             }
-            Stmt::Print(expr) => self.resolve_expr(expr),
+            Stmt::Print(_, expr) => self.resolve_expr(expr),
             Stmt::Return(keyword, expr) => match self.current_function {
-                FunctionType::None => {
-                    Parser::error::<()>(keyword, "Can't return from top-level code.")
-                }
-                FunctionType::Initializer if !matches!(expr, Expr::Literal(Literal::None)) => {
-                    Parser::error::<()>(keyword, "Can't return a value from an initializer.")
+                FunctionType::None => Resolver::error(keyword, "Can't return from top-level code."),
+                FunctionType::Initializer if !matches!(**expr, Expr::Literal(Literal::None)) => {
+                    Resolver::error(keyword, "Can't return a value from an initializer.")
                 }
                 _ => self.resolve_expr(expr),
             },
-            Stmt::While(condition, body) => {
+            Stmt::While(_, condition, body) => {
                 self.resolve_expr(condition)?;
                 self.resolve_stmt(body)
             }
+            Stmt::For(keyword, initializer, condition, increment, body) => {
+                // Nothing but a `return` can end a `loop`, there being no `break`
+                if keyword.lexeme == "loop" && !returns(body) {
+                    self.warn(
+                        Lint::Endless,
+                        keyword,
+                        "Loop has no 'return' to leave it by.",
+                    );
+                }
+                // The initializer gets a scope of its own, shared by all iterations
+                self.begin_scope();
+                if let Some(init) = initializer {
+                    self.resolve_stmt(init)?;
+                }
+                if let Some(cond) = condition {
+                    self.resolve_expr(cond)?;
+                }
+                self.resolve_stmt(body)?;
+                if let Some(inc) = increment {
+                    self.resolve_expr(inc)?;
+                }
+                self.end_scope();
+                Ok(())
+            }
+            Stmt::ForIn(_, name, collection, body) => {
+                self.resolve_expr(collection)?;
+                // Every iteration binds the variable anew, in a scope of its own
+                self.begin_scope();
+                self.declare(name)?;
+                self.define(name)?;
+                self.resolve_stmt(body)?;
+                self.end_scope();
+                Ok(())
+            }
         }
     }
 
-    fn resolve_expr(&mut self, expr: &Expr) -> Result<(), String> {
+    fn resolve_expr(&mut self, expr: &Expr) -> Result<(), LoxError> {
         match expr {
-            Expr::Variable(name) => {
-                if let Some(false) = self.scopes.last().and_then(|x| x.get(&name.lexeme)) {
-                    return Parser::error::<()>(
+            Expr::Variable(id, name) => {
+                if let Some(false) = self
+                    .scopes
+                    .last()
+                    .and_then(|x| x.get(&name.lexeme))
+                    .map(|x| x.defined)
+                {
+                    return Resolver::error(
                         name,
                         "Can't read local variable in its own initializer.",
                     );
                 }
-                self.resolve_local(expr, name);
+                self.resolve_local(*id, name, true);
                 Ok(())
             }
-            Expr::Assign(name, value) => {
+            Expr::Assign(id, name, value) => {
                 self.resolve_expr(value)?;
-                self.resolve_local(expr, name);
+                let local = self.scopes.iter().rev().find_map(|x| x.get(&name.lexeme));
+                let constant = match local {
+                    Some(local) => local.constant,
+                    None => self.constants.contains(&name.lexeme),
+                };
+                if constant {
+                    return Resolver::error(
+                        name,
+                        &format!("Can't assign to constant '{}'.", name.lexeme),
+                    );
+                }
+                self.resolve_local(*id, name, false);
                 Ok(())
             }
             Expr::Binary(left, _, right) => {
@@ -176,29 +394,16 @@ impl Resolver {
                 }
                 Ok(())
             }
-            Expr::Get(obj, _) => self.resolve_expr(obj),
+            Expr::Get(_, obj, _) => self.resolve_expr(obj),
             Expr::Set(obj, _, val) => {
                 self.resolve_expr(val)?;
                 self.resolve_expr(obj)?;
                 Ok(())
             }
-            Expr::Super(keyword, _) => {
-                if self.current_class == ClassType::None {
-                    Parser::error::<()>(keyword, "Can't use 'super' outside of a class.")
-                } else if self.current_class != ClassType::SubClass {
-                    Parser::error::<()>(keyword, "Can't use 'super' in a class with no superclass.")
-                } else {
-                    self.resolve_local(expr, keyword);
-                    Ok(())
-                }
-            }
-            Expr::This(keyword) => {
-                if self.current_class == ClassType::None {
-                    Parser::error::<()>(keyword, "Can't use 'this' outside of a class.")
-                } else {
-                    self.resolve_local(expr, keyword);
-                    Ok(())
-                }
+            Expr::Super(id, keyword, _) | Expr::This(id, keyword) => {
+                self.check_instance(keyword)?;
+                self.resolve_local(*id, keyword, true);
+                Ok(())
             }
             Expr::Grouping(expr) => self.resolve_expr(expr),
             Expr::Literal(_) => Ok(()),
@@ -211,27 +416,74 @@ impl Resolver {
         }
     }
 
-    fn resolve_local(&mut self, expr: &Expr, name: &Token) {
-        for (i, scope) in self.scopes.iter().rev().enumerate() {
-            if scope.contains_key(&name.lexeme) {
-                self.interpreter.borrow_mut().resolve(expr, i);
+    /// Assigning a variable doesn't count as using it, only reading it does
+    fn resolve_local(&mut self, id: ExprId, name: &Token, read: bool) {
+        for (i, scope) in self.scopes.iter_mut().rev().enumerate() {
+            if let Some(local) = scope.get_mut(&name.lexeme) {
+                local.used |= read;
+                let (depth, slot) = (i, local.slot);
+                self.interpreter.resolve(id, depth, slot);
+                self.record(name, Resolution::Local { depth, slot });
                 return;
             }
         }
+        self.interpreter.resolve_global(id, &name.lexeme);
+        self.record(name, Resolution::Global);
+    }
+
+    fn record(&mut self, name: &Token, resolution: Resolution) {
+        if let Some(references) = &mut self.references {
+            references.push(Reference {
+                name: name.clone(),
+                resolution,
+            });
+        }
     }
 
-    fn resolve_function(&mut self, stmt: &Stmt, type_: FunctionType) -> Result<(), String> {
-        if let Stmt::Function(_, params, body) = stmt {
+    /// `this` and `super` need an instance of the class being declared
+    fn check_instance(&self, keyword: &Token) -> Result<(), LoxError> {
+        let word = &keyword.lexeme;
+        match (self.current_class, self.current_method) {
+            (ClassType::None, _) => Resolver::error(
+                keyword,
+                &format!("Can't use '{}' outside of a class.", word),
+            ),
+            (_, FunctionType::StaticMethod) => Resolver::error(
+                keyword,
+                &format!("Can't use '{}' in a static method.", word),
+            ),
+            (ClassType::Class, _) if word == "super" => {
+                Resolver::error(keyword, "Can't use 'super' in a class with no superclass.")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn resolve_function(&mut self, stmt: &Stmt, type_: FunctionType) -> Result<(), LoxError> {
+        if let Stmt::Function(_, params, body, _) = stmt {
             let enclosing_function = self.current_function;
+            let enclosing_method = self.current_method;
             self.current_function = type_;
+            if type_.is_method() {
+                self.current_method = type_;
+            }
             self.begin_scope();
-            for param in params {
+            for param in params.iter() {
                 self.declare(param)?;
                 self.define(param)?;
+                if let Some(local) = self
+                    .scopes
+                    .last_mut()
+                    .and_then(|x| x.get_mut(&param.lexeme))
+                {
+                    // Parameters often have to be there even when they aren't needed
+                    local.used = true;
+                }
             }
-            self.resolve(body)?;
+            self.resolve_all(body);
             self.end_scope();
             self.current_function = enclosing_function;
+            self.current_method = enclosing_method;
         }
         Ok(())
     }
@@ -241,26 +493,86 @@ impl Resolver {
     }
 
     fn end_scope(&mut self) {
-        self.scopes.pop();
+        let Some(scope) = self.scopes.pop() else {
+            return;
+        };
+        let mut unused: Vec<Token> = scope
+            .into_values()
+            .filter(|x| !x.used)
+            .filter_map(|x| x.name)
+            .collect();
+        unused.sort_by_key(|x| (x.line, x.column));
+        for name in unused {
+            self.warn(Lint::Unused, &name, "Local variable is never used.");
+        }
     }
 
-    fn declare(&mut self, name: &Token) -> Result<(), String> {
+    fn declare(&mut self, name: &Token) -> Result<(), LoxError> {
+        let shadowed = self
+            .scopes
+            .iter()
+            .rev()
+            .skip(1)
+            .find_map(|x| x.get(&name.lexeme))
+            .and_then(|x| x.name.clone());
+        if let Some(shadowed) = shadowed {
+            self.warnings.push(Warning {
+                lint: Lint::Shadow,
+                token: Box::new(name.clone()),
+                message: format!("Shadows a variable declared at {}.", shadowed.location()),
+                note: Some(Note {
+                    token: Box::new(shadowed),
+                    message: "The shadowed variable is declared here.".into(),
+                }),
+            });
+        }
+        if self.scopes.is_empty() {
+            self.constants.remove(&name.lexeme);
+        }
         if let Some(scope) = self.scopes.last_mut() {
             if scope.contains_key(&name.lexeme) {
-                return Parser::error::<()>(
-                    name,
-                    "Already a variable with this name in this scope.",
-                );
+                return Resolver::error(name, "Already a variable with this name in this scope.");
             }
-            scope.insert(name.lexeme.clone(), false);
+            let slot = scope.len();
+            scope.insert(
+                name.lexeme.clone(),
+                Local {
+                    name: Some(name.clone()),
+                    defined: false,
+                    used: false,
+                    constant: false,
+                    slot,
+                },
+            );
         }
         Ok(())
     }
 
-    fn define(&mut self, name: &Token) -> Result<(), String> {
-        if let Some(scope) = self.scopes.last_mut() {
-            scope.insert(name.lexeme.clone(), true);
+    fn define(&mut self, name: &Token) -> Result<(), LoxError> {
+        if let Some(local) = self.scopes.last_mut().and_then(|x| x.get_mut(&name.lexeme)) {
+            local.defined = true;
         }
         Ok(())
     }
 }
+
+/// Whether `statement` has a `return` in it somewhere, leaving aside the
+/// functions declared in it, which only return from themselves
+fn returns(statement: &Stmt) -> bool {
+    match statement {
+        Stmt::Return(_, _) => true,
+        Stmt::Block(statements) => statements.iter().any(returns),
+        Stmt::If(_, then_branch, else_branch) => {
+            returns(then_branch) || else_branch.as_deref().is_some_and(returns)
+        }
+        Stmt::For(_, _, _, _, body) | Stmt::ForIn(_, _, _, body) | Stmt::While(_, _, body) => {
+            returns(body)
+        }
+        Stmt::Class(_, _, _, _)
+        | Stmt::Expression(_)
+        | Stmt::Function(_, _, _, _)
+        | Stmt::Print(_, _)
+        | Stmt::Test(_, _)
+        | Stmt::Var(_, _, _) => false,
+    }
+}
@@ -0,0 +1,125 @@
+// An interpreter of its own thread, for servers evaluating scripts on behalf
+// of requests handled on many threads. The requests queue up and are run one
+// at a time, in the order they came, all with the same globals.
+//
+//     let runtime = Arc::new(LoxRuntime::new(&Config::default()));
+//     runtime.run("fun greet(name) { return \"Hello, \" + name; }")?;
+//     let greeting = runtime.call("greet", vec![Value::String("Ada".into())])?;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
+
+use crate::config::Config;
+use crate::error::LoxError;
+use crate::interpreter::Interpreter;
+use crate::value::Value;
+
+type Reply = mpsc::Sender<Result<Value, LoxError>>;
+
+enum Request {
+    Run(String, Reply),
+    Call(String, Vec<Value>, Reply),
+}
+
+/// A handle on the interpreter, which can be shared between threads. The
+/// thread stops once the handle is dropped, after the requests already made.
+pub struct LoxRuntime {
+    requests: Option<mpsc::Sender<Request>>,
+    interrupt: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl LoxRuntime {
+    pub fn new(config: &Config) -> Self {
+        LoxRuntime::with_setup(config, |_| ())
+    }
+
+    /// `setup` gets the interpreter first thing, to define natives say
+    pub fn with_setup(
+        config: &Config,
+        setup: impl FnOnce(&mut Interpreter) + Send + 'static,
+    ) -> Self {
+        let (requests, received) = mpsc::channel::<Request>();
+        let interrupt = Arc::new(AtomicBool::new(false));
+        let config = config.clone();
+        let flag = interrupt.clone();
+        let thread = thread::spawn(move || {
+            let mut interpreter = Interpreter::with_config(&config);
+            interpreter.set_interrupt(flag.clone());
+            setup(&mut interpreter);
+            for request in received {
+                // Only what runs once interrupted is stopped
+                flag.store(false, Ordering::Relaxed);
+                // The caller may have given up waiting, which is fine
+                let _ = match request {
+                    Request::Run(source, reply) => reply.send(interpreter.eval_source(&source)),
+                    Request::Call(name, arguments, reply) => {
+                        reply.send(call(&mut interpreter, &name, arguments))
+                    }
+                };
+            }
+        });
+        LoxRuntime {
+            requests: Some(requests),
+            interrupt,
+            thread: Some(thread),
+        }
+    }
+
+    /// Runs a script like `Interpreter::eval_source`, waiting for its turn
+    pub fn run(&self, source: &str) -> Result<Value, LoxError> {
+        self.request(|reply| Request::Run(source.to_string(), reply))
+    }
+
+    /// Calls the global function or class `name`, waiting for its turn
+    pub fn call(&self, name: &str, arguments: Vec<Value>) -> Result<Value, LoxError> {
+        self.request(|reply| Request::Call(name.to_string(), arguments, reply))
+    }
+
+    /// Stops the script running at the time, if any, which fails with a
+    /// runtime error. Requests still waiting for their turn aren't affected.
+    pub fn interrupt(&self) {
+        self.interrupt.store(true, Ordering::Relaxed);
+    }
+
+    fn request(&self, request: impl FnOnce(Reply) -> Request) -> Result<Value, LoxError> {
+        let (reply, result) = mpsc::channel();
+        if let Some(requests) = &self.requests {
+            // When the thread is gone, so is the reply, which `recv` tells
+            let _ = requests.send(request(reply));
+        }
+        result.recv().unwrap_or_else(|_| {
+            Err(LoxError::RuntimeError {
+                token: None,
+                message: "The runtime stopped, a native must have panicked.".to_string(),
+                trace: Vec::new(),
+            })
+        })
+    }
+}
+
+impl Drop for LoxRuntime {
+    fn drop(&mut self) {
+        // Ends the loop of the thread, once it got through the requests left
+        self.requests = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn call(
+    interpreter: &mut Interpreter,
+    name: &str,
+    arguments: Vec<Value>,
+) -> Result<Value, LoxError> {
+    let Some(callee) = interpreter.global(name) else {
+        return Err(LoxError::RuntimeError {
+            token: None,
+            message: format!("Undefined variable '{}'.", name),
+            trace: Vec::new(),
+        });
+    };
+    interpreter.call_value(&callee, arguments)
+}
@@ -0,0 +1,126 @@
+use std::fmt;
+
+use serde::Deserialize;
+
+use crate::config::Limits;
+
+/// What natives reaching outside of the interpreter need to be allowed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    Filesystem,
+    Network,
+    Process,
+    Environment,
+    /// Holding up the thread, with `sleep` or the timers of the event loop
+    Waiting,
+    /// Loading native code, which nothing else in the policy applies to
+    Extensions,
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Capability::Filesystem => "access to files",
+            Capability::Network => "access to the network",
+            Capability::Process => "running processes",
+            Capability::Environment => "access to the environment",
+            Capability::Waiting => "waiting",
+            Capability::Extensions => "loading native extensions",
+        })
+    }
+}
+
+/// What scripts may do outside of the interpreter, and caps on how much they
+/// may do inside of it. Privileged natives check it with `require` each time
+/// they are called, never when they are defined, so that they all fail the
+/// same way and a changed policy applies right away.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SandboxPolicy {
+    pub filesystem: bool,
+    pub network: bool,
+    pub process: bool,
+    pub environment: bool,
+    pub waiting: bool,
+    pub extensions: bool,
+    /// Caps on the limits, which can lower them but never raise them
+    pub max_stack_depth: Option<usize>,
+    pub max_steps: Option<u64>,
+    pub max_output_bytes: Option<usize>,
+    pub max_memory_bytes: Option<usize>,
+}
+
+/// Everything but native code, which has to be asked for, and no caps
+impl Default for SandboxPolicy {
+    fn default() -> Self {
+        SandboxPolicy {
+            filesystem: true,
+            network: true,
+            process: true,
+            environment: true,
+            waiting: true,
+            extensions: false,
+            max_stack_depth: None,
+            max_steps: None,
+            max_output_bytes: None,
+            max_memory_bytes: None,
+        }
+    }
+}
+
+impl SandboxPolicy {
+    /// Nothing outside of the interpreter, and caps enough for any example
+    /// while still stopping a script that loops forever in a second or so
+    pub fn sandboxed() -> Self {
+        SandboxPolicy {
+            filesystem: false,
+            network: false,
+            process: false,
+            environment: false,
+            waiting: false,
+            extensions: false,
+            max_stack_depth: Some(1000),
+            max_steps: Some(10_000_000),
+            max_output_bytes: Some(1 << 20),
+            max_memory_bytes: Some(64 << 20),
+        }
+    }
+
+    pub fn allows(&self, capability: Capability) -> bool {
+        match capability {
+            Capability::Filesystem => self.filesystem,
+            Capability::Network => self.network,
+            Capability::Process => self.process,
+            Capability::Environment => self.environment,
+            Capability::Waiting => self.waiting,
+            Capability::Extensions => self.extensions,
+        }
+    }
+
+    /// What privileged natives start with, failing with the message scripts
+    /// get when `capability` isn't allowed
+    pub fn require(&self, capability: Capability) -> Result<(), String> {
+        if self.allows(capability) {
+            Ok(())
+        } else {
+            Err(format!("The sandbox doesn't allow {}.", capability))
+        }
+    }
+
+    /// `limits`, lowered to the caps where they are above them or unbounded
+    pub fn cap(&self, limits: Limits) -> Limits {
+        Limits {
+            max_stack_depth: lower(limits.max_stack_depth, self.max_stack_depth),
+            max_steps: lower(limits.max_steps, self.max_steps),
+            max_output_bytes: lower(limits.max_output_bytes, self.max_output_bytes),
+            max_memory_bytes: lower(limits.max_memory_bytes, self.max_memory_bytes),
+        }
+    }
+}
+
+fn lower<T: Ord>(limit: Option<T>, cap: Option<T>) -> Option<T> {
+    match (limit, cap) {
+        (Some(limit), Some(cap)) => Some(limit.min(cap)),
+        (limit, cap) => limit.or(cap),
+    }
+}
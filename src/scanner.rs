@@ -1,17 +1,19 @@
 use itertools::peek_nth;
 
+use crate::shared::{Rc, RefCell};
 use itertools::structs::PeekNth;
-use itertools::Itertools;
-use std::cell::RefCell;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::fmt;
 use std::hash::{Hash, Hasher};
-use std::rc::Rc;
+use std::io::{self, BufRead};
 use std::str::Chars;
 
-use crate::lox_callable::{LoxCallable, LoxInstance};
+use crate::error::{Location, LoxError};
+use crate::symbol::Symbol;
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub enum TokenType {
     // Single-character tokens.
     LeftParen,
@@ -62,24 +64,49 @@ pub enum TokenType {
     Eof,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Literal {
+    /// Numbers written without a decimal point, unless too large for 64 bits
+    Integer(i64),
     Double(f64),
-    String(String),
+    String(Rc<str>),
     Boolean(bool),
-    Callable(LoxCallable),
-    LoxInstance(Rc<RefCell<LoxInstance>>),
     None,
 }
 
+/// 2^63, the first double past every integer
+const INTEGER_LIMIT: f64 = 9_223_372_036_854_775_808.0;
+
+/// How an integer compares to a double, exactly. Converting the integer to a
+/// double instead rounds it past 2^53, which makes neighbours equal. `None`
+/// when the double is NaN.
+pub(crate) fn compare_integer(int: i64, double: f64) -> Option<Ordering> {
+    if double.is_nan() {
+        None
+    } else if double >= INTEGER_LIMIT {
+        Some(Ordering::Less)
+    } else if double < -INTEGER_LIMIT {
+        Some(Ordering::Greater)
+    } else {
+        // Within the limits, the whole part converts without rounding
+        Some(
+            int.cmp(&(double.trunc() as i64))
+                .then(0.0.partial_cmp(&double.fract())?),
+        )
+    }
+}
+
 impl PartialEq for Literal {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
+            (Literal::Integer(a), Literal::Integer(b)) => a == b,
+            (Literal::Integer(a), Literal::Double(b))
+            | (Literal::Double(b), Literal::Integer(a)) => {
+                compare_integer(*a, *b) == Some(Ordering::Equal)
+            }
             (Literal::Double(a), Literal::Double(b)) => a == b,
             (Literal::String(a), Literal::String(b)) => a == b,
             (Literal::Boolean(a), Literal::Boolean(b)) => a == b,
-            (Literal::Callable(a), Literal::Callable(b)) => a == b,
-            (Literal::LoxInstance(a), Literal::LoxInstance(b)) => Rc::ptr_eq(a, b),
             (Literal::None, Literal::None) => true,
             _ => false,
         }
@@ -89,11 +116,10 @@ impl PartialEq for Literal {
 impl Hash for Literal {
     fn hash<H: Hasher>(&self, state: &mut H) {
         match self {
+            Literal::Integer(int) => (*int as f64).to_bits().hash(state),
             Literal::Double(float) => float.to_bits().hash(state),
             Literal::String(string) => string.hash(state),
             Literal::Boolean(boolean) => boolean.hash(state),
-            Literal::Callable(callable) => callable.hash(state),
-            Literal::LoxInstance(instance) => Rc::as_ptr(instance).hash(state),
             Literal::None => 0.hash(state),
         }
     }
@@ -102,24 +128,90 @@ impl Hash for Literal {
 impl fmt::Display for Literal {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Literal::Double(num) if num.fract() == 0.0 => write!(f, "{}", *num as i64),
+            Literal::Integer(num) => write!(f, "{}", num),
+            // Whole doubles too large for an integer are written out in full
+            Literal::Double(num) if num.fract() == 0.0 && num.abs() < INTEGER_LIMIT => {
+                write!(f, "{}", *num as i64)
+            }
             Literal::Double(num) => write!(f, "{}", num),
             Literal::String(s) => write!(f, "\"{}\"", s),
             Literal::Boolean(b) => write!(f, "{}", b),
-            Literal::Callable(lox) => write!(f, "{}", lox),
-            Literal::LoxInstance(lox) => write!(f, "{}", lox.borrow()),
             Literal::None => write!(f, "nil"),
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+impl From<f64> for Literal {
+    fn from(n: f64) -> Self {
+        Literal::Double(n)
+    }
+}
+
+impl From<&str> for Literal {
+    fn from(s: &str) -> Self {
+        Literal::String(Rc::from(s))
+    }
+}
+
+impl From<bool> for Literal {
+    fn from(b: bool) -> Self {
+        Literal::Boolean(b)
+    }
+}
+
+/// The conversions back hand the literal over again when it is of another kind
+impl TryFrom<Literal> for f64 {
+    type Error = Literal;
+
+    fn try_from(literal: Literal) -> Result<Self, Literal> {
+        match literal {
+            Literal::Integer(n) => Ok(n as f64),
+            Literal::Double(n) => Ok(n),
+            _ => Err(literal),
+        }
+    }
+}
+
+impl TryFrom<Literal> for String {
+    type Error = Literal;
+
+    fn try_from(literal: Literal) -> Result<Self, Literal> {
+        match literal {
+            Literal::String(s) => Ok(s.to_string()),
+            _ => Err(literal),
+        }
+    }
+}
+
+impl TryFrom<Literal> for bool {
+    type Error = Literal;
+
+    fn try_from(literal: Literal) -> Result<Self, Literal> {
+        match literal {
+            Literal::Boolean(b) => Ok(b),
+            _ => Err(literal),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Token {
     pub token: TokenType,
-    pub lexeme: String,
+    pub lexeme: Symbol,
     pub literal: Literal,
     pub line: usize,
     pub column: usize,
+    pub file: Option<Rc<str>>,
+}
+
+impl Token {
+    pub fn location(&self) -> Location {
+        Location {
+            file: self.file.clone(),
+            line: self.line,
+            column: self.column,
+        }
+    }
 }
 
 impl fmt::Display for Token {
@@ -128,16 +220,93 @@ impl fmt::Display for Token {
     }
 }
 
-pub struct Scanner<'a> {
-    chars: PeekNth<Chars<'a>>,
-    tokens: Vec<Result<Token, String>>,
-    current: usize,
-    line: usize,
+/// Where something is in the source, in bytes from where the scanner started
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A `//` comment, which the parser never sees but tools like the formatter
+/// need to keep around
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comment {
+    pub text: String,
+    pub line: usize,
+    /// Whether the comment follows some code on the same line
+    pub trailing: bool,
+    pub span: Span,
+}
+
+/// Scans the characters of `I`, by default those of a script already read in
+pub struct Scanner<'a, I: Iterator<Item = char> = Chars<'a>> {
+    chars: PeekNth<I>,
+    tokens: Vec<Result<Token, LoxError>>,
+    /// Where each of `tokens` is, errors included
+    spans: Vec<Span>,
+    comments: Vec<Comment>,
+    blank_lines: Vec<usize>,
+    /// How far into the script the scanner is
+    at: Mark,
+    /// Where the token being scanned starts
+    start: Mark,
+    /// Where each line starts, for the positions of tokens to be worked out
+    /// from where they start
+    line_starts: Vec<Mark>,
+    /// What the first line is, for scripts scanned from the middle
+    first_line: usize,
     keywords: HashMap<&'a str, TokenType>,
+    file: Option<Rc<str>>,
+    /// What stopped the reading of the script, when it is read as it goes
+    read_error: Option<Rc<RefCell<Option<io::Error>>>>,
+}
+
+impl<'a> Scanner<'a> {
+    pub fn new(source: &'a str) -> Scanner<'a> {
+        Scanner::with_file(source, None)
+    }
+
+    /// Scans `source` as if it were the rest of a bigger script from `line` and
+    /// `column` on, for the tokens to have their places in that script
+    pub fn resume(
+        source: &'a str,
+        line: usize,
+        column: usize,
+        file: Option<Rc<str>>,
+    ) -> Scanner<'a> {
+        // The first line starts that many characters before the source does
+        let at = Mark {
+            offset: 0,
+            chars: column.saturating_sub(1),
+        };
+        Scanner {
+            at,
+            start: at,
+            first_line: line,
+            ..Scanner::with_file(source, file)
+        }
+    }
+
+    pub fn with_file(source: &'a str, file: Option<Rc<str>>) -> Scanner<'a> {
+        Scanner::over(source.chars(), file)
+    }
+}
+
+impl<R: BufRead> Scanner<'static, ReadChars<R>> {
+    /// Scans a script while reading it, without ever holding all of its text.
+    /// If reading fails, the error is reported after the tokens read so far.
+    pub fn from_reader(reader: R, file: Option<Rc<str>>) -> Self {
+        let chars = ReadChars::new(reader);
+        let read_error = Some(chars.error.clone());
+        Scanner {
+            read_error,
+            ..Scanner::over(chars, file)
+        }
+    }
 }
 
-impl Scanner<'_> {
-    pub fn new(source: &str) -> Scanner {
+impl<'a, I: Iterator<Item = char>> Scanner<'a, I> {
+    fn over(chars: I, file: Option<Rc<str>>) -> Self {
         let keywords: HashMap<&str, TokenType> = [
             ("and", TokenType::And),
             ("class", TokenType::Class),
@@ -161,17 +330,26 @@ impl Scanner<'_> {
         .collect();
 
         Scanner {
-            chars: peek_nth(source.chars()),
+            chars: peek_nth(chars),
             tokens: Vec::new(),
-            current: 0,
-            line: 1,
+            spans: Vec::new(),
+            comments: Vec::new(),
+            blank_lines: Vec::new(),
+            at: Mark::default(),
+            start: Mark::default(),
+            line_starts: vec![Mark::default()],
+            first_line: 1,
             keywords,
+            file,
+            read_error: None,
         }
     }
 
     fn munch(&mut self, expected: char) -> bool {
         let res = self.chars.next_if_eq(&expected).is_some();
-        self.current += res as usize;
+        if res {
+            self.count(expected);
+        }
         res
     }
 
@@ -183,21 +361,101 @@ impl Scanner<'_> {
         *self.chars.peek_nth(1).unwrap_or(&'\0')
     }
 
+    /// Everything consumed goes through here, so that no character is missed
+    /// when counting where the lines start
     fn advance(&mut self) -> Option<char> {
-        self.current += 1;
-        self.chars.next()
+        let c = self.chars.next()?;
+        self.count(c);
+        Some(c)
     }
 
-    pub fn scan_tokens(&mut self) -> &[Result<Token, String>] {
+    fn count(&mut self, c: char) {
+        self.at.offset += c.len_utf8();
+        self.at.chars += 1;
+        if c == '\n' {
+            self.line_starts.push(self.at);
+        }
+    }
+
+    /// Consumes the characters up to the first one `accept` doesn't, and
+    /// returns them
+    fn advance_while(&mut self, mut accept: impl FnMut(char) -> bool) -> String {
+        let mut text = String::new();
+        while let Some(c) = self.chars.next_if(|&x| accept(x)) {
+            self.count(c);
+            text.push(c);
+        }
+        text
+    }
+
+    /// The line the scanner is on
+    fn line(&self) -> usize {
+        self.first_line + self.line_starts.len() - 1
+    }
+
+    /// The line and column of `mark`, the column counting characters, so that
+    /// tabs and characters taking several bytes count as one
+    fn position(&self, mark: Mark) -> (usize, usize) {
+        let index = self
+            .line_starts
+            .partition_point(|x| x.offset <= mark.offset)
+            - 1;
+        let column = mark.chars - self.line_starts[index].chars + 1;
+        (self.first_line + index, column)
+    }
+
+    pub fn scan_tokens(&mut self) -> &[Result<Token, LoxError>] {
+        self.scan_until(|_| false);
+        &self.tokens
+    }
+
+    /// Scans like [`Scanner::scan_tokens`], but stops right before the first
+    /// token `stop` accepts, leaving it out, and tells whether there was one
+    pub fn scan_until(&mut self, mut stop: impl FnMut(&Token) -> bool) -> bool {
         while self.chars.peek().is_some() {
+            let count = self.tokens.len();
             self.scan_token();
+            if let Some(Ok(token)) = self.tokens.get(count) {
+                if stop(token) {
+                    self.tokens.truncate(count);
+                    self.spans.truncate(count);
+                    return true;
+                }
+            }
         }
 
+        self.start = self.at;
+        let read_error = self.read_error.as_ref().and_then(|x| x.borrow_mut().take());
+        if let Some(e) = read_error {
+            self.error(&format!("Couldn't read the rest of the script: {}.", e));
+        }
         self.add_token(TokenType::Eof, "".into());
-        &self.tokens
+        false
+    }
+
+    pub fn into_tokens(self) -> Vec<Result<Token, LoxError>> {
+        self.tokens
+    }
+
+    /// Where each of the tokens of [`Scanner::scan_tokens`] is in the source,
+    /// errors included, the unterminated strings running to its end
+    pub fn spans(&self) -> &[Span] {
+        &self.spans
+    }
+
+    /// The comments seen by [`Scanner::scan_tokens`], in source order
+    pub fn comments(&self) -> &[Comment] {
+        &self.comments
+    }
+
+    /// The lines with nothing but whitespace on them
+    pub fn blank_lines(&self) -> &[usize] {
+        &self.blank_lines
     }
 
     fn scan_token(&mut self) {
+        self.start = self.at;
+        let line = self.line();
         let c = self.advance();
         match c {
             Some(x @ '(') => self.add_token(TokenType::LeftParen, x.into()),
@@ -211,130 +469,273 @@ impl Scanner<'_> {
             Some(x @ ';') => self.add_token(TokenType::Semicolon, x.into()),
             Some(x @ '*') => self.add_token(TokenType::Star, x.into()),
 
-            Some('!') if self.munch('=') => self.add_munched_token(TokenType::BangEqual, "!=".into()),
+            Some('!') if self.munch('=') => self.add_token(TokenType::BangEqual, "!=".into()),
             Some(x @ '!') => self.add_token(TokenType::Bang, x.into()),
-            Some('=') if self.munch('=') => self.add_munched_token(TokenType::EqualEqual, "==".into()),
+            Some('=') if self.munch('=') => self.add_token(TokenType::EqualEqual, "==".into()),
             Some(x @ '=') => self.add_token(TokenType::Equal, x.into()),
-            Some('<') if self.munch('=') => self.add_munched_token(TokenType::LessEqual, "<=".into()),
+            Some('<') if self.munch('=') => self.add_token(TokenType::LessEqual, "<=".into()),
             Some(x @ '<') => self.add_token(TokenType::Less, x.into()),
-            Some('>') if self.munch('=') => self.add_munched_token(TokenType::GreaterEqual, ">=".into()),
+            Some('>') if self.munch('=') => self.add_token(TokenType::GreaterEqual, ">=".into()),
             Some(x @ '>') => self.add_token(TokenType::Greater, x.into()),
 
             Some('/') if self.munch('/') => {
-                let _: String = self.chars.by_ref().take_while(|&x| x != '\n').collect();
-                self.line += 1;
-                self.current = 0;
+                let text = self.advance_while(|x| x != '\n');
+                let trailing = self
+                    .tokens
+                    .iter()
+                    .rev()
+                    .find_map(|x| x.as_ref().ok())
+                    .is_some_and(|x| x.line == line);
+                self.comments.push(Comment {
+                    text: format!("//{}", text.trim_end()),
+                    line,
+                    trailing,
+                    span: self.span(),
+                });
             }
             Some(x @ '/') => self.add_token(TokenType::Slash, x.into()),
             Some(' ') | Some('\t') | Some('\r') => (),
             Some('\n') => {
-                self.line += 1;
-                self.current = 0;
+                let comment = self.comments.last().map(|x| x.line);
+                let token = self.tokens.iter().rev().find_map(|x| x.as_ref().ok());
+                if comment != Some(line) && token.is_none_or(|x| x.line != line) {
+                    self.blank_lines.push(line);
+                }
             }
 
             Some('"') => {
-                let mut lines = 0;
-                let mut count = self.current;
-                let res: String = self
-                    .chars
-                    .take_while_ref(|&x| match x {
-                        '"' => false,
-                        '\n' => {
-                            lines += 1;
-                            count = 0;
-                            true
-                        }
-                        _ => {
-                            count += 1;
-                            true
+                // What the source says, and what it means once escapes are replaced
+                let (mut lexeme, mut text) = (String::new(), String::new());
+                let mut invalid = None;
+                loop {
+                    let plain = self.advance_while(|x| x != '"' && x != '\\');
+                    lexeme.push_str(&plain);
+                    text.push_str(&plain);
+                    let backslash = self.at;
+                    match self.advance() {
+                        Some('\\') => {
+                            let escaped = self.advance();
+                            lexeme.push('\\');
+                            lexeme.extend(escaped);
+                            match escaped.and_then(unescape) {
+                                Some(x) => text.push(x),
+                                // Kept as written, and the first one reported
+                                None => {
+                                    text.push('\\');
+                                    text.extend(escaped);
+                                    invalid = invalid.or(Some(backslash));
+                                }
+                            }
                         }
-                    })
-                    .collect();
-                if self.chars.peek().is_none() {
-                    self.tokens.push(Err(format!(
-                        "[line {}:{}] Error: Unterminated string.",
-                        self.line, self.current
-                    )))
-                } else {
-                    self.add_string_token(TokenType::String, &res);
-                    self.line += lines;
-                    self.current = count;
-                    self.advance(); // consume final "
+                        Some(_) => break,
+                        None => return self.error("Unterminated string."),
+                    }
+                }
+                // The string still goes to the parser, which has no reason to
+                // report more errors about it
+                self.add_string_token(TokenType::String, &lexeme, &text);
+                if let Some(backslash) = invalid {
+                    self.error_at(backslash, "Invalid escape sequence.");
                 }
             }
 
             Some(x) if x.is_ascii_digit() => {
                 let mut digits: String = x.to_string();
-                digits.extend(self.chars.take_while_ref(|y| y.is_ascii_digit()));
+                digits.push_str(&self.advance_while(|y| y.is_ascii_digit()));
                 if self.peek() == '.' && self.peek_next().is_ascii_digit() {
-                    digits.extend(self.chars.next());
-                    digits.extend(self.chars.take_while_ref(|y| y.is_ascii_digit()));
+                    digits.extend(self.advance());
+                    digits.push_str(&self.advance_while(|y| y.is_ascii_digit()));
                 }
-                let count = digits.len() - 1;
                 self.add_numeric_token(TokenType::Number, digits);
-                self.current += count;
             }
             Some(x) if x.is_alphabetic() || x == '_' => {
                 let mut ident: String = x.to_string();
-                ident.extend(
-                    self.chars
-                        .take_while_ref(|y| y.is_alphanumeric() || *y == '_'),
-                );
-                let count = ident.len() - 1;
+                ident.push_str(&self.advance_while(|y| y.is_alphanumeric() || y == '_'));
                 let token = self.keywords.get(ident.as_str()).copied();
                 match token {
                     Some(y) => self.add_token(y, ident),
                     None => self.add_token(TokenType::Identifier, ident),
                 }
-                self.current += count;
             }
 
-            _ => self.tokens.push(Err(format!(
-                "[line {}:{}] Error: Unexpected character.",
-                self.line, self.current
-            ))),
+            _ => self.error("Unexpected character."),
         }
     }
 
-    fn add_token(&mut self, token: TokenType, lexeme: String) {
-        self.tokens.push(Ok(Token {
-            token,
-            lexeme,
-            literal: Literal::None,
-            line: self.line,
-            column: self.current,
-        }));
+    /// Where the token being scanned is, from its start to where the scanner is
+    fn span(&self) -> Span {
+        Span {
+            start: self.start.offset,
+            end: self.at.offset,
+        }
     }
 
-    fn add_munched_token(&mut self, token: TokenType, lexeme: String) {
-        let offset = lexeme.len() - 1;
-        self.tokens.push(Ok(Token {
-            token,
-            lexeme,
-            literal: Literal::None,
-            line: self.line,
-            column: self.current - offset,
+    fn error(&mut self, message: &str) {
+        self.error_at(self.start, message);
+    }
+
+    /// An error about the token being scanned, pointing at `mark` in it
+    fn error_at(&mut self, mark: Mark, message: &str) {
+        self.spans.push(self.span());
+        let (line, column) = self.position(mark);
+        self.tokens.push(Err(LoxError::ScanError {
+            location: Location {
+                file: self.file.clone(),
+                line,
+                column,
+            },
+            message: message.into(),
         }));
     }
 
+    fn add_token(&mut self, token: TokenType, lexeme: String) {
+        self.add_literal_token(token, Symbol::intern(&lexeme), Literal::None);
+    }
+
     fn add_numeric_token(&mut self, token: TokenType, lexeme: String) {
-        let num = lexeme.parse::<f64>().unwrap_or(0.0);
+        let literal = match lexeme.parse::<i64>() {
+            Ok(int) => Literal::Integer(int),
+            Err(_) => Literal::Double(lexeme.parse::<f64>().unwrap_or(0.0)),
+        };
+        self.add_literal_token(token, Symbol::intern(&lexeme), literal);
+    }
+
+    /// The same strings in the source share their text in the literals too.
+    /// The lexeme is the string as written, `text` what its escapes stand for.
+    fn add_string_token(&mut self, token: TokenType, lexeme: &str, text: &str) {
+        let lexeme = Symbol::intern(lexeme);
+        let literal = Literal::String(Symbol::intern(text).as_rc());
+        self.add_literal_token(token, lexeme, literal);
+    }
+
+    fn add_literal_token(&mut self, token: TokenType, lexeme: Symbol, literal: Literal) {
+        self.spans.push(self.span());
+        let (line, column) = self.position(self.start);
         self.tokens.push(Ok(Token {
             token,
             lexeme,
-            literal: Literal::Double(num),
-            line: self.line,
-            column: self.current,
+            literal,
+            line,
+            column,
+            file: self.file.clone(),
         }));
     }
+}
 
-    fn add_string_token(&mut self, token: TokenType, lexeme: &str) {
-        self.tokens.push(Ok(Token {
-            token,
-            lexeme: lexeme.into(),
-            literal: Literal::String(lexeme.into()),
-            line: self.line,
-            column: self.current,
-        }));
+/// The character an escape sequence like `\n` stands for, given what follows
+/// the backslash
+fn unescape(escaped: char) -> Option<char> {
+    match escaped {
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        'r' => Some('\r'),
+        '"' => Some('"'),
+        '\\' => Some('\\'),
+        '0' => Some('\0'),
+        _ => None,
+    }
+}
+
+/// A place in the script, in bytes and in characters from where scanning started
+#[derive(Debug, Clone, Copy, Default)]
+struct Mark {
+    offset: usize,
+    chars: usize,
+}
+
+/// The characters of a UTF-8 script, decoded a buffer at a time as they are
+/// read. Broken UTF-8 reads as U+FFFD, as `String::from_utf8_lossy` has it,
+/// and the characters stop at the first error reading.
+pub struct ReadChars<R> {
+    reader: R,
+    decoded: String,
+    /// How far into `decoded` the characters were handed out
+    at: usize,
+    /// The start of a character whose other bytes haven't been read yet
+    pending: Vec<u8>,
+    error: Rc<RefCell<Option<io::Error>>>,
+}
+
+impl<R: BufRead> ReadChars<R> {
+    pub fn new(reader: R) -> Self {
+        ReadChars {
+            reader,
+            decoded: String::new(),
+            at: 0,
+            pending: Vec::new(),
+            error: Rc::new(RefCell::new(None)),
+        }
+    }
+
+    /// Decodes the next buffer, if there is one
+    fn refill(&mut self) -> bool {
+        loop {
+            let buffer = match self.reader.fill_buf() {
+                Ok(buffer) => buffer,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    *self.error.borrow_mut() = Some(e);
+                    return false;
+                }
+            };
+            if buffer.is_empty() && self.pending.is_empty() {
+                return false;
+            }
+            let mut bytes = std::mem::take(&mut self.pending);
+            bytes.extend_from_slice(buffer);
+            let read = buffer.len();
+            self.reader.consume(read);
+            // What is left of a character cut off at the end of the script is
+            // broken, but at the end of the buffer it waits for the next one
+            let complete = match read {
+                0 => bytes.len(),
+                _ => cut_off(&bytes),
+            };
+            self.pending = bytes.split_off(complete);
+            self.decoded = String::from_utf8_lossy(&bytes).into_owned();
+            self.at = 0;
+            if !self.decoded.is_empty() {
+                return true;
+            }
+        }
+    }
+}
+
+/// Where a character cut off at the end of `bytes` starts, or their length if
+/// there is none
+fn cut_off(bytes: &[u8]) -> usize {
+    for back in 1..=bytes.len().min(3) {
+        let byte = bytes[bytes.len() - back];
+        // Anything but a continuation byte starts a character
+        if byte & 0xc0 != 0x80 {
+            let width = match byte {
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                0xf0..=0xf7 => 4,
+                _ => 1,
+            };
+            return if width > back {
+                bytes.len() - back
+            } else {
+                bytes.len()
+            };
+        }
+    }
+    bytes.len()
+}
+
+impl<R: BufRead> Iterator for ReadChars<R> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        loop {
+            if let Some(c) = self.decoded[self.at..].chars().next() {
+                self.at += c.len_utf8();
+                return Some(c);
+            }
+            if !self.refill() {
+                return None;
+            }
+        }
     }
 }
@@ -0,0 +1,180 @@
+// `rjlox serve`, for trying Lox from a browser without installing anything: a
+// small HTTP server giving a page to write scripts in at `/`, and running the
+// scripts posted to `/run` the way the browser playground does, sandboxed and
+// within its limits, answering with what they printed and their errors as
+// JSON. Only as much HTTP as that takes, one thread per connection, with as
+// few connections and as little time for each as a public server needs.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tracing::{info, warn};
+
+use crate::playground;
+
+const PAGE: &str = include_str!("../web/index.html");
+
+/// Scripts longer than this are refused
+const MAX_BODY: usize = 1 << 20;
+
+/// The request line and headers longer than this, all together, are refused
+const MAX_HEAD: usize = 8 << 10;
+
+/// How long a request may take, from connecting to the script being done, and
+/// then how long answering it may take
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connections handled at once. Any more are turned away until one is done.
+const MAX_CONNECTIONS: usize = 64;
+
+/// What a request gets back
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn new(status: &'static str, content_type: &'static str, body: impl Into<Vec<u8>>) -> Self {
+        Response {
+            status,
+            content_type,
+            body: body.into(),
+        }
+    }
+
+    fn error(status: &'static str) -> Self {
+        Response::new(status, "text/plain; charset=utf-8", format!("{}\n", status))
+    }
+}
+
+/// Serves whoever connects to `listener`, until the process is stopped
+pub fn serve(listener: TcpListener) {
+    let open = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        match stream {
+            // Only this thread opens connections, so there can't be more
+            Ok(mut stream) if open.load(Ordering::Relaxed) >= MAX_CONNECTIONS => {
+                warn!("busy connections={}", MAX_CONNECTIONS);
+                let _ = stream.set_write_timeout(Some(TIMEOUT));
+                let _ = respond(&mut stream, &Response::error("503 Service Unavailable"));
+            }
+            Ok(stream) => {
+                let connection = Connection::open(&open);
+                let spawned = std::thread::Builder::new().spawn(move || {
+                    let _connection = connection;
+                    if let Err(e) = handle(stream, Instant::now() + TIMEOUT) {
+                        warn!("connection error={}", e);
+                    }
+                });
+                if let Err(e) = spawned {
+                    warn!("spawn error={}", e);
+                }
+            }
+            Err(e) => warn!("accept error={}", e),
+        }
+    }
+}
+
+/// Counts as an open connection until dropped
+struct Connection(Arc<AtomicUsize>);
+
+impl Connection {
+    fn open(open: &Arc<AtomicUsize>) -> Self {
+        open.fetch_add(1, Ordering::Relaxed);
+        Connection(open.clone())
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Reads from the client until the request is out of time
+struct Timed {
+    stream: TcpStream,
+    deadline: Instant,
+}
+
+impl Read for Timed {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        self.stream.read(buf)
+    }
+}
+
+fn handle(mut stream: TcpStream, deadline: Instant) -> io::Result<()> {
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(Timed {
+        stream: stream.try_clone()?,
+        deadline,
+    });
+    let mut head = (&mut reader).take(MAX_HEAD as u64);
+    let mut request_line = String::new();
+    head.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let mut length = 0;
+    let mut ended = false;
+    loop {
+        let mut header = String::new();
+        if head.read_line(&mut header)? == 0 || !header.ends_with('\n') {
+            break;
+        }
+        if header.trim_end().is_empty() {
+            ended = true;
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().unwrap_or(usize::MAX);
+            }
+        }
+    }
+    let too_long = !ended && head.limit() == 0;
+    let response = match (method, path) {
+        _ if too_long => Response::error("431 Request Header Fields Too Large"),
+        ("GET", "/") => Response::new("200 OK", "text/html; charset=utf-8", PAGE),
+        ("POST", "/run") if length > MAX_BODY => Response::error("413 Payload Too Large"),
+        ("POST", "/run") => {
+            let mut source = vec![0; length];
+            reader.read_exact(&mut source)?;
+            let outcome = playground::run_until(&String::from_utf8_lossy(&source), deadline);
+            let json = serde_json::to_vec(&outcome).expect("Outcomes are plain data");
+            Response::new("200 OK", "application/json", json)
+        }
+        (_, "/" | "/run") => Response::error("405 Method Not Allowed"),
+        _ => Response::error("404 Not Found"),
+    };
+    info!(
+        "request method={} path={} status={}",
+        method, path, response.status
+    );
+    respond(&mut stream, &response)?;
+    // Whatever is left of the request is read before closing, or the client
+    // could be reset before it gets the answer
+    stream.shutdown(Shutdown::Write)?;
+    io::copy(&mut reader.take(MAX_BODY as u64), &mut io::sink())?;
+    Ok(())
+}
+
+fn respond(stream: &mut TcpStream, response: &Response) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    )?;
+    stream.write_all(&response.body)?;
+    stream.flush()
+}
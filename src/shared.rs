@@ -0,0 +1,87 @@
+// How the interpreter shares and mutates its values, environments and syntax
+// trees. `Rc` and `RefCell` by default, or with the `sync` feature `Arc` and a
+// mutex with the same interface, so that a whole interpreter is `Send` and can
+// be handed from thread to thread, at some cost to single-threaded speed.
+
+#[cfg(not(feature = "sync"))]
+pub use std::cell::{Ref, RefCell, RefMut};
+#[cfg(not(feature = "sync"))]
+pub use std::rc::{Rc, Weak};
+
+#[cfg(feature = "sync")]
+pub use lock::{Ref, RefCell, RefMut};
+#[cfg(feature = "sync")]
+pub use std::sync::{Arc as Rc, Weak};
+
+/// What the objects of the heap have to be to go in a shared pointer: `Send`
+/// and `Sync` with the `sync` feature, anything without it
+#[cfg(not(feature = "sync"))]
+pub trait Shareable {}
+#[cfg(not(feature = "sync"))]
+impl<T: ?Sized> Shareable for T {}
+
+#[cfg(feature = "sync")]
+pub trait Shareable: Send + Sync {}
+#[cfg(feature = "sync")]
+impl<T: ?Sized + Send + Sync> Shareable for T {}
+
+/// Where the interpreter and the VM write what scripts print, stdout unless
+/// the host gives them something else
+#[cfg(not(feature = "sync"))]
+pub type Output = Box<dyn std::io::Write>;
+#[cfg(feature = "sync")]
+pub type Output = Box<dyn std::io::Write + Send>;
+
+#[cfg(feature = "sync")]
+mod lock {
+    use std::fmt;
+    use std::sync::{Mutex, MutexGuard, PoisonError};
+
+    pub type Ref<'a, T> = MutexGuard<'a, T>;
+    pub type RefMut<'a, T> = MutexGuard<'a, T>;
+
+    /// A `RefCell` as far as the interpreter uses one. Borrowing locks it,
+    /// mutably or not, so nothing may be borrowed twice at the same time.
+    #[derive(Default)]
+    pub struct RefCell<T: ?Sized>(Mutex<T>);
+
+    impl<T> RefCell<T> {
+        pub fn new(value: T) -> Self {
+            RefCell(Mutex::new(value))
+        }
+    }
+
+    impl<T: ?Sized> RefCell<T> {
+        pub fn borrow(&self) -> Ref<'_, T> {
+            // A panic while borrowed already ends the run, the value is as good
+            // as it was for the next one
+            self.0.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        pub fn borrow_mut(&self) -> RefMut<'_, T> {
+            self.borrow()
+        }
+    }
+
+    impl<T: ?Sized + PartialEq> PartialEq for RefCell<T> {
+        fn eq(&self, other: &Self) -> bool {
+            std::ptr::eq(self, other) || *self.borrow() == *other.borrow()
+        }
+    }
+
+    impl<T: ?Sized + fmt::Debug> fmt::Debug for RefCell<T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_tuple("RefCell").field(&&*self.borrow()).finish()
+        }
+    }
+}
+
+/// Fails to build with the `sync` feature if anything the interpreter holds on
+/// to can't be sent to another thread
+#[cfg(feature = "sync")]
+const _: fn() = || {
+    fn send<T: Send>() {}
+    send::<crate::interpreter::Interpreter>();
+    send::<crate::value::Value>();
+    send::<crate::environment::Environment>();
+};
@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+
+use crate::environment::Environment;
+use crate::gc::{Heap, Pinned, Trace, Tracer};
+use crate::lox_callable::{LoxClass, LoxInstance};
+use crate::shared::{Rc, RefCell};
+use crate::symbol::Symbol;
+use crate::value::Value;
+
+/// The global variables at some point, for [`Interpreter::restore`] to go back
+/// to. Instances are copied, along with the instances they lead to, so that
+/// changing them afterwards doesn't change the snapshot. Everything else stays
+/// shared, as it can't change: functions and classes find the globals through
+/// the slots the resolver gave them, and so see the restored values. All of it
+/// is pinned in the heap, so that the closures saved keep their environments.
+///
+/// [`Interpreter::restore`]: crate::interpreter::Interpreter::restore
+#[derive(Debug, Clone)]
+pub struct Snapshot(Pinned<Saves>);
+
+#[derive(Debug)]
+struct Saves {
+    /// Like [`Environment`] keeps them: `None` for the globals not declared yet
+    /// and `Some(None)` for the ones nothing was assigned to yet
+    globals: Vec<Option<Option<Saved>>>,
+    instances: Vec<SavedInstance>,
+}
+
+#[derive(Debug, Clone)]
+enum Saved {
+    Value(Value),
+    /// By index in the instances of the snapshot, for the ones referring to
+    /// each other to still do so once restored
+    Instance(usize),
+}
+
+#[derive(Debug, Clone)]
+struct SavedInstance {
+    class: Rc<LoxClass>,
+    fields: Vec<(Symbol, Saved)>,
+}
+
+impl Snapshot {
+    pub fn take(globals: &Environment, heap: &mut Heap) -> Self {
+        let mut saves = Saves {
+            globals: Vec::new(),
+            instances: Vec::new(),
+        };
+        let mut seen = HashMap::new();
+        saves.globals = globals
+            .global_values()
+            .iter()
+            .map(|x| {
+                x.as_ref()
+                    .map(|x| x.as_ref().map(|x| saves.save(x, &mut seen)))
+            })
+            .collect();
+        Snapshot(heap.pin(saves))
+    }
+
+    /// Puts the globals back as they were, with fresh copies of the instances.
+    /// Globals declared since are undeclared again.
+    pub fn restore(&self, globals: &mut Environment, heap: &mut Heap) {
+        self.0.restore(globals, heap)
+    }
+}
+
+impl Saves {
+    /// Copies the instances reachable from `value` that weren't yet, by address
+    fn save(&mut self, value: &Value, seen: &mut HashMap<usize, usize>) -> Saved {
+        let Value::LoxInstance(instance) = value else {
+            return Saved::Value(value.clone());
+        };
+        let address = Rc::as_ptr(instance) as *const () as usize;
+        if let Some(&index) = seen.get(&address) {
+            return Saved::Instance(index);
+        }
+        let index = self.instances.len();
+        seen.insert(address, index);
+        let (class, fields) = {
+            let instance = instance.borrow();
+            let fields: Vec<_> = instance
+                .fields()
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect();
+            (instance.class().clone(), fields)
+        };
+        self.instances.push(SavedInstance {
+            class,
+            fields: Vec::new(),
+        });
+        let fields = fields
+            .into_iter()
+            .map(|(name, value)| (name, self.save(&value, seen)))
+            .collect();
+        self.instances[index].fields = fields;
+        Saved::Instance(index)
+    }
+
+    fn restore(&self, globals: &mut Environment, heap: &mut Heap) {
+        let instances: Vec<Rc<RefCell<LoxInstance>>> = self
+            .instances
+            .iter()
+            .map(|x| {
+                let instance = Rc::new(RefCell::new(LoxInstance::new(x.class.clone())));
+                heap.register(&instance);
+                instance
+            })
+            .collect();
+        let value = |saved: &Saved| match saved {
+            Saved::Value(value) => value.clone(),
+            Saved::Instance(index) => Value::LoxInstance(instances[*index].clone()),
+        };
+        for (saved, instance) in self.instances.iter().zip(&instances) {
+            let mut instance = instance.borrow_mut();
+            for (name, field) in &saved.fields {
+                instance.set_field(name.clone(), value(field));
+            }
+        }
+        globals.restore_globals(
+            self.globals
+                .iter()
+                .map(|x| x.as_ref().map(|x| x.as_ref().map(value)))
+                .collect(),
+        );
+    }
+}
+
+impl Trace for Saves {
+    fn trace(&self, tracer: &mut Tracer) {
+        let fields = self.instances.iter().flat_map(|x| &x.fields);
+        let saved = self.globals.iter().flatten().flatten();
+        for saved in saved.chain(fields.map(|(_, x)| x)) {
+            if let Saved::Value(value) = saved {
+                value.trace(tracer);
+            }
+        }
+        self.instances.iter().for_each(|x| x.class.trace(tracer));
+    }
+}
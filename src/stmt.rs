@@ -1,29 +1,102 @@
 use crate::expr::vec_to_string;
-use crate::{expr::Expr, scanner::Token};
+use crate::shared::Rc;
+use crate::{
+    expr::Expr,
+    scanner::{Literal, Token},
+};
+use serde::{Deserialize, Serialize};
 use std::fmt;
 
-#[derive(Debug, Clone, PartialEq)]
+/// Like in `Expr`, whatever the interpreter may have to come back to is shared
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Stmt {
-    Block(Vec<Stmt>),
-    Class(Token, Option<Expr>, Vec<Stmt>),
-    Expression(Expr),
-    Function(Token, Vec<Token>, Vec<Stmt>),
-    If(Expr, Box<Stmt>, Option<Box<Stmt>>),
-    Print(Expr),
-    Return(Token, Expr),
-    Var(Token, Option<Expr>),
-    While(Expr, Box<Stmt>),
+    Block(Rc<[Stmt]>),
+    /// Classes and functions end with the `///` comments right before them,
+    /// without the slashes, for `rjlox doc` and editors
+    Class(Token, Option<Expr>, Vec<Stmt>, Option<Rc<str>>),
+    Expression(Rc<Expr>),
+    /// The `for` keyword comes first, so that the loop can be pointed at even
+    /// when all of its clauses are empty
+    For(
+        Token,
+        Option<Rc<Stmt>>,
+        Option<Rc<Expr>>,
+        Option<Rc<Expr>>,
+        Rc<Stmt>,
+    ),
+    /// `for (name in collection) body`, which asks the collection for an
+    /// iterator with `iterator()`, and then binds each `next()` of it to `name`
+    /// until it is `done()`
+    ForIn(Token, Token, Rc<Expr>, Rc<Stmt>),
+    /// The parameters and body are shared with every function made from the
+    /// declaration, so that neither running it nor binding methods copies them
+    Function(Token, Rc<[Token]>, Rc<[Stmt]>, Option<Rc<str>>),
+    If(Rc<Expr>, Rc<Stmt>, Option<Rc<Stmt>>),
+    Print(Token, Rc<Expr>),
+    Return(Token, Rc<Expr>),
+    /// `test "name" { ... }`, with the string token of the name. Skipped when
+    /// the script is run, only `rjlox test` runs the body.
+    Test(Token, Rc<[Stmt]>),
+    /// Flagged for `const` declarations, which always have an initializer
+    Var(Token, Option<Rc<Expr>>, bool),
+    /// With the `while` keyword, for the same reason as `For`
+    While(Token, Rc<Expr>, Rc<Stmt>),
+}
+
+impl Stmt {
+    /// The name of a test declaration, `None` for any other statement
+    pub fn test_name(&self) -> Option<&str> {
+        match self {
+            Stmt::Test(name, _) => match &name.literal {
+                Literal::String(name) => Some(name),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// A token to report the statement's position with, see [`Expr::token`].
+    pub fn token(&self) -> Option<&Token> {
+        match self {
+            Stmt::Block(statements) => statements.iter().find_map(|x| x.token()),
+            Stmt::Class(name, _, _, _) => Some(name),
+            Stmt::Expression(expr) => expr.token(),
+            Stmt::For(keyword, _, _, _, _)
+            | Stmt::ForIn(keyword, _, _, _)
+            | Stmt::While(keyword, _, _) => Some(keyword),
+            Stmt::Function(name, _, _, _) => Some(name),
+            Stmt::If(cond, then_branch, _) => cond.token().or_else(|| then_branch.token()),
+            Stmt::Print(keyword, _) => Some(keyword),
+            Stmt::Return(keyword, _) => Some(keyword),
+            Stmt::Test(name, _) => Some(name),
+            Stmt::Var(name, _, _) => Some(name),
+        }
+    }
 }
 
 impl fmt::Display for Stmt {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Stmt::Block(statements) => write!(f, "(block {})", vec_to_string(statements)),
-            Stmt::Class(name, _, methods) => {
+            Stmt::Class(name, _, methods, _) => {
                 write!(f, "(class {} ({}))", name.lexeme, vec_to_string(methods))
             }
             Stmt::Expression(expr) => write!(f, "{}", expr),
-            Stmt::Function(name, params, body) => write!(
+            Stmt::For(_, init, cond, inc, body) => {
+                let show = |x: Option<String>| x.unwrap_or("nil".into());
+                write!(
+                    f,
+                    "(for {} {} {} (body {}))",
+                    show(init.as_ref().map(|x| x.to_string())),
+                    show(cond.as_ref().map(|x| x.to_string())),
+                    show(inc.as_ref().map(|x| x.to_string())),
+                    body
+                )
+            }
+            Stmt::ForIn(_, name, collection, body) => {
+                write!(f, "(for {} in {} (body {}))", name.lexeme, collection, body)
+            }
+            Stmt::Function(name, params, body, _) => write!(
                 f,
                 "(fun {} ({}) ({}))",
                 name.lexeme,
@@ -36,11 +109,17 @@ impl fmt::Display for Stmt {
                 cond, then_branch, else_branch
             ),
             Stmt::If(cond, then_branch, None) => write!(f, "(if {} (then {}))", cond, then_branch),
-            Stmt::Print(expr) => write!(f, "(print {})", expr),
+            Stmt::Print(_, expr) => write!(f, "(print {})", expr),
             Stmt::Return(_token, value) => write!(f, "(return {})", value),
-            Stmt::Var(token, Some(expr)) => write!(f, "(var {} {})", token.lexeme, expr),
-            Stmt::Var(token, None) => write!(f, "(var {} nil)", token.lexeme),
-            Stmt::While(cond, body) => write!(f, "(while {} (body {}))", cond, body),
+            Stmt::Test(name, body) => {
+                write!(f, "(test {} ({}))", name.literal, vec_to_string(body))
+            }
+            Stmt::Var(token, Some(expr), true) => {
+                write!(f, "(const {} {})", token.lexeme, expr)
+            }
+            Stmt::Var(token, Some(expr), false) => write!(f, "(var {} {})", token.lexeme, expr),
+            Stmt::Var(token, None, _) => write!(f, "(var {} nil)", token.lexeme),
+            Stmt::While(_, cond, body) => write!(f, "(while {} (body {}))", cond, body),
         }
     }
 }
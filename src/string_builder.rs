@@ -0,0 +1,67 @@
+// `StringBuilder()`, for scripts putting a long string together piece by
+// piece. `s = s + piece;` copies all of `s` every time, which makes a loop
+// building a string quadratic, where appending to a builder only copies the
+// piece. Tree-walker only, like the other host objects.
+//
+//     var builder = StringBuilder();
+//     builder.append("a");
+//     builder.append(1);
+//     builder.len();       // 2
+//     builder.toString();  // "a1"
+
+use std::borrow::Cow;
+
+use crate::interpreter::Interpreter;
+use crate::lox_callable::NativeFunction;
+use crate::lox_object::LoxObject;
+use crate::shared::{Rc, RefCell};
+use crate::value::Value;
+
+#[derive(Debug, Default)]
+pub struct StringBuilder(RefCell<String>);
+
+impl LoxObject for StringBuilder {
+    fn class_name(&self) -> &str {
+        "StringBuilder"
+    }
+
+    fn get(&self, _: &str) -> Option<Value> {
+        None
+    }
+
+    fn method_arity(&self, name: &str) -> Option<usize> {
+        match name {
+            "toString" | "len" => Some(0),
+            "append" => Some(1),
+            _ => None,
+        }
+    }
+
+    fn call_method(
+        &self,
+        _: &mut Interpreter,
+        name: &str,
+        arguments: &[Value],
+    ) -> Result<Value, String> {
+        match name {
+            "append" => {
+                // Anything but a string as `print` shows it
+                let piece = match &arguments[0] {
+                    Value::String(text) => Cow::Borrowed(&**text),
+                    value => Cow::Owned(value.to_string()),
+                };
+                self.0.borrow_mut().push_str(&piece);
+                Ok(Value::None)
+            }
+            // In characters, like the string it makes
+            "len" => Ok(Value::Integer(self.0.borrow().chars().count() as i64)),
+            _ => Ok(Value::String(self.0.borrow().as_str().into())),
+        }
+    }
+}
+
+pub fn natives() -> Vec<NativeFunction> {
+    vec![NativeFunction::new("StringBuilder", 0, |_, _| {
+        Ok(Value::Foreign(Rc::new(StringBuilder::default())))
+    })]
+}
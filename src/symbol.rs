@@ -0,0 +1,149 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::{BuildHasherDefault, Hash, Hasher};
+use std::ops::Deref;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::shared::Rc;
+
+#[cfg(not(feature = "sync"))]
+thread_local! {
+    /// Every symbol made so far, shared by all the scanners of the interpreter
+    static SYMBOLS: std::cell::RefCell<HashSet<Rc<str>>> = Default::default();
+}
+
+/// Every symbol made so far, shared by all the interpreters, which can move from
+/// thread to thread and still compare their symbols
+#[cfg(feature = "sync")]
+static SYMBOLS: std::sync::LazyLock<std::sync::Mutex<HashSet<Rc<str>>>> =
+    std::sync::LazyLock::new(Default::default);
+
+#[cfg(not(feature = "sync"))]
+fn with_symbols<R>(f: impl FnOnce(&mut HashSet<Rc<str>>) -> R) -> R {
+    SYMBOLS.with_borrow_mut(f)
+}
+
+#[cfg(feature = "sync")]
+fn with_symbols<R>(f: impl FnOnce(&mut HashSet<Rc<str>>) -> R) -> R {
+    f(&mut SYMBOLS
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner))
+}
+
+/// An interned string. There is only ever one symbol with the same text, so
+/// comparing and hashing them only looks at the pointer.
+#[derive(Clone)]
+pub struct Symbol(Rc<str>);
+
+impl Symbol {
+    pub fn intern(text: &str) -> Self {
+        with_symbols(|symbols| match symbols.get(text) {
+            Some(symbol) => Symbol(symbol.clone()),
+            None => {
+                let symbol: Rc<str> = Rc::from(text);
+                symbols.insert(symbol.clone());
+                Symbol(symbol)
+            }
+        })
+    }
+
+    /// The text, shared with the symbol
+    pub fn as_rc(&self) -> Rc<str> {
+        self.0.clone()
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Symbol {}
+
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        &*self.0 == other
+    }
+}
+
+impl PartialEq<&str> for Symbol {
+    fn eq(&self, other: &&str) -> bool {
+        &*self.0 == *other
+    }
+}
+
+/// Saved as their text, and interned again when loaded
+impl Serialize for Symbol {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Symbol {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        Ok(Symbol::intern(&text))
+    }
+}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Rc::as_ptr(&self.0).cast::<u8>().hash(state)
+    }
+}
+
+/// Symbols only hash their pointer, which needs none of the protection the
+/// default hasher gives against crafted keys
+#[derive(Default)]
+pub struct SymbolHasher(u64);
+
+impl Hasher for SymbolHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_u64(byte as u64);
+        }
+    }
+
+    fn write_usize(&mut self, n: usize) {
+        self.write_u64(n as u64);
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.0 = (self.0.rotate_left(5) ^ n).wrapping_mul(0x517c_c1b7_2722_0a95);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// A map keyed by symbols, hashing them as fast as they compare
+pub type SymbolMap<V> = HashMap<Symbol, V, BuildHasherDefault<SymbolHasher>>;
+
+impl Deref for Symbol {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for Symbol {
+    fn from(text: &str) -> Self {
+        Symbol::intern(text)
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&*self.0, f)
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&*self.0, f)
+    }
+}
@@ -0,0 +1,85 @@
+// Terminal control, for the natives text-UI scripts draw with: the ANSI escape
+// sequences they print, and the size of the terminal they print them to.
+
+/// Clears the screen and puts the cursor at the top left
+pub const CLEAR_SCREEN: &str = "\x1b[2J\x1b[H";
+
+/// Moves the cursor to `row` and `column`, both counted from 1
+pub fn move_cursor(row: f64, column: f64) -> Result<String, String> {
+    let position = |n: f64| {
+        (n.fract() == 0.0 && n >= 1.0)
+            .then_some(n as u32)
+            .ok_or("Rows and columns must be whole numbers from 1.")
+    };
+    Ok(format!("\x1b[{};{}H", position(row)?, position(column)?))
+}
+
+/// Sets the color of the text printed next, or back to the terminal's own
+/// with `reset`
+pub fn set_color(name: &str) -> Result<&'static str, String> {
+    Ok(match name {
+        "reset" => "\x1b[0m",
+        "black" => "\x1b[30m",
+        "red" => "\x1b[31m",
+        "green" => "\x1b[32m",
+        "yellow" => "\x1b[33m",
+        "blue" => "\x1b[34m",
+        "magenta" => "\x1b[35m",
+        "cyan" => "\x1b[36m",
+        "white" => "\x1b[37m",
+        _ => return Err(format!("Unknown color '{}'.", name)),
+    })
+}
+
+/// Rows and columns of the terminal stdout is, or else of the one `LINES`
+/// and `COLUMNS` say, or else of the usual 24 by 80
+pub fn size() -> (u16, u16) {
+    let variable = |name| std::env::var(name).ok()?.parse().ok();
+    window_size()
+        .or_else(|| Some((variable("LINES")?, variable("COLUMNS")?)))
+        .unwrap_or((24, 80))
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn window_size() -> Option<(u16, u16)> {
+    use std::ffi::{c_int, c_ulong};
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct Winsize {
+        rows: u16,
+        columns: u16,
+        width: u16,
+        height: u16,
+    }
+    extern "C" {
+        fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
+    }
+    #[cfg(target_os = "linux")]
+    const TIOCGWINSZ: c_ulong = 0x5413;
+    #[cfg(target_os = "macos")]
+    const TIOCGWINSZ: c_ulong = 0x40087468;
+
+    let mut size = Winsize::default();
+    // Safe, as TIOCGWINSZ only ever writes a winsize, and fails on anything
+    // that isn't a terminal
+    let done = unsafe { ioctl(1, TIOCGWINSZ, &mut size as *mut Winsize) };
+    (done == 0 && size.rows > 0 && size.columns > 0).then_some((size.rows, size.columns))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn window_size() -> Option<(u16, u16)> {
+    None
+}
+
+/// Waits for `milliseconds`, for animations and games to go at a steady pace.
+/// Nothing at all when it isn't more than none.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn sleep(milliseconds: f64) -> Result<(), String> {
+    if milliseconds > 0.0 {
+        let duration = std::time::Duration::try_from_secs_f64(milliseconds / 1000.0)
+            .map_err(|_| format!("Can't sleep for {} milliseconds.", milliseconds))?;
+        std::thread::sleep(duration);
+    }
+    Ok(())
+}
@@ -0,0 +1,278 @@
+use crate::cli::exit_code;
+use crate::config::Config;
+use crate::error::LoxError;
+use crate::interpreter::Interpreter;
+use crate::resolver::Resolver;
+use crate::shared::Rc;
+use crate::stmt::Stmt;
+use colored::Colorize;
+use itertools::Itertools;
+use std::env;
+use std::fs;
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// What a test script expects to happen when it is run, read from the comments
+/// in it:
+///
+///   print 1; // expect: 1
+///   nil.foo; // expect runtime error: [line 1:5] RuntimeError: Only instances have properties.
+///   var;     // [line 1:4] Error at ';': Expect variable name.
+///
+/// Warnings come before any error on stderr, so they are only checked along
+/// with errors:
+///
+///   { var a; } // expect warning: [line 1:7] Warning at 'a': Local variable is never used.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expected {
+    Output(String),
+    RuntimeError(String),
+    CompileError(String),
+}
+
+impl Expected {
+    pub fn parse(source: &str) -> Self {
+        let warnings = collect(source, |line| line.split("// expect warning: ").nth(1));
+        let runtime_error = collect(source, |line| {
+            line.split("// expect runtime error: ").nth(1)
+        });
+        if !runtime_error.is_empty() {
+            return Expected::RuntimeError(warnings + &runtime_error);
+        }
+
+        let compile_error = collect(source, |line| {
+            let comment = &line[line.find("//")?..];
+            // Skip all errors supposed to be generated by clox
+            if comment.contains("[c line") || !comment.contains(" Error") {
+                return None;
+            }
+            line.split("// ").nth(1)
+        });
+        if !compile_error.is_empty() {
+            return Expected::CompileError(warnings + &compile_error);
+        }
+
+        Expected::Output(collect(source, |line| line.split("// expect: ").nth(1)))
+    }
+
+    pub fn code(&self) -> i32 {
+        match self {
+            Expected::Output(_) => exit_code::OK,
+            Expected::RuntimeError(_) => exit_code::SOFTWARE,
+            Expected::CompileError(_) => exit_code::DATA_ERROR,
+        }
+    }
+
+    /// Compares a run of the script with the expectations, describing what is
+    /// different when they don't match. Only stderr is checked for errors.
+    pub fn check(&self, code: i32, stdout: &str, stderr: &str) -> Result<(), String> {
+        let (stream, expected, actual) = match self {
+            Expected::Output(out) => ("stdout", out, stdout),
+            Expected::RuntimeError(err) | Expected::CompileError(err) => ("stderr", err, stderr),
+        };
+        let mut problems = Vec::new();
+        if code != self.code() {
+            problems.push(format!(
+                "Expected exit code {} but got {}.",
+                self.code(),
+                code
+            ));
+        }
+        if expected != actual {
+            problems.push(format!(
+                "Unexpected {}:\n{}",
+                stream,
+                diff(expected, actual)
+            ));
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.join("\n"))
+        }
+    }
+}
+
+fn collect<'a>(source: &'a str, expectation: impl Fn(&'a str) -> Option<&'a str>) -> String {
+    source
+        .lines()
+        .filter_map(expectation)
+        .map(|line| line.to_string() + "\n")
+        .collect()
+}
+
+fn diff(expected: &str, actual: &str) -> String {
+    let mut res = String::new();
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    for i in 0..expected.len().max(actual.len()) {
+        match (expected.get(i), actual.get(i)) {
+            (Some(x), Some(y)) if x == y => res += &format!("  {}\n", x),
+            (x, y) => {
+                if let Some(x) = x {
+                    res += &format!("{}\n", format!("- {}", x).green());
+                }
+                if let Some(y) = y {
+                    res += &format!("{}\n", format!("+ {}", y).red());
+                }
+            }
+        }
+    }
+    res
+}
+
+/// All the Lox scripts under `dir`, skipping anything inside `exclude`
+fn scripts(dir: &Path, exclude: &[PathBuf], found: &mut Vec<PathBuf>) -> Result<(), String> {
+    if exclude.iter().any(|x| dir.starts_with(x)) {
+        return Ok(());
+    }
+    if dir.is_file() {
+        found.push(dir.to_path_buf());
+        return Ok(());
+    }
+    let entries = fs::read_dir(dir)
+        .map_err(|e| format!("Can't read test directory '{}': {}", dir.display(), e))?;
+    for path in entries.flatten().map(|entry| entry.path()) {
+        if path.is_dir() {
+            scripts(&path, exclude, found)?;
+        } else if path.extension().is_some_and(|ext| ext == "lox")
+            && !exclude.iter().any(|x| path.starts_with(x))
+        {
+            found.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn run_script(exe: &Path, script: &Path, source: &str) -> Result<(), String> {
+    let expected = Expected::parse(source);
+    let output = Command::new(exe)
+        .arg(script)
+        .output()
+        .map_err(|e| format!("Can't run the script: {}", e))?;
+    expected.check(
+        output.status.code().unwrap_or(-1),
+        &String::from_utf8_lossy(&output.stdout),
+        &String::from_utf8_lossy(&output.stderr),
+    )
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    passed: usize,
+    failed: usize,
+    filtered: usize,
+}
+
+impl Counts {
+    fn fail(&mut self, what: String, problem: &str) {
+        self.failed += 1;
+        println!("{} {}", "FAIL".red(), what);
+        println!("{}", problem);
+    }
+}
+
+/// The statements of a script declaring tests, `None` for the others, which
+/// are checked against their comments instead. Scripts that don't parse are
+/// among them, as they may well be expected not to.
+fn suite(source: &str) -> Option<Vec<Stmt>> {
+    let statements = crate::parse(crate::scan(source).ok()?).ok()?;
+    statements
+        .iter()
+        .any(|x| matches!(x, Stmt::Test(_, _)))
+        .then_some(statements)
+}
+
+/// Runs the script, then each of its tests whose name contains `filter`,
+/// all starting from the globals the script left
+fn run_suite(
+    script: &Path,
+    statements: &[Stmt],
+    filter: Option<&str>,
+    config: &Config,
+    counts: &mut Counts,
+) {
+    let mut interpreter = Interpreter::with_config(config);
+    interpreter.script = Rc::from(script.display().to_string());
+    let ran = Resolver::new(&mut interpreter)
+        .resolve(statements)
+        .and_then(|_| interpreter.interpret(statements).map_err(|e| vec![e]));
+    if let Err(errors) = ran {
+        let problem = errors.iter().map(describe).join("\n");
+        counts.fail(script.display().to_string(), &problem);
+        return;
+    }
+    for statement in statements {
+        let (Some(name), Stmt::Test(_, body)) = (statement.test_name(), statement) else {
+            continue;
+        };
+        if filter.is_some_and(|x| !name.contains(x)) {
+            counts.filtered += 1;
+            continue;
+        }
+        match interpreter.run_test(body) {
+            Ok(()) => counts.passed += 1,
+            Err(e) => counts.fail(format!("{}: {}", script.display(), name), &describe(&e)),
+        }
+    }
+}
+
+/// An error along with the calls that led to it, natives included, which is
+/// all that tells where a failed assertion was
+fn describe(error: &LoxError) -> String {
+    iter::once(error.to_string())
+        .chain(error.trace().iter().map(|x| format!("  {}", x)))
+        .join("\n")
+}
+
+/// Runs every script under `dir` in its own interpreter and checks it against
+/// the expectations in its comments, or runs its tests when it declares some.
+/// Prints the failures and a summary. With a `filter`, only the tests whose
+/// name contains it are run.
+pub fn run(dir: &Path, exclude: &[PathBuf], filter: Option<&str>, config: &Config) -> i32 {
+    let exe = env::current_exe().expect("Can't find the rjlox executable...");
+    let mut found = Vec::new();
+    if let Err(e) = scripts(dir, exclude, &mut found) {
+        eprintln!("{}", format!("Error: {}", e).red());
+        return exit_code::NO_INPUT;
+    }
+    found.sort();
+
+    let mut counts = Counts::default();
+    for script in &found {
+        let source = match fs::read(script).map(|x| String::from_utf8_lossy(&x).into_owned()) {
+            Ok(source) => source,
+            Err(e) => {
+                counts.fail(
+                    script.display().to_string(),
+                    &format!("Can't read the script: {}", e),
+                );
+                continue;
+            }
+        };
+        if let Some(statements) = suite(&source) {
+            run_suite(script, &statements, filter, config, &mut counts);
+        } else if filter.is_some() {
+            counts.filtered += 1;
+        } else {
+            match run_script(&exe, script, &source) {
+                Ok(()) => counts.passed += 1,
+                Err(e) => counts.fail(script.display().to_string(), &e),
+            }
+        }
+    }
+
+    let mut summary = format!("{} passed, {} failed", counts.passed, counts.failed);
+    if filter.is_some() {
+        summary += &format!(", {} filtered out", counts.filtered);
+    }
+    summary.push('.');
+    if counts.failed == 0 {
+        println!("{}", summary.green());
+        exit_code::OK
+    } else {
+        println!("{}", summary.red());
+        exit_code::FAILURE
+    }
+}
@@ -0,0 +1,79 @@
+// Timers for the tree-walker's event loop: `setTimeout` and `setInterval` set
+// them, `clearTimer` clears them, and `runEventLoop` calls them back as they
+// come due, until there are none left.
+
+use std::time::{Duration, Instant};
+
+use crate::gc::{Trace, Tracer};
+use crate::value::Value;
+
+#[derive(Default)]
+pub struct Timers {
+    timers: Vec<Timer>,
+    /// Ids are never reused, so clearing a timer that already went off can't
+    /// clear another
+    last_id: u32,
+    /// Orders timers due at the same time by when they were set, intervals
+    /// by when they last went off, so that none keeps the others waiting
+    scheduled: u64,
+}
+
+struct Timer {
+    id: u32,
+    due: Instant,
+    scheduled: u64,
+    /// Intervals go off again this long after they were due
+    every: Option<Duration>,
+    callback: Value,
+}
+
+impl Timers {
+    /// Sets a timer going off after `delay`, and every `delay` after that if
+    /// it `repeats`. Gives its id.
+    pub fn set(&mut self, callback: Value, delay: Duration, repeats: bool) -> u32 {
+        self.last_id += 1;
+        self.scheduled += 1;
+        self.timers.push(Timer {
+            id: self.last_id,
+            due: Instant::now() + delay,
+            scheduled: self.scheduled,
+            every: repeats.then_some(delay),
+            callback,
+        });
+        self.last_id
+    }
+
+    /// Nothing when there is no such timer, or it already went off
+    pub fn clear(&mut self, id: u32) {
+        self.timers.retain(|x| x.id != id);
+    }
+
+    /// Waits for the next timer to come due and gives its callback, `None`
+    /// when there are no timers left. Timeouts are cleared and intervals set
+    /// to go off again.
+    pub fn wait(&mut self) -> Option<Value> {
+        let (next, timer) = self
+            .timers
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, x)| (x.due, x.scheduled))?;
+        std::thread::sleep(timer.due.saturating_duration_since(Instant::now()));
+        match timer.every {
+            Some(every) => {
+                self.scheduled += 1;
+                let timer = &mut self.timers[next];
+                // Late ones go off once, not once for every time they missed
+                timer.due = (timer.due + every).max(Instant::now());
+                timer.scheduled = self.scheduled;
+                Some(timer.callback.clone())
+            }
+            None => Some(self.timers.remove(next).callback),
+        }
+    }
+}
+
+impl Trace for Timers {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.timers.iter().for_each(|x| x.callback.trace(tracer));
+    }
+}
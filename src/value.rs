@@ -0,0 +1,176 @@
+use crate::shared::Rc;
+use crate::shared::RefCell;
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+use crate::gc::{Trace, Tracer};
+use crate::lox_callable::{LoxCallable, LoxInstance};
+use crate::lox_object::LoxObject;
+use crate::scanner::{compare_integer, Literal};
+
+/// What the tree-walker computes with. Only numbers, strings, booleans and nil
+/// can be written in the source, the rest only exist while running.
+#[derive(Debug, Clone)]
+pub enum Value {
+    /// Numbers written without a decimal point, and what arithmetic on them
+    /// gives while it fits
+    Integer(i64),
+    Double(f64),
+    String(Rc<str>),
+    Boolean(bool),
+    Callable(LoxCallable),
+    LoxInstance(Rc<RefCell<LoxInstance>>),
+    /// An object of the host, which scripts use like an instance
+    Foreign(Rc<dyn LoxObject>),
+    None,
+}
+
+impl From<&Literal> for Value {
+    fn from(literal: &Literal) -> Self {
+        match literal {
+            Literal::Integer(n) => Value::Integer(*n),
+            Literal::Double(n) => Value::Double(*n),
+            Literal::String(s) => Value::String(s.clone()),
+            Literal::Boolean(b) => Value::Boolean(*b),
+            Literal::None => Value::None,
+        }
+    }
+}
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        Value::Double(n)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(Rc::from(s))
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Boolean(b)
+    }
+}
+
+/// Integers come out as doubles too, like natives taking numbers see them
+impl TryFrom<Value> for f64 {
+    type Error = Value;
+
+    fn try_from(value: Value) -> Result<Self, Value> {
+        value.number().ok_or(value)
+    }
+}
+
+impl TryFrom<Value> for String {
+    type Error = Value;
+
+    fn try_from(value: Value) -> Result<Self, Value> {
+        match value {
+            Value::String(s) => Ok(s.to_string()),
+            _ => Err(value),
+        }
+    }
+}
+
+impl TryFrom<Value> for bool {
+    type Error = Value;
+
+    fn try_from(value: Value) -> Result<Self, Value> {
+        match value {
+            Value::Boolean(b) => Ok(b),
+            _ => Err(value),
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Integer(a), Value::Integer(b)) => a == b,
+            (Value::Integer(a), Value::Double(b)) | (Value::Double(b), Value::Integer(a)) => {
+                compare_integer(*a, *b) == Some(Ordering::Equal)
+            }
+            (Value::Double(a), Value::Double(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Boolean(a), Value::Boolean(b)) => a == b,
+            (Value::Callable(a), Value::Callable(b)) => a == b,
+            (Value::LoxInstance(a), Value::LoxInstance(b)) => Rc::ptr_eq(a, b),
+            (Value::Foreign(a), Value::Foreign(b)) => Rc::ptr_eq(a, b),
+            (Value::None, Value::None) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            // As the double it equals, if any
+            Value::Integer(int) => (*int as f64).to_bits().hash(state),
+            Value::Double(float) => float.to_bits().hash(state),
+            Value::String(string) => string.hash(state),
+            Value::Boolean(boolean) => boolean.hash(state),
+            Value::Callable(callable) => callable.hash(state),
+            Value::LoxInstance(instance) => Rc::as_ptr(instance).hash(state),
+            Value::Foreign(object) => (Rc::as_ptr(object) as *const ()).hash(state),
+            Value::None => 0.hash(state),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Integer(num) => write!(f, "{}", num),
+            Value::Double(num) => write!(f, "{}", Literal::Double(*num)),
+            Value::String(s) => write!(f, "\"{}\"", s),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Callable(lox) => write!(f, "{}", lox),
+            Value::LoxInstance(lox) => write!(f, "{}", lox.borrow()),
+            Value::Foreign(object) => write!(f, "{} instance", object.class_name()),
+            Value::None => write!(f, "nil"),
+        }
+    }
+}
+
+impl Value {
+    /// A number of either kind as a double, for natives taking numbers
+    pub fn number(&self) -> Option<f64> {
+        match self {
+            Value::Integer(n) => Some(*n as f64),
+            Value::Double(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// Roughly how many bytes it takes, with the text of strings
+    pub fn size(&self) -> usize {
+        match self {
+            Value::String(s) => size_of::<Value>() + s.len(),
+            _ => size_of::<Value>(),
+        }
+    }
+}
+
+impl Trace for Value {
+    fn trace(&self, tracer: &mut Tracer) {
+        match self {
+            Value::Callable(callable) => callable.trace(tracer),
+            Value::LoxInstance(instance) => tracer.mark(instance),
+            Value::Foreign(object) => {
+                if tracer.visit(object) {
+                    object.trace(tracer);
+                }
+            }
+            Value::Integer(_)
+            | Value::Double(_)
+            | Value::String(_)
+            | Value::Boolean(_)
+            | Value::None => (),
+        }
+    }
+}
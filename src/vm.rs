@@ -0,0 +1,1289 @@
+use crate::shared::RefCell;
+use crate::shared::{Output, Rc};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{self, Write};
+use tracing::{debug_span, Span};
+
+use crate::call_span::CallSpan;
+use crate::chunk::{Chunk, Op, Site};
+use crate::config::{Config, Limits};
+use crate::encoding;
+use crate::error::{Frame, Level, Lint, LoxError, Warning};
+use crate::gc::{Collect, Heap, Trace, Tracer};
+#[cfg(feature = "hash")]
+use crate::hash;
+use crate::interpreter;
+#[cfg(all(feature = "keyboard", unix))]
+use crate::keyboard;
+use crate::lox_callable::NativeFunction;
+use crate::path;
+use crate::sandbox::{Capability, SandboxPolicy};
+use crate::scanner::{compare_integer, Literal, Token};
+use crate::symbol::{Symbol, SymbolMap};
+use crate::terminal;
+
+/// A value on the stack of the virtual machine. Strings and objects are shared,
+/// so copying values around never copies what they point to.
+#[derive(Clone)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    /// Numbers written without a decimal point, and what arithmetic on them
+    /// gives while it fits, as in the tree-walker
+    Integer(i64),
+    Number(f64),
+    String(Rc<str>),
+    /// Only ever a constant, for `Op::Closure` to make closures of
+    Function(Rc<Function>),
+    Closure(Rc<Closure>),
+    Native(Rc<Native>),
+    Class(Rc<Class>),
+    Instance(Rc<Instance>),
+    BoundMethod(Rc<BoundMethod>),
+    /// What variables declared without a value hold until they are assigned
+    Unassigned,
+}
+
+impl Value {
+    /// Roughly how many bytes it takes, with the text of strings
+    fn size(&self) -> usize {
+        match self {
+            Value::String(s) => size_of::<Value>() + s.len(),
+            _ => size_of::<Value>(),
+        }
+    }
+
+    /// A number of either kind as a double, for natives taking numbers
+    fn number(&self) -> Option<f64> {
+        match self {
+            Value::Integer(n) => Some(*n as f64),
+            Value::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    fn is_falsey(&self) -> bool {
+        matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    fn equals(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Integer(a), Value::Integer(b)) => a == b,
+            (Value::Integer(a), Value::Number(b)) | (Value::Number(b), Value::Integer(a)) => {
+                compare_integer(*a, *b) == Some(Ordering::Equal)
+            }
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Closure(a), Value::Closure(b)) => Rc::ptr_eq(a, b),
+            (Value::Native(a), Value::Native(b)) => Rc::ptr_eq(a, b),
+            (Value::Class(a), Value::Class(b)) => Rc::ptr_eq(a, b),
+            (Value::Instance(a), Value::Instance(b)) => Rc::ptr_eq(a, b),
+            (Value::BoundMethod(a), Value::BoundMethod(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+/// Printed the way the tree-walker prints the same values
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Nil | Value::Unassigned => write!(f, "nil"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Integer(n) => write!(f, "{}", n),
+            Value::Number(n) => write!(f, "{}", Literal::Double(*n)),
+            Value::String(s) => write!(f, "{}", s),
+            Value::Function(function) => write!(f, "{}", function),
+            Value::Closure(closure) => write!(f, "{}", closure.function),
+            Value::Native(native) => write!(f, "<native fn {}>", native.name),
+            Value::Class(class) => write!(f, "<class {}>", class.name),
+            Value::Instance(instance) => write!(f, "<class {}> instance", instance.class.name),
+            Value::BoundMethod(bound) => write!(f, "{}", bound.method.function),
+        }
+    }
+}
+
+impl Trace for Value {
+    fn trace(&self, tracer: &mut Tracer) {
+        match self {
+            Value::Closure(closure) => closure.trace(tracer),
+            Value::Class(class) => tracer.mark(class),
+            Value::Instance(instance) => tracer.mark(instance),
+            Value::BoundMethod(bound) => {
+                bound.receiver.trace(tracer);
+                bound.method.trace(tracer);
+            }
+            // Functions only ever hold constants
+            Value::Nil
+            | Value::Bool(_)
+            | Value::Integer(_)
+            | Value::Number(_)
+            | Value::String(_)
+            | Value::Function(_)
+            | Value::Native(_)
+            | Value::Unassigned => (),
+        }
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::String(s) => write!(f, "{:?}", s),
+            _ => write!(f, "{}", self),
+        }
+    }
+}
+
+/// Where a closure finds one of the variables it captured
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Capture {
+    /// Whether it is a local of the function right around the closure, or one
+    /// of the upvalues of that function
+    pub local: bool,
+    pub index: u32,
+}
+
+#[derive(Debug)]
+pub struct Function {
+    /// `None` for the top level of a script
+    pub name: Option<Token>,
+    pub arity: usize,
+    pub chunk: Chunk,
+    pub captures: Vec<Capture>,
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "<fn {}>", name.lexeme),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+/// A captured variable, in the stack while the function declaring it runs and
+/// moved out of it when it returns
+#[derive(Debug)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+impl Trace for RefCell<Upvalue> {
+    fn trace(&self, tracer: &mut Tracer) {
+        // Open ones point into the stack, which is traced anyway
+        if let Upvalue::Closed(value) = &*self.borrow() {
+            value.trace(tracer);
+        }
+    }
+}
+
+impl Collect for RefCell<Upvalue> {
+    fn clear(&self) {
+        *self.borrow_mut() = Upvalue::Closed(Value::Nil);
+    }
+
+    fn describe(&self) -> String {
+        "upvalue".to_string()
+    }
+
+    fn size(&self) -> usize {
+        match &*self.borrow() {
+            Upvalue::Open(_) => size_of::<Self>(),
+            Upvalue::Closed(value) => size_of::<Self>() + value.size(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Closure {
+    pub function: Rc<Function>,
+    upvalues: Box<[Rc<RefCell<Upvalue>>]>,
+}
+
+impl Trace for Rc<Closure> {
+    fn trace(&self, tracer: &mut Tracer) {
+        if tracer.visit(self) {
+            self.upvalues.iter().for_each(|x| tracer.mark(x));
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Native {
+    name: &'static str,
+    arity: usize,
+    /// Fails with the message of the runtime error, like the tree-walker's
+    function: fn(&mut Vm, &[Value]) -> Result<Value, String>,
+}
+
+/// What a native turning one string into another returns, like the encodings
+fn text(arguments: &[Value], convert: fn(&str) -> Result<String, String>) -> Result<Value, String> {
+    match &arguments[0] {
+        Value::String(text) => Ok(Value::String(convert(text)?.into())),
+        _ => Err("Argument must be a string.".into()),
+    }
+}
+
+#[derive(Debug)]
+pub struct Class {
+    name: Symbol,
+    /// Inherited ones included, they are copied down when the class is made
+    methods: RefCell<SymbolMap<Rc<Closure>>>,
+}
+
+impl Trace for Class {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.methods.borrow().values().for_each(|x| x.trace(tracer));
+    }
+}
+
+impl Collect for Class {
+    fn clear(&self) {
+        self.methods.borrow_mut().clear();
+    }
+
+    fn describe(&self) -> String {
+        self.name.to_string()
+    }
+
+    fn size(&self) -> usize {
+        size_of::<Self>() + self.methods.borrow().len() * size_of::<(Symbol, Rc<Closure>)>()
+    }
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    class: Rc<Class>,
+    fields: RefCell<SymbolMap<Value>>,
+}
+
+impl Trace for Instance {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.mark(&self.class);
+        self.fields.borrow().values().for_each(|x| x.trace(tracer));
+    }
+}
+
+/// The class is left alone, only the fields can make cycles
+impl Collect for Instance {
+    fn clear(&self) {
+        self.fields.borrow_mut().clear();
+    }
+
+    fn describe(&self) -> String {
+        format!("{} instance", self.class.name)
+    }
+
+    fn size(&self) -> usize {
+        let fields = self.fields.borrow();
+        size_of::<Self>()
+            + fields.len() * size_of::<Symbol>()
+            + fields.values().map(Value::size).sum::<usize>()
+    }
+}
+
+#[derive(Debug)]
+pub struct BoundMethod {
+    receiver: Value,
+    method: Rc<Closure>,
+}
+
+struct CallFrame {
+    closure: Rc<Closure>,
+    /// What the callee is called in stack traces when it isn't the name of
+    /// the function, like the class for initializers
+    name: Option<Symbol>,
+    /// Where to go on with once the call the frame made returns, the current
+    /// instruction of the innermost frame is `Vm::ip`
+    ip: usize,
+    /// Where the callee is on the stack, its locals start right after
+    base: usize,
+    /// Traces the call until the frame goes
+    _span: CallSpan,
+}
+
+/// Runs the bytecode the compiler makes of scripts. Behaves just like the
+/// tree-walking `Interpreter`, which stays the reference for what Lox programs
+/// do, only faster.
+pub struct Vm {
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    /// The next instruction of the innermost frame, kept out of it so that
+    /// fetching instructions doesn't have to go through the frames
+    ip: usize,
+    /// The upvalues still pointing into the stack, by slot
+    open_upvalues: Vec<(usize, Rc<RefCell<Upvalue>>)>,
+    /// Numbered by the compiler the first time it sees their name, `None`
+    /// until they are actually declared
+    globals: Vec<Option<Value>>,
+    indices: SymbolMap<usize>,
+    /// The globals declared with `const`, by index. The resolver already stops
+    /// the assignments it can see, which leaves those in functions declared
+    /// before the constant.
+    constants: HashSet<usize>,
+    /// Name of the script being run, used when its tokens don't carry a file name
+    pub script: Rc<str>,
+    /// What `print` writes to
+    pub stdout: Output,
+    limits: Limits,
+    /// What the natives reaching outside of the VM may do
+    policy: SandboxPolicy,
+    /// Instructions executed, which is what `max_steps` counts here
+    steps: u64,
+    output_bytes: usize,
+    /// What to do about a number added to a string, `Deny` in strict mode
+    concat: Level,
+    /// Found while running, for the caller to report
+    warnings: Vec<Warning>,
+    init: Symbol,
+    /// The upvalues, classes and instances made while running, for the cycles
+    /// between them to be collected
+    heap: Heap,
+}
+
+impl Vm {
+    pub fn with_config(config: &Config) -> Self {
+        let mut vm = Vm {
+            stack: Vec::new(),
+            frames: Vec::new(),
+            ip: 0,
+            open_upvalues: Vec::new(),
+            globals: Vec::new(),
+            indices: SymbolMap::default(),
+            constants: HashSet::new(),
+            script: Rc::from("<script>"),
+            stdout: Box::new(io::stdout()),
+            limits: config.effective_limits(),
+            policy: config.sandbox,
+            steps: 0,
+            output_bytes: 0,
+            concat: if config.strict {
+                Level::Deny
+            } else {
+                config.level(Lint::Concat)
+            },
+            warnings: Vec::new(),
+            init: Symbol::intern("init"),
+            heap: Heap::new(),
+        };
+        let natives = [
+            Native {
+                name: "clock",
+                arity: 0,
+                function: |_, _| Ok(Value::Number(interpreter::clock())),
+            },
+            Native {
+                name: "println",
+                arity: 1,
+                function: |vm, arguments| {
+                    vm.print(&arguments[0], true)?;
+                    Ok(Value::Nil)
+                },
+            },
+            Native {
+                name: "printRaw",
+                arity: 1,
+                function: |vm, arguments| {
+                    vm.print(&arguments[0], false)?;
+                    Ok(Value::Nil)
+                },
+            },
+            Native {
+                name: "clearScreen",
+                arity: 0,
+                function: |vm, _| {
+                    vm.print(&Value::String(terminal::CLEAR_SCREEN.into()), false)?;
+                    Ok(Value::Nil)
+                },
+            },
+            Native {
+                name: "moveCursor",
+                arity: 2,
+                function: |vm, arguments| {
+                    let (Some(row), Some(column)) = (arguments[0].number(), arguments[1].number())
+                    else {
+                        return Err("Rows and columns must be whole numbers from 1.".into());
+                    };
+                    let escape = terminal::move_cursor(row, column)?;
+                    vm.print(&Value::String(escape.into()), false)?;
+                    Ok(Value::Nil)
+                },
+            },
+            Native {
+                name: "setColor",
+                arity: 1,
+                function: |vm, arguments| {
+                    let Value::String(name) = &arguments[0] else {
+                        return Err("Color names must be strings.".into());
+                    };
+                    let escape = terminal::set_color(name)?;
+                    vm.print(&Value::String(escape.into()), false)?;
+                    Ok(Value::Nil)
+                },
+            },
+            Native {
+                name: "terminalSize",
+                arity: 0,
+                function: |vm, _| {
+                    let (rows, columns) = terminal::size();
+                    let class = Rc::new(Class {
+                        name: Symbol::intern("TerminalSize"),
+                        methods: RefCell::new(SymbolMap::default()),
+                    });
+                    let fields = [("rows", rows), ("columns", columns)]
+                        .map(|(name, n)| (Symbol::intern(name), Value::Integer(n.into())));
+                    let instance = Rc::new(Instance {
+                        class,
+                        fields: RefCell::new(fields.into_iter().collect()),
+                    });
+                    vm.heap.register(&instance);
+                    vm.heap.allocate(instance.size());
+                    Ok(Value::Instance(instance))
+                },
+            },
+            #[cfg(not(target_arch = "wasm32"))]
+            Native {
+                name: "sleep",
+                arity: 1,
+                function: |vm, arguments| {
+                    vm.policy.require(Capability::Waiting)?;
+                    let Some(milliseconds) = arguments[0].number() else {
+                        return Err("Milliseconds must be a number.".into());
+                    };
+                    terminal::sleep(milliseconds)?;
+                    Ok(Value::Nil)
+                },
+            },
+            #[cfg(all(feature = "keyboard", unix))]
+            Native {
+                name: "pollKey",
+                arity: 0,
+                function: |_, _| {
+                    Ok(keyboard::poll().map_or(Value::Nil, |x| Value::String(x.into())))
+                },
+            },
+            Native {
+                name: "base64Encode",
+                arity: 1,
+                function: |_, arguments| text(arguments, |x| Ok(encoding::base64_encode(x))),
+            },
+            Native {
+                name: "base64Decode",
+                arity: 1,
+                function: |_, arguments| text(arguments, encoding::base64_decode),
+            },
+            Native {
+                name: "hexEncode",
+                arity: 1,
+                function: |_, arguments| text(arguments, |x| Ok(encoding::hex_encode(x))),
+            },
+            Native {
+                name: "hexDecode",
+                arity: 1,
+                function: |_, arguments| text(arguments, encoding::hex_decode),
+            },
+            Native {
+                name: "pathJoin",
+                arity: 2,
+                function: |_, arguments| match (&arguments[0], &arguments[1]) {
+                    (Value::String(a), Value::String(b)) => {
+                        Ok(Value::String(path::join(a, b).into()))
+                    }
+                    _ => Err("Paths must be strings.".into()),
+                },
+            },
+            Native {
+                name: "basename",
+                arity: 1,
+                function: |_, arguments| text(arguments, |x| Ok(path::basename(x))),
+            },
+            Native {
+                name: "dirname",
+                arity: 1,
+                function: |_, arguments| text(arguments, |x| Ok(path::dirname(x))),
+            },
+            Native {
+                name: "extension",
+                arity: 1,
+                function: |_, arguments| text(arguments, |x| Ok(path::extension(x))),
+            },
+            Native {
+                name: "absolutePath",
+                arity: 1,
+                function: |vm, arguments| {
+                    vm.policy.require(Capability::Filesystem)?;
+                    text(arguments, path::absolute)
+                },
+            },
+            #[cfg(feature = "hash")]
+            Native {
+                name: "sha256",
+                arity: 1,
+                function: |_, arguments| text(arguments, |x| Ok(hash::sha256(x))),
+            },
+            #[cfg(feature = "hash")]
+            Native {
+                name: "md5",
+                arity: 1,
+                function: |_, arguments| text(arguments, |x| Ok(hash::md5(x))),
+            },
+            Native {
+                name: "assert",
+                arity: 1,
+                function: |_, arguments| {
+                    if arguments[0].is_falsey() {
+                        Err("Assertion failed.".into())
+                    } else {
+                        Ok(Value::Nil)
+                    }
+                },
+            },
+            Native {
+                name: "assertEqual",
+                arity: 2,
+                function: |_, arguments| {
+                    let (actual, expected) = (&arguments[0], &arguments[1]);
+                    if actual.equals(expected) {
+                        Ok(Value::Nil)
+                    } else {
+                        Err(format!("Expected {} but got {}.", expected, actual))
+                    }
+                },
+            },
+        ];
+        // Only a name when it isn't a keyword
+        let print = Native {
+            name: "print",
+            arity: 1,
+            function: |vm, arguments| {
+                vm.print(&arguments[0], true)?;
+                Ok(Value::Nil)
+            },
+        };
+        let natives = natives
+            .into_iter()
+            .chain(config.native_print.then_some(print));
+        for native in natives {
+            if config.native_enabled(native.name) {
+                let index = vm.global_index(&Symbol::intern(native.name));
+                vm.globals[index] = Some(Value::Native(Rc::new(native)));
+            }
+        }
+        vm
+    }
+
+    /// The index of a global variable, whether it has been declared yet or not
+    pub fn global_index(&mut self, name: &Symbol) -> usize {
+        if let Some(&index) = self.indices.get(name) {
+            return index;
+        }
+        self.globals.push(None);
+        self.indices.insert(name.clone(), self.globals.len() - 1);
+        self.globals.len() - 1
+    }
+
+    /// The names of the globals, by index
+    pub fn global_names(&self) -> Vec<Symbol> {
+        let mut names = vec![Symbol::intern(""); self.globals.len()];
+        for (name, &index) in &self.indices {
+            names[index] = name.clone();
+        }
+        names
+    }
+
+    /// The warnings found while running since the last call
+    pub fn take_warnings(&mut self) -> Vec<Warning> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    /// Runs a compiled script. Its globals stay around for the next one, but
+    /// nothing else does, errors or not.
+    pub fn interpret(&mut self, function: Rc<Function>) -> Result<(), LoxError> {
+        let closure = Rc::new(Closure {
+            function,
+            upvalues: Box::new([]),
+        });
+        self.stack.push(Value::Closure(closure.clone()));
+        self.frames.push(CallFrame {
+            closure,
+            name: None,
+            ip: 0,
+            base: 0,
+            _span: CallSpan::enter(Span::none()),
+        });
+        self.ip = 0;
+        let result = self
+            .run()
+            .map_err(|e| e.in_frames(self.trace().into_iter()));
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+        result
+    }
+
+    /// The calls in progress, outermost first, each with where it was made from
+    fn trace(&self) -> Vec<Frame> {
+        self.frames
+            .windows(2)
+            .map(|pair| {
+                let site = pair[0].closure.function.chunk.site(pair[0].ip - 1);
+                let paren = site.paren.as_ref().unwrap_or(&site.token);
+                Frame {
+                    function: match (&pair[1].name, &pair[1].closure.function.name) {
+                        (Some(name), _) | (None, Some(Token { lexeme: name, .. })) => {
+                            name.to_string()
+                        }
+                        (None, None) => String::new(),
+                    },
+                    file: paren.file.clone().unwrap_or_else(|| self.script.clone()),
+                    line: paren.line,
+                }
+            })
+            .collect()
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("A script is always running")
+    }
+
+    /// Where the instruction running came from
+    fn site(&self) -> &Site {
+        self.frame().closure.function.chunk.site(self.ip - 1)
+    }
+
+    /// Prints `value` like the tree-walker, on a line of its own when
+    /// `newline`, counting it against the output limit
+    fn print(&mut self, value: &Value, newline: bool) -> Result<(), String> {
+        // Strings are printed straight from the value, without a copy
+        let output = match value {
+            Value::String(s) => Cow::Borrowed(&**s),
+            _ => Cow::Owned(value.to_string()),
+        };
+        self.output_bytes += output.len() + usize::from(newline);
+        if self
+            .limits
+            .max_output_bytes
+            .is_some_and(|max| self.output_bytes > max)
+        {
+            return Err("Output limit exceeded.".into());
+        }
+        let printed = if newline {
+            writeln!(self.stdout, "{}", output)
+        } else {
+            write!(self.stdout, "{}", output).and_then(|_| self.stdout.flush())
+        };
+        printed.map_err(|e| format!("Couldn't print: {}.", e))
+    }
+
+    fn error(&self, message: impl Into<String>) -> LoxError {
+        LoxError::runtime(&self.site().token, message)
+    }
+
+    /// An error calling something, which points at the parenthesis of the call
+    fn call_error(&self, message: impl Into<String>) -> LoxError {
+        let site = self.site();
+        LoxError::runtime(site.paren.as_ref().unwrap_or(&site.token), message)
+    }
+
+    /// Properties looked up on a `nil` are mostly a mistake, which deserves
+    /// saying where it came from
+    fn not_an_instance(&self, message: &str, object: &Value) -> LoxError {
+        let hint = match (&self.site().subject, object) {
+            (Some(subject), Value::Nil) => {
+                format!(" '{}' is nil; did you forget to initialize it?", subject)
+            }
+            _ => String::new(),
+        };
+        self.error(format!("{}{}", message, hint))
+    }
+
+    /// Pushes the value of a variable, which has to have been assigned
+    fn push_variable(&mut self, value: Value) -> Result<(), LoxError> {
+        if let Value::Unassigned = value {
+            let name = &self.site().token.lexeme;
+            return Err(self.error(format!("Variable '{}' used before being assigned.", name)));
+        }
+        self.stack.push(value);
+        Ok(())
+    }
+
+    fn undefined(&self) -> LoxError {
+        self.error(format!(
+            "Undefined variable '{}'.",
+            self.site().token.lexeme
+        ))
+    }
+
+    fn peek(&self, distance: usize) -> &Value {
+        &self.stack[self.stack.len() - 1 - distance]
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack
+            .pop()
+            .expect("The compiler keeps the stack balanced")
+    }
+
+    fn constant(&self, index: u32) -> &Value {
+        &self.frame().closure.function.chunk.constants[index as usize]
+    }
+
+    fn name(&self, index: u32) -> Symbol {
+        self.frame().closure.function.chunk.names[index as usize].clone()
+    }
+
+    /// Replaces the two numbers on top of the stack with what `exact` makes of
+    /// them when they are both integers and it gives anything, like when the
+    /// result fits, and with what `op` makes of them as doubles otherwise
+    fn arithmetic(
+        &mut self,
+        exact: impl Fn(i64, i64) -> Option<Value>,
+        op: impl Fn(f64, f64) -> Value,
+    ) -> Result<(), LoxError> {
+        let len = self.stack.len();
+        let (a, b) = (&self.stack[len - 2], &self.stack[len - 1]);
+        let value = match (a, b) {
+            (Value::Integer(a), Value::Integer(b)) => exact(*a, *b),
+            _ => None,
+        };
+        let value = match (value, a.number(), b.number()) {
+            (Some(value), _, _) => value,
+            (None, Some(a), Some(b)) => op(a, b),
+            _ => return Err(self.error("Operands must be numbers.")),
+        };
+        self.stack.pop();
+        self.stack[len - 2] = value;
+        Ok(())
+    }
+
+    /// Replaces the two numbers on top of the stack with whether `test` holds
+    /// for how they compare, exactly, even where doubles can't tell integers
+    /// apart
+    fn compare(&mut self, test: fn(Ordering) -> bool) -> Result<(), LoxError> {
+        let len = self.stack.len();
+        let ordering = match (&self.stack[len - 2], &self.stack[len - 1]) {
+            (Value::Integer(a), Value::Integer(b)) => Some(a.cmp(b)),
+            (Value::Integer(a), Value::Number(b)) => compare_integer(*a, *b),
+            (Value::Number(a), Value::Integer(b)) => compare_integer(*b, *a).map(Ordering::reverse),
+            (Value::Number(a), Value::Number(b)) => a.partial_cmp(b),
+            _ => return Err(self.error("Operands must be numbers.")),
+        };
+        self.stack.pop();
+        self.stack[len - 2] = Value::Bool(ordering.is_some_and(test));
+        Ok(())
+    }
+
+    fn run(&mut self) -> Result<(), LoxError> {
+        let max_steps = self.limits.max_steps.unwrap_or(u64::MAX);
+        let max_memory = self.limits.max_memory_bytes.unwrap_or(usize::MAX);
+        // Only change with the frame, on calls and returns
+        let (mut function, mut base) = self.current();
+        loop {
+            // In between instructions, everything still needed is in the VM
+            if self.heap.should_collect() || self.heap.bytes() > max_memory {
+                self.collect();
+            }
+            let op = function.chunk.code[self.ip];
+            self.ip += 1;
+            self.steps += 1;
+            if self.steps > max_steps {
+                return Err(self.error("Execution limit exceeded."));
+            }
+            if self.heap.bytes() > max_memory {
+                return Err(self.error("Memory limit exceeded."));
+            }
+            match op {
+                Op::Constant(index) => {
+                    let value = function.chunk.constants[index as usize].clone();
+                    self.stack.push(value);
+                }
+                Op::Nil => self.stack.push(Value::Nil),
+                Op::True => self.stack.push(Value::Bool(true)),
+                Op::False => self.stack.push(Value::Bool(false)),
+                Op::Unassigned => self.stack.push(Value::Unassigned),
+                Op::Pop => {
+                    self.pop();
+                }
+                Op::GetLocal(slot) => {
+                    self.push_variable(self.stack[base + slot as usize].clone())?;
+                }
+                Op::SetLocal(slot) => {
+                    self.stack[base + slot as usize] = self.peek(0).clone();
+                }
+                Op::GetGlobal(index) => match &self.globals[index as usize] {
+                    Some(value) => self.push_variable(value.clone())?,
+                    None => return Err(self.undefined()),
+                },
+                Op::DefineGlobal(index) => {
+                    self.globals[index as usize] = Some(self.pop());
+                    // Declared again, it is whatever the new declaration says
+                    self.constants.remove(&(index as usize));
+                }
+                Op::DefineConstant(index) => {
+                    self.globals[index as usize] = Some(self.pop());
+                    self.constants.insert(index as usize);
+                }
+                Op::SetGlobal(index) => {
+                    if self.globals[index as usize].is_none() {
+                        return Err(self.undefined());
+                    }
+                    if self.constants.contains(&(index as usize)) {
+                        return Err(self.error(format!(
+                            "Can't assign to constant '{}'.",
+                            self.site().token.lexeme
+                        )));
+                    }
+                    self.globals[index as usize] = Some(self.peek(0).clone());
+                }
+                Op::GetUpvalue(index) => {
+                    let value = match &*self.frame().closure.upvalues[index as usize].borrow() {
+                        Upvalue::Open(slot) => self.stack[*slot].clone(),
+                        Upvalue::Closed(value) => value.clone(),
+                    };
+                    self.push_variable(value)?;
+                }
+                Op::SetUpvalue(index) => {
+                    let value = self.peek(0).clone();
+                    let upvalue = self.frame().closure.upvalues[index as usize].clone();
+                    let mut upvalue = upvalue.borrow_mut();
+                    match &mut *upvalue {
+                        Upvalue::Open(slot) => self.stack[*slot] = value,
+                        Upvalue::Closed(closed) => *closed = value,
+                    }
+                }
+                Op::GetProperty(name) => {
+                    let Value::Instance(instance) = self.peek(0) else {
+                        return Err(
+                            self.not_an_instance("Only instances have properties.", self.peek(0))
+                        );
+                    };
+                    let instance = instance.clone();
+                    let name = self.name(name);
+                    let field = instance.fields.borrow().get(&name).cloned();
+                    let value = match field {
+                        Some(value) => value,
+                        None => {
+                            self.bind(&instance.class, &name, Value::Instance(instance.clone()))?
+                        }
+                    };
+                    self.pop();
+                    self.stack.push(value);
+                }
+                Op::SetProperty(name) => {
+                    let Value::Instance(instance) = self.peek(1) else {
+                        return Err(
+                            self.not_an_instance("Only instances have fields.", self.peek(1))
+                        );
+                    };
+                    let instance = instance.clone();
+                    let value = self.pop();
+                    self.heap.allocate(size_of::<Symbol>() + value.size());
+                    instance
+                        .fields
+                        .borrow_mut()
+                        .insert(self.name(name), value.clone());
+                    self.pop();
+                    self.stack.push(value);
+                }
+                Op::GetSuper(name) => {
+                    let Value::Class(superclass) = self.pop() else {
+                        unreachable!("'super' is always a class")
+                    };
+                    let receiver = self.pop();
+                    let method = self.bind(&superclass, &self.name(name), receiver)?;
+                    self.stack.push(method);
+                }
+                Op::Equal => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    self.stack.push(Value::Bool(a.equals(&b)));
+                }
+                Op::Greater => self.compare(Ordering::is_gt)?,
+                Op::GreaterEqual => self.compare(Ordering::is_ge)?,
+                Op::Less => self.compare(Ordering::is_lt)?,
+                Op::LessEqual => self.compare(Ordering::is_le)?,
+                Op::Add if self.peek(0).number().is_some() && self.peek(1).number().is_some() => {
+                    self.arithmetic(
+                        |a, b| a.checked_add(b).map(Value::Integer),
+                        |a, b| Value::Number(a + b),
+                    )?
+                }
+                Op::Add => {
+                    let len = self.stack.len();
+                    let value = match (&self.stack[len - 2], &self.stack[len - 1]) {
+                        (Value::String(a), Value::String(b)) => self.string(format!("{}{}", a, b)),
+                        (Value::String(a), Value::Integer(b)) => {
+                            let value = self.string(format!("{}{}", a, b));
+                            self.concat()?;
+                            value
+                        }
+                        (Value::Integer(a), Value::String(b)) => {
+                            let value = self.string(format!("{}{}", a, b));
+                            self.concat()?;
+                            value
+                        }
+                        (Value::String(a), Value::Number(b)) => {
+                            let value = self.string(format!("{}{}", a, b));
+                            self.concat()?;
+                            value
+                        }
+                        (Value::Number(a), Value::String(b)) => {
+                            let value = self.string(format!("{}{}", a, b));
+                            self.concat()?;
+                            value
+                        }
+                        _ => return Err(self.error("Operands must be two numbers or two strings.")),
+                    };
+                    self.stack.pop();
+                    self.stack[len - 2] = value;
+                }
+                Op::Subtract => self.arithmetic(
+                    |a, b| a.checked_sub(b).map(Value::Integer),
+                    |a, b| Value::Number(a - b),
+                )?,
+                Op::Multiply => self.arithmetic(
+                    |a, b| a.checked_mul(b).map(Value::Integer),
+                    |a, b| Value::Number(a * b),
+                )?,
+                // Like any arithmetic with a double in it, even for integers
+                Op::Divide => self.arithmetic(|_, _| None, |a, b| Value::Number(a / b))?,
+                Op::Not => {
+                    let value = self.pop();
+                    self.stack.push(Value::Bool(value.is_falsey()));
+                }
+                Op::Negate => match self.peek(0) {
+                    Value::Integer(n) => {
+                        let n = n
+                            .checked_neg()
+                            .map_or(Value::Number(-(*n as f64)), Value::Integer);
+                        self.pop();
+                        self.stack.push(n);
+                    }
+                    Value::Number(n) => {
+                        let n = -n;
+                        self.pop();
+                        self.stack.push(Value::Number(n));
+                    }
+                    _ => return Err(self.error("Operand must be a number.")),
+                },
+                Op::Print => {
+                    let value = self.pop();
+                    if let Err(e) = self.print(&value, true) {
+                        return Err(self.error(e));
+                    }
+                }
+                Op::Jump(target) => self.jump(target),
+                Op::JumpIfFalse(target) => {
+                    if self.peek(0).is_falsey() {
+                        self.jump(target);
+                    }
+                }
+                Op::JumpIfTrue(target) => {
+                    if !self.peek(0).is_falsey() {
+                        self.jump(target);
+                    }
+                }
+                Op::Call(argc) => {
+                    self.call_value(argc as usize)?;
+                    (function, base) = self.current();
+                }
+                Op::Invoke(name, argc) => {
+                    self.invoke(name, argc as usize)?;
+                    (function, base) = self.current();
+                }
+                Op::SuperInvoke(name, argc) => {
+                    let Value::Class(superclass) = self.pop() else {
+                        unreachable!("'super' is always a class")
+                    };
+                    let name = self.name(name);
+                    let method = superclass.methods.borrow().get(&name).cloned();
+                    match method {
+                        Some(method) => self.call_closure(method, argc as usize, None)?,
+                        None => return Err(self.error(format!("Undefined property '{}'.", name))),
+                    }
+                    (function, base) = self.current();
+                }
+                Op::Closure(index) => {
+                    let Value::Function(function) = self.constant(index).clone() else {
+                        unreachable!("Closures are made of function constants")
+                    };
+                    let upvalues = function
+                        .captures
+                        .iter()
+                        .map(|capture| match capture.local {
+                            true => self.capture(base + capture.index as usize),
+                            false => self.frame().closure.upvalues[capture.index as usize].clone(),
+                        })
+                        .collect();
+                    let closure = Closure { function, upvalues };
+                    self.stack.push(Value::Closure(Rc::new(closure)));
+                }
+                Op::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                }
+                Op::Return => {
+                    let result = self.pop();
+                    self.close_upvalues(base);
+                    self.frames.pop();
+                    self.stack.truncate(base);
+                    let Some(caller) = self.frames.last() else {
+                        return Ok(());
+                    };
+                    self.ip = caller.ip;
+                    self.stack.push(result);
+                    (function, base) = self.current();
+                }
+                Op::Class(name) => {
+                    let class = Rc::new(Class {
+                        name: self.name(name),
+                        methods: RefCell::new(SymbolMap::default()),
+                    });
+                    self.heap.register(&class);
+                    self.stack.push(Value::Class(class));
+                }
+                Op::Inherit => {
+                    let Value::Class(superclass) = self.peek(1) else {
+                        return Err(self.error("Superclass must be a class."));
+                    };
+                    let Value::Class(class) = self.peek(0) else {
+                        unreachable!("Only classes inherit")
+                    };
+                    class
+                        .methods
+                        .borrow_mut()
+                        .extend(superclass.methods.borrow().clone());
+                    self.pop();
+                }
+                Op::Method(name) => {
+                    let Value::Closure(method) = self.pop() else {
+                        unreachable!("Methods are closures")
+                    };
+                    let Value::Class(class) = self.peek(0) else {
+                        unreachable!("Methods are added to classes")
+                    };
+                    class.methods.borrow_mut().insert(self.name(name), method);
+                }
+            }
+        }
+    }
+
+    fn jump(&mut self, target: u32) {
+        self.ip = target as usize;
+    }
+
+    /// The function running and where its slots start
+    fn current(&self) -> (Rc<Function>, usize) {
+        let frame = self.frame();
+        (frame.closure.function.clone(), frame.base)
+    }
+
+    /// A string made while running, accounted for against `max-memory-bytes`
+    fn string(&mut self, text: String) -> Value {
+        self.heap.allocate(size_of::<Value>() + text.len());
+        Value::String(text.into())
+    }
+
+    /// Adding a number and a string is an extension to the book, which some
+    /// want to be warned about or to keep out altogether
+    fn concat(&mut self) -> Result<(), LoxError> {
+        let op = &self.site().token;
+        match self.concat {
+            Level::Allow => Ok(()),
+            // Once per `+`, however many times it runs
+            Level::Warn if self.warnings.iter().any(|x| *x.token == *op) => Ok(()),
+            Level::Warn => {
+                self.warnings.push(Warning {
+                    lint: Lint::Concat,
+                    token: Box::new(op.clone()),
+                    message: "Number implicitly converted to a string.".into(),
+                    note: None,
+                });
+                Ok(())
+            }
+            Level::Deny => Err(self.error("Operands must be two numbers or two strings.")),
+        }
+    }
+
+    /// The method `name` of `class`, bound to `receiver`
+    fn bind(&self, class: &Class, name: &Symbol, receiver: Value) -> Result<Value, LoxError> {
+        match class.methods.borrow().get(name) {
+            Some(method) => Ok(Value::BoundMethod(Rc::new(BoundMethod {
+                receiver,
+                method: method.clone(),
+            }))),
+            None => Err(self.error(format!("Undefined property '{}'.", name))),
+        }
+    }
+
+    fn invoke(&mut self, name: u32, argc: usize) -> Result<(), LoxError> {
+        let Value::Instance(instance) = self.peek(argc) else {
+            return Err(self.not_an_instance("Only instances have properties.", self.peek(argc)));
+        };
+        let instance = instance.clone();
+        let name = self.name(name);
+        if let Some(field) = instance.fields.borrow().get(&name) {
+            let slot = self.stack.len() - argc - 1;
+            self.stack[slot] = field.clone();
+            return self.call_value(argc);
+        }
+        let method = instance.class.methods.borrow().get(&name).cloned();
+        match method {
+            Some(method) => self.call_closure(method, argc, None),
+            None => Err(self.error(format!("Undefined property '{}'.", name))),
+        }
+    }
+
+    fn call_value(&mut self, argc: usize) -> Result<(), LoxError> {
+        let slot = self.stack.len() - argc - 1;
+        match self.stack[slot].clone() {
+            Value::Closure(closure) => self.call_closure(closure, argc, None),
+            Value::BoundMethod(bound) => {
+                self.stack[slot] = bound.receiver.clone();
+                self.call_closure(bound.method.clone(), argc, None)
+            }
+            Value::Class(class) => {
+                let instance = Rc::new(Instance {
+                    class: class.clone(),
+                    fields: RefCell::new(SymbolMap::default()),
+                });
+                self.heap.register(&instance);
+                self.heap.allocate(instance.size());
+                self.stack[slot] = Value::Instance(instance);
+                let initializer = class.methods.borrow().get(&self.init).cloned();
+                match initializer {
+                    Some(initializer) => self.call_closure(initializer, argc, Some(&class.name)),
+                    None => self.check_call(&class.name, 0, None, argc),
+                }
+            }
+            Value::Native(native) => {
+                self.check_call(native.name, native.arity, None, argc)?;
+                // Natives may print, which takes the whole VM
+                let arguments = self.stack.split_off(slot + 1);
+                let result = (native.function)(self, &arguments).map_err(NativeFunction::error)?;
+                self.stack.truncate(slot);
+                self.stack.push(result);
+                Ok(())
+            }
+            _ => Err(self.call_error("Can only call functions and classes.")),
+        }
+    }
+
+    /// Checks the arguments and the depth the way the tree-walker does, with
+    /// the same messages
+    fn check_call(
+        &self,
+        name: &str,
+        arity: usize,
+        declaration: Option<&Token>,
+        argc: usize,
+    ) -> Result<(), LoxError> {
+        if argc != arity {
+            let declared = declaration
+                .map(|x| format!(" declared at {}", x.location()))
+                .unwrap_or_default();
+            return Err(self.call_error(format!(
+                "Expected {} arguments but got {} in call to '{}'{}.",
+                arity, argc, name, declared
+            )));
+        }
+        // The script itself isn't a call
+        if self
+            .limits
+            .max_stack_depth
+            .is_some_and(|max| self.frames.len() > max)
+        {
+            return Err(self.call_error("Stack overflow."));
+        }
+        Ok(())
+    }
+
+    /// Calls a closure with its receiver or itself and the arguments already
+    /// on the stack. Initializers are called by the name of their `class`.
+    fn call_closure(
+        &mut self,
+        closure: Rc<Closure>,
+        argc: usize,
+        class: Option<&Symbol>,
+    ) -> Result<(), LoxError> {
+        let function = &closure.function;
+        let name = function.name.as_ref().expect("Only scripts have no name");
+        self.check_call(
+            class.unwrap_or(&name.lexeme),
+            function.arity,
+            Some(name),
+            argc,
+        )?;
+        // The script is the first frame, so calls are one deeper than there are frames
+        let span = debug_span!(
+            "call",
+            function = %class.unwrap_or(&name.lexeme),
+            depth = self.frames.len(),
+            line = self.site().token.line
+        );
+        if let Some(caller) = self.frames.last_mut() {
+            caller.ip = self.ip;
+        }
+        self.ip = 0;
+        self.frames.push(CallFrame {
+            closure,
+            name: class.cloned(),
+            ip: 0,
+            base: self.stack.len() - argc - 1,
+            _span: CallSpan::enter(span),
+        });
+        Ok(())
+    }
+
+    /// The upvalue for a stack slot, shared by all the closures capturing it
+    fn capture(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+        if let Some((_, upvalue)) = self.open_upvalues.iter().rev().find(|(x, _)| *x == slot) {
+            return upvalue.clone();
+        }
+        let upvalue = Rc::new(RefCell::new(Upvalue::Open(slot)));
+        self.heap.register(&upvalue);
+        self.heap.allocate(upvalue.size());
+        self.open_upvalues.push((slot, upvalue.clone()));
+        upvalue
+    }
+
+    /// Frees the upvalues, classes and instances the program can't get to
+    /// anymore
+    fn collect(&mut self) {
+        let Vm {
+            stack,
+            frames,
+            open_upvalues,
+            globals,
+            heap,
+            ..
+        } = self;
+        heap.collect(|tracer: &mut Tracer| {
+            for value in stack.iter().chain(globals.iter().flatten()) {
+                tracer.count(value.size());
+                value.trace(tracer);
+            }
+            frames.iter().for_each(|x| x.closure.trace(tracer));
+            open_upvalues.iter().for_each(|(_, x)| tracer.mark(x));
+        });
+    }
+
+    /// Moves the variables from `slot` up out of the stack, into their upvalues
+    fn close_upvalues(&mut self, slot: usize) {
+        let stack = &self.stack;
+        self.open_upvalues.retain(|(x, upvalue)| {
+            if *x < slot {
+                return true;
+            }
+            *upvalue.borrow_mut() = Upvalue::Closed(stack[*x].clone());
+            false
+        });
+    }
+}
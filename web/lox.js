@@ -0,0 +1,32 @@
+// Runs Lox scripts in the browser, on the interpreter built for
+// wasm32-unknown-unknown and passed through wasm-bindgen as the README says:
+//
+//     const lox = await load("pkg/rjlox_bg.wasm");
+//     const { stdout, errors } = lox.run('print "Hello, world!";');
+//     const parts = lox.highlight('print "Hello, world!";');
+
+import init, { run, highlight } from "./pkg/rjlox.js";
+
+// `module` is where to fetch the module from, or its bytes
+export async function load(module) {
+  await init({ module_or_path: module });
+  return {
+    run(source) {
+      return JSON.parse(run(source));
+    },
+    // The parts of the source to highlight, in order, as { start, end, kind }
+    // with kind one of keyword, constant, number, string, identifier,
+    // operator, punctuation, comment and error. Whitespace is left out.
+    highlight(source) {
+      const bytes = new TextEncoder().encode(source);
+      // The spans count bytes of UTF-8, where strings count UTF-16 units
+      const decoder = new TextDecoder();
+      const index = (offset) => decoder.decode(bytes.subarray(0, offset)).length;
+      return JSON.parse(highlight(source)).map(([span, kind]) => ({
+        start: index(span.start),
+        end: index(span.end),
+        kind,
+      }));
+    },
+  };
+}
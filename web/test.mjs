@@ -0,0 +1,30 @@
+// Runs lox.js on the module built for the browser, as CI does:
+//
+//     cargo rustc --release --lib --target wasm32-unknown-unknown --crate-type cdylib
+//     wasm-bindgen --target web --out-dir web/pkg target/wasm32-unknown-unknown/release/rjlox.wasm
+//     node web/test.mjs
+
+import assert from "node:assert/strict";
+import { readFile } from "node:fs/promises";
+import { load } from "./lox.js";
+
+const lox = await load(await readFile(new URL("pkg/rjlox_bg.wasm", import.meta.url)));
+
+assert.deepEqual(lox.run('print "Hello, " + "world!";'), {
+  stdout: "Hello, world!\n",
+  errors: [],
+});
+assert.deepEqual(lox.run("print 1 +;"), {
+  stdout: "",
+  errors: ["[line 1:10] Error at ';': Expect expression."],
+});
+assert.equal(lox.run("print clock() > 0;").stdout, "true\n");
+assert.match(lox.run("while (true) {}").errors[0], /RuntimeError/);
+
+assert.deepEqual(lox.highlight('print "é";'), [
+  { start: 0, end: 5, kind: "keyword" },
+  { start: 6, end: 9, kind: "string" },
+  { start: 9, end: 10, kind: "punctuation" },
+]);
+
+console.log("lox.js works");